@@ -0,0 +1,345 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+use walkdir::WalkDir;
+
+const SCHEME: &str = "adb://";
+
+/// Remembers what was pushed to the phone, so only files that changed since are pushed again.
+/// Kept in the staging directory, next to the records.
+const MANIFEST_FILENAME: &str = ".syncbops_adb.json";
+
+/// An Android phone as the target library, given like `adb://<serial>/sdcard/Music`. Leave the
+/// serial out (`adb:///sdcard/Music`) if only one phone is connected.
+///
+/// The songs are synchronised into a staging directory on this computer as usual, which also
+/// holds the records. Afterwards, whatever changed in there is pushed to the phone with adb.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdbTarget {
+    pub serial: Option<String>,
+    /// The directory on the phone, like `/sdcard/Music`.
+    pub root: String,
+}
+
+/// How a file in the staging directory was when it was pushed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct FileState {
+    size: u64,
+    /// Seconds since the unix epoch.
+    modified: u64,
+}
+
+/// What was done to the phone.
+#[derive(Debug, Default)]
+pub struct MirrorStats {
+    pub pushed: usize,
+    pub removed: usize,
+    pub failed: Vec<(PathBuf, AdbError)>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AdbError {
+    #[error("could not run adb. Is it installed, and on the PATH?")]
+    Io(#[from] std::io::Error),
+    #[error("'{target}' is not a valid adb target. Use adb://<serial>/<directory on the phone>, like adb://R58M12345/sdcard/Music")]
+    InvalidTarget { target: String },
+    #[error("adb {action} failed: {stderr}")]
+    Failed { action: String, stderr: String },
+    #[error("Could not find a directory to stage the songs in before pushing them. Pass one with --adb-staging.")]
+    NoStagingDirectory,
+    #[error("Could not create the staging directory {path}")]
+    StagingDirectory {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+impl AdbTarget {
+    /// None if the target library is just a directory.
+    pub fn parse(target_library: &Path) -> Option<Result<AdbTarget, AdbError>> {
+        let target = target_library.to_str()?;
+        let rest = target.strip_prefix(SCHEME)?;
+        let invalid = || AdbError::InvalidTarget {
+            target: target.to_owned(),
+        };
+        let Some((serial, root)) = rest.split_once('/') else {
+            return Some(Err(invalid()));
+        };
+        let root = format!("/{}", root.trim_end_matches('/'));
+        if root == "/" {
+            return Some(Err(invalid()));
+        }
+        Some(Ok(AdbTarget {
+            serial: (!serial.is_empty()).then(|| serial.to_owned()),
+            root,
+        }))
+    }
+
+    /// Where the songs are synchronised to before they are pushed, if not given with
+    /// `--adb-staging`. One per phone and directory on it.
+    pub fn default_staging(&self) -> Option<PathBuf> {
+        let name = format!(
+            "{}_{:016x}",
+            self.serial.as_deref().unwrap_or("default"),
+            rapidhash::rapidhash(self.root.as_bytes())
+        );
+        Some(
+            dirs::data_local_dir()?
+                .join("syncbops")
+                .join("adb")
+                .join(name),
+        )
+    }
+
+    fn adb(&self) -> Command {
+        let mut command = Command::new("adb");
+        if let Some(serial) = &self.serial {
+            command.arg("-s").arg(serial);
+        }
+        command
+    }
+
+    /// Runs a command in the shell of the phone, and returns what it printed.
+    fn shell(&self, action: &str, script: &str) -> Result<String, AdbError> {
+        let output = self.adb().arg("shell").arg(script).output()?;
+        if !output.status.success() {
+            return Err(AdbError::Failed {
+                action: action.to_owned(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn remote_path(&self, relative: &Path) -> String {
+        // The phone always uses forward slashes, also when syncing from Windows.
+        let relative = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        format!("{}/{relative}", self.root)
+    }
+
+    /// The files that are already on the phone, relative to the root, with their size.
+    fn list(&self) -> Result<HashMap<PathBuf, u64>, AdbError> {
+        let root = shell_quote(&self.root);
+        let listing = self.shell(
+            "listing the files on the phone",
+            &format!("mkdir -p {root} && find {root} -type f -exec stat -c '%s %n' {{}} +"),
+        )?;
+        Ok(parse_listing(&listing, &self.root))
+    }
+
+    fn push(&self, local: &Path, relative: &Path) -> Result<(), AdbError> {
+        let output = self
+            .adb()
+            .arg("push")
+            .arg(local)
+            .arg(self.remote_path(relative))
+            .output()?;
+        if !output.status.success() {
+            return Err(AdbError::Failed {
+                action: "push".to_owned(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Makes the directory on the phone the same as the staging directory. Only files that
+    /// changed since they were last pushed, or that are not on the phone (anymore), are pushed.
+    /// Files on the phone that were not pushed by syncbops are left alone.
+    pub fn mirror(&self, staging: &Path) -> Result<MirrorStats, AdbError> {
+        let local = staged_files(staging);
+        let pushed_before = read_manifest(staging);
+        let remote = self.list()?;
+        let (to_push, to_remove) = changes(&local, &pushed_before, &remote);
+
+        let mut stats = MirrorStats::default();
+        // Files that are on the phone like they are in the staging directory.
+        let pushing = to_push.iter().collect::<HashSet<_>>();
+        let mut manifest = local
+            .iter()
+            .filter(|(path, _)| !pushing.contains(path))
+            .map(|(path, state)| (path.clone(), *state))
+            .collect::<HashMap<_, _>>();
+        for relative in &to_push {
+            match self.push(&staging.join(relative), relative) {
+                Ok(()) => {
+                    stats.pushed += 1;
+                    manifest.insert(relative.clone(), local[relative]);
+                }
+                Err(e) => stats.failed.push((relative.clone(), e)),
+            }
+        }
+        // A few at a time, to not make the command too long.
+        for chunk in to_remove.chunks(100) {
+            let paths = chunk
+                .iter()
+                .map(|relative| shell_quote(&self.remote_path(relative)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            match self.shell("removing files from the phone", &format!("rm -f {paths}")) {
+                Ok(_) => stats.removed += chunk.len(),
+                Err(e) => {
+                    // Still pushed by syncbops, so try again next time.
+                    for relative in chunk {
+                        manifest.insert(relative.clone(), pushed_before[relative]);
+                    }
+                    stats.failed.push((PathBuf::from(&self.root), e));
+                }
+            }
+        }
+        write_manifest(staging, &manifest);
+        Ok(stats)
+    }
+}
+
+/// The files to push, and the files to remove from the phone.
+fn changes(
+    local: &HashMap<PathBuf, FileState>,
+    pushed_before: &HashMap<PathBuf, FileState>,
+    remote: &HashMap<PathBuf, u64>,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut to_push = local
+        .iter()
+        .filter(|(path, state)| {
+            remote.get(*path) != Some(&state.size) || pushed_before.get(*path) != Some(state)
+        })
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    // Only what syncbops pushed itself is removed, so that other music on the phone stays.
+    let mut to_remove = pushed_before
+        .keys()
+        .filter(|path| !local.contains_key(*path) && remote.contains_key(*path))
+        .cloned()
+        .collect::<Vec<_>>();
+    to_push.sort();
+    to_remove.sort();
+    (to_push, to_remove)
+}
+
+/// Reads the output of `stat -c '%s %n'`, of which the lines are like
+/// "4816210 /sdcard/Music/Artist/Album/01 Song.opus".
+fn parse_listing(listing: &str, root: &str) -> HashMap<PathBuf, u64> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let (size, path) = line.split_once(' ')?;
+            let relative = path.strip_prefix(root)?.trim_start_matches('/');
+            Some((PathBuf::from(relative), size.parse().ok()?))
+        })
+        .collect()
+}
+
+/// The files in the staging directory that belong on the phone. The records and other files of
+/// syncbops itself stay on this computer.
+fn staged_files(staging: &Path) -> HashMap<PathBuf, FileState> {
+    WalkDir::new(staging)
+        .into_iter()
+        // Like the backups in .syncbops-backup.
+        .filter_entry(|entry| !entry.file_name().to_string_lossy().starts_with(".syncbops"))
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let modified = metadata
+                .modified()
+                .ok()?
+                .duration_since(UNIX_EPOCH)
+                .ok()?
+                .as_secs();
+            let relative = entry.path().strip_prefix(staging).ok()?.to_path_buf();
+            Some((
+                relative,
+                FileState {
+                    size: metadata.len(),
+                    modified,
+                },
+            ))
+        })
+        .collect()
+}
+
+fn read_manifest(staging: &Path) -> HashMap<PathBuf, FileState> {
+    std::fs::read_to_string(staging.join(MANIFEST_FILENAME))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+fn write_manifest(staging: &Path, manifest: &HashMap<PathBuf, FileState>) {
+    let path = staging.join(MANIFEST_FILENAME);
+    let json = serde_json::to_string(manifest).expect("manifest should always be serialisable");
+    if let Err(e) = std::fs::write(&path, json) {
+        // Only costs pushing everything again next time.
+        eprintln!("Could not save what was pushed to {}: {e}", path.display());
+    }
+}
+
+/// Quotes a path for the shell of the phone.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changes, parse_listing, AdbTarget, FileState};
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    /// Only what changed is pushed, and only what syncbops pushed itself is removed.
+    fn adb_target_changes() {
+        let target = AdbTarget::parse(Path::new("adb://R58M12345/sdcard/Music/"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(target.serial.as_deref(), Some("R58M12345"));
+        assert_eq!(target.root, "/sdcard/Music");
+        let target = AdbTarget::parse(Path::new("adb:///sdcard/Music"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(target.serial, None);
+        assert!(AdbTarget::parse(Path::new("adb://R58M12345"))
+            .unwrap()
+            .is_err());
+        assert!(AdbTarget::parse(Path::new("/mnt/phone/Music")).is_none());
+
+        let remote = parse_listing(
+            "100 /sdcard/Music/A/same.opus\n\
+            100 /sdcard/Music/A/retagged.opus\n\
+            50 /sdcard/Music/A/removed.opus\n\
+            70 /sdcard/Music/Not synced by syncbops.mp3\n",
+            "/sdcard/Music",
+        );
+        assert_eq!(remote[Path::new("Not synced by syncbops.mp3")], 70);
+        let state = |size, modified| FileState { size, modified };
+        let local = HashMap::from([
+            (PathBuf::from("A/same.opus"), state(100, 1)),
+            (PathBuf::from("A/retagged.opus"), state(100, 2)),
+            (PathBuf::from("A/new.opus"), state(80, 2)),
+        ]);
+        let pushed_before = HashMap::from([
+            (PathBuf::from("A/same.opus"), state(100, 1)),
+            (PathBuf::from("A/retagged.opus"), state(100, 1)),
+            (PathBuf::from("A/removed.opus"), state(50, 1)),
+        ]);
+        let (to_push, to_remove) = changes(&local, &pushed_before, &remote);
+        assert_eq!(
+            to_push,
+            [
+                PathBuf::from("A/new.opus"),
+                PathBuf::from("A/retagged.opus")
+            ]
+        );
+        assert_eq!(to_remove, [PathBuf::from("A/removed.opus")]);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::{
+    ffmpeg_interface::SongMetaData,
+    hashing::{hash_file, PreviousSyncDb, SyncRecord},
+    music_library::UpdateType,
+    song::Song,
+    sync_song::SyncSettings,
+    target_path::TargetPlan,
+};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Why a song could not be adopted.
+#[derive(thiserror::Error, Debug)]
+pub enum AdoptError {
+    #[error("there is no file at {}", .0.display())]
+    NoShadow(PathBuf),
+
+    #[error("the file at {} can't be read", .0.display())]
+    Unreadable(PathBuf),
+
+    #[error("the file at {} seems to be a different song", .0.display())]
+    DifferentSong(PathBuf),
+}
+
+/// Makes records for a target library that was not made by syncbops (or of which the records
+/// were lost), so that the first sync doesn't transcode everything again. Every song is matched
+/// with the file at the place it would be synchronised to, if its tags and duration agree.
+/// Songs that already have a record are left alone. Returns the new records, and the songs that
+/// could not be adopted.
+pub fn adopt_shadows<'a>(
+    songs: &'a [Song],
+    target_plan: &TargetPlan,
+    target_library: &Path,
+    settings: &SyncSettings,
+    previous_sync_db: Option<&PreviousSyncDb>,
+) -> (Vec<SyncRecord>, Vec<(&'a Song, AdoptError)>) {
+    let pb = ProgressBar::new(songs.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed}] [{bar:60.cyan/blue}] {pos}/{len} [ETA: {eta}] {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    let results = songs
+        .par_iter()
+        .progress_with(pb.clone())
+        .filter(|song| {
+            previous_sync_db.is_none_or(|db| !db.contains_key(&song.library_relative_path))
+        })
+        .map(|song| {
+            pb.set_message(format!("{}", song.library_relative_path.display()));
+            let shadow = &target_plan[&song.library_relative_path];
+            (song, adopt_shadow(song, shadow, target_library, settings))
+        })
+        .collect::<Vec<_>>();
+    pb.finish();
+
+    let mut adopted = Vec::new();
+    let mut not_adopted = Vec::new();
+    for (song, result) in results {
+        match result {
+            Ok(record) => adopted.push(record),
+            Err(e) => not_adopted.push((song, e)),
+        }
+    }
+    not_adopted.sort_by(|(a, _), (b, _)| a.library_relative_path.cmp(&b.library_relative_path));
+    (adopted, not_adopted)
+}
+
+fn adopt_shadow(
+    song: &Song,
+    shadow: &Path,
+    target_library: &Path,
+    settings: &SyncSettings,
+) -> Result<SyncRecord, AdoptError> {
+    let target_relative_path = shadow
+        .strip_prefix(target_library)
+        .expect("shadow should be in the target library")
+        .to_path_buf();
+    if !shadow.exists() {
+        return Err(AdoptError::NoShadow(target_relative_path));
+    }
+    let Ok(shadow_metadata) = SongMetaData::parse_file(shadow) else {
+        return Err(AdoptError::Unreadable(target_relative_path));
+    };
+    if !is_same_song(&song.metadata, &shadow_metadata) {
+        return Err(AdoptError::DifferentSong(target_relative_path));
+    }
+    Ok(SyncRecord::from_song(song, settings)
+        .set_target_relative_path(target_relative_path)
+        .set_update_type(UpdateType::Adopted)
+        .set_target_hash(shadow, hash_file(shadow)))
+}
+
+/// Whether the tags and duration of the two agree. Tags that are missing in either are not
+/// compared, as not every format can hold every tag.
+fn is_same_song(source: &SongMetaData, shadow: &SongMetaData) -> bool {
+    let same_tags = [
+        (source.title.as_deref(), shadow.title.as_deref()),
+        (source.artist(), shadow.artist()),
+        (source.album(), shadow.album()),
+    ]
+    .iter()
+    .all(|pair| match pair {
+        (Some(a), Some(b)) => a.trim() == b.trim(),
+        _ => true,
+    });
+    same_tags && source.has_same_duration(shadow)
+}
@@ -0,0 +1,182 @@
+use crate::{
+    log_failure,
+    music_library::{identify_file_type, FileType},
+    song::Song,
+    sync_song::SyncSettings,
+    target_path::{target_relative_path, TargetPlan},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Images in an artist folder with one of these names are used as the artist image, in this
+/// order of preference.
+const ARTIST_IMAGE_STEMS: [&str; 3] = ["artist", "fanart", "folder"];
+
+/// A folder with all the albums of an artist, like `Artist/Album/song.mp3`.
+#[derive(Debug, PartialEq)]
+pub struct ArtistFolder {
+    /// Relative to the source library.
+    pub source: PathBuf,
+    /// Where the songs of this artist end up, relative to the target library.
+    pub target: PathBuf,
+    /// Absolute path to the artist image, if there is one.
+    pub image: Option<PathBuf>,
+}
+
+/// Finds the artist folders in the source library: top-level folders that contain album folders.
+/// Songs that are not in an album folder inside an artist folder are ignored.
+pub fn find_artist_folders(
+    songs: &[Song],
+    target_plan: &TargetPlan,
+    source_library: &Path,
+    target_library: &Path,
+) -> Vec<ArtistFolder> {
+    let mut folders: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+    for song in songs {
+        let Some(source) = artist_folder(&song.source_relative_path(source_library)) else {
+            continue;
+        };
+        let shadow = target_plan[&song.library_relative_path]
+            .strip_prefix(target_library)
+            .expect("shadow should be in the target library");
+        // If the songs don't end up in a folder, there is nowhere to put the artist image.
+        let Some(target) = artist_folder(shadow)
+            .or_else(|| (shadow.components().count() == 2).then(|| first_component(shadow)))
+        else {
+            continue;
+        };
+        // Songs of the same artist could end up in different places if their tags differ, so
+        // always pick the same one.
+        folders
+            .entry(source)
+            .and_modify(|existing| {
+                if target < *existing {
+                    existing.clone_from(&target)
+                }
+            })
+            .or_insert(target);
+    }
+    folders
+        .into_iter()
+        .map(|(source, target)| ArtistFolder {
+            image: find_artist_image(&source_library.join(&source)),
+            source,
+            target,
+        })
+        .collect()
+}
+
+/// The artist folder for a song at `Artist/Album/song.mp3` is `Artist`.
+fn artist_folder(relative_path: &Path) -> Option<PathBuf> {
+    (relative_path.components().count() >= 3).then(|| first_component(relative_path))
+}
+
+fn first_component(path: &Path) -> PathBuf {
+    path.components()
+        .next()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .unwrap_or_default()
+}
+
+fn find_artist_image(folder: &Path) -> Option<PathBuf> {
+    let images = fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| identify_file_type(path) == Some(FileType::Art))
+        .collect::<Vec<_>>();
+    ARTIST_IMAGE_STEMS.iter().find_map(|stem| {
+        images
+            .iter()
+            .find(|path| {
+                path.file_stem()
+                    .is_some_and(|s| s.to_ascii_lowercase() == *stem)
+            })
+            .cloned()
+    })
+}
+
+/// Copies the artist images into the matching artist folders in the target library. Returns the
+/// paths of the images that were newly added.
+pub fn copy_artist_images(
+    artist_folders: &[ArtistFolder],
+    target_library: &Path,
+    settings: &SyncSettings,
+) -> Vec<PathBuf> {
+    let mut new_images = Vec::new();
+    for folder in artist_folders {
+        let Some(image) = &folder.image else {
+            continue;
+        };
+        let name = Path::new(image.file_name().expect("image should have a file name"));
+        let shadow = target_library
+            .join(&folder.target)
+            .join(target_relative_path(
+                name,
+                target_library,
+                &settings.target_paths,
+            ));
+        if fs::exists(&shadow).unwrap_or(true) {
+            continue;
+        }
+        if !settings.dry_run {
+            let _ = fs::create_dir_all(shadow.parent().expect("Cannot get parent dir of image"));
+            if let Err(e) = fs::copy(image, &shadow) {
+                log_failure(
+                    format!("Could not copy artist image {}: {}", image.display(), e),
+                    None,
+                );
+                continue;
+            }
+        }
+        new_images.push(shadow);
+    }
+    new_images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_artist_folders, ArtistFolder};
+    use crate::{
+        music_library::{Id3Tags, MusicFileType},
+        song::Song,
+        target_path::{plan_target_paths, TargetPathOptions},
+    };
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn artist_folders_from_songs() {
+        let songs = vec![
+            Song::new_fake("Björk/Post/01 Army of Me.flac", &[]),
+            Song::new_fake("Björk/Debut/CD1/01 Human Behaviour.flac", &[]),
+            Song::new_fake("Loose/song.flac", &[]),
+            Song::new_fake("song.flac", &[]),
+        ];
+        let source_library = Path::new("/source_library");
+        let target_library = Path::new("/nonexistent/target");
+        let options = TargetPathOptions {
+            transliterate_to_ascii: true,
+            ..Default::default()
+        };
+        let (plan, _) = plan_target_paths(
+            &songs,
+            target_library,
+            |_| MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default(),
+            },
+            &options,
+        );
+        let folders = find_artist_folders(&songs, &plan, source_library, target_library);
+        assert_eq!(
+            folders,
+            vec![ArtistFolder {
+                source: PathBuf::from("Björk"),
+                target: PathBuf::from("Bjork"),
+                image: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{
+    cue::{self, CueSheet, CueTrack},
+    ffmpeg_interface::{read_chapters, Chapter},
+    log_failure,
+    music_library::MusicFileType,
+    song::Song,
+};
+use std::path::PathBuf;
+
+/// What to do with the chapters of audiobooks.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum Chapters {
+    /// Keep them in the synchronised file, if its container can have them (MP3, Opus, Vorbis,
+    /// and FLAC can).
+    Keep,
+    /// Make a song of every chapter, for players that can't skip to a chapter.
+    Split,
+    /// Leave them out.
+    Drop,
+}
+
+/// Which songs are audiobooks, and how they are synchronised instead of like songs: in mono, at
+/// a low bitrate, and without art, as that is all a book needs.
+#[derive(Clone, Debug)]
+pub struct Audiobooks {
+    /// Folders in the source library (relative to it) with audiobooks. .m4b files are always
+    /// audiobooks.
+    pub folders: Vec<PathBuf>,
+    pub filetype: MusicFileType,
+    pub chapters: Chapters,
+}
+
+impl Audiobooks {
+    pub fn contains(&self, song: &Song) -> bool {
+        let path = &song.library_relative_path;
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("m4b"))
+            || self.folders.iter().any(|folder| path.starts_with(folder))
+    }
+
+    /// Replaces the audiobooks that have chapters with a song for every chapter, like
+    /// `Book/03 Chapter Three.m4b`. Returns how many audiobooks were split.
+    pub fn split_chapters(&self, songs: Vec<Song>) -> (Vec<Song>, usize) {
+        let mut split = Vec::with_capacity(songs.len());
+        let mut n_split = 0;
+        for song in songs {
+            if song.segment.is_some() || !self.contains(&song) {
+                split.push(song);
+                continue;
+            }
+            let chapters = read_chapters(&song.absolute_path).unwrap_or_else(|e| {
+                log_failure(
+                    format!(
+                        "Could not read the chapters of {}: {}",
+                        song.library_relative_path.display(),
+                        e
+                    ),
+                    None,
+                );
+                Vec::new()
+            });
+            if chapters.len() < 2 {
+                split.push(song);
+                continue;
+            }
+            n_split += 1;
+            split.extend(cue::tracks(&song, &chapter_sheet(&song, &chapters)));
+        }
+        (split, n_split)
+    }
+}
+
+/// The chapters written as a .cue sheet, so that the audiobook is split like an album.
+fn chapter_sheet(song: &Song, chapters: &[Chapter]) -> CueSheet {
+    let metadata = &song.metadata;
+    let title = metadata
+        .album()
+        .or(metadata.title.as_deref())
+        .map(str::to_owned)
+        .or_else(|| {
+            song.library_relative_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        });
+    CueSheet {
+        title,
+        performer: metadata.artist().map(str::to_owned),
+        genre: metadata.genre().map(str::to_owned),
+        date: metadata.year().map(str::to_owned),
+        files: Vec::new(),
+        tracks: chapters
+            .iter()
+            .enumerate()
+            .map(|(i, chapter)| CueTrack {
+                number: i as u32 + 1,
+                title: chapter.title.clone(),
+                performer: None,
+                start: chapter.start,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chapter_sheet, Audiobooks, Chapters};
+    use crate::{
+        cue,
+        ffmpeg_interface::Chapter,
+        music_library::{Id3Tags, MusicFileType},
+        song::Song,
+    };
+    use std::{path::Path, time::Duration};
+
+    #[test]
+    /// Audiobooks are split into a song per chapter, with the title of the book as album.
+    fn audiobook_chapters() {
+        let audiobooks = Audiobooks {
+            folders: vec!["Audiobooks".into()],
+            filetype: MusicFileType::Mp3CBR {
+                bitrate: 64,
+                id3: Id3Tags::default(),
+            },
+            chapters: Chapters::Split,
+        };
+        assert!(audiobooks.contains(&Song::new_fake("Books/Dune.M4B", &[])));
+        assert!(audiobooks.contains(&Song::new_fake("Audiobooks/Dune/01.mp3", &[])));
+        assert!(!audiobooks.contains(&Song::new_fake("Audio/Song.mp3", &[])));
+
+        let mut book = Song::new_fake(
+            "Books/Dune.m4b",
+            &[("title", "Dune"), ("artist", "Frank Herbert")],
+        );
+        book.metadata.duration = Some(Duration::from_secs(3000));
+        let chapters = [
+            Chapter {
+                start: Duration::ZERO,
+                title: Some("Opening Credits".to_owned()),
+            },
+            Chapter {
+                start: Duration::from_secs(40),
+                title: None,
+            },
+        ];
+        let tracks = cue::tracks(&book, &chapter_sheet(&book, &chapters));
+        assert_eq!(
+            tracks[1].library_relative_path,
+            Path::new("Books/02 Track 2.m4b")
+        );
+        assert_eq!(tracks[1].metadata.tags["album"], "Dune");
+        assert_eq!(tracks[1].metadata.tags["artist"], "Frank Herbert");
+        assert_eq!(tracks[0].metadata.duration, Some(Duration::from_secs(40)));
+        // Still audiobooks after splitting.
+        assert!(audiobooks.contains(&tracks[1]));
+    }
+}
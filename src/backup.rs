@@ -0,0 +1,253 @@
+use crate::journal::Journal;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use walkdir::WalkDir;
+
+/// The folder in the target library that the backups are kept in. Like the other files of
+/// syncbops, it is not pushed to the phone.
+pub const BACKUP_DIR: &str = ".syncbops-backup";
+
+/// Keeps the files in the target library that are about to be overwritten or deleted, instead
+/// of destroying them, so that a sync with the wrong settings can be undone by hand. Every sync
+/// gets its own folder in `.syncbops-backup`, named after when it started (in seconds since
+/// 1970), in which the files keep their place in the target library. Along with them is the
+/// journal of the sync, to undo it with.
+#[derive(Clone, Debug)]
+pub struct Backup {
+    target_library: PathBuf,
+    /// The folder of this sync.
+    dir: PathBuf,
+    retention: Retention,
+    journal: Arc<Journal>,
+}
+
+/// How many backups are kept. The oldest ones are removed first.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// The number of syncs of which the backups are kept.
+    pub keep: usize,
+    /// How much space the backups may take up together, in bytes. None means no limit.
+    pub max_bytes: Option<u64>,
+}
+
+impl Backup {
+    pub fn new(target_library: &Path, retention: Retention) -> Backup {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let dir = target_library.join(BACKUP_DIR).join(started.to_string());
+        Backup {
+            target_library: target_library.to_path_buf(),
+            journal: Arc::new(Journal::new(target_library, &dir)),
+            dir,
+            retention,
+        }
+    }
+
+    /// Moves the file at `path` in the target library into the backup. Returns where it ended
+    /// up, or None if there was no file to back up.
+    pub fn back_up(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let backup = self.backup_path(path);
+        if backup.exists() {
+            // Replaced twice in one sync, like a sidecar that moved. The file from before the
+            // sync is already in the backup, and this one was written by the sync itself.
+            fs::remove_file(path)?;
+            return Ok(None);
+        }
+        fs::create_dir_all(backup.parent().expect("backup should have a parent"))?;
+        if fs::rename(path, &backup).is_err() {
+            // Like when part of the target library is a different filesystem.
+            fs::copy(path, &backup)?;
+            fs::remove_file(path)?;
+        }
+        self.journal.backed_up(path, &backup)?;
+        Ok(Some(backup))
+    }
+
+    /// Copies the file at `path` in the target library into the backup, for files that are
+    /// changed in place, like the records. Files that are not there yet are journaled as
+    /// created, so that undoing removes them.
+    pub fn snapshot(&self, path: &Path) -> io::Result<()> {
+        if !path.is_file() {
+            return self.journal.created(path);
+        }
+        let backup = self.backup_path(path);
+        fs::create_dir_all(backup.parent().expect("backup should have a parent"))?;
+        fs::copy(path, &backup)?;
+        self.journal.backed_up(path, &backup)
+    }
+
+    /// Notes in the journal that the file at `path` in the target library was written.
+    pub fn created(&self, path: &Path) -> io::Result<()> {
+        self.journal.created(path)
+    }
+
+    fn backup_path(&self, path: &Path) -> PathBuf {
+        self.dir.join(
+            path.strip_prefix(&self.target_library)
+                .expect("backed up files should be in the target library"),
+        )
+    }
+
+    /// Removes the backups of the oldest syncs, until what is left fits the retention. The
+    /// backup of this sync is always kept. Returns the removed folders.
+    pub fn prune(&self) -> io::Result<Vec<PathBuf>> {
+        let Ok(entries) = fs::read_dir(self.target_library.join(BACKUP_DIR)) else {
+            return Ok(Vec::new());
+        };
+        let mut backups = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir() && *path != self.dir)
+            .filter_map(|path| {
+                let started = path.file_name()?.to_str()?.parse::<u64>().ok()?;
+                Some((started, path))
+            })
+            .collect::<Vec<_>>();
+        backups.sort();
+        // Newest first, starting with this sync.
+        let mut sizes = backups
+            .iter()
+            .rev()
+            .map(|(_, path)| size_of_dir(path))
+            .collect::<Vec<_>>();
+        sizes.insert(0, size_of_dir(&self.dir));
+
+        let mut kept_size = 0;
+        let mut removed = Vec::new();
+        for (i, size) in sizes.into_iter().enumerate() {
+            kept_size += size;
+            if i == 0 {
+                continue;
+            }
+            let too_many = i >= self.retention.keep.max(1);
+            let too_large = self.retention.max_bytes.is_some_and(|max| kept_size > max);
+            if too_many || too_large {
+                let (_, path) = &backups[backups.len() - i];
+                fs::remove_dir_all(path)?;
+                removed.push(path.clone());
+                kept_size -= size;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// What happens to the files in the target library that are overwritten or removed.
+#[derive(Clone, Debug, Default)]
+pub enum Disposal {
+    /// They are gone for good.
+    #[default]
+    Delete,
+    /// They are moved into the backup.
+    Backup(Backup),
+    /// They are moved to the trash of the desktop, from where they can be restored.
+    Trash,
+}
+
+impl Disposal {
+    /// Moves the file out of the way before it is replaced, if it should be kept. Returns where
+    /// it went, if that is known. Files that are deleted are left where they are, so they can
+    /// be replaced in place.
+    pub fn set_aside(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        match self {
+            Disposal::Delete => Ok(None),
+            Disposal::Backup(backup) => backup.back_up(path),
+            Disposal::Trash => {
+                if path.is_file() {
+                    trash::delete(path).map_err(io::Error::other)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes the file from the target library, keeping it if it should be kept.
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Disposal::Delete => fs::remove_file(path),
+            _ => self.set_aside(path).map(|_| ()),
+        }
+    }
+
+    /// Notes in the journal that the file at `path` in the target library was written, if
+    /// there is one.
+    pub fn created(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Disposal::Backup(backup) => backup.created(path),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn backup(&self) -> Option<&Backup> {
+        match self {
+            Disposal::Backup(backup) => Some(backup),
+            _ => None,
+        }
+    }
+}
+
+fn size_of_dir(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backup, Retention, BACKUP_DIR};
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    /// Replaced files keep their place in the backup, and the oldest backups go first.
+    fn backups_are_kept_and_pruned() {
+        let target_library = PathBuf::from(format!(
+            "/tmp/syncbops/test_backup_{}",
+            random_string::generate(24, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let song = target_library.join("Artist/Album/01 Song.opus");
+        fs::create_dir_all(song.parent().unwrap()).unwrap();
+        fs::write(&song, b"old").unwrap();
+        for old in ["100", "200", "300"] {
+            fs::create_dir_all(target_library.join(BACKUP_DIR).join(old)).unwrap();
+            fs::write(
+                target_library.join(BACKUP_DIR).join(old).join("a"),
+                [0; 1000],
+            )
+            .unwrap();
+        }
+
+        let retention = Retention {
+            keep: 3,
+            max_bytes: Some(2000),
+        };
+        let backup = Backup::new(&target_library, retention);
+        let backed_up = backup.back_up(&song).unwrap().unwrap();
+        assert!(!song.exists());
+        assert!(backed_up.ends_with("Artist/Album/01 Song.opus"));
+        assert_eq!(fs::read(&backed_up).unwrap(), b"old");
+        assert_eq!(backup.back_up(&song).unwrap(), None);
+
+        // Only the newest one fits next to this one.
+        assert_eq!(
+            backup.prune().unwrap(),
+            [
+                target_library.join(BACKUP_DIR).join("200"),
+                target_library.join(BACKUP_DIR).join("100"),
+            ]
+        );
+        assert!(backed_up.exists());
+        fs::remove_dir_all(&target_library).unwrap();
+    }
+}
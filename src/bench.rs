@@ -0,0 +1,300 @@
+use crate::{
+    ffmpeg_interface::{
+        ensure_ffmpeg_capable, transcode_song, ArtEmbedding, AudioConversion, PictureSelection,
+    },
+    log_failure,
+    music_library::{find_songs_in_library, MusicFileType, MusicLibraryError},
+    song::Song,
+};
+use clap::Parser;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Transcodes a few songs from a library with several settings, and tells how large the results
+/// are and how fast they were made. Helps with choosing a setting for the target library based on
+/// your own music, instead of guessing.
+#[derive(clap::Parser)]
+#[command(bin_name = "syncbops bench", version)]
+pub struct BenchCli {
+    /// The library to take the songs from.
+    source_library: PathBuf,
+
+    /// How many songs to transcode with every setting. They are spread out over the library.
+    #[arg(short, long, default_value_t = 5)]
+    samples: usize,
+
+    /// A setting to try, written like the target filetype of a regular sync, e.g. "mp3-vbr -q 2"
+    /// or "opus --bitrate 96". Can be given multiple times. If not given, tries a range of common
+    /// settings.
+    #[arg(long = "setting", value_name = "SETTING", value_parser = parse_setting)]
+    settings: Vec<BenchSetting>,
+}
+
+/// A target filetype to try, and how it was written.
+#[derive(Clone, Debug)]
+pub struct BenchSetting {
+    name: String,
+    filetype: MusicFileType,
+}
+
+/// Used when no settings are given.
+const DEFAULT_SETTINGS: [&str; 10] = [
+    "mp3-vbr -q 0",
+    "mp3-vbr -q 2",
+    "mp3-vbr -q 5",
+    "mp3-cbr -b 320",
+    "opus -b 64",
+    "opus -b 96",
+    "opus -b 128",
+    "opus -b 160",
+    "vorbis -q 4",
+    "vorbis -q 6",
+];
+
+/// Just the target filetype of the regular command line, to parse the settings with.
+#[derive(clap::Parser)]
+#[command(no_binary_name = true)]
+struct SettingParser {
+    #[command(subcommand)]
+    filetype: MusicFileType,
+}
+
+fn parse_setting(s: &str) -> Result<BenchSetting, String> {
+    let parsed = SettingParser::try_parse_from(s.split_whitespace()).map_err(|e| e.to_string())?;
+    Ok(BenchSetting {
+        name: s.split_whitespace().collect::<Vec<_>>().join(" "),
+        filetype: parsed.filetype,
+    })
+}
+
+/// How the songs turned out with one setting.
+pub struct BenchResult {
+    pub setting: BenchSetting,
+    /// Total size of the transcoded songs, in bytes.
+    pub size: u64,
+    /// Total size of the songs they were transcoded from, in bytes.
+    pub source_size: u64,
+    /// Total duration of the songs.
+    pub duration: Duration,
+    /// How long transcoding took in total.
+    pub encode_time: Duration,
+    /// How many of the songs could not be transcoded with this setting.
+    pub failed: usize,
+}
+
+impl BenchResult {
+    pub fn bitrate_kbps(&self) -> f64 {
+        self.size as f64 * 8. / self.duration.as_secs_f64() / 1000.
+    }
+
+    /// Seconds of audio that were transcoded per second.
+    pub fn speed(&self) -> f64 {
+        self.duration.as_secs_f64() / self.encode_time.as_secs_f64()
+    }
+}
+
+/// Transcodes the sample with every setting. Songs are transcoded one at a time, so that the
+/// measured speed is that of a single encoder.
+pub fn bench(cli: &BenchCli) -> Result<Vec<BenchResult>, MusicLibraryError> {
+    if !cli.source_library.is_dir() {
+        return Err(MusicLibraryError::NotADirectory {
+            path: cli.source_library.clone(),
+        });
+    }
+    let settings = if cli.settings.is_empty() {
+        DEFAULT_SETTINGS
+            .iter()
+            .map(|s| parse_setting(s).expect("default settings should be valid"))
+            .collect()
+    } else {
+        cli.settings.clone()
+    };
+    // Settings that ffmpeg can't encode are left out, instead of failing for every song.
+    let settings = settings
+        .into_iter()
+        .filter(|setting| match ensure_ffmpeg_capable(&setting.filetype) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Skipping {}: {e}", setting.name);
+                false
+            }
+        })
+        .collect::<Vec<_>>();
+
+    println!("Discovering files in {}", cli.source_library.display());
+    let songs = find_songs_in_library(&cli.source_library, &[], false, None, None)?;
+    let sample = spread_sample(&songs, cli.samples);
+    if sample.is_empty() {
+        return Ok(Vec::new());
+    }
+    println!(
+        "Transcoding {} songs with {} settings...",
+        sample.len(),
+        settings.len()
+    );
+
+    let scratch = std::env::temp_dir().join(format!("syncbops_bench_{}", std::process::id()));
+    std::fs::create_dir_all(&scratch).map_err(|e| MusicLibraryError::ScratchDirectory {
+        path: scratch.clone(),
+        source: e,
+    })?;
+    let pb = ProgressBar::new((sample.len() * settings.len()) as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed}] [{bar:60.cyan/blue}] {pos}/{len} [ETA: {eta}] {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    let results = settings
+        .into_iter()
+        .map(|setting| {
+            pb.set_message(setting.name.clone());
+            let mut result = BenchResult {
+                setting,
+                size: 0,
+                source_size: 0,
+                duration: Duration::ZERO,
+                encode_time: Duration::ZERO,
+                failed: 0,
+            };
+            for (i, song) in sample.iter().enumerate() {
+                let target = scratch.join(format!("{i}.{}", result.setting.filetype));
+                let started = Instant::now();
+                let transcoded = transcode_song(
+                    &song.absolute_path,
+                    &target,
+                    result.setting.filetype.clone(),
+                    AudioConversion::default(),
+                    // Only the audio is compared, art would only make the sizes less telling.
+                    ArtEmbedding {
+                        embed: false,
+                        external_art: None,
+                        max_resolution: 0,
+                        pictures: PictureSelection::All,
+                        recompress_quality: None,
+                    },
+                    &[],
+                    None,
+                );
+                let encode_time = started.elapsed();
+                let sizes = std::fs::metadata(&target)
+                    .and_then(|target| Ok((target.len(), song.absolute_path.metadata()?.len())));
+                match (transcoded, sizes, song.metadata.duration) {
+                    (Ok(()), Ok((size, source_size)), Some(duration)) => {
+                        result.size += size;
+                        result.source_size += source_size;
+                        result.duration += duration;
+                        result.encode_time += encode_time;
+                    }
+                    (Err(e), ..) => {
+                        log_failure(
+                            format!(
+                                "Could not transcode {} with {}: {e}",
+                                song.library_relative_path.display(),
+                                result.setting.name
+                            ),
+                            Some(&pb),
+                        );
+                        result.failed += 1;
+                    }
+                    _ => result.failed += 1,
+                }
+                let _ = std::fs::remove_file(&target);
+                pb.inc(1);
+            }
+            result
+        })
+        .collect();
+    pb.finish();
+    let _ = std::fs::remove_dir_all(&scratch);
+    Ok(results)
+}
+
+/// Songs evenly spread over the library (in alphabetical order), so that the sample isn't all
+/// from the same artist or album. Only songs of which the duration is known can be used.
+fn spread_sample(songs: &[Song], n: usize) -> Vec<&Song> {
+    let mut candidates = songs
+        .iter()
+        .filter(|song| song.metadata.duration.is_some_and(|d| !d.is_zero()))
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| a.library_relative_path.cmp(&b.library_relative_path));
+    if n == 0 || candidates.is_empty() {
+        return Vec::new();
+    }
+    let n = n.min(candidates.len());
+    (0..n)
+        .map(|i| candidates[i * candidates.len() / n])
+        .collect()
+}
+
+pub fn summarize_bench(results: &[BenchResult]) {
+    if results.is_empty() {
+        println!("There are no songs to transcode in the library.");
+        return;
+    }
+    println!(
+        "{:<24} {:>10} {:>12} {:>10}",
+        "Setting", "Bitrate", "Size", "Speed"
+    );
+    for result in results {
+        if result.duration.is_zero() {
+            println!("{:<24} could not transcode any song", result.setting.name);
+            continue;
+        }
+        println!(
+            "{:<24} {:>6.0} kbps {:>10.0} % {:>9.0}x{}",
+            result.setting.name,
+            result.bitrate_kbps(),
+            result.size as f64 / result.source_size as f64 * 100.,
+            result.speed(),
+            if result.failed > 0 {
+                format!(" ({} songs failed)", result.failed)
+            } else {
+                String::new()
+            }
+        );
+    }
+    println!("Size is compared to the source files. Speed is how many times faster than real time a single song is transcoded.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_setting, spread_sample};
+    use crate::{music_library::MusicFileType, song::Song};
+    use std::time::Duration;
+
+    #[test]
+    /// Settings are written like the target filetype of a regular sync.
+    fn parse_bench_settings() {
+        let setting = parse_setting(" opus  --bitrate 96").unwrap();
+        assert_eq!(setting.name, "opus --bitrate 96");
+        assert!(matches!(
+            setting.filetype,
+            MusicFileType::Opus { bitrate: 96, .. }
+        ));
+        assert!(matches!(
+            parse_setting("mp3-vbr -q 2").unwrap().filetype,
+            MusicFileType::Mp3VBR { quality: 2, .. }
+        ));
+        assert!(parse_setting("wav").is_err());
+
+        let songs = (0..10)
+            .map(|i| {
+                let mut song = Song::new_fake(&format!("{i}.flac"), &[]);
+                song.metadata.duration = Some(Duration::from_secs(200));
+                song
+            })
+            .collect::<Vec<_>>();
+        let sample = spread_sample(&songs, 3);
+        assert_eq!(
+            sample
+                .iter()
+                .map(|song| song.library_relative_path.to_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["0.flac", "3.flac", "6.flac"]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use crate::{
+    bench::BenchCli, doctor::DoctorCli, journal::UndoCli, plan::ApplyCli, verify::VerifyCli, Cli,
+};
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+
+/// Prints a script that completes the arguments of syncbops in the shell, including the options
+/// of every target filetype. E.g. for bash, add `source <(syncbops completions bash)` to
+/// ~/.bashrc.
+#[derive(clap::Parser)]
+#[command(bin_name = "syncbops completions", version)]
+pub struct CompletionsCli {
+    shell: Shell,
+}
+
+/// The regular command line, with the commands that are dispatched before parsing it next to
+/// the target filetypes, like they are typed.
+fn full_command() -> clap::Command {
+    Cli::command()
+        .subcommand(Cli::command().name("adopt"))
+        .subcommand(ApplyCli::command().name("apply"))
+        .subcommand(BenchCli::command().name("bench"))
+        .subcommand(CompletionsCli::command().name("completions"))
+        .subcommand(DoctorCli::command().name("doctor"))
+        .subcommand(UndoCli::command().name("undo"))
+        .subcommand(VerifyCli::command().name("verify"))
+}
+
+pub fn print_completions(args: impl IntoIterator<Item = std::ffi::OsString>) {
+    let cli = CompletionsCli::parse_from(args);
+    clap_complete::generate(
+        cli.shell,
+        &mut full_command(),
+        "syncbops",
+        &mut std::io::stdout(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::full_command;
+    use clap_complete::Shell;
+
+    #[test]
+    /// The options of the target filetypes are completed too.
+    fn completions_include_filetype_options() {
+        let mut script = Vec::new();
+        clap_complete::generate(Shell::Bash, &mut full_command(), "syncbops", &mut script);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("mp3-vbr"));
+        assert!(script.contains("--bitrate"));
+        assert!(script.contains("verify"));
+    }
+}
@@ -0,0 +1,335 @@
+use crate::song::Song;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The part of a file that a song is, for albums that are one big file with a .cue sheet.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: Duration,
+    /// None for the last track, which runs until the end of the file.
+    pub end: Option<Duration>,
+}
+
+/// What is in a .cue sheet, as far as it is needed to split the file into songs.
+#[derive(Debug, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub genre: Option<String>,
+    pub date: Option<String>,
+    /// The files that the tracks are in. Single-file albums only have one.
+    pub files: Vec<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Where INDEX 01 is, so without the pregap.
+    pub start: Duration,
+}
+
+/// Tags that are replaced by the ones of the track, under all the names they can have.
+const TRACK_TAGS: &[&str] = &[
+    "title",
+    "artist",
+    "album",
+    "album_artist",
+    "albumartist",
+    "album artist",
+    "track",
+    "tracknumber",
+    "tracktotal",
+    "totaltracks",
+    "genre",
+    "date",
+    "year",
+    "cuesheet",
+];
+
+impl CueSheet {
+    pub fn parse(text: &str) -> CueSheet {
+        let mut sheet = CueSheet::default();
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            let track = sheet.tracks.last_mut();
+            match (command.to_uppercase().as_str(), track) {
+                ("FILE", _) => sheet.files.push(file_name(rest)),
+                ("TRACK", _) => sheet.tracks.push(CueTrack {
+                    number: rest
+                        .split_whitespace()
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or(sheet.tracks.len() as u32 + 1),
+                    ..Default::default()
+                }),
+                ("TITLE", Some(track)) => track.title = Some(unquote(rest)),
+                ("TITLE", None) => sheet.title = Some(unquote(rest)),
+                ("PERFORMER", Some(track)) => track.performer = Some(unquote(rest)),
+                ("PERFORMER", None) => sheet.performer = Some(unquote(rest)),
+                ("INDEX", Some(track)) => {
+                    if let Some(("01", time)) = rest.split_once(char::is_whitespace) {
+                        if let Some(start) = parse_time(time.trim()) {
+                            track.start = start;
+                        }
+                    }
+                }
+                ("REM", _) => match rest.split_once(char::is_whitespace) {
+                    Some((key, value)) if key.eq_ignore_ascii_case("GENRE") => {
+                        sheet.genre = Some(unquote(value.trim()))
+                    }
+                    Some((key, value)) if key.eq_ignore_ascii_case("DATE") => {
+                        sheet.date = Some(unquote(value.trim()))
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        sheet
+    }
+
+    /// Whether the sheet is for this file, and has it split into tracks. The name in the sheet
+    /// often has a different extension than the file, e.g. because the album was ripped to .wav
+    /// and converted to FLAC later on.
+    fn describes(&self, file: &Path) -> bool {
+        let [name] = self.files.as_slice() else {
+            return false;
+        };
+        let name = Path::new(name);
+        self.tracks.len() > 1
+            && (name.file_name() == file.file_name() || name.file_stem() == file.file_stem())
+    }
+}
+
+/// The name of the file in a FILE line, like `"Album.flac" WAVE`.
+fn file_name(rest: &str) -> String {
+    let name = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+        None => rest.rsplit_once(' ').map_or(rest, |(name, _)| name),
+    };
+    // Only the name matters, as the sheet is next to the file.
+    name.rsplit(['/', '\\']).next().unwrap_or(name).to_owned()
+}
+
+fn unquote(value: &str) -> String {
+    value.trim_matches('"').to_owned()
+}
+
+/// Parses `mm:ss:ff`, where there are 75 frames in a second.
+fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    Some(Duration::from_secs(minutes * 60 + seconds) + Duration::from_millis(frames * 1000 / 75))
+}
+
+/// Finds the .cue sheet next to the song that splits it into tracks, if there is one.
+fn cue_sheet_for(song: &Path) -> Option<CueSheet> {
+    let folder = song.parent()?;
+    let mut sheets = fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+        })
+        .collect::<Vec<_>>();
+    // The one with the same name first.
+    sheets.sort_by_key(|path| path.file_stem() != song.file_stem());
+    sheets.into_iter().find_map(|path| {
+        let bytes = fs::read(&path).ok()?;
+        let sheet = CueSheet::parse(&String::from_utf8_lossy(&bytes));
+        sheet.describes(song).then_some(sheet)
+    })
+}
+
+/// Replaces the songs that are a whole album with a .cue sheet with a song for every track in
+/// it. They are all in the same file, but each has its own part of it, its own tags, and its
+/// own name in the library, like `Album/03 Title.flac`. Returns how many files were split.
+pub fn split_cue_sheets(songs: Vec<Song>) -> (Vec<Song>, usize) {
+    let mut split = Vec::with_capacity(songs.len());
+    let mut n_split = 0;
+    for song in songs {
+        let sheet = song
+            .segment
+            .is_none()
+            .then(|| cue_sheet_for(&song.absolute_path))
+            .flatten();
+        match sheet {
+            Some(sheet) => {
+                n_split += 1;
+                split.extend(tracks(&song, &sheet));
+            }
+            None => split.push(song),
+        }
+    }
+    (split, n_split)
+}
+
+/// The songs for the tracks of the file.
+pub fn tracks(song: &Song, sheet: &CueSheet) -> Vec<Song> {
+    let extension = song
+        .absolute_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    sheet
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| {
+            let end = sheet.tracks.get(i + 1).map(|next| next.start);
+            let title = track
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Track {}", track.number));
+            let mut metadata = song.metadata.clone();
+            metadata
+                .tags
+                .retain(|key, _| !TRACK_TAGS.contains(&key.as_str()));
+            let tags = [
+                ("title", Some(title.clone())),
+                (
+                    "artist",
+                    track.performer.clone().or(sheet.performer.clone()),
+                ),
+                ("album", sheet.title.clone()),
+                ("album_artist", sheet.performer.clone()),
+                (
+                    "track",
+                    Some(format!("{}/{}", track.number, sheet.tracks.len())),
+                ),
+                ("genre", sheet.genre.clone()),
+                ("date", sheet.date.clone()),
+            ];
+            for (key, value) in tags {
+                if let Some(value) = value {
+                    metadata.tags.insert(key.to_owned(), value);
+                }
+            }
+            metadata.title = Some(title.clone());
+            metadata.duration = match (end, song.metadata.duration) {
+                (Some(end), _) => Some(end.saturating_sub(track.start)),
+                (None, Some(total)) => Some(total.saturating_sub(track.start)),
+                (None, None) => None,
+            };
+            let name = format!(
+                "{:02} {}.{extension}",
+                track.number,
+                title.replace(['/', '\\'], "-")
+            );
+            Song {
+                absolute_path: song.absolute_path.clone(),
+                library_relative_path: song.library_relative_path.with_file_name(name),
+                external_album_art: song.external_album_art.clone(),
+                metadata,
+                loudness: None,
+                segment: Some(Segment {
+                    start: track.start,
+                    end,
+                }),
+            }
+        })
+        .collect()
+}
+
+/// The tags of a track of a single-file album, which are written to its synchronised copy on
+/// top of the ones of the whole file. None for other songs.
+pub fn track_tags(song: &Song) -> Vec<(String, String)> {
+    if song.segment.is_none() {
+        return Vec::new();
+    }
+    [
+        "title",
+        "artist",
+        "album",
+        "album_artist",
+        "track",
+        "genre",
+        "date",
+    ]
+    .iter()
+    .filter_map(|key| Some((key.to_string(), song.metadata.tags.get(*key)?.clone())))
+    .collect()
+}
+
+/// Where the file that the track is a part of is, relative to the source library.
+pub fn whole_file(song: &Song, source_library: &Path) -> Option<PathBuf> {
+    song.segment?;
+    Some(song.source_relative_path(source_library))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CueSheet, Segment};
+    use crate::song::Song;
+    use std::{path::Path, time::Duration};
+
+    #[test]
+    /// The tracks of a single-file album become songs of their own.
+    fn cue_sheet_tracks() {
+        let sheet = CueSheet::parse(
+            "\u{feff}REM GENRE \"Progressive Rock\"\n\
+            REM DATE 1973\n\
+            PERFORMER \"Pink Floyd\"\n\
+            TITLE \"The Dark Side of the Moon\"\n\
+            FILE \"Pink Floyd - The Dark Side of the Moon.wav\" WAVE\n  \
+              TRACK 01 AUDIO\n    \
+                TITLE \"Speak to Me\"\n    \
+                INDEX 01 00:00:00\n  \
+              TRACK 02 AUDIO\n    \
+                TITLE \"Breathe / In the Air\"\n    \
+                INDEX 00 01:07:20\n    \
+                INDEX 01 01:08:45\n",
+        );
+        assert_eq!(sheet.genre.as_deref(), Some("Progressive Rock"));
+        assert_eq!(sheet.tracks.len(), 2);
+        assert_eq!(
+            sheet.tracks[1].start,
+            Duration::from_secs(68) + Duration::from_millis(600)
+        );
+        assert!(sheet.describes(Path::new(
+            "/music/Pink Floyd - The Dark Side of the Moon.flac"
+        )));
+        assert!(!sheet.describes(Path::new("/music/Other.flac")));
+
+        let mut album = Song::new_fake(
+            "Pink Floyd/Pink Floyd - The Dark Side of the Moon.flac",
+            &[("title", "The Dark Side of the Moon"), ("tracknumber", "1")],
+        );
+        album.metadata.duration = Some(Duration::from_secs(300));
+        let tracks = super::tracks(&album, &sheet);
+        assert_eq!(
+            tracks[1].library_relative_path,
+            Path::new("Pink Floyd/02 Breathe - In the Air.flac")
+        );
+        assert_eq!(tracks[1].metadata.tags["track"], "2/2");
+        assert!(!tracks[1].metadata.tags.contains_key("tracknumber"));
+        assert_eq!(tracks[0].metadata.tags["artist"], "Pink Floyd");
+        assert_eq!(
+            tracks[0].metadata.duration,
+            Some(Duration::from_millis(68600))
+        );
+        assert_eq!(
+            tracks[1].segment,
+            Some(Segment {
+                start: Duration::from_millis(68600),
+                end: None
+            })
+        );
+        assert_eq!(
+            super::whole_file(&tracks[1], Path::new("/source_library")).as_deref(),
+            Some(Path::new(
+                "Pink Floyd/Pink Floyd - The Dark Side of the Moon.flac"
+            ))
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use crate::ffmpeg_interface::{ffmpeg_encoders, ffmpeg_version};
+use std::{path::PathBuf, process::Command};
+
+/// Checks whether everything that syncbops needs is there, like ffmpeg and its encoders, and
+/// tells how to fix what is missing. Better than finding out through an error for every song.
+#[derive(clap::Parser)]
+#[command(bin_name = "syncbops doctor", version)]
+pub struct DoctorCli {
+    /// Also check whether this target library can be written to.
+    target_library: Option<PathBuf>,
+}
+
+/// The encoders that syncbops can use, and what they are needed for.
+const ENCODERS: [(&str, &str); 5] = [
+    ("libmp3lame", "mp3-cbr and mp3-vbr"),
+    ("libopus", "opus"),
+    ("libvorbis", "vorbis"),
+    ("flac", "flac"),
+    (
+        "libfdk_aac",
+        "AAC, the best AAC encoder, but hardly ever included",
+    ),
+];
+
+/// Encoders without which syncbops works fine.
+const OPTIONAL_ENCODERS: [&str; 1] = ["libfdk_aac"];
+
+/// The result of a single check, and what to do about it if it failed.
+struct Check {
+    ok: bool,
+    what: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(what: String) -> Check {
+        Check {
+            ok: true,
+            what,
+            fix: None,
+        }
+    }
+
+    fn failed(what: String, fix: &str) -> Check {
+        Check {
+            ok: false,
+            what,
+            fix: Some(fix.to_owned()),
+        }
+    }
+}
+
+/// Runs all checks and prints the results. Returns whether everything is fine.
+pub fn doctor(cli: &DoctorCli) -> bool {
+    let mut checks = Vec::new();
+    let ffmpeg = ffmpeg_version();
+    let has_ffmpeg = ffmpeg.is_some()
+        || Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_ok_and(|output| output.status.success());
+    checks.push(match (has_ffmpeg, ffmpeg) {
+        (true, Some(version)) => Check::ok(format!("ffmpeg {version} is installed")),
+        (true, None) => Check::ok("ffmpeg is installed (unknown version)".to_owned()),
+        (false, _) => Check::failed(
+            "ffmpeg is not on the PATH".to_owned(),
+            "Install ffmpeg, e.g. with the package manager of your system, and make sure the \
+            `ffmpeg` command works in a terminal.",
+        ),
+    });
+    let has_ffprobe = Command::new("ffprobe")
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    checks.push(if has_ffprobe {
+        Check::ok("ffprobe is installed".to_owned())
+    } else {
+        Check::failed(
+            "ffprobe is not on the PATH".to_owned(),
+            "ffprobe comes with ffmpeg. Install a full build of ffmpeg, not just the ffmpeg \
+            binary.",
+        )
+    });
+
+    if has_ffmpeg {
+        match ffmpeg_encoders() {
+            Ok(encoders) => {
+                for (encoder, used_for) in ENCODERS {
+                    let check = if encoders.contains(encoder) {
+                        Check::ok(format!("{encoder} is available, for {used_for}"))
+                    } else if OPTIONAL_ENCODERS.contains(&encoder) {
+                        // Not a problem, so not worth failing for.
+                        Check::ok(format!("{encoder} is not available ({used_for})"))
+                    } else {
+                        Check::failed(
+                            format!("{encoder} is not available, so can't transcode to {used_for}"),
+                            "Install a build of ffmpeg that includes it (most full builds do), or \
+                            choose another target filetype.",
+                        )
+                    };
+                    checks.push(check);
+                }
+            }
+            Err(e) => checks.push(Check::failed(
+                format!("could not list the encoders of ffmpeg: {e}"),
+                "Check whether `ffmpeg -encoders` works in a terminal.",
+            )),
+        }
+    }
+
+    if let Some(target_library) = &cli.target_library {
+        let probe = target_library.join(format!(".syncbops_doctor_{}", std::process::id()));
+        checks.push(match std::fs::write(&probe, b"") {
+            Ok(()) => {
+                let _ = std::fs::remove_file(&probe);
+                Check::ok(format!("{} can be written to", target_library.display()))
+            }
+            Err(e) if !target_library.is_dir() => Check::failed(
+                format!("{} does not exist: {e}", target_library.display()),
+                "Create the folder, or check for a typo. Is the drive mounted?",
+            ),
+            Err(e) => Check::failed(
+                format!("{} can't be written to: {e}", target_library.display()),
+                "Check the permissions of the folder, and whether the drive is mounted \
+                read-only.",
+            ),
+        });
+    }
+
+    for check in &checks {
+        if check.ok {
+            println!("[{}] {}", console::style("ok").green(), check.what);
+        } else {
+            println!("[{}] {}", console::style("!!").red(), check.what);
+        }
+        if let Some(fix) = &check.fix {
+            println!("     {fix}");
+        }
+    }
+    let all_ok = checks.iter().all(|check| check.ok);
+    if all_ok {
+        println!("Everything looks fine.");
+    }
+    all_ok
+}
@@ -0,0 +1,75 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Removes the folders in the target library that have nothing in them anymore, like the album
+/// and artist folders that are left behind when songs are moved around in the source library.
+/// Folders that only have empty folders in them are removed as well, from the bottom up. The
+/// target library itself is kept. Returns the removed folders, deepest first; with `dry_run`,
+/// the ones that would be removed.
+pub fn remove_empty_dirs(target_library: &Path, dry_run: bool) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(target_library)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_if_empty(&entry.path(), dry_run, &mut removed)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns whether the folder was (or would be) removed.
+fn remove_if_empty(dir: &Path, dry_run: bool, removed: &mut Vec<PathBuf>) -> io::Result<bool> {
+    let mut empty = true;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Symlinks are never followed, and count as something that is in the folder.
+        if !entry.file_type()?.is_dir() || !remove_if_empty(&entry.path(), dry_run, removed)? {
+            empty = false;
+        }
+    }
+    if empty {
+        if !dry_run {
+            fs::remove_dir(dir)?;
+        }
+        removed.push(dir.to_path_buf());
+    }
+    Ok(empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::remove_empty_dirs;
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    /// Folders with only empty folders in them go too, but folders with a file in them stay.
+    fn removes_empty_dirs_bottom_up() {
+        let target_library = PathBuf::from(format!(
+            "/tmp/syncbops/test_empty_dirs_{}",
+            random_string::generate(24, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        fs::create_dir_all(target_library.join("Artist/Old Album/CD1")).unwrap();
+        fs::create_dir_all(target_library.join("Artist/Album")).unwrap();
+        fs::write(target_library.join("Artist/Album/01 Song.mp3"), b"").unwrap();
+
+        let would_remove = remove_empty_dirs(&target_library, true).unwrap();
+        assert_eq!(
+            would_remove,
+            [
+                target_library.join("Artist/Old Album/CD1"),
+                target_library.join("Artist/Old Album"),
+            ]
+        );
+        assert!(target_library.join("Artist/Old Album/CD1").exists());
+
+        assert_eq!(
+            remove_empty_dirs(&target_library, false).unwrap(),
+            would_remove
+        );
+        assert!(!target_library.join("Artist/Old Album").exists());
+        assert!(target_library.join("Artist/Album/01 Song.mp3").exists());
+        fs::remove_dir_all(&target_library).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{
+    hashing::{encode_speeds, estimate_transcode_time, PreviousSyncDb},
+    music_library::MusicFileType,
+    song::Song,
+    sync_song::{would_copy, SyncSettings},
+    target_path::TargetPlan,
+};
+use indicatif::HumanDuration;
+use std::{collections::HashMap, fmt::Display, path::PathBuf, time::Duration};
+
+/// What a sync is expected to do, worked out before starting it, so that it can be decided
+/// whether to run it now or e.g. overnight. Songs that changed since the last sync can't be
+/// known without hashing them, so those count as unchanged.
+#[derive(Debug, PartialEq)]
+pub struct SyncEstimate {
+    pub to_transcode: usize,
+    pub to_copy: usize,
+    pub unchanged: usize,
+    /// Bytes that will be written to the target library.
+    pub written: u64,
+    /// Size of all the songs in the target library after the sync, in bytes.
+    pub target_size: u64,
+    /// How long transcoding will take. None if it is unknown how fast songs are transcoded,
+    /// e.g. because there was no sync before.
+    pub time: Option<Duration>,
+}
+
+/// `parallel` is how many songs are transcoded at the same time.
+pub fn estimate_sync(
+    songs: &[Song],
+    target_plan: &TargetPlan,
+    previous_sync_db: Option<&PreviousSyncDb>,
+    duplicates: &HashMap<PathBuf, PathBuf>,
+    settings: &SyncSettings,
+    parallel: usize,
+) -> SyncEstimate {
+    let mut estimate = SyncEstimate {
+        to_transcode: 0,
+        to_copy: 0,
+        unchanged: 0,
+        written: 0,
+        target_size: 0,
+        time: None,
+    };
+    let mut transcoded = Vec::new();
+    for song in songs {
+        // Duplicates are links to the original, so they take no space.
+        if duplicates.contains_key(&song.library_relative_path) {
+            continue;
+        }
+        let shadow = &target_plan[&song.library_relative_path];
+        let synchronised_before = previous_sync_db.map_or(shadow.exists(), |db| {
+            db.contains_key(&song.library_relative_path)
+        });
+        if synchronised_before && !settings.force {
+            estimate.unchanged += 1;
+            estimate.target_size += std::fs::metadata(shadow).map_or(0, |m| m.len());
+            continue;
+        }
+        let source_size = std::fs::metadata(&song.absolute_path).map_or(0, |m| m.len());
+        let size = if would_copy(song, settings) {
+            estimate.to_copy += 1;
+            source_size
+        } else {
+            estimate.to_transcode += 1;
+            transcoded.push(song);
+            transcoded_size(song, source_size, settings)
+        };
+        estimate.written += size;
+        estimate.target_size += size;
+    }
+    if let Some(db) = previous_sync_db {
+        estimate.time =
+            estimate_transcode_time(&transcoded, settings, &encode_speeds(db), parallel);
+    }
+    estimate
+}
+
+/// Roughly how large the song will be after transcoding it.
+pub fn transcoded_size(song: &Song, source_size: u64, settings: &SyncSettings) -> u64 {
+    let filetype = settings.target_filetype_for(song);
+    let Some(duration) = song.metadata.duration else {
+        return source_size;
+    };
+    if matches!(filetype, MusicFileType::Copy) {
+        return source_size;
+    }
+    let kbps = filetype
+        .equivalent_bitrate()
+        .min(song.metadata.bitrate_kbps);
+    (kbps as f64 * 1000. / 8. * duration.as_secs_f64()) as u64
+}
+
+impl Display for SyncEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} songs will be transcoded, {} copied, and {} are probably unchanged.",
+            self.to_transcode, self.to_copy, self.unchanged
+        )?;
+        writeln!(
+            f,
+            "About {} MB will be written, making the songs in the target library about {} MB.",
+            self.written / 1_000_000,
+            self.target_size / 1_000_000
+        )?;
+        match self.time {
+            Some(time) => writeln!(
+                f,
+                "Transcoding will take about {}, judging by earlier syncs.",
+                HumanDuration(time)
+            ),
+            None if self.to_transcode > 0 => writeln!(
+                f,
+                "How long transcoding will take is known after the first sync."
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::estimate_sync;
+    use crate::{
+        hashing::{PreviousSyncDb, SyncRecord},
+        music_library::{ArtStrategy, MusicFileType, UpdateType},
+        song::Song,
+        sync_song::SyncSettings,
+        target_path::TargetPlan,
+    };
+    use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+    #[test]
+    /// Songs with a record count as unchanged, new ones are transcoded or copied depending on
+    /// their bitrate.
+    fn estimate_what_a_sync_does() {
+        let songs = [("old.flac", 900), ("new.flac", 900), ("low.mp3", 96)].map(|(path, kbps)| {
+            let mut song = Song::new_fake(path, &[]);
+            song.metadata.bitrate_kbps = kbps;
+            song.metadata.duration = Some(Duration::from_secs(100));
+            song
+        });
+        let target_plan: TargetPlan = songs
+            .iter()
+            .map(|song| {
+                (
+                    song.library_relative_path.clone(),
+                    PathBuf::from("/nonexistent_target").join(&song.library_relative_path),
+                )
+            })
+            .collect();
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Opus {
+                bitrate: 128,
+                compression_level: 10,
+                vbr: crate::music_library::OpusVbr::On,
+            },
+            ArtStrategy::None,
+        );
+        let mut db = PreviousSyncDb::new();
+        db.insert(
+            "old.flac".into(),
+            SyncRecord::from_song(&songs[0], &settings).set_update_type(UpdateType::NewTranscode),
+        );
+
+        let estimate = estimate_sync(
+            &songs,
+            &target_plan,
+            Some(&db),
+            &HashMap::new(),
+            &settings,
+            1,
+        );
+        assert_eq!(estimate.unchanged, 1);
+        assert_eq!(estimate.to_transcode, 1);
+        assert_eq!(estimate.to_copy, 1);
+        // Nothing was transcoded before, so there is no speed to go by.
+        assert_eq!(estimate.time, None);
+    }
+
+    #[test]
+    /// The estimate is shown before the sync starts, so making it doesn't write anything to the
+    /// target library.
+    fn estimate_before_writing() {
+        let target_library = PathBuf::from(format!(
+            "/tmp/syncbops/test_estimate_{}",
+            random_string::generate(24, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        std::fs::create_dir_all(&target_library).unwrap();
+        let song = Song::new_fake("Artist/Album/01 Song.flac", &[]);
+        let target_plan: TargetPlan = [(
+            song.library_relative_path.clone(),
+            target_library.join("Artist/Album/01 Song.opus"),
+        )]
+        .into_iter()
+        .collect();
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Opus {
+                bitrate: 128,
+                compression_level: 10,
+                vbr: crate::music_library::OpusVbr::On,
+            },
+            ArtStrategy::None,
+        );
+
+        let estimate = estimate_sync(&[song], &target_plan, None, &HashMap::new(), &settings, 1);
+        assert_eq!(estimate.to_transcode, 1);
+        assert_eq!(std::fs::read_dir(&target_library).unwrap().count(), 0);
+        std::fs::remove_dir_all(&target_library).unwrap();
+    }
+}
@@ -1,26 +1,325 @@
-use crate::music_library::MusicFileType;
+use crate::{
+    audiobooks::Chapters, cue::Segment, music_library::MusicFileType,
+    native_metadata::parse_natively, replaygain::Loudness,
+};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::{
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex, OnceLock,
+    },
+    time::Duration,
 };
 
+/// Print every ffmpeg command before running it, to see exactly what is done to a song.
+pub static PRINT_COMMANDS: AtomicBool = AtomicBool::new(false);
+
+fn print_command(command: &Command) {
+    if PRINT_COMMANDS.load(Ordering::Relaxed) {
+        println!(
+            "{} {}",
+            command.get_program().to_string_lossy(),
+            command
+                .get_args()
+                .map(|osstr| osstr.to_string_lossy())
+                .join(" ")
+        );
+    }
+}
+
+/// Caps how many songs are transcoded at the same time. Every transcode is an ffmpeg process
+/// that can use multiple threads itself, so running one for every thread in the pool can load
+/// the machine a lot more than intended.
+pub static ENCODER_LIMIT: EncoderLimit = EncoderLimit::new();
+
+/// Counts how many encoders are running, and makes new ones wait while there are too many.
+pub struct EncoderLimit {
+    /// How many are running, and how many may run. No maximum if None.
+    running: Mutex<(usize, Option<usize>)>,
+    finished: Condvar,
+}
+
+impl EncoderLimit {
+    const fn new() -> Self {
+        EncoderLimit {
+            running: Mutex::new((0, None)),
+            finished: Condvar::new(),
+        }
+    }
+
+    pub fn set_max(&self, max: usize) {
+        self.running.lock().unwrap().1 = Some(max.max(1));
+        self.finished.notify_all();
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.running.lock().unwrap().1
+    }
+
+    /// Waits until another encoder may run. It counts as running until the slot is dropped.
+    fn acquire(&self) -> EncoderSlot<'_> {
+        let mut running = self
+            .finished
+            .wait_while(self.running.lock().unwrap(), |(running, max)| {
+                max.is_some_and(|max| *running >= max)
+            })
+            .unwrap();
+        running.0 += 1;
+        EncoderSlot(self)
+    }
+}
+
+struct EncoderSlot<'a>(&'a EncoderLimit);
+
+impl Drop for EncoderSlot<'_> {
+    fn drop(&mut self) {
+        self.0.running.lock().unwrap().0 -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
 /// Gets stuff like title, artist name, etc.
 /// Also, whether the song has album art.
-#[derive(Debug)]
+/// Increase the version in `METADATA_CACHE_HEADER` when changing this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SongMetaData {
     pub title: Option<String>,
     pub bitrate_kbps: u32,
+    /// In Hz.
+    pub sample_rate: Option<u32>,
+    /// Amount of audio channels, e.g. 2 for stereo.
+    pub channels: Option<u32>,
+    /// Bits per sample, e.g. 24 for hi-res FLAC. None for lossy formats, which don't have one.
+    pub bit_depth: Option<u32>,
+    /// Name of the audio codec, as ffmpeg calls it, e.g. "flac" or "mp3".
+    pub codec: Option<String>,
     pub has_embedded_album_art: bool,
-    // TODO: Extend with Duration, Artist, Album Artist, Album, etc. Considering how many tags
-    // there are, maybe even save all actual 'tags' as a hashmap.
+    /// Whether the file has an actual video in it, like a recording of a live set. Embedded
+    /// pictures don't count.
+    pub has_video: bool,
+    /// Width and height of the embedded art, in pixels.
+    pub embedded_art_resolution: Option<(u32, u32)>,
+    /// All the pictures embedded in the file. Usually just the cover, but it can also be e.g. the
+    /// scans of a booklet.
+    pub embedded_pictures: Vec<EmbeddedPicture>,
+    /// All the tags in the file. Keys are lowercased, because different formats capitalise them
+    /// differently.
+    pub tags: HashMap<String, String>,
+    pub duration: Option<Duration>,
+}
+
+/// A picture embedded in a music file, as a separate (video) stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddedPicture {
+    /// The index of the stream in the file.
+    pub stream_index: usize,
+    pub resolution: Option<(u32, u32)>,
+    pub is_front_cover: bool,
+}
+
+/// How to handle the album art when transcoding.
+#[derive(Debug, Clone, Copy)]
+pub struct ArtEmbedding<'a> {
+    /// Whether to have any art in the target file at all.
+    pub embed: bool,
+    /// Embed this art instead of the art embedded in the source.
+    pub external_art: Option<&'a Path>,
+    /// Art larger than this (in either direction) is scaled down. 0 means don't scale.
+    pub max_resolution: u32,
+    /// Which pictures that are embedded in the source to keep, if not using external art.
+    pub pictures: PictureSelection,
+    /// Re-encode the art as JPEG with this quality (1-100), to make it smaller.
+    pub recompress_quality: Option<u8>,
+}
+
+/// Changes to make to the audio itself, on top of encoding it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AudioConversion {
+    /// Resample to this sample rate (in Hz).
+    pub sample_rate: Option<u32>,
+    /// Mix down to this amount of channels.
+    pub channels: Option<u32>,
+    /// Reduce the samples to 16 bits. Only has effect for lossless targets.
+    pub to_16_bit: bool,
+    /// Dither when reducing the bit depth, so the quantisation error becomes noise instead of
+    /// distortion.
+    pub dither: bool,
+    /// Only take this part of the source, for the tracks of a single-file album.
+    pub segment: Option<Segment>,
+    /// What to do with the chapters, for audiobooks. None for other songs, which keep them if
+    /// the ffmpeg command is used and the target container can have them.
+    pub chapters: Option<Chapters>,
+}
+
+/// Which of the pictures embedded in the source file to carry over into the target file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PictureSelection {
+    All,
+    /// Only the picture in the stream with this index.
+    Stream(usize),
 }
 
 impl SongMetaData {
+    /// Common formats are read in-process, the rest with ffprobe.
     pub fn parse_file(path: &Path) -> Result<SongMetaData, FfmpegError> {
+        if let Some(metadata) = parse_natively(path) {
+            return Ok(metadata);
+        }
         parse_music_file_metadata(path)
     }
+
+    /// Gets the first of the given tags that is present and not empty.
+    pub fn tag(&self, keys: &[&str]) -> Option<&str> {
+        keys.iter()
+            .filter_map(|key| self.tags.get(*key))
+            .map(|value| value.trim())
+            .find(|value| !value.is_empty())
+    }
+
+    pub fn artist(&self) -> Option<&str> {
+        self.tag(&["artist"])
+    }
+
+    /// Falls back to the track artist if there is no album artist.
+    pub fn album_artist(&self) -> Option<&str> {
+        self.tag(&["album_artist", "albumartist", "album artist"])
+            .or_else(|| self.artist())
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.tag(&["album"])
+    }
+
+    /// Whether the audio is stored without loss of quality. None if the codec is unknown.
+    pub fn is_lossless(&self) -> Option<bool> {
+        const LOSSLESS_CODECS: [&str; 8] = [
+            "flac",
+            "alac",
+            "wavpack",
+            "ape",
+            "tta",
+            "mlp",
+            "truehd",
+            "wmalossless",
+        ];
+        let codec = self.codec.as_deref()?;
+        // WAV and AIFF files in all their sample formats, and DSD (dsf and dff) files.
+        Some(
+            LOSSLESS_CODECS.contains(&codec)
+                || codec.starts_with("pcm_")
+                || codec.starts_with("dsd_"),
+        )
+    }
+
+    /// The embedded picture that is the front cover of the album.
+    pub fn front_cover(&self) -> Option<&EmbeddedPicture> {
+        front_cover(&self.embedded_pictures)
+    }
+
+    pub fn genre(&self) -> Option<&str> {
+        self.tag(&["genre"])
+    }
+
+    pub fn year(&self) -> Option<&str> {
+        // Dates are often given in full, but the year is the bit that matters.
+        self.tag(&["date", "year", "originaldate"])
+            .map(|date| date.split('-').next().unwrap_or(date))
+    }
+
+    pub fn track_number(&self) -> Option<u32> {
+        self.tag(&["track", "tracknumber"]).and_then(parse_position)
+    }
+
+    pub fn disc_number(&self) -> Option<u32> {
+        self.tag(&["disc", "discnumber"]).and_then(parse_position)
+    }
+
+    /// The rating of the song in stars, from 0 to 5. Read from the FMPS rating (0 to 1), the
+    /// ID3v2 popularimeter (0 to 255), or a rating tag (1 to 5, or 0 to 100). None if the song
+    /// is not rated.
+    pub fn rating(&self) -> Option<f64> {
+        if let Some(fmps) = self
+            .tag(&["fmps_rating"])
+            .and_then(|r| r.parse::<f64>().ok())
+        {
+            return Some((fmps * 5.).clamp(0., 5.));
+        }
+        // Only read in-process, ffprobe leaves the popularimeter out. The key has the email of
+        // the player that set it after it, like "popm:no@email".
+        let popularimeter = self
+            .tags
+            .iter()
+            .filter(|(key, _)| key.starts_with("popm"))
+            .find_map(|(_, value)| value.trim().parse::<u8>().ok());
+        if let Some(popularimeter) = popularimeter {
+            // The steps that Windows Media Player and most other players use. 0 is unrated.
+            return match popularimeter {
+                0 => None,
+                1..=31 => Some(1.),
+                32..=95 => Some(2.),
+                96..=159 => Some(3.),
+                160..=223 => Some(4.),
+                _ => Some(5.),
+            };
+        }
+        let rating = self.tag(&["rating"])?.parse::<f64>().ok()?;
+        Some(if rating > 5. {
+            (rating / 20.).min(5.)
+        } else {
+            rating
+        })
+    }
+
+    /// Whether the durations of the two are about the same, like they are for a song and its
+    /// synchronised copy. True if either is unknown.
+    pub fn has_same_duration(&self, other: &SongMetaData) -> bool {
+        match (self.duration, other.duration) {
+            (Some(a), Some(b)) => a.abs_diff(b) <= MAX_DURATION_DIFFERENCE,
+            _ => true,
+        }
+    }
+
+    /// Whether the song is part of an album with songs from many different artists. Either it
+    /// is explicitly flagged as such, or the album artist says so.
+    pub fn is_compilation(&self) -> bool {
+        const VARIOUS_ARTISTS: [&str; 4] = ["various artists", "various", "va", "v.a."];
+        let flagged = self
+            .tag(&["compilation", "itunescompilation", "tcmp"])
+            .is_some_and(|x| x == "1" || x.eq_ignore_ascii_case("true"));
+        // Don't use album_artist(), because that falls back to the track artist.
+        let various = self
+            .tag(&["album_artist", "albumartist", "album artist"])
+            .is_some_and(|x| VARIOUS_ARTISTS.contains(&x.to_lowercase().as_str()));
+        flagged || various
+    }
+}
+
+/// Durations of a song and its synchronised copy may differ a bit, because of encoder padding.
+const MAX_DURATION_DIFFERENCE: Duration = Duration::from_secs(2);
+
+/// Tags that can have the title, in the order they are tried. Besides the usual one, some
+/// taggers leave the raw ID3 frame or MP4 atom, and WAV files can have it in a RIFF INFO chunk.
+const TITLE_TAGS: &[&str] = &["title", "tit2", "\u{a9}nam", "inam"];
+
+/// The title of the song, from the first of the `TITLE_TAGS` that is not empty. Songs without
+/// any are named after their file instead (see `Song::title()`).
+pub fn title_from_tags(tags: &HashMap<String, String>) -> Option<String> {
+    TITLE_TAGS
+        .iter()
+        .filter_map(|key| tags.get(*key))
+        .map(|title| title.trim())
+        .find(|title| !title.is_empty())
+        .map(str::to_owned)
+}
+
+/// Track and disc numbers are often written as "3/12".
+fn parse_position(s: &str) -> Option<u32> {
+    s.split('/').next()?.trim().parse().ok()
 }
 
 fn parse_music_file_metadata(path: &Path) -> Result<SongMetaData, FfmpegError> {
@@ -69,6 +368,25 @@ fn parse_music_file_metadata(path: &Path) -> Result<SongMetaData, FfmpegError> {
         })
         .expect("File does not have an audio stream.");
 
+    // Given as a string.
+    let sample_rate = match &audio_stream["sample_rate"] {
+        JsonValue::Number(x) => x.as_u64().map(|a| a as u32),
+        JsonValue::String(s) => s.parse::<u32>().ok(),
+        _ => None,
+    };
+
+    let channels = audio_stream["channels"].as_u64().map(|a| a as u32);
+
+    // Given as a string, and missing (or 0) for lossy formats.
+    let bit_depth = match &audio_stream["bits_per_raw_sample"] {
+        JsonValue::Number(x) => x.as_u64().map(|a| a as u32),
+        JsonValue::String(s) => s.parse::<u32>().ok(),
+        _ => None,
+    }
+    .filter(|bits| *bits > 0);
+
+    let codec = audio_stream["codec_name"].as_str().map(|s| s.to_owned());
+
     // If it is given as a string, turn it into a number.
     let Some(bitrate_kbps) = match &audio_stream["bit_rate"] {
         JsonValue::Number(x) => x.as_u64().map(|a| a as u32),
@@ -89,53 +407,401 @@ fn parse_music_file_metadata(path: &Path) -> Result<SongMetaData, FfmpegError> {
         });
     };
 
-    // Extract the title from the global metadata block
-    let title = parsed["format"]["tags"]["title"]
-        .as_str()
-        // in FLAC, often fully capitalised
-        .or_else(|| parsed["format"]["tags"]["TITLE"].as_str())
-        // in .ogg, sometimes the global metadata block is missing. Then try the audio
-        // stream-specific block.
-        .or_else(|| audio_stream["tags"]["TITLE"].as_str())
-        .or_else(|| audio_stream["tags"]["title"].as_str())
-        .or_else(|| todo!("Can't extract title. Implement other fallbacks!"))
-        .map(|s| s.to_owned());
-
-    // To check if the thing has album art, just check if there is a video stream.
-    let video_stream: &JsonValue = &parsed["streams"][1];
-    let has_embedded_album_art = !video_stream.is_null();
-    // debug_assert!(video_stream["codec_type"].as_str().unwrap() == "video")
+    // Tags can be in the global metadata block, or in the stream-specific one (e.g. in .ogg).
+    // The global ones take precedence.
+    let mut tags = HashMap::new();
+    for block in [&audio_stream["tags"], &parsed["format"]["tags"]] {
+        let Some(block) = block.as_object() else {
+            continue;
+        };
+        for (key, value) in block {
+            if let Some(value) = value.as_str() {
+                tags.insert(key.to_lowercase(), value.to_owned());
+            }
+        }
+    }
+
+    let title = title_from_tags(&tags);
+
+    let has_video = parsed["streams"]
+        .as_array()
+        .is_some_and(|streams| streams.iter().any(is_actual_video));
+    let duration = match &parsed["format"]["duration"] {
+        JsonValue::Number(x) => x.as_f64(),
+        JsonValue::String(s) => s.parse::<f64>().ok(),
+        _ => None,
+    }
+    .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+
+    let embedded_pictures = parse_embedded_pictures(&parsed["streams"]);
+    // Pictures are stored as (single frame) video streams.
+    let has_embedded_album_art = !embedded_pictures.is_empty();
+    let embedded_art_resolution = front_cover(&embedded_pictures).and_then(|p| p.resolution);
 
     Ok(SongMetaData {
         title,
         bitrate_kbps,
+        sample_rate,
+        channels,
+        bit_depth,
+        codec,
         has_embedded_album_art,
+        has_video,
+        embedded_art_resolution,
+        embedded_pictures,
+        tags,
+        duration,
     })
 }
 
-pub fn ensure_ffmpeg_capable(filetype: &MusicFileType) -> Result<(), FfmpegCapabilityError> {
+/// The size in bytes of each picture embedded in a music file, by stream index.
+pub fn embedded_picture_sizes(path: &Path) -> Result<HashMap<usize, u64>, FfmpegError> {
+    // `ffprobe -loglevel 0 -print_format json -select_streams v -show_entries packet=stream_index,size <path>`
+    let mut binding = Command::new("ffprobe");
+    binding
+        .arg("-loglevel")
+        .arg("0")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-select_streams")
+        .arg("v")
+        .arg("-show_entries")
+        .arg("packet=stream_index,size")
+        .arg(path);
+    let ffprobe = binding
+        .output()
+        .map_err(|e| FfmpegError::CheckForAlbumArtCommand {
+            source: e,
+            arguments: binding
+                .get_args()
+                .map(|osstr| osstr.to_string_lossy())
+                .join(" "),
+        })?;
+    let parsed: JsonValue =
+        serde_json::from_slice(&ffprobe.stdout).map_err(|_| FfmpegError::JsonMetadata)?;
+    let mut sizes = HashMap::new();
+    for packet in parsed["packets"].as_array().into_iter().flatten() {
+        let Some(index) = packet["stream_index"].as_u64() else {
+            continue;
+        };
+        // Sizes are given as a string.
+        let size = match &packet["size"] {
+            JsonValue::Number(x) => x.as_u64(),
+            JsonValue::String(s) => s.parse::<u64>().ok(),
+            _ => None,
+        };
+        *sizes.entry(index as usize).or_default() += size.unwrap_or(0);
+    }
+    Ok(sizes)
+}
+
+/// A chapter of an audiobook, or of another file with chapters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub start: Duration,
+    pub title: Option<String>,
+}
+
+/// The chapters in a file, like
+/// `ffprobe -loglevel 0 -print_format json -show_chapters <path>`.
+pub fn read_chapters(path: &Path) -> Result<Vec<Chapter>, FfmpegError> {
+    let mut binding = Command::new("ffprobe");
+    binding
+        .arg("-loglevel")
+        .arg("0")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_chapters")
+        .arg(path);
+    let ffprobe = binding.output().map_err(|e| FfmpegError::ChaptersCommand {
+        source: e,
+        arguments: binding
+            .get_args()
+            .map(|osstr| osstr.to_string_lossy())
+            .join(" "),
+    })?;
+    let parsed: JsonValue =
+        serde_json::from_slice(&ffprobe.stdout).map_err(|_| FfmpegError::JsonMetadata)?;
+    Ok(parse_chapters(&parsed))
+}
+
+fn parse_chapters(parsed: &JsonValue) -> Vec<Chapter> {
+    parsed["chapters"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|chapter| {
+            // Times are given as a string, in seconds.
+            let start = chapter["start_time"].as_str()?.parse::<f64>().ok()?;
+            Some(Chapter {
+                start: Duration::from_secs_f64(start.max(0.)),
+                title: chapter["tags"]["title"].as_str().map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+/// Measures the loudness of a song according to EBU R128, like
+/// `ffmpeg -nostats -i <path> -filter_complex ebur128=peak=true -f null -`.
+pub fn measure_loudness(path: &Path) -> Result<Loudness, FfmpegError> {
     let mut binding = Command::new("ffmpeg");
-    binding.arg("-hide_banner").arg("-buildconf");
-    let ffprobe = binding.output()?;
-    let stdout = String::from_utf8(ffprobe.stdout)?;
+    binding
+        .arg("-nostats")
+        .arg("-i")
+        .arg(path)
+        .arg("-filter_complex")
+        .arg("ebur128=peak=true")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    let arguments = binding
+        .get_args()
+        .map(|osstr| osstr.to_string_lossy())
+        .join(" ");
+    print_command(&binding);
+    let output = binding.output().map_err(|e| FfmpegError::LoudnessCommand {
+        source: e,
+        arguments: arguments.clone(),
+    })?;
+    // The filter logs its results, so they end up in stderr.
+    let msg = String::from_utf8_lossy(&output.stderr).to_string();
+    if !output.status.success() {
+        return Err(FfmpegError::FfmpegNotSuccesful {
+            file: path.into(),
+            arguments,
+            msg,
+        });
+    }
+    parse_ebur128_summary(&msg).ok_or(FfmpegError::Loudness {
+        path: path.to_string_lossy().into_owned(),
+    })
+}
+
+/// Reads the integrated loudness and true peak from the summary the ebur128 filter prints at the
+/// end.
+fn parse_ebur128_summary(output: &str) -> Option<Loudness> {
+    let last_value = |label: &str| {
+        output
+            .lines()
+            .rev()
+            .find_map(|line| line.trim().strip_prefix(label))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|value| value.parse::<f64>().ok())
+    };
+    Some(Loudness {
+        integrated_lufs: last_value("I:")?,
+        true_peak_dbfs: last_value("Peak:")?,
+    })
+}
+
+/// Width and height of an image, in pixels.
+pub fn image_resolution(path: &Path) -> Result<(u32, u32), FfmpegError> {
+    // `ffprobe -loglevel 0 -print_format json -show_streams <path>`
+    let mut binding = Command::new("ffprobe");
+    binding
+        .arg("-loglevel")
+        .arg("0")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_streams")
+        .arg(path);
+    let ffprobe = binding.output().map_err(|e| FfmpegError::ArtCommand {
+        source: e,
+        arguments: binding
+            .get_args()
+            .map(|osstr| osstr.to_string_lossy())
+            .join(" "),
+    })?;
+    let parsed: JsonValue =
+        serde_json::from_slice(&ffprobe.stdout).map_err(|_| FfmpegError::JsonMetadata)?;
+    let stream = &parsed["streams"][0];
+    match (stream["width"].as_u64(), stream["height"].as_u64()) {
+        (Some(width), Some(height)) => Ok((width as u32, height as u32)),
+        _ => Err(FfmpegError::JsonMetadata),
+    }
+}
+
+fn parse_embedded_pictures(streams: &JsonValue) -> Vec<EmbeddedPicture> {
+    let Some(streams) = streams.as_array() else {
+        return Vec::new();
+    };
+    streams
+        .iter()
+        .filter(|stream| stream["codec_type"].as_str() == Some("video") && !is_actual_video(stream))
+        .filter_map(|stream| {
+            let resolution = match (stream["width"].as_u64(), stream["height"].as_u64()) {
+                (Some(width), Some(height)) => Some((width as u32, height as u32)),
+                _ => None,
+            };
+            // ffmpeg puts the picture type (as in ID3v2 and FLAC) in the comment.
+            let comment = stream["tags"]["comment"]
+                .as_str()
+                .or_else(|| stream["tags"]["COMMENT"].as_str());
+            Some(EmbeddedPicture {
+                stream_index: stream["index"].as_u64()? as usize,
+                resolution,
+                is_front_cover: comment == Some("Cover (front)"),
+            })
+        })
+        .collect()
+}
+
+/// Whether the stream is a moving video, instead of a picture embedded in a music file. Pictures
+/// are flagged as attached pictures, and are encoded as images.
+fn is_actual_video(stream: &JsonValue) -> bool {
+    const IMAGE_CODECS: [&str; 5] = ["mjpeg", "png", "bmp", "webp", "gif"];
+    stream["codec_type"].as_str() == Some("video")
+        && stream["disposition"]["attached_pic"].as_u64() == Some(0)
+        && !stream["codec_name"]
+            .as_str()
+            .is_some_and(|codec| IMAGE_CODECS.contains(&codec))
+}
+
+/// The picture that is flagged as the front cover. If none are, the first one is used.
+fn front_cover(pictures: &[EmbeddedPicture]) -> Option<&EmbeddedPicture> {
+    pictures
+        .iter()
+        .find(|p| p.is_front_cover)
+        .or_else(|| pictures.first())
+}
+
+/// Use the encoders that come with ffmpeg itself when the build of ffmpeg doesn't have the
+/// preferred ones, instead of failing.
+pub static ALLOW_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+/// The audio encoders of ffmpeg, so that they are only listed once instead of for every song.
+static ENCODERS: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// The ffmpeg encoder that a filetype is encoded with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Encoder {
+    pub name: &'static str,
+    /// The preferred encoder, if ffmpeg doesn't have it and this one is used instead.
+    pub fallback_for: Option<&'static str>,
+}
+
+impl Encoder {
+    /// The fallbacks are marked as experimental by ffmpeg, which have to be allowed explicitly.
+    pub fn is_experimental(&self) -> bool {
+        self.fallback_for.is_some()
+    }
+}
+
+/// The encoder that gives the best result for the filetype. None if nothing is encoded.
+fn preferred_encoder(filetype: &MusicFileType) -> Option<&'static str> {
     match filetype {
-        MusicFileType::Mp3CBR { .. } => (),
-        MusicFileType::Mp3VBR { .. } => (),
-        MusicFileType::Opus { .. } => {
-            if !stdout.contains("--enable-libopus") {
-                return Err(FfmpegCapabilityError::OpusNotAvailable);
-            }
-        }
-        MusicFileType::Vorbis { .. } => {
-            if !stdout.contains("--enable-libvorbis") {
-                return Err(FfmpegCapabilityError::VorbisNotAvailable);
-            }
+        MusicFileType::Mp3CBR { .. } | MusicFileType::Mp3VBR { .. } => Some("libmp3lame"),
+        MusicFileType::Vorbis { .. } => Some("libvorbis"),
+        MusicFileType::Opus { .. } => Some("libopus"),
+        MusicFileType::Flac { .. } => Some("flac"),
+        MusicFileType::Copy => None,
+    }
+}
+
+/// The encoder of ffmpeg itself that does the same as one from an external library. They are
+/// not as good, but better than not being able to transcode at all.
+fn fallback_encoder(encoder: &str) -> Option<&'static str> {
+    match encoder {
+        "libopus" => Some("opus"),
+        "libvorbis" => Some("vorbis"),
+        _ => None,
+    }
+}
+
+/// Which of the `available` encoders to use for the filetype.
+pub fn choose_encoder(
+    filetype: &MusicFileType,
+    available: &HashSet<String>,
+    allow_fallback: bool,
+) -> Result<Option<Encoder>, FfmpegCapabilityError> {
+    let Some(preferred) = preferred_encoder(filetype) else {
+        return Ok(None);
+    };
+    if available.contains(preferred) {
+        return Ok(Some(Encoder {
+            name: preferred,
+            fallback_for: None,
+        }));
+    }
+    let fallback = fallback_encoder(preferred).filter(|fallback| available.contains(*fallback));
+    match fallback {
+        Some(fallback) if allow_fallback => Ok(Some(Encoder {
+            name: fallback,
+            fallback_for: Some(preferred),
+        })),
+        _ => Err(FfmpegCapabilityError::EncoderNotAvailable {
+            encoder: preferred,
+            fallback,
+        }),
+    }
+}
+
+/// Checks whether ffmpeg can encode to the filetype, and with which encoder. ffmpeg is only
+/// asked for its encoders the first time.
+pub fn ensure_ffmpeg_capable(
+    filetype: &MusicFileType,
+) -> Result<Option<Encoder>, FfmpegCapabilityError> {
+    let encoders = match ENCODERS.get() {
+        Some(encoders) => encoders,
+        None => {
+            let encoders = ffmpeg_encoders()?;
+            ENCODERS.get_or_init(|| encoders)
         }
+    };
+    choose_encoder(filetype, encoders, ALLOW_FALLBACK.load(Ordering::Relaxed))
+}
+
+/// The encoder that `ensure_ffmpeg_capable()` picked for the filetype, like "libopus" or its
+/// fallback "opus". None if ffmpeg was not asked for its encoders, or the filetype is not
+/// encoded.
+pub fn chosen_encoder(filetype: &MusicFileType) -> Option<&'static str> {
+    let encoders = ENCODERS.get()?;
+    choose_encoder(filetype, encoders, ALLOW_FALLBACK.load(Ordering::Relaxed))
+        .ok()?
+        .map(|encoder| encoder.name)
+}
 
-        MusicFileType::Flac { .. } => (),
+/// Names of the audio encoders that ffmpeg has, like "libmp3lame" and "flac".
+pub fn ffmpeg_encoders() -> Result<HashSet<String>, FfmpegCapabilityError> {
+    let output = Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-encoders")
+        .output()?;
+    if !output.status.success() {
+        return Err(FfmpegCapabilityError::NotInstalled);
     }
+    Ok(parse_audio_encoders(&String::from_utf8(output.stdout)?))
+}
 
-    Ok(())
+/// Reads the list of `ffmpeg -encoders`, of which the lines are like
+/// " A....D libmp3lame           libmp3lame MP3 (MPEG audio layer 3) (codec mp3)". The first
+/// letter of the flags is A for audio encoders.
+fn parse_audio_encoders(list: &str) -> HashSet<String> {
+    list.lines()
+        // The legend at the top is also indented, so only take the lines after it.
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let flags = words.next()?;
+            let name = words.next()?;
+            flags.starts_with('A').then(|| name.to_owned())
+        })
+        .collect()
+}
+
+/// The version of ffmpeg that is used, like "6.1.1". None if it can't be determined.
+pub fn ffmpeg_version() -> Option<String> {
+    let output = Command::new("ffmpeg").arg("-version").output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The first line is like "ffmpeg version 6.1.1 Copyright (c) 2000-2023 the FFmpeg developers"
+    stdout
+        .lines()
+        .next()?
+        .strip_prefix("ffmpeg version ")?
+        .split_whitespace()
+        .next()
+        .map(str::to_owned)
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -147,11 +813,24 @@ pub enum FfmpegCapabilityError {
     #[error("ffmpeg does not appear to be available. Are you sure you have installed it?")]
     NotInstalled,
     #[error(
-        "Cannot encode to Vorbis (ogg), because ffmpeg was not built with `--enable-libvorbis`."
+        "Cannot encode with {encoder}, because this build of ffmpeg does not have it.{}",
+        fallback_hint(.fallback)
     )]
-    VorbisNotAvailable,
-    #[error("Cannot encode to OPUS, because ffmpeg was not built with `--enable-libopus`.")]
-    OpusNotAvailable,
+    EncoderNotAvailable {
+        encoder: &'static str,
+        /// The encoder that could be used instead with `--allow-fallback`.
+        fallback: Option<&'static str>,
+    },
+}
+
+fn fallback_hint(fallback: &Option<&'static str>) -> String {
+    match fallback {
+        Some(fallback) => format!(
+            " Install a build of ffmpeg that has it, or pass --allow-fallback to use the {fallback} \
+            encoder of ffmpeg itself, which does not sound as good."
+        ),
+        None => " Install a build of ffmpeg that has it, or choose another target filetype.".into(),
+    }
 }
 
 /// Takes a path of a song file, transcodes it using ffmpeg, and saves it to the target path. Returns the path of the output file. Like `ffmpeg -i [input file] -codec:a libmp3lame -q:a [V-level] [output file].mp3`
@@ -159,64 +838,159 @@ pub fn transcode_song(
     source: &Path,
     target: &Path,
     target_type: MusicFileType,
-    embed_art: bool,
-    external_art_to_embed: Option<&Path>,
+    audio: AudioConversion,
+    art: ArtEmbedding,
+    // Tags to set in the target, on top of the ones that are carried over from the source. Tags
+    // with an empty value are removed.
+    tags: &[(String, String)],
+    // Take the already encoded audio from this file, instead of encoding the source again.
+    // For when only the tags of the source have changed.
+    reuse_audio: Option<&Path>,
 ) -> Result<(), FfmpegError> {
-    ensure_ffmpeg_capable(&target_type)?;
+    let encoder = ensure_ffmpeg_capable(&target_type)?;
+    let _slot = ENCODER_LIMIT.acquire();
+    #[cfg(feature = "libav")]
+    if let Some(result) = crate::libav::transcode_song(
+        source,
+        target,
+        &target_type,
+        encoder,
+        audio,
+        art.embed,
+        tags,
+        reuse_audio,
+    ) {
+        return result;
+    }
+    let embed_art = art.embed;
+    let external_art_to_embed = art.external_art;
 
     let mut binding = Command::new("ffmpeg");
-    binding
-        // Replace file if it already exists
-        .arg("-y")
-        // input url: the source file
-        .arg("-i")
-        .arg(source);
+    // Replace file if it already exists
+    binding.arg("-y");
+    if let Some(segment) = audio.segment {
+        // As input options, so that ffmpeg seeks to the start instead of decoding up to it.
+        binding
+            .arg("-ss")
+            .arg(format!("{:.3}", segment.start.as_secs_f64()));
+        if let Some(end) = segment.end {
+            binding.arg("-to").arg(format!("{:.3}", end.as_secs_f64()));
+        }
+    }
+    // input url: the source file
+    binding.arg("-i").arg(source);
 
+    let mut n_inputs = 1;
     if embed_art {
         if let Some(path) = external_art_to_embed {
             // Second input url: the external album art.
             binding.arg("-i").arg(path);
+            n_inputs += 1;
         }
     }
+    // Which input the audio is taken from.
+    let audio_input = match reuse_audio {
+        Some(path) => {
+            binding.arg("-i").arg(path);
+            n_inputs
+        }
+        None => 0,
+    };
 
     // Mp3:
     // `ffmpeg -i input.wav -i cover.jpg -codec:a libmp3lame -qscale:a 2 -metadata:s:v title="Cover" -metadata:s:v comment="Cover" -map 0:a -map 1:v output.mp3`
 
     binding.arg("-codec:a");
 
+    let encoder_name = encoder.map_or("copy", |encoder| encoder.name);
     use MusicFileType as M;
     match target_type {
-        M::Mp3VBR { quality } => {
-            binding.arg("libmp3lame");
+        // The audio is already encoded like it should be.
+        _ if reuse_audio.is_some() => {
+            binding.arg("copy");
+        }
+        M::Mp3VBR { quality, .. } => {
+            binding.arg(encoder_name);
             // Specific for vbr: quality scale of the audio track, instead of the bitrate.
             // should be between 0 and 9. See https://trac.ffmpeg.org/wiki/Encode/MP3#VBREncoding
             binding.arg("-q:a").arg(quality.to_string());
         }
-        M::Mp3CBR { bitrate } => {
-            binding.arg("libmp3lame");
+        M::Mp3CBR { bitrate, .. } => {
+            binding.arg(encoder_name);
             // Constant bitrate in kbps.
             // See https://trac.ffmpeg.org/wiki/Encode/MP3#VBREncoding
             binding.arg("-b:a").arg(format!("{}k", bitrate));
         }
         M::Vorbis { quality } => {
             binding
-                .arg("libvorbis")
+                .arg(encoder_name)
                 .arg("-qscale:a")
                 .arg(format!("{quality:.3}"));
         }
         M::Opus {
             bitrate,
-            // TODO: Respect compression level
-            compression_level: _,
+            compression_level,
+            vbr,
         } => {
             binding
-                .arg("libopus")
+                .arg(encoder_name)
                 .arg("-b:a")
-                .arg(format!("{}k", bitrate));
+                .arg(format!("{}k", bitrate))
+                .arg("-compression_level")
+                .arg(compression_level.to_string());
+            // Only libopus has the choice; the fallback always uses a constant bitrate.
+            if encoder.is_some_and(|encoder| !encoder.is_experimental()) {
+                binding.arg("-vbr").arg(vbr.ffmpeg_value());
+            }
         }
-        M::Flac { quality: _ } => {
-            panic!("Encoding to flac not yet implemented as a target. Feel free to send a PR <3")
+        M::Flac { quality } => {
+            binding
+                .arg(encoder_name)
+                .arg("-compression_level")
+                .arg(quality.to_string());
+            if audio.to_16_bit {
+                binding.arg("-sample_fmt").arg("s16");
+            }
         }
+        // Only remuxing, e.g. to change the art.
+        M::Copy => {
+            binding.arg("copy");
+        }
+    }
+    if reuse_audio.is_none() && encoder.is_some_and(|encoder| encoder.is_experimental()) {
+        binding.arg("-strict").arg("experimental");
+    }
+
+    // Resampling and reducing the bit depth are done by the same filter, so that it's only
+    // converted once.
+    let mut resample_options = Vec::new();
+    if let Some(sample_rate) = audio.sample_rate {
+        resample_options.push(sample_rate.to_string());
+    }
+    if audio.to_16_bit && matches!(target_type, M::Flac { .. }) {
+        resample_options.push("osf=s16".to_owned());
+        if audio.dither {
+            resample_options.push("dither_method=triangular".to_owned());
+        }
+    }
+    // Copied audio can't be filtered, but it was already filtered when it was encoded.
+    if !resample_options.is_empty() && reuse_audio.is_none() {
+        binding
+            .arg("-filter:a")
+            .arg(format!("aresample={}", resample_options.join(":")));
+    }
+    if let Some(channels) = audio.channels.filter(|_| reuse_audio.is_none()) {
+        binding.arg("-ac").arg(channels.to_string());
+    }
+    // The chapters of the whole file don't fit a part of it.
+    match (audio.segment, audio.chapters) {
+        (Some(_), _) | (None, Some(Chapters::Drop)) => {
+            binding.arg("-map_chapters").arg("-1");
+        }
+        (None, Some(Chapters::Keep | Chapters::Split)) => {
+            binding.arg("-map_chapters").arg("0");
+        }
+        (None, None) => (),
     }
 
     // Take all the metadata from file 0 (source library music file).
@@ -227,28 +1001,57 @@ pub fn transcode_song(
         .arg("0")
         .arg("-map_metadata")
         .arg("0:s:0");
+    for (key, value) in tags {
+        binding.arg("-metadata").arg(format!("{key}={value}"));
+    }
+    // ffmpeg writes its own encoder tag, unless it is told to be bitexact.
+    if tags
+        .iter()
+        .any(|(key, value)| key == "encoder" && value.is_empty())
+    {
+        binding
+            .arg("-fflags")
+            .arg("+bitexact")
+            .arg("-flags:a")
+            .arg("+bitexact");
+    }
 
     // NOTE: For some reason, when transcoding MP3 to Ogg, it really wants to put the video track
     // first. At least, that is what ffprobe reports. I don't think this is a problem, but maybe
     // this should be fixed.
 
     // More metadata mapping operations:
-    match target_type {
-        MusicFileType::Mp3VBR { .. } => {
-            // Write tags as ID3v2.3. This is more broadly supported than ID3v2.4.
-            binding.arg("-id3v2_version").arg("3");
-        }
-        MusicFileType::Mp3CBR { .. } => {
-            // Write tags as ID3v2.3. This is more broadly supported than ID3v2.4.
-            binding.arg("-id3v2_version").arg("3");
+    if let Some(id3) = target_type.id3() {
+        binding
+            .arg("-id3v2_version")
+            .arg(id3.id3_version.ffmpeg_value());
+        if id3.id3v1 {
+            binding.arg("-write_id3v1").arg("1");
         }
-        MusicFileType::Opus { .. } => (),
-        MusicFileType::Vorbis { .. } => (),
-        MusicFileType::Flac { .. } => (),
-    };
+    }
 
-    // TODO: Downscale art if it is higher resolution than required. If the desired resolution is
-    // higher, then don't do any scaling.
+    // Downscale art if it is higher resolution than required. If the art is smaller than the
+    // maximum, the expression leaves its size as it is. The aspect ratio is kept.
+    if embed_art && art.max_resolution > 0 {
+        binding
+            .arg("-filter:v")
+            .arg(downscale_filter(art.max_resolution));
+    }
+    if let Some(quality) = art.recompress_quality.filter(|_| embed_art) {
+        binding
+            .arg("-codec:v")
+            .arg("mjpeg")
+            .arg("-q:v")
+            .arg(jpeg_quality_to_qscale(quality).to_string());
+    } else if embed_art && matches!(target_type, M::Copy) {
+        // Without a codec, ffmpeg picks the default video codec of the container, which for m4a
+        // is H.264. Keep the picture as it is, unless it has to be scaled.
+        binding.arg("-codec:v").arg(if art.max_resolution > 0 {
+            "mjpeg"
+        } else {
+            "copy"
+        });
+    }
 
     if external_art_to_embed.is_some() && embed_art {
         // We have an external art to embed.
@@ -266,19 +1069,32 @@ pub fn transcode_song(
             .arg("comments=\"Cover\"")
             // Use the first provided file (source library audio file) as the audio track
             .arg("-map")
-            .arg("0:a")
+            .arg(format!("{audio_input}:a"))
             // Use the second provided source (external album art) as the video track.
             .arg("-map")
             .arg("1:v");
     } else if !embed_art {
         // -vn drops the video track
         binding.arg("-vn");
+        if reuse_audio.is_some() {
+            binding.arg("-map").arg(format!("{audio_input}:a"));
+        }
+    } else {
+        // Without mapping explicitly, ffmpeg picks the picture with the highest resolution,
+        // which is not necessarily the cover.
+        binding.arg("-map").arg(format!("{audio_input}:a"));
+        match art.pictures {
+            // The question mark makes it not fail if there are no pictures.
+            PictureSelection::All => binding.arg("-map").arg("0:v?"),
+            PictureSelection::Stream(index) => binding.arg("-map").arg(format!("0:{index}")),
+        };
     }
 
     binding.arg(target);
 
     // Check if there is any problem with the generated command. If this error occurs, it is
     // most likely an implementation error
+    print_command(&binding);
     let output = binding
         .output()
         .map_err(|e| FfmpegError::TranscodeCommand {
@@ -304,6 +1120,176 @@ pub fn transcode_song(
     Ok(())
 }
 
+/// ffmpeg filter that scales an image down so that it fits in a square of `max_resolution`
+/// pixels, keeping the aspect ratio. Images that are already small enough are not touched.
+fn downscale_filter(max_resolution: u32) -> String {
+    let n = max_resolution;
+    format!("scale='min(iw,{n})':'min(ih,{n})':force_original_aspect_ratio=decrease")
+}
+
+/// ffmpeg's JPEG quality scale goes from 2 (best) to 31 (worst). Translates the more common
+/// 1-100 scale (higher is better) to that.
+fn jpeg_quality_to_qscale(quality: u8) -> u8 {
+    let quality = quality.clamp(1, 100) as u32;
+    (2 + ((100 - quality) * 29 + 49) / 99) as u8
+}
+
+/// Quality (1-100) to use when re-encoding art to a lossy format without an explicit quality.
+/// Without it, ffmpeg falls back to a bitrate that makes JPEGs look pretty bad.
+const DEFAULT_ART_QUALITY: u8 = 90;
+
+/// Writes a (possibly) smaller version of an album art image to `target`. The image format is
+/// based on the extension of `target`. `source` can also be a music file, in which case its
+/// embedded art is used.
+/// Art larger than `max_resolution` (in either direction) is scaled down, 0 means don't scale.
+/// Lossy formats (JPEG, WebP) are encoded with `quality` (1-100).
+pub fn convert_art(
+    source: &Path,
+    target: &Path,
+    max_resolution: u32,
+    quality: Option<u8>,
+) -> Result<(), FfmpegError> {
+    let mut binding = Command::new("ffmpeg");
+    binding
+        // Replace file if it already exists
+        .arg("-y")
+        .arg("-i")
+        .arg(source);
+    if max_resolution > 0 {
+        binding
+            .arg("-filter:v")
+            .arg(downscale_filter(max_resolution));
+    }
+    let quality = quality.unwrap_or(DEFAULT_ART_QUALITY);
+    let extension = target
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("jpg") | Some("jpeg") => {
+            binding
+                .arg("-q:v")
+                .arg(jpeg_quality_to_qscale(quality).to_string());
+        }
+        Some("webp") => {
+            binding.arg("-quality").arg(quality.to_string());
+        }
+        // Lossless, so there is no quality to set.
+        _ => (),
+    }
+    // It's a still image, so there is only a single frame. If the source is a music file,
+    // drop the audio.
+    binding.arg("-frames:v").arg("1").arg("-an");
+    binding.arg(target);
+
+    print_command(&binding);
+    let output = binding.output().map_err(|e| FfmpegError::ArtCommand {
+        source: e,
+        arguments: binding
+            .get_args()
+            .map(|osstr| osstr.to_string_lossy())
+            .join(" "),
+    })?;
+    if !output.status.success() {
+        let cmd_txt = binding
+            .get_args()
+            .map(|osstr| osstr.to_string_lossy())
+            .join(" ");
+        let msg = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(FfmpegError::FfmpegNotSuccesful {
+            file: source.into(),
+            arguments: cmd_txt,
+            msg,
+        });
+    }
+    Ok(())
+}
+
+/// Saves a representative frame of a video file as an image, to use as cover art. The image
+/// format is based on the extension of `target`.
+pub fn grab_video_frame(source: &Path, target: &Path) -> Result<(), FfmpegError> {
+    let mut binding = Command::new("ffmpeg");
+    binding
+        .arg("-y")
+        .arg("-i")
+        .arg(source)
+        .arg("-map")
+        .arg("0:v:0")
+        // Picks the most representative frame of the first hundred or so, so it's not just the
+        // black frame at the start.
+        .arg("-filter:v")
+        .arg("thumbnail")
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-an")
+        .arg(target);
+
+    print_command(&binding);
+    let output = binding.output().map_err(|e| FfmpegError::ArtCommand {
+        source: e,
+        arguments: binding
+            .get_args()
+            .map(|osstr| osstr.to_string_lossy())
+            .join(" "),
+    })?;
+    if !output.status.success() {
+        let cmd_txt = binding
+            .get_args()
+            .map(|osstr| osstr.to_string_lossy())
+            .join(" ");
+        let msg = String::from_utf8_lossy(&output.stderr).to_string();
+        return Err(FfmpegError::FfmpegNotSuccesful {
+            file: source.into(),
+            arguments: cmd_txt,
+            msg,
+        });
+    }
+    Ok(())
+}
+
+/// Decodes the whole file without writing anything, to find out whether it is corrupt or
+/// truncated. Any complaint from the decoder counts as a failure, even if ffmpeg manages to
+/// get through the file.
+pub fn decode_check(path: &Path) -> Result<(), FfmpegError> {
+    let mut binding = Command::new("ffmpeg");
+    binding
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .arg("-map")
+        .arg("0:a")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    let arguments = binding
+        .get_args()
+        .map(|osstr| osstr.to_string_lossy())
+        .join(" ");
+
+    print_command(&binding);
+    let output = binding
+        .output()
+        .map_err(|e| FfmpegError::DecodeCheckCommand {
+            source: e,
+            arguments: arguments.clone(),
+        })?;
+    let msg = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if !output.status.success() {
+        return Err(FfmpegError::FfmpegNotSuccesful {
+            file: path.into(),
+            arguments,
+            msg,
+        });
+    }
+    if !msg.is_empty() {
+        return Err(FfmpegError::DecodeErrors {
+            file: path.into(),
+            msg,
+        });
+    }
+    Ok(())
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum FfmpegError {
     #[error(
@@ -327,6 +1313,36 @@ pub enum FfmpegError {
         arguments: String,
     },
 
+    #[error("could not run the command to convert album art. Ran ffmpeg with arguments `{arguments}`: {source}")]
+    ArtCommand {
+        source: std::io::Error,
+        arguments: String,
+    },
+
+    #[error("could not run ffprobe to read the chapters. Ran ffprobe with arguments `{arguments}`: {source}")]
+    ChaptersCommand {
+        source: std::io::Error,
+        arguments: String,
+    },
+
+    #[error("could not run ffmpeg to measure loudness. Ran ffmpeg with arguments `{arguments}`: {source}")]
+    LoudnessCommand {
+        source: std::io::Error,
+        arguments: String,
+    },
+
+    #[error("could not run ffmpeg to check if a file can be decoded. Ran ffmpeg with arguments `{arguments}`: {source}")]
+    DecodeCheckCommand {
+        source: std::io::Error,
+        arguments: String,
+    },
+
+    #[error("{file} could not be decoded cleanly: {msg}")]
+    DecodeErrors { file: PathBuf, msg: String },
+
+    #[error("Could not read the measured loudness of `{path}` from the output of ffmpeg")]
+    Loudness { path: String },
+
     #[error("Could not determine the bitrate for file `{path}`")]
     Bitrate { path: String },
 
@@ -338,6 +1354,13 @@ pub enum FfmpegError {
 
     #[error("ffmpeg does not have the required capabilities.")]
     Capability(#[from] FfmpegCapabilityError),
+
+    #[cfg(feature = "libav")]
+    #[error("could not transcode {file} with the ffmpeg libraries: {source}")]
+    Libav {
+        file: PathBuf,
+        source: ffmpeg_next::Error,
+    },
 }
 
 #[cfg(test)]
@@ -351,6 +1374,143 @@ mod tests {
     // miette::Diagnostic/ miette::Result is only used in tests, so can't use the derive macro.
     impl miette::Diagnostic for FfmpegError {}
 
+    #[test]
+    /// Songs without a title tag get one from another tag, or else from their file name.
+    fn title_fallbacks() {
+        use super::title_from_tags;
+        use crate::song::Song;
+        let tags = |tags: &[(&str, &str)]| {
+            tags.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            title_from_tags(&tags(&[("title", " "), ("tit2", "Raw frame")])).as_deref(),
+            Some("Raw frame")
+        );
+        assert_eq!(title_from_tags(&tags(&[("artist", "Band")])), None);
+
+        let song = Song::new_fake("Band/03 Untitled.mp3", &[("artist", "Band")]);
+        assert_eq!(song.title().as_deref(), Some("03 Untitled"));
+    }
+
+    #[test]
+    /// A target that is much shorter than its source was cut off.
+    fn duration_tolerance() {
+        use crate::song::Song;
+        use std::time::Duration;
+        let with_duration = |seconds: Option<f64>| {
+            let mut song = Song::new_fake("a.flac", &[]);
+            song.metadata.duration = seconds.map(Duration::from_secs_f64);
+            song.metadata
+        };
+        let source = with_duration(Some(200.));
+        assert!(source.has_same_duration(&with_duration(Some(201.5))));
+        assert!(!source.has_same_duration(&with_duration(Some(120.))));
+        // Unknown durations can't be compared.
+        assert!(source.has_same_duration(&with_duration(None)));
+    }
+
+    #[test]
+    fn parse_encoder_list() {
+        use super::parse_audio_encoders;
+        let list = "Encoders:
+ V..... = Video
+ A..... = Audio
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ A....D flac                 FLAC (Free Lossless Audio Codec)
+ A....D libmp3lame           libmp3lame MP3 (MPEG audio layer 3) (codec mp3)
+";
+        let encoders = parse_audio_encoders(list);
+        assert_eq!(encoders.len(), 2);
+        assert!(encoders.contains("libmp3lame"));
+        assert!(!encoders.contains("libx264"));
+    }
+
+    #[test]
+    /// The encoders of ffmpeg itself are only used when asked for, and only if there is one.
+    fn encoder_fallback() {
+        use super::{choose_encoder, FfmpegCapabilityError};
+        use crate::music_library::{Id3Tags, OpusVbr};
+        let available = ["libmp3lame", "opus", "flac"]
+            .map(str::to_owned)
+            .into_iter()
+            .collect();
+        let opus = MusicFileType::Opus {
+            bitrate: 96,
+            compression_level: 10,
+            vbr: OpusVbr::On,
+        };
+        let mp3 = MusicFileType::Mp3CBR {
+            bitrate: 192,
+            id3: Id3Tags::default(),
+        };
+        let vorbis = MusicFileType::Vorbis { quality: 5. };
+
+        let encoder = choose_encoder(&mp3, &available, false).unwrap().unwrap();
+        assert_eq!((encoder.name, encoder.fallback_for), ("libmp3lame", None));
+        assert!(matches!(
+            choose_encoder(&opus, &available, false),
+            Err(FfmpegCapabilityError::EncoderNotAvailable {
+                encoder: "libopus",
+                fallback: Some("opus")
+            })
+        ));
+        let encoder = choose_encoder(&opus, &available, true).unwrap().unwrap();
+        assert_eq!(
+            (encoder.name, encoder.fallback_for),
+            ("opus", Some("libopus"))
+        );
+        // Without the vorbis encoder either, there is nothing to fall back to.
+        assert!(matches!(
+            choose_encoder(&vorbis, &available, true),
+            Err(FfmpegCapabilityError::EncoderNotAvailable { fallback: None, .. })
+        ));
+        assert_eq!(
+            choose_encoder(&MusicFileType::Copy, &available, false).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    /// No more encoders run at the same time than the maximum.
+    fn encoder_limit() {
+        use super::EncoderLimit;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let limit = EncoderLimit::new();
+        limit.set_max(2);
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| {
+                    let _slot = limit.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(most_running.into_inner(), 2);
+    }
+
+    #[test]
+    /// A FLAC file with garbage in the middle fails its checksums.
+    fn decode_check_finds_corruption() -> miette::Result<()> {
+        use super::decode_check;
+        decode_check(&TestFile::FlacWithoutArt.path())?;
+
+        let mut bytes = std::fs::read(TestFile::FlacWithoutArt.path()).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle..middle + 4096].fill(0xAB);
+        let corrupt = std::env::temp_dir().join("syncbops_corrupt.flac");
+        std::fs::write(&corrupt, bytes).unwrap();
+        assert!(decode_check(&corrupt).is_err());
+        Ok(())
+    }
+
     #[test]
     fn metadata_mp3_with_art() -> miette::Result<()> {
         let md = SongMetaData::parse_file(&TestFile::Mp3CBRWithArt.path())?;
@@ -412,6 +1572,114 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn metadata_wav() -> miette::Result<()> {
+        let md = SongMetaData::parse_file(&TestFile::WavWithoutArt.path())?;
+        dbg!(&md);
+        assert!(!md.has_embedded_album_art);
+        assert!(md.title == Some("wav without art".to_string()));
+        assert!(md.bitrate_kbps == 705);
+        assert_eq!(md.bit_depth, Some(16));
+        assert_eq!(md.is_lossless(), Some(true));
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_aiff() -> miette::Result<()> {
+        let md = SongMetaData::parse_file(&TestFile::AiffWithoutArt.path())?;
+        dbg!(&md);
+        assert!(!md.has_embedded_album_art);
+        assert!(md.title == Some("aiff without art".to_string()));
+        assert!(md.bitrate_kbps == 705);
+        assert_eq!(md.bit_depth, Some(16));
+        assert_eq!(md.is_lossless(), Some(true));
+        Ok(())
+    }
+
+    /// Encodes the WAV fixture to a format that needs its own encoder, with the given title. The
+    /// encoders come with ffmpeg, so these don't need to be kept in the test data.
+    fn encode_fixture(codec: &str, extension: &str, title: &str) -> PathBuf {
+        let path: PathBuf = format!(
+            "/tmp/syncbops/fixture_{}.{extension}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        )
+        .into();
+        let _ = std::fs::create_dir_all(path.parent().unwrap());
+        let output = std::process::Command::new("ffmpeg")
+            .arg("-loglevel")
+            .arg("error")
+            .arg("-i")
+            .arg(TestFile::WavWithoutArt.path())
+            .arg("-map_metadata")
+            .arg("-1")
+            .arg("-codec:a")
+            .arg(codec)
+            .arg("-metadata")
+            .arg(format!("title={title}"))
+            .arg(&path)
+            .output()
+            .expect("ffmpeg should be installed");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        path
+    }
+
+    /// Sets the title of the song by remuxing it, and reads it back.
+    fn rewrite_title(source: &std::path::Path, title: &str) -> miette::Result<SongMetaData> {
+        use super::{transcode_song, ArtEmbedding, AudioConversion, PictureSelection};
+        let target = source.with_file_name(format!(
+            "retagged_{}",
+            source.file_name().unwrap().to_string_lossy()
+        ));
+        transcode_song(
+            source,
+            &target,
+            MusicFileType::Copy,
+            AudioConversion::default(),
+            ArtEmbedding {
+                embed: false,
+                external_art: None,
+                max_resolution: 0,
+                pictures: PictureSelection::All,
+                recompress_quality: None,
+            },
+            &[("title".to_owned(), title.to_owned())],
+            None,
+        )?;
+        Ok(SongMetaData::parse_file(&target)?)
+    }
+
+    #[test]
+    fn metadata_wavpack() -> miette::Result<()> {
+        let path = encode_fixture("wavpack", "wv", "wavpack without art");
+        let md = SongMetaData::parse_file(&path)?;
+        dbg!(&md);
+        assert!(!md.has_embedded_album_art);
+        assert!(md.title == Some("wavpack without art".to_string()));
+        assert_eq!(md.codec.as_deref(), Some("wavpack"));
+        assert_eq!(md.is_lossless(), Some(true));
+        let md = rewrite_title(&path, "new title")?;
+        assert!(md.title == Some("new title".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_wma() -> miette::Result<()> {
+        let path = encode_fixture("wmav2", "wma", "wma without art");
+        let md = SongMetaData::parse_file(&path)?;
+        dbg!(&md);
+        assert!(!md.has_embedded_album_art);
+        assert!(md.title == Some("wma without art".to_string()));
+        assert_eq!(md.codec.as_deref(), Some("wmav2"));
+        assert_eq!(md.is_lossless(), Some(false));
+        let md = rewrite_title(&path, "new title")?;
+        assert!(md.title == Some("new title".to_string()));
+        Ok(())
+    }
+
     #[test]
     fn metadata_m4a_with_art() -> miette::Result<()> {
         let md = SongMetaData::parse_file(&TestFile::M4aWithArt.path())?;
@@ -432,6 +1700,77 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn ebur128_summary() {
+        let output = "[Parsed_ebur128_0 @ 0x5581] Summary:
+
+  Integrated loudness:
+    I:         -16.9 LUFS
+    Threshold: -27.1 LUFS
+
+  Loudness range:
+    LRA:         6.9 LU
+    Threshold: -37.2 LUFS
+    LRA low:   -21.4 LUFS
+    LRA high:  -14.5 LUFS
+
+  True peak:
+    Peak:       -0.2 dBFS";
+        let loudness = super::parse_ebur128_summary(output).unwrap();
+        assert_eq!(loudness.integrated_lufs, -16.9);
+        assert_eq!(loudness.true_peak_dbfs, -0.2);
+        assert!(super::parse_ebur128_summary("garbage").is_none());
+    }
+
+    #[test]
+    fn jpeg_quality_scale() {
+        use super::jpeg_quality_to_qscale;
+        assert_eq!(jpeg_quality_to_qscale(100), 2);
+        assert_eq!(jpeg_quality_to_qscale(1), 31);
+        assert_eq!(jpeg_quality_to_qscale(0), 31);
+        assert!((5..=7).contains(&jpeg_quality_to_qscale(85)));
+    }
+
+    #[test]
+    fn embedded_pictures_front_cover() {
+        use super::{parse_embedded_pictures, EmbeddedPicture};
+        let streams = serde_json::json!([
+            { "index": 0, "codec_type": "audio" },
+            { "index": 1, "codec_type": "video", "width": 3000, "height": 2000,
+              "tags": { "comment": "Other" } },
+            { "index": 2, "codec_type": "video", "width": 600, "height": 600,
+              "tags": { "comment": "Cover (front)" } },
+        ]);
+        let pictures = parse_embedded_pictures(&streams);
+        assert_eq!(pictures.len(), 2);
+        assert_eq!(
+            super::front_cover(&pictures),
+            Some(&EmbeddedPicture {
+                stream_index: 2,
+                resolution: Some((600, 600)),
+                is_front_cover: true,
+            })
+        );
+    }
+
+    #[test]
+    /// The video of e.g. a live set is not an embedded picture.
+    fn video_is_not_a_picture() {
+        use super::{is_actual_video, parse_embedded_pictures};
+        let streams = serde_json::json!([
+            { "index": 0, "codec_type": "video", "codec_name": "h264", "width": 1920,
+              "height": 1080, "disposition": { "attached_pic": 0 } },
+            { "index": 1, "codec_type": "audio", "codec_name": "aac" },
+            { "index": 2, "codec_type": "video", "codec_name": "mjpeg", "width": 600,
+              "height": 600, "disposition": { "attached_pic": 1 } },
+        ]);
+        assert!(is_actual_video(&streams[0]));
+        assert!(!is_actual_video(&streams[2]));
+        let pictures = parse_embedded_pictures(&streams);
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].stream_index, 2);
+    }
+
     // Convenience function to see if file transcoding actually works as intended.
     fn transcode_file_test(
         test_file: TestFile,
@@ -439,7 +1778,7 @@ mod tests {
         external_art_to_embed: Option<TestFile>,
         target_type: MusicFileType,
     ) -> miette::Result<()> {
-        use super::transcode_song;
+        use super::{transcode_song, ArtEmbedding, AudioConversion, PictureSelection};
         let source = test_file.path();
 
         let random_string = random_string::generate(16, "abcdefghijklmnopqrstuvwxyz");
@@ -460,8 +1799,16 @@ mod tests {
             &source,
             &target,
             target_type,
-            embed_art,
-            external_art_to_embed.clone().map(|tf| tf.path()).as_deref(),
+            AudioConversion::default(),
+            ArtEmbedding {
+                embed: embed_art,
+                external_art: external_art_to_embed.clone().map(|tf| tf.path()).as_deref(),
+                max_resolution: 0,
+                pictures: PictureSelection::All,
+                recompress_quality: None,
+            },
+            &[],
+            None,
         )?;
         assert!(std::fs::exists(&target).unwrap());
         let source_md = SongMetaData::parse_file(&source)?;
@@ -482,7 +1829,10 @@ mod tests {
     }
 
     mod to_mp3_vbr {
-        use crate::{music_library::MusicFileType, test_data::TestFile};
+        use crate::{
+            music_library::{Id3Tags, MusicFileType},
+            test_data::TestFile,
+        };
 
         /// Setting up a test to transcode into mp3 vbr
         fn build(
@@ -494,7 +1844,10 @@ mod tests {
                 test_file,
                 embed_art,
                 external_art_to_embed,
-                MusicFileType::Mp3VBR { quality: 6 },
+                MusicFileType::Mp3VBR {
+                    quality: 6,
+                    id3: Id3Tags::default(),
+                },
             )
         }
 
@@ -720,7 +2073,10 @@ mod tests {
     }
 
     mod to_mp3_cbr {
-        use crate::{music_library::MusicFileType, test_data::TestFile};
+        use crate::{
+            music_library::{Id3Tags, MusicFileType},
+            test_data::TestFile,
+        };
 
         /// Setting up a test to transcode into mp3 vbr
         fn build(
@@ -732,7 +2088,10 @@ mod tests {
                 test_file,
                 embed_art,
                 external_art_to_embed,
-                MusicFileType::Mp3CBR { bitrate: 80 },
+                MusicFileType::Mp3CBR {
+                    bitrate: 80,
+                    id3: Id3Tags::default(),
+                },
             )
         }
 
@@ -1196,7 +2555,10 @@ mod tests {
     }
 
     mod to_opus {
-        use crate::{music_library::MusicFileType, test_data::TestFile};
+        use crate::{
+            music_library::{MusicFileType, OpusVbr},
+            test_data::TestFile,
+        };
 
         /// Setting up a test to transcode into mp3 vbr
         fn build(
@@ -1211,6 +2573,7 @@ mod tests {
                 MusicFileType::Opus {
                     bitrate: 96,
                     compression_level: 3,
+                    vbr: OpusVbr::On,
                 },
             )
         }
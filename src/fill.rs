@@ -0,0 +1,123 @@
+use crate::{
+    estimate::transcoded_size,
+    song::Song,
+    sync_song::{would_copy, SyncSettings},
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How the songs that were not selected are picked to fill up the target library. The same
+/// seed picks the same songs every run, so nothing changes on the device until the library,
+/// the selection or the rotation does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FillOrder {
+    pub seed: u64,
+    /// Every song gets a new chance to be picked once every this many days, each on a different
+    /// day, so that the filler changes a bit every day instead of all at once.
+    pub rotate_days: Option<u64>,
+    /// Days since the unix epoch.
+    pub today: u64,
+}
+
+impl FillOrder {
+    pub fn new(seed: u64, rotate_days: Option<u64>) -> FillOrder {
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / (24 * 60 * 60));
+        FillOrder {
+            seed,
+            rotate_days,
+            today,
+        }
+    }
+
+    /// Where the song is in the random order.
+    fn key(&self, song: &Song) -> u64 {
+        let path = song.library_relative_path.as_os_str().as_encoded_bytes();
+        let round = match self.rotate_days {
+            Some(days) if days > 0 => {
+                // Otherwise all songs would get a new chance on the same day.
+                let offset = rapidhash::rapidhash(path) % days;
+                (self.today + offset) / days
+            }
+            _ => 0,
+        };
+        let mut bytes = Vec::with_capacity(path.len() + 16);
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&round.to_le_bytes());
+        bytes.extend_from_slice(path);
+        rapidhash::rapidhash(&bytes)
+    }
+}
+
+/// Roughly how much space the song takes in the target library. Estimated instead of measured,
+/// so that the same songs are picked whether they were synchronised before or not.
+pub fn estimated_size(song: &Song, settings: &SyncSettings) -> u64 {
+    let source_size = std::fs::metadata(&song.absolute_path).map_or(0, |m| m.len());
+    if would_copy(song, settings) {
+        source_size
+    } else {
+        transcoded_size(song, source_size, settings)
+    }
+}
+
+/// Picks songs in random order until the next one doesn't fit in `space` bytes anymore, and
+/// then tries the rest in case smaller ones do. Returns the picked songs, and the ones that were
+/// left out.
+pub fn pick_filler(
+    mut candidates: Vec<Song>,
+    space: u64,
+    order: FillOrder,
+    size: impl Fn(&Song) -> u64,
+) -> (Vec<Song>, Vec<Song>) {
+    candidates.sort_by_cached_key(|song| order.key(song));
+    let mut left = space;
+    candidates.into_iter().partition(|song| {
+        let size = size(song);
+        let fits = size <= left;
+        if fits {
+            left -= size;
+        }
+        fits
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick_filler, FillOrder};
+    use crate::song::Song;
+    use std::collections::HashSet;
+
+    #[test]
+    /// The same songs are picked every run, unless the seed changes or they rotate.
+    fn random_fill() {
+        let songs = || {
+            (0..100)
+                .map(|i| Song::new_fake(&format!("song {i}.flac"), &[]))
+                .collect::<Vec<_>>()
+        };
+        let picked = |order: FillOrder| {
+            let (picked, left_out) = pick_filler(songs(), 250, order, |_| 10);
+            assert_eq!(picked.len(), 25);
+            assert_eq!(left_out.len(), 75);
+            picked
+                .into_iter()
+                .map(|song| song.library_relative_path)
+                .collect::<HashSet<_>>()
+        };
+        let order = |seed, rotate_days, today| FillOrder {
+            seed,
+            rotate_days,
+            today,
+        };
+
+        let first = picked(order(1, None, 100));
+        assert_eq!(first, picked(order(1, None, 200)));
+        assert_ne!(first, picked(order(2, None, 100)));
+
+        // Only some of the songs get a new chance the next day.
+        let rotating = picked(order(1, Some(10), 100));
+        let next_day = picked(order(1, Some(10), 101));
+        assert_ne!(rotating, next_day);
+        assert!(rotating.intersection(&next_day).count() > 10);
+    }
+}
@@ -1,11 +1,21 @@
-use crate::{music_library::UpdateType, song::Song, PREVIOUS_SYNC_DB_FILENAME};
+use crate::{
+    ffmpeg_interface::chosen_encoder,
+    music_library::{find_in_library, ArtStrategy, MusicFileType, UpdateType},
+    replaygain::Loudness,
+    song::Song,
+    sqlite_records::SQLITE_RECORDS_FILENAME,
+    sync_song::SyncSettings,
+    target_path::NormalizationForm,
+    PREVIOUS_SYNC_DB_FILENAME,
+};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::BufReader,
+    io::{BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
-    time::SystemTime,
+    time::{Duration, SystemTime},
 };
 
 /// Data about how a file is at a certain point in time. By comparing SyncRecords, you can see
@@ -17,15 +27,147 @@ pub struct SyncRecord {
     pub update_type: Option<UpdateType>,
     pub date: SystemTime,
     pub hash: Option<u64>,
+    /// Where the synchronised copy was written to, relative to the target library. This is not
+    /// always the same as the source path, e.g. when the name had to be shortened.
+    #[serde(default)]
+    pub target_relative_path: Option<PathBuf>,
+    /// The maximum resolution embedded art was scaled to. 0 if not scaled.
+    #[serde(default)]
+    pub embed_art_resolution: u32,
+    /// The measured loudness of the source, so it doesn't need to be measured again.
+    #[serde(default)]
+    pub loudness: Option<Loudness>,
+    /// Hash of the synchronised copy, as it was written. If the copy has a different hash
+    /// later on, it was changed (retagged, corrupted, etc.) outside of syncbops.
+    #[serde(default)]
+    pub target_hash: Option<u64>,
+    /// Size and modification time of the synchronised copy when `target_hash` was taken. The
+    /// copy is only hashed again to see whether it was changed if these differ.
+    #[serde(default)]
+    pub target_stamp: Option<FileStamp>,
+    /// Hash of only the audio of the source, without the tags. Only saved when syncing with
+    /// `hash_audio_only`.
+    #[serde(default)]
+    pub audio_hash: Option<u64>,
+    /// How the synchronised copy was encoded. None for records from before this was recorded.
+    #[serde(default)]
+    pub encoder: Option<EncoderSettings>,
+    /// Seconds of audio that were encoded per second when this song was last transcoded. Used
+    /// to estimate how long the next sync will take.
+    #[serde(default)]
+    pub encode_speed: Option<f64>,
+    /// Whether this is the record of a sidecar file, like a .cue sheet or a booklet, instead of
+    /// a song.
+    #[serde(default)]
+    pub sidecar: bool,
+    /// Whether this is the record of an album art file that was copied next to the songs.
+    #[serde(default)]
+    pub art: bool,
+}
+
+/// The size and modification time of a file, to cheaply tell whether it could have changed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl FileStamp {
+    pub fn of(path: &Path) -> Option<FileStamp> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileStamp {
+            size: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+/// The settings a song was encoded with. If these change, the song is synchronised again, even
+/// if the source did not change.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncoderSettings {
+    pub filetype: MusicFileType,
+    pub art_strategy: ArtStrategy,
+    /// Only kept for reference: updating ffmpeg should not mean transcoding everything again.
+    /// That takes --force.
+    pub ffmpeg_version: Option<String>,
+    /// The encoder of ffmpeg that was used, like "libopus" or its fallback "opus". If another one
+    /// is used now, like when ffmpeg got libopus after the fallback was used, the song is
+    /// synchronised again.
+    #[serde(default)]
+    pub encoder_name: Option<String>,
+    /// The tags of the source that were left out.
+    #[serde(default)]
+    pub strip_tags: Vec<String>,
+    /// The tags that were changed by the tag rules, with their new values.
+    #[serde(default)]
+    pub rewritten_tags: Vec<(String, String)>,
 }
 
 impl SyncRecord {
-    pub fn from_song(song: &Song) -> SyncRecord {
+    pub fn from_song(song: &Song, settings: &SyncSettings) -> SyncRecord {
         SyncRecord {
             library_relative_path: song.library_relative_path.clone(),
             update_type: None,
             date: SystemTime::now(),
             hash: hash_file(&song.absolute_path),
+            target_relative_path: None,
+            embed_art_resolution: settings.embed_art_resolution,
+            loudness: song.loudness,
+            target_hash: None,
+            target_stamp: None,
+            audio_hash: settings
+                .hash_audio_only
+                .then(|| hash_audio(&song.absolute_path))
+                .flatten(),
+            encoder: Some(EncoderSettings {
+                filetype: settings.target_filetype_for(song).clone(),
+                art_strategy: settings.art_strategy_for(song),
+                ffmpeg_version: settings.ffmpeg_version.clone(),
+                encoder_name: chosen_encoder(settings.target_filetype_for(song)).map(str::to_owned),
+                strip_tags: settings.tags_to_strip(song),
+                rewritten_tags: settings.tag_rules.rewrite(&song.metadata),
+            }),
+            encode_speed: None,
+            sidecar: false,
+            art: false,
+        }
+    }
+
+    /// For a sidecar file that was copied to `target_relative_path`.
+    pub fn from_sidecar(
+        library_relative_path: &Path,
+        source: &Path,
+        target_relative_path: &Path,
+    ) -> SyncRecord {
+        SyncRecord {
+            library_relative_path: library_relative_path.to_path_buf(),
+            update_type: None,
+            date: SystemTime::now(),
+            hash: hash_file(source),
+            target_relative_path: Some(target_relative_path.to_path_buf()),
+            embed_art_resolution: 0,
+            loudness: None,
+            target_hash: None,
+            target_stamp: None,
+            audio_hash: None,
+            encoder: None,
+            encode_speed: None,
+            sidecar: true,
+            art: false,
+        }
+    }
+
+    /// For an album art file that was copied (or converted) to `target_relative_path`.
+    pub fn from_art(
+        library_relative_path: &Path,
+        source: &Path,
+        target_relative_path: &Path,
+    ) -> SyncRecord {
+        SyncRecord {
+            sidecar: false,
+            art: true,
+            ..SyncRecord::from_sidecar(library_relative_path, source, target_relative_path)
         }
     }
 
@@ -34,19 +176,116 @@ impl SyncRecord {
         proxy.update_type = Some(update_type);
         proxy
     }
+
+    pub fn set_target_relative_path(self, target_relative_path: PathBuf) -> SyncRecord {
+        let mut proxy = self;
+        proxy.target_relative_path = Some(target_relative_path);
+        proxy
+    }
+
+    /// Also notes the size and modification time of the synchronised copy at `shadow`, which the
+    /// hash is of.
+    pub fn set_target_hash(self, shadow: &Path, target_hash: Option<u64>) -> SyncRecord {
+        let mut proxy = self;
+        proxy.target_hash = target_hash;
+        proxy.target_stamp = target_hash.and_then(|_| FileStamp::of(shadow));
+        proxy
+    }
+
+    pub fn set_encode_speed(self, encode_speed: Option<f64>) -> SyncRecord {
+        let mut proxy = self;
+        proxy.encode_speed = encode_speed;
+        proxy
+    }
+}
+
+/// How the records of previous syncs are stored.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum RecordsFormat {
+    /// A single JSON file, which is written all at once at the end of the sync.
+    Json,
+    /// An SQLite database, to which every record is written as soon as its song is
+    /// synchronised. Faster for large libraries, and an interrupted sync keeps the records of
+    /// everything that was done. Existing JSON records are moved into it.
+    Sqlite,
+    /// A single compact binary file. Much quicker to read than JSON, e.g. on a Raspberry Pi.
+    /// Records written by a different version of syncbops can't be read, so everything is
+    /// checked again after updating.
+    Binary,
+}
+
+/// Name of the records file in the binary format.
+const BINARY_RECORDS_FILENAME: &str = ".syncbops.bin";
+/// At the start of every binary records file. The last byte is the version of the format,
+/// which has to be increased whenever `SyncRecord` changes, as fields can't be skipped or
+/// defaulted like they can in JSON.
+const BINARY_RECORDS_HEADER: &[u8; 4] = b"SBR\x0a";
+
+impl RecordsFormat {
+    /// Name of the file the records are written to.
+    pub fn filename(&self) -> &'static str {
+        match self {
+            RecordsFormat::Json => PREVIOUS_SYNC_DB_FILENAME,
+            RecordsFormat::Sqlite => SQLITE_RECORDS_FILENAME,
+            RecordsFormat::Binary => BINARY_RECORDS_FILENAME,
+        }
+    }
 }
 
 /// Knowledge on how the previous sync was done.
 /// Map where the keys are source-library relative paths.
 pub type PreviousSyncDb = HashMap<PathBuf, SyncRecord>;
 
-/// Tries to read the previous sync db into one of the possible locations.
-pub fn read_records_of_previous_sync(target_library: &Path) -> Option<PreviousSyncDb> {
-    let file_candidates = potential_locations_for_records_of_previous_syncs(target_library);
-    for file in file_candidates {
-        match read_records_from_file(&file) {
+/// Version of the JSON records. Increase it whenever older records can't be read as they are
+/// anymore (new fields with a `#[serde(default)]` are fine), and add a step to
+/// `migrate_records()`.
+const RECORDS_VERSION: u64 = 2;
+
+/// How the JSON records are written.
+#[derive(Serialize)]
+struct VersionedRecords<'a> {
+    version: u64,
+    records: &'a PreviousSyncDb,
+}
+
+/// Brings records of an older version up to date.
+fn migrate_records(version: u64, records: serde_json::Value) -> Result<PreviousSyncDb, String> {
+    match version {
+        // Version 1 did not have a version yet, but can be read as it is.
+        1 | RECORDS_VERSION => serde_json::from_value(records).map_err(|e| e.to_string()),
+        newer if newer > RECORDS_VERSION => Err(format!(
+            "they are of version {newer}, which is newer than what this version of syncbops can \
+            read (version {RECORDS_VERSION})"
+        )),
+        older => Err(format!("they are of unknown version {older}")),
+    }
+}
+
+/// Tries to read the previous sync db into one of the possible locations. If there are none in
+/// the given format, records in the JSON format (from before switching formats) are used.
+/// See `read_records_from_file()` for `move_unreadable`.
+pub fn read_records_of_previous_sync(
+    target_library: &Path,
+    format: RecordsFormat,
+    move_unreadable: bool,
+) -> Option<PreviousSyncDb> {
+    let mut file_candidates =
+        potential_locations_for_records_of_previous_syncs(target_library, format)
+            .into_iter()
+            .map(|file| (file, format))
+            .collect::<Vec<_>>();
+    if format != RecordsFormat::Json {
+        file_candidates.extend(
+            potential_locations_for_records_of_previous_syncs(target_library, RecordsFormat::Json)
+                .into_iter()
+                .filter(|file| file.exists())
+                .map(|file| (file, RecordsFormat::Json)),
+        );
+    }
+    for (file, format) in file_candidates {
+        match read_records_from_file(&file, format, move_unreadable) {
             Some(x) => {
-                println!("Read records from {}", file.display());
+                say!("Read records from {}", file.display());
                 return Some(x);
             }
             None => {
@@ -54,12 +293,19 @@ pub fn read_records_of_previous_sync(target_library: &Path) -> Option<PreviousSy
             }
         }
     }
-    println!("Could not find any records of previous syncs.");
+    say!("Could not find any records of previous syncs.");
     None
 }
 
-/// Attempts to read records of a previous sync fron the given path.
-fn read_records_from_file(path: &Path) -> Option<PreviousSyncDb> {
+/// Attempts to read records of a previous sync fron the given path. If the file can't be read,
+/// it can be moved out of the way with `move_unreadable`, so it is not overwritten by the
+/// records of this sync, in case it can still be recovered (e.g. by a newer version of
+/// syncbops).
+pub fn read_records_from_file(
+    path: &Path,
+    format: RecordsFormat,
+    move_unreadable: bool,
+) -> Option<PreviousSyncDb> {
     // Deserialise it. If it fails, it's better to just handle it like a new sync; assume an empty PreviousSyncDb.
     let file = match File::open(path) {
         Ok(x) => x,
@@ -74,13 +320,26 @@ fn read_records_from_file(path: &Path) -> Option<PreviousSyncDb> {
     };
     // Open the file in read-only mode with buffer, and parse into PreviousSyncDb
     let reader = BufReader::new(file);
-    let previous_sync_db: PreviousSyncDb = match serde_json::from_reader(reader) {
+    let parsed = match format {
+        RecordsFormat::Binary => read_binary_records(reader),
+        // SQLite records are not a plain file, see `SqliteRecords`.
+        RecordsFormat::Json | RecordsFormat::Sqlite => read_json_records(reader),
+    };
+    let previous_sync_db: PreviousSyncDb = match parsed {
         Ok(x) => x,
         Err(e) => {
+            let unreadable = path.with_extension("unreadable");
+            let moved = move_unreadable && std::fs::rename(path, &unreadable).is_ok();
             eprintln!(
-                "Cannot load previous sync result from {}: {}. Ignoring contents of the file.",
+                "Cannot read the records of previous syncs in {}, because {}. {}The records are \
+                rebuilt during this sync, by comparing every song with its synchronised copy.",
                 path.display(),
-                e
+                e,
+                if moved {
+                    format!("Moved the file to {}. ", unreadable.display())
+                } else {
+                    String::new()
+                }
             );
             return None;
         }
@@ -88,47 +347,94 @@ fn read_records_from_file(path: &Path) -> Option<PreviousSyncDb> {
     Some(previous_sync_db)
 }
 
+fn read_json_records(reader: impl Read) -> Result<PreviousSyncDb, String> {
+    let mut value: serde_json::Value =
+        serde_json::from_reader(reader).map_err(|e| format!("they are not valid JSON ({e})"))?;
+    // Records without a version are from before there was a version.
+    let version = value.get("version").and_then(|v| v.as_u64());
+    let (version, records) = match (version, value.as_object_mut()) {
+        (Some(version), Some(object)) => (version, object.remove("records").unwrap_or_default()),
+        _ => (1, value),
+    };
+    migrate_records(version, records)
+}
+
+fn read_binary_records(mut reader: impl Read) -> Result<PreviousSyncDb, String> {
+    let mut header = [0; BINARY_RECORDS_HEADER.len()];
+    reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if &header != BINARY_RECORDS_HEADER {
+        return Err("they were written by a different version of syncbops".to_owned());
+    }
+    bincode::deserialize_from(reader).map_err(|e| e.to_string())
+}
+
+/// Re-keys the records so that they use the given unicode normalisation form. Records written on
+/// a different filesystem (or with a different normalisation form) then still match up.
+pub fn normalize_record_keys(
+    previous_sync_db: PreviousSyncDb,
+    form: NormalizationForm,
+) -> PreviousSyncDb {
+    previous_sync_db
+        .into_values()
+        .map(|mut record| {
+            record.library_relative_path = form.normalize_path(&record.library_relative_path);
+            (record.library_relative_path.clone(), record)
+        })
+        .collect()
+}
+
 /// Previous sync records should normally be saved in the target library, but they can be
 /// missing or somewhere else. This generates potential locations it could be found at.
-fn potential_locations_for_records_of_previous_syncs(target_library: &Path) -> Vec<PathBuf> {
+fn potential_locations_for_records_of_previous_syncs(
+    target_library: &Path,
+    format: RecordsFormat,
+) -> Vec<PathBuf> {
     let mut potential_dirs = Vec::new();
 
     // File in target library itself
-    potential_dirs.push(target_library.join(PREVIOUS_SYNC_DB_FILENAME));
+    potential_dirs.push(target_library.join(format.filename()));
 
     // File in current working directory
     if let Ok(pwd) = std::env::current_dir() {
-        potential_dirs.push(pwd.join(PREVIOUS_SYNC_DB_FILENAME))
+        potential_dirs.push(pwd.join(format.filename()))
     };
 
     // File in user's home directory
     if let Some(pwd) = dirs::home_dir() {
-        potential_dirs.push(pwd.join(PREVIOUS_SYNC_DB_FILENAME))
+        potential_dirs.push(pwd.join(format.filename()))
     };
     potential_dirs
 }
 
 /// Tries to write the previous sync db into one of the possible locations, so that they can be
 /// checked against in the next sync.
-pub fn write_records_of_current_sync(previous_sync_db: &PreviousSyncDb, target_library: &Path) {
-    let file_candidates = potential_locations_for_records_of_previous_syncs(target_library);
+pub fn write_records_of_current_sync(
+    previous_sync_db: &PreviousSyncDb,
+    target_library: &Path,
+    format: RecordsFormat,
+) {
+    let file_candidates = potential_locations_for_records_of_previous_syncs(target_library, format);
     let mut success = false;
     for file in file_candidates {
-        success = write_sync_records_to_file(previous_sync_db, &file);
+        success = write_sync_records_to_file(previous_sync_db, &file, format);
         if success {
-            println!("Written records to {}", file.display());
+            say!("Written records to {}", file.display());
             break;
         }
     }
     if !success {
-        println!(
+        say!(
                 "Could not find any suitable file to write records to. No previous sync data will be saved. This probably means your next sync will unnecessarily redo a lot of things :(" 
             );
     }
 }
 
 /// Attempt to write to this specific file
-fn write_sync_records_to_file(previous_sync_db: &PreviousSyncDb, path: &Path) -> bool {
+fn write_sync_records_to_file(
+    previous_sync_db: &PreviousSyncDb,
+    path: &Path,
+    format: RecordsFormat,
+) -> bool {
     // Open file for writing
     let file = match File::create(path) {
         Ok(x) => x,
@@ -139,7 +445,25 @@ fn write_sync_records_to_file(previous_sync_db: &PreviousSyncDb, path: &Path) ->
             return false;
         }
     };
-    let written = serde_json::to_writer(file, previous_sync_db);
+    let mut writer = BufWriter::new(file);
+    let written = match format {
+        RecordsFormat::Binary => writer
+            .write_all(BINARY_RECORDS_HEADER)
+            .map_err(|e| e.to_string())
+            .and_then(|()| {
+                bincode::serialize_into(&mut writer, previous_sync_db).map_err(|e| e.to_string())
+            }),
+        // SQLite records are written while synchronising, see `SqliteRecords`.
+        RecordsFormat::Json | RecordsFormat::Sqlite => serde_json::to_writer(
+            &mut writer,
+            &VersionedRecords {
+                version: RECORDS_VERSION,
+                records: previous_sync_db,
+            },
+        )
+        .map_err(|e| e.to_string()),
+    }
+    .and_then(|()| writer.flush().map_err(|e| e.to_string()));
     match written {
         Ok(_) => true,
         Err(e) => {
@@ -164,12 +488,115 @@ pub fn register_record_to_previous_sync_db(
     // more useful information.
     // Therefore, only write information if it is actually useful.
     if update_type == UpdateType::NoChange {
+        // Except for how it is encoded, if that was not recorded yet, so that changing the
+        // settings later on is noticed.
+        if let Some(previous) = previous_sync_db.get_mut(&sync_record.library_relative_path) {
+            if previous.encoder.is_none() {
+                previous.encoder = sync_record.encoder;
+            }
+        }
         return;
     }
     // Returned value is old value, don't need it anymore.
     let _ = previous_sync_db.insert(sync_record.library_relative_path.clone(), sync_record);
 }
 
+/// Removes the records of songs that are no longer in the source library. Otherwise they pile up
+/// forever, and keep pointing at files in the target library that are not synchronised anymore.
+/// `discovered` are the library relative paths of all songs that were found in the source
+/// library; records that don't match those are only dropped if their source file is really gone.
+/// Records of sidecar files are left to `remove_stale_sidecars()`, which also removes their
+/// copies. Returns the removed records.
+pub fn prune_stale_records(
+    previous_sync_db: &mut PreviousSyncDb,
+    source_library: &Path,
+    discovered: &HashSet<PathBuf>,
+) -> Vec<SyncRecord> {
+    let mut stale = previous_sync_db
+        .iter()
+        .filter(|(path, record)| {
+            !record.sidecar
+                && !discovered.contains(*path)
+                && find_in_library(source_library, path).is_none()
+        })
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    stale.sort();
+    stale
+        .iter()
+        .filter_map(|path| previous_sync_db.remove(path))
+        .collect()
+}
+
+/// The average speed at which songs were encoded during earlier syncs, in seconds of audio per
+/// second, for every type of file that songs were transcoded to.
+pub fn encode_speeds(previous_sync_db: &PreviousSyncDb) -> HashMap<String, f64> {
+    let mut speeds: HashMap<String, Vec<f64>> = HashMap::new();
+    for record in previous_sync_db.values() {
+        if let (Some(encoder), Some(speed)) = (&record.encoder, record.encode_speed) {
+            speeds
+                .entry(encoder.filetype.to_string())
+                .or_default()
+                .push(speed);
+        }
+    }
+    speeds
+        .into_iter()
+        .map(|(filetype, speeds)| (filetype, speeds.iter().sum::<f64>() / speeds.len() as f64))
+        .collect()
+}
+
+/// How long transcoding these songs will take, judging by how fast songs were encoded before.
+/// `parallel` is how many songs are transcoded at the same time. Songs that are transcoded to
+/// a type of file that was never transcoded to before are not counted. None if none of the
+/// songs can be estimated.
+pub fn estimate_transcode_time(
+    songs: &[&Song],
+    settings: &SyncSettings,
+    speeds: &HashMap<String, f64>,
+    parallel: usize,
+) -> Option<Duration> {
+    let seconds = songs
+        .iter()
+        .filter_map(|song| {
+            let speed = speeds.get(&settings.target_filetype_for(song).to_string())?;
+            Some(song.metadata.duration?.as_secs_f64() / speed)
+        })
+        .reduce(|a, b| a + b)?;
+    Duration::try_from_secs_f64(seconds / parallel.max(1) as f64).ok()
+}
+
+/// Finds songs that are bit-identical to another song in the library. Maps the library relative
+/// path of each duplicate to the one of the song it is a duplicate of. Of identical songs, the
+/// first in alphabetical order is the one that is kept.
+pub fn find_duplicate_songs(songs: &[Song]) -> HashMap<PathBuf, PathBuf> {
+    let mut fingerprints = songs
+        .par_iter()
+        // The tracks of a single-file album are all in the same file.
+        .filter(|song| song.segment.is_none())
+        .filter_map(|song| {
+            let size = std::fs::metadata(&song.absolute_path).ok()?.len();
+            let hash = hash_file(&song.absolute_path)?;
+            Some(((hash, size), &song.library_relative_path))
+        })
+        .collect::<Vec<_>>();
+    fingerprints.sort_by_key(|(_, path)| *path);
+
+    let mut originals: HashMap<(u64, u64), &PathBuf> = HashMap::new();
+    let mut duplicates = HashMap::new();
+    for (fingerprint, path) in fingerprints {
+        match originals.get(&fingerprint) {
+            Some(original) => {
+                duplicates.insert(path.clone(), (*original).clone());
+            }
+            None => {
+                originals.insert(fingerprint, path);
+            }
+        }
+    }
+    duplicates
+}
+
 /// Simple hash to see if a file has changed. Non-cryptographic!
 pub fn hash_file(path: &Path) -> Option<u64> {
     let mut file = std::fs::File::open(path).ok()?;
@@ -177,6 +604,73 @@ pub fn hash_file(path: &Path) -> Option<u64> {
     Some(hash)
 }
 
+/// Like `hash_file()`, but only hashes the audio, so it stays the same when only the tags are
+/// edited. Only works for MP3 and FLAC files, None for anything else.
+pub fn hash_audio(path: &Path) -> Option<u64> {
+    let bytes = std::fs::read(path).ok()?;
+    Some(rapidhash::rapidhash(audio_frames(&bytes)?))
+}
+
+/// The part of an MP3 or FLAC file that holds the audio, without the tags around it.
+fn audio_frames(bytes: &[u8]) -> Option<&[u8]> {
+    let bytes = skip_id3v2(bytes);
+    if let Some(flac) = bytes.strip_prefix(b"fLaC") {
+        return skip_flac_metadata(flac);
+    }
+    // MP3 frames start with 11 set bits.
+    if bytes.len() < 2 || bytes[0] != 0xFF || bytes[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    Some(strip_trailing_mp3_tags(bytes))
+}
+
+/// ID3v2 tags are in front of the audio, and tell how large they are.
+fn skip_id3v2(bytes: &[u8]) -> &[u8] {
+    if bytes.len() < 10 || !bytes.starts_with(b"ID3") {
+        return bytes;
+    }
+    // "Synchsafe" integer: only the lower 7 bits of every byte are used.
+    let size = bytes[6..10]
+        .iter()
+        .fold(0usize, |size, b| (size << 7) | (*b & 0x7F) as usize);
+    let has_footer = bytes[5] & 0x10 != 0;
+    let total = 10 + size + if has_footer { 10 } else { 0 };
+    bytes.get(total..).unwrap_or_default()
+}
+
+/// FLAC metadata blocks (which includes the tags and pictures) come before the audio frames.
+fn skip_flac_metadata(mut bytes: &[u8]) -> Option<&[u8]> {
+    loop {
+        let header = bytes.get(..4)?;
+        let is_last = header[0] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        bytes = bytes.get(4 + length..)?;
+        if is_last {
+            return Some(bytes);
+        }
+    }
+}
+
+/// ID3v1 and APEv2 tags are put after the audio of MP3 files.
+fn strip_trailing_mp3_tags(mut bytes: &[u8]) -> &[u8] {
+    const ID3V1_LENGTH: usize = 128;
+    const APE_FOOTER_LENGTH: usize = 32;
+    if bytes.len() >= ID3V1_LENGTH && bytes[bytes.len() - ID3V1_LENGTH..].starts_with(b"TAG") {
+        bytes = &bytes[..bytes.len() - ID3V1_LENGTH];
+    }
+    if bytes.len() >= APE_FOOTER_LENGTH {
+        let footer = &bytes[bytes.len() - APE_FOOTER_LENGTH..];
+        if footer.starts_with(b"APETAGEX") {
+            // The size includes the footer, but not the header.
+            let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as usize;
+            let has_header = footer[23] & 0x80 != 0;
+            let total = size + if has_header { APE_FOOTER_LENGTH } else { 0 };
+            bytes = &bytes[..bytes.len().saturating_sub(total)];
+        }
+    }
+    bytes
+}
+
 // #[cfg(test)]
 // mod tests {
 //
@@ -200,3 +694,221 @@ pub fn hash_file(path: &Path) -> Option<u64> {
 //     panic!("hash_file takes avg {:.6?} per file", avg_time_per_item);
 // }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        audio_frames, prune_stale_records, read_records_from_file, write_sync_records_to_file,
+        PreviousSyncDb, RecordsFormat, SyncRecord,
+    };
+    use crate::music_library::UpdateType;
+    use std::{collections::HashSet, path::PathBuf, time::SystemTime};
+
+    #[test]
+    /// Records survive being written and read in the binary format. Files in an unknown
+    /// version of the format are ignored.
+    fn binary_records_roundtrip() {
+        let mut db = PreviousSyncDb::new();
+        db.insert(
+            "a.flac".into(),
+            SyncRecord {
+                library_relative_path: "a.flac".into(),
+                update_type: Some(UpdateType::NewTranscode),
+                date: SystemTime::now(),
+                hash: Some(42),
+                target_relative_path: Some("a.opus".into()),
+                embed_art_resolution: 500,
+                loudness: None,
+                target_hash: Some(7),
+                target_stamp: None,
+                audio_hash: None,
+                encoder: None,
+                encode_speed: None,
+                sidecar: false,
+                art: false,
+            },
+        );
+        let path = std::env::temp_dir().join(format!(
+            "syncbops_records_{}.bin",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        assert!(write_sync_records_to_file(
+            &db,
+            &path,
+            RecordsFormat::Binary
+        ));
+        let read = read_records_from_file(&path, RecordsFormat::Binary, false).unwrap();
+        let record = &read[&PathBuf::from("a.flac")];
+        assert_eq!(record.hash, Some(42));
+        assert_eq!(record.target_relative_path, Some("a.opus".into()));
+        assert_eq!(record.target_hash, Some(7));
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[3] += 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(read_records_from_file(&path, RecordsFormat::Binary, false).is_none());
+    }
+
+    #[test]
+    /// The time to transcode is estimated from how fast each type of file was encoded before.
+    fn estimate_transcode_time_from_earlier_syncs() {
+        use super::{encode_speeds, estimate_transcode_time, EncoderSettings};
+        use crate::{
+            music_library::{ArtStrategy, Id3Tags, MusicFileType},
+            song::Song,
+            sync_song::SyncSettings,
+        };
+        use std::time::Duration;
+
+        let mp3 = MusicFileType::Mp3VBR {
+            quality: 2,
+            id3: Id3Tags::default(),
+        };
+        let mut db = PreviousSyncDb::new();
+        for (path, filetype, speed) in [
+            ("a.flac", mp3.clone(), 20.),
+            ("b.flac", mp3.clone(), 40.),
+            ("c.flac", MusicFileType::Flac { quality: 8 }, 100.),
+        ] {
+            db.insert(
+                path.into(),
+                SyncRecord {
+                    library_relative_path: path.into(),
+                    update_type: Some(UpdateType::NewTranscode),
+                    date: SystemTime::now(),
+                    hash: Some(1),
+                    target_relative_path: None,
+                    embed_art_resolution: 0,
+                    loudness: None,
+                    target_hash: None,
+                    target_stamp: None,
+                    audio_hash: None,
+                    encoder: Some(EncoderSettings {
+                        filetype,
+                        art_strategy: ArtStrategy::None,
+                        ffmpeg_version: None,
+                        encoder_name: None,
+                        strip_tags: Vec::new(),
+                        rewritten_tags: Vec::new(),
+                    }),
+                    encode_speed: Some(speed),
+                    sidecar: false,
+                    art: false,
+                },
+            );
+        }
+        let speeds = encode_speeds(&db);
+        assert_eq!(speeds["mp3"], 30.);
+
+        let mut song = Song::new_fake("d.flac", &[]);
+        song.metadata.duration = Some(Duration::from_secs(600));
+        let settings = SyncSettings::new_debug(mp3, ArtStrategy::None);
+        assert_eq!(
+            estimate_transcode_time(&[&song, &song], &settings, &speeds, 2),
+            Some(Duration::from_secs(20))
+        );
+        let opus = SyncSettings::new_debug(
+            MusicFileType::Opus {
+                bitrate: 128,
+                compression_level: 10,
+                vbr: crate::music_library::OpusVbr::On,
+            },
+            ArtStrategy::None,
+        );
+        assert_eq!(estimate_transcode_time(&[&song], &opus, &speeds, 2), None);
+    }
+
+    #[test]
+    /// Only records of songs that are neither discovered nor on disk are removed.
+    fn prune_records_of_removed_songs() {
+        let source_library: PathBuf = format!(
+            "/tmp/syncbops/prune_records_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        )
+        .into();
+        std::fs::create_dir_all(&source_library).unwrap();
+        std::fs::write(source_library.join("on_disk.flac"), "").unwrap();
+        let mut db = PreviousSyncDb::new();
+        for path in ["discovered.flac", "on_disk.flac", "removed.flac"] {
+            db.insert(
+                path.into(),
+                SyncRecord {
+                    library_relative_path: path.into(),
+                    update_type: Some(UpdateType::NewTranscode),
+                    date: SystemTime::now(),
+                    hash: Some(1),
+                    target_relative_path: None,
+                    embed_art_resolution: 0,
+                    loudness: None,
+                    target_hash: None,
+                    target_stamp: None,
+                    audio_hash: None,
+                    encoder: None,
+                    encode_speed: None,
+                    sidecar: false,
+                    art: false,
+                },
+            );
+        }
+        let discovered = HashSet::from([PathBuf::from("discovered.flac")]);
+
+        let removed = prune_stale_records(&mut db, &source_library, &discovered);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(
+            removed[0].library_relative_path,
+            PathBuf::from("removed.flac")
+        );
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    /// Records from before they had a version can still be read, records from a newer version
+    /// can't.
+    fn json_records_versions() {
+        let path = std::env::temp_dir().join(format!(
+            "syncbops_records_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let record = r#"{"library_relative_path": "a.flac", "update_type": "Copied",
+            "date": {"secs_since_epoch": 1700000000, "nanos_since_epoch": 0}, "hash": 42}"#;
+
+        std::fs::write(&path, format!(r#"{{"a.flac": {record}}}"#)).unwrap();
+        let read = read_records_from_file(&path, RecordsFormat::Json, false).unwrap();
+        assert_eq!(read[&PathBuf::from("a.flac")].hash, Some(42));
+
+        std::fs::write(
+            &path,
+            format!(r#"{{"version": 99, "records": {{"a.flac": {record}}}}}"#),
+        )
+        .unwrap();
+        assert!(read_records_from_file(&path, RecordsFormat::Json, false).is_none());
+
+        let db = read;
+        assert!(write_sync_records_to_file(&db, &path, RecordsFormat::Json));
+        let read = read_records_from_file(&path, RecordsFormat::Json, false).unwrap();
+        assert_eq!(read[&PathBuf::from("a.flac")].hash, Some(42));
+    }
+
+    #[test]
+    /// Tags before and after the audio are left out, the audio itself is not.
+    fn audio_frames_without_tags() {
+        let frames = [0xFF, 0xFB, 0x90, 0x64, 0x00, 0x01, 0x02];
+
+        let mut mp3 = b"ID3\x03\x00\x00\x00\x00\x00\x05title".to_vec();
+        mp3.extend_from_slice(&frames);
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, b' ');
+        mp3.extend_from_slice(&id3v1);
+        assert_eq!(audio_frames(&mp3), Some(&frames[..]));
+
+        let mut flac = b"fLaC".to_vec();
+        flac.extend_from_slice(&[0x00, 0x00, 0x00, 0x02, 0xAA, 0xBB]);
+        flac.extend_from_slice(&[0x84, 0x00, 0x00, 0x01, 0xCC]);
+        flac.extend_from_slice(&frames);
+        assert_eq!(audio_frames(&flac), Some(&frames[..]));
+
+        assert_eq!(audio_frames(b"OggS\x00\x02"), None);
+        // Metadata that claims to be longer than the file.
+        assert_eq!(audio_frames(b"fLaC\x80\x00\x10\x00"), None);
+    }
+}
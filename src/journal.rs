@@ -0,0 +1,219 @@
+use crate::{backup::BACKUP_DIR, lock::TargetLibraryLock, music_library::MusicLibraryError};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Name of the journal in the folder of the backup of a sync.
+const JOURNAL_FILENAME: &str = ".syncbops-journal";
+
+/// A change to the target library. Paths are relative to the target library.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// A file was written where there was none (anymore).
+    Created { path: PathBuf },
+    /// A file was moved (or copied) into the backup, because it is replaced or removed.
+    BackedUp { path: PathBuf, backup: PathBuf },
+}
+
+/// Every change that a sync makes to the target library, kept in the folder of its backup so
+/// that `syncbops undo` can revert it. Written as the changes happen, one JSON object per line,
+/// so that an interrupted sync can be undone too.
+#[derive(Debug)]
+pub struct Journal {
+    target_library: PathBuf,
+    path: PathBuf,
+    /// Only opened once there is something to write, so syncs that don't change anything don't
+    /// leave a journal behind.
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    /// The journal of the sync of which the backup is in `backup_dir`.
+    pub fn new(target_library: &Path, backup_dir: &Path) -> Journal {
+        Journal {
+            target_library: target_library.to_path_buf(),
+            path: backup_dir.join(JOURNAL_FILENAME),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Notes that the file at `path` in the target library was written.
+    pub fn created(&self, path: &Path) -> io::Result<()> {
+        self.record(Change::Created {
+            path: self.relative(path).to_path_buf(),
+        })
+    }
+
+    /// Notes that the file at `path` in the target library was moved to `backup`.
+    pub fn backed_up(&self, path: &Path, backup: &Path) -> io::Result<()> {
+        self.record(Change::BackedUp {
+            path: self.relative(path).to_path_buf(),
+            backup: self.relative(backup).to_path_buf(),
+        })
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.target_library)
+            .expect("journaled files should be in the target library")
+    }
+
+    fn record(&self, change: Change) -> io::Result<()> {
+        let mut file = self
+            .file
+            .lock()
+            .expect("journal lock should not be poisoned");
+        if file.is_none() {
+            fs::create_dir_all(self.path.parent().expect("journal should have a parent"))?;
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let mut line = serde_json::to_string(&change).map_err(io::Error::other)?;
+        line.push('\n');
+        file.as_mut()
+            .expect("journal was just opened")
+            .write_all(line.as_bytes())
+    }
+}
+
+/// What `undo_last_sync()` did.
+#[derive(Debug, Default)]
+pub struct Undone {
+    /// The folder of the backup of the sync that was undone.
+    pub backup: PathBuf,
+    /// Files written by the sync that were removed again.
+    pub removed: usize,
+    /// Files that were put back from the backup.
+    pub restored: usize,
+}
+
+/// Reverts the changes of the last sync with backups, in the opposite order in which they were
+/// made, and then removes its backup. Running it again reverts the sync before that. None if
+/// there is no sync to undo.
+pub fn undo_last_sync(target_library: &Path) -> io::Result<Option<Undone>> {
+    let Ok(entries) = fs::read_dir(target_library.join(BACKUP_DIR)) else {
+        return Ok(None);
+    };
+    let last = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|dir| dir.join(JOURNAL_FILENAME).is_file())
+        .filter_map(|dir| Some((dir.file_name()?.to_str()?.parse::<u64>().ok()?, dir)))
+        .max();
+    let Some((_, backup)) = last else {
+        return Ok(None);
+    };
+    let changes = BufReader::new(File::open(backup.join(JOURNAL_FILENAME))?)
+        .lines()
+        .map(|line| serde_json::from_str::<Change>(&line?).map_err(io::Error::other))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut undone = Undone::default();
+    for change in changes.into_iter().rev() {
+        match change {
+            Change::Created { path } => match fs::remove_file(target_library.join(path)) {
+                Ok(()) => undone.removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            },
+            Change::BackedUp { path, backup } => {
+                let (path, backup) = (target_library.join(path), target_library.join(backup));
+                fs::create_dir_all(path.parent().expect("restored file should have a parent"))?;
+                if fs::rename(&backup, &path).is_err() {
+                    fs::copy(&backup, &path)?;
+                }
+                undone.restored += 1;
+            }
+        }
+    }
+    fs::remove_dir_all(&backup)?;
+    undone.backup = backup;
+    Ok(Some(undone))
+}
+
+/// Reverts the last sync to a target library that was made with --backup: removes the files it
+/// wrote, and puts back the ones it replaced or removed, records included.
+#[derive(clap::Parser)]
+#[command(bin_name = "syncbops undo", version)]
+pub struct UndoCli {
+    target_library: PathBuf,
+}
+
+/// Undoes the last sync, and tells what was done.
+pub fn undo(cli: &UndoCli) -> Result<(), MusicLibraryError> {
+    let _lock = TargetLibraryLock::acquire(&cli.target_library, false)?;
+    let undone = undo_last_sync(&cli.target_library).map_err(|source| MusicLibraryError::Undo {
+        path: cli.target_library.clone(),
+        source,
+    })?;
+    match undone {
+        Some(undone) => say!(
+            "Undid the sync of {}: removed {} files it wrote, and put back {} files it replaced or removed.",
+            undone.backup.display(),
+            undone.removed,
+            undone.restored
+        ),
+        None => say!(
+            "There is no sync to undo in {}. Only syncs with --backup can be undone.",
+            cli.target_library.display()
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::undo_last_sync;
+    use crate::backup::{Backup, Retention};
+    use std::{fs, path::PathBuf};
+
+    #[test]
+    /// Undoing puts back what was replaced or removed, and removes what was added.
+    fn undo_reverts_the_last_sync() {
+        let target_library = PathBuf::from(format!(
+            "/tmp/syncbops/test_undo_{}",
+            random_string::generate(24, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let replaced = target_library.join("Artist/01 Replaced.opus");
+        let removed = target_library.join("Artist/02 Removed.opus");
+        let added = target_library.join("Artist/03 Added.opus");
+        fs::create_dir_all(replaced.parent().unwrap()).unwrap();
+        fs::write(&replaced, b"old").unwrap();
+        fs::write(&removed, b"old").unwrap();
+
+        let backup = Backup::new(
+            &target_library,
+            Retention {
+                keep: 5,
+                max_bytes: None,
+            },
+        );
+        backup.back_up(&replaced).unwrap();
+        fs::write(&replaced, b"new").unwrap();
+        backup.created(&replaced).unwrap();
+        // Replaced a second time in the same sync.
+        backup.back_up(&replaced).unwrap();
+        fs::write(&replaced, b"newer").unwrap();
+        backup.created(&replaced).unwrap();
+        backup.back_up(&removed).unwrap();
+        fs::write(&added, b"new").unwrap();
+        backup.created(&added).unwrap();
+
+        let undone = undo_last_sync(&target_library).unwrap().unwrap();
+        assert_eq!((undone.removed, undone.restored), (2, 2));
+        assert_eq!(fs::read(&replaced).unwrap(), b"old");
+        assert_eq!(fs::read(&removed).unwrap(), b"old");
+        assert!(!added.exists());
+        assert!(!undone.backup.exists());
+        assert!(undo_last_sync(&target_library).unwrap().is_none());
+        fs::remove_dir_all(&target_library).unwrap();
+    }
+}
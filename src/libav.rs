@@ -0,0 +1,344 @@
+use crate::{
+    audiobooks::Chapters,
+    ffmpeg_interface::{AudioConversion, Encoder, FfmpegError},
+    music_library::MusicFileType,
+};
+use ffmpeg_next::{
+    codec::{self, capabilities::Capabilities},
+    filter, format, frame, media, ChannelLayout, Dictionary, Packet, Rational, Rescale,
+};
+use std::{path::Path, sync::Once};
+
+/// `-q:a` on the command line is multiplied by this (`FF_QP2LAMBDA`) before it is passed to the
+/// encoder.
+const QP2LAMBDA: f64 = 118.;
+
+/// Transcodes the song in-process with the ffmpeg libraries, instead of starting an ffmpeg
+/// process for it. The result is the same as that of the ffmpeg command. Only songs of which
+/// the audio is encoded as a whole and no art is embedded are done like this for now; None for
+/// anything else, so that those can be transcoded with the ffmpeg command.
+pub fn transcode_song(
+    source: &Path,
+    target: &Path,
+    target_type: &MusicFileType,
+    encoder: Option<Encoder>,
+    audio: AudioConversion,
+    embed_art: bool,
+    tags: &[(String, String)],
+    reuse_audio: Option<&Path>,
+) -> Option<Result<(), FfmpegError>> {
+    let encoder = match encoder {
+        // Chapters are not carried over yet, and only the ffmpeg command leaves out its own
+        // encoder tag.
+        Some(encoder)
+            if !embed_art
+                && reuse_audio.is_none()
+                && !tags
+                    .iter()
+                    .any(|(key, value)| key == "encoder" && value.is_empty())
+                && audio.segment.is_none()
+                && audio
+                    .chapters
+                    .is_none_or(|chapters| chapters == Chapters::Drop) =>
+        {
+            encoder
+        }
+        _ => return None,
+    };
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let _ = ffmpeg_next::init();
+        ffmpeg_next::log::set_level(ffmpeg_next::log::Level::Error);
+    });
+    Some(
+        transcode(source, target, target_type, encoder, audio, tags).map_err(|e| {
+            FfmpegError::Libav {
+                file: source.into(),
+                source: e,
+            }
+        }),
+    )
+}
+
+/// The options of the encoder. The same as what `transcode_song()` in `ffmpeg_interface` passes
+/// on the command line.
+fn encoder_options(target_type: &MusicFileType, encoder: Encoder) -> Dictionary<'static> {
+    let mut options = Dictionary::new();
+    if encoder.is_experimental() {
+        options.set("strict", "experimental");
+    }
+    match target_type {
+        MusicFileType::Mp3VBR { quality, .. } => {
+            options.set("flags", "+qscale");
+            options.set(
+                "global_quality",
+                &((*quality as f64 * QP2LAMBDA) as i64).to_string(),
+            );
+        }
+        MusicFileType::Mp3CBR { bitrate, .. } => {
+            options.set("b", &format!("{bitrate}k"));
+        }
+        MusicFileType::Vorbis { quality } => {
+            options.set("flags", "+qscale");
+            options.set(
+                "global_quality",
+                &((quality * QP2LAMBDA) as i64).to_string(),
+            );
+        }
+        MusicFileType::Opus {
+            bitrate,
+            compression_level,
+            vbr,
+        } => {
+            options.set("b", &format!("{bitrate}k"));
+            options.set("compression_level", &compression_level.to_string());
+            if !encoder.is_experimental() {
+                options.set("vbr", vbr.ffmpeg_value());
+            }
+        }
+        MusicFileType::Flac { quality } => {
+            options.set("compression_level", &quality.to_string());
+        }
+        MusicFileType::Copy => unreachable!("copied songs are not encoded"),
+    }
+    options
+}
+
+fn transcode(
+    source: &Path,
+    target: &Path,
+    target_type: &MusicFileType,
+    encoder: Encoder,
+    audio: AudioConversion,
+    tags: &[(String, String)],
+) -> Result<(), ffmpeg_next::Error> {
+    let mut ictx = format::input(&source)?;
+    let mut octx = format::output(&target)?;
+
+    let input = ictx
+        .streams()
+        .best(media::Type::Audio)
+        .ok_or(ffmpeg_next::Error::StreamNotFound)?;
+    let input_index = input.index();
+    let input_time_base = input.time_base();
+    // Like `-map_metadata 0 -map_metadata 0:s:0`: the tags of the file and of the audio stream.
+    let mut entries = Vec::<(String, String)>::new();
+    let given = tags
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()));
+    for (key, value) in ictx
+        .metadata()
+        .iter()
+        .chain(input.metadata().iter())
+        .chain(given)
+    {
+        entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        // Like `-metadata key=`, an empty value removes the tag.
+        if !value.is_empty() {
+            entries.push((key.to_owned(), value.to_owned()));
+        }
+    }
+    let mut metadata = Dictionary::new();
+    for (key, value) in &entries {
+        metadata.set(key, value);
+    }
+    let decoder = codec::context::Context::from_parameters(input.parameters())?
+        .decoder()
+        .audio()?;
+    let input_layout = match decoder.channel_layout() {
+        layout if layout.is_empty() => ChannelLayout::default(decoder.channels() as i32),
+        layout => layout,
+    };
+
+    let options = encoder_options(target_type, encoder);
+    let codec = ffmpeg_next::encoder::find_by_name(encoder.name)
+        .ok_or(ffmpeg_next::Error::EncoderNotFound)?
+        .audio()?;
+    let global_header = octx
+        .format()
+        .flags()
+        .contains(format::flag::Flags::GLOBAL_HEADER);
+    let mut output = octx.add_stream(codec)?;
+    let mut encoder = codec::context::Context::from_parameters(output.parameters())?
+        .encoder()
+        .audio()?;
+    if global_header {
+        encoder.set_flags(codec::flag::Flags::GLOBAL_HEADER);
+    }
+
+    // Use what the source has, unless it has to be changed, or the encoder can't handle it.
+    let channels = audio
+        .channels
+        .map_or(input_layout.channels(), |channels| channels as i32);
+    let layout = match codec.channel_layouts() {
+        Some(layouts) => layouts.best(channels),
+        None => ChannelLayout::default(channels),
+    };
+    let wanted_rate = audio.sample_rate.unwrap_or(decoder.rate()) as i32;
+    let rate = codec
+        .rates()
+        .and_then(|rates| rates.min_by_key(|rate| (rate - wanted_rate).abs()))
+        .unwrap_or(wanted_rate);
+    let sample_format = if audio.to_16_bit && matches!(target_type, MusicFileType::Flac { .. }) {
+        format::Sample::I16(format::sample::Type::Packed)
+    } else {
+        let supported = codec.formats().map(Iterator::collect::<Vec<_>>);
+        match supported {
+            Some(formats) if !formats.contains(&decoder.format()) => formats
+                .iter()
+                .find(|format| format.bytes() >= decoder.format().bytes())
+                .or(formats.first())
+                .copied()
+                .unwrap_or(decoder.format()),
+            _ => decoder.format(),
+        }
+    };
+    encoder.set_rate(rate);
+    encoder.set_channel_layout(layout);
+    encoder.set_format(sample_format);
+    encoder.set_time_base((1, rate));
+    output.set_time_base((1, rate));
+    let encoder = encoder.open_as_with(codec, options)?;
+    output.set_parameters(&encoder);
+
+    // The same filter as on the command line. The conversions to what the encoder needs are
+    // added around it automatically.
+    let mut resample_options = Vec::new();
+    if let Some(sample_rate) = audio.sample_rate {
+        resample_options.push(sample_rate.to_string());
+    }
+    if audio.to_16_bit && matches!(target_type, MusicFileType::Flac { .. }) {
+        resample_options.push("osf=s16".to_owned());
+        if audio.dither {
+            resample_options.push("dither_method=triangular".to_owned());
+        }
+    }
+    let filter_spec = if resample_options.is_empty() {
+        "anull".to_owned()
+    } else {
+        format!("aresample={}", resample_options.join(":"))
+    };
+    let mut graph = filter::Graph::new();
+    graph.add(
+        &filter::find("abuffer").ok_or(ffmpeg_next::Error::FilterNotFound)?,
+        "in",
+        &format!(
+            "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            input_time_base,
+            decoder.rate(),
+            decoder.format().name(),
+            input_layout.bits()
+        ),
+    )?;
+    graph.add(
+        &filter::find("abuffersink").ok_or(ffmpeg_next::Error::FilterNotFound)?,
+        "out",
+        "",
+    )?;
+    {
+        let mut out = graph.get("out").ok_or(ffmpeg_next::Error::FilterNotFound)?;
+        out.set_sample_format(encoder.format());
+        out.set_channel_layout(encoder.channel_layout());
+        out.set_sample_rate(encoder.rate());
+    }
+    graph
+        .output("in", 0)?
+        .input("out", 0)?
+        .parse(&filter_spec)?;
+    graph.validate()?;
+    let source = graph.get("in").ok_or(ffmpeg_next::Error::FilterNotFound)?;
+    let mut sink = graph.get("out").ok_or(ffmpeg_next::Error::FilterNotFound)?;
+    if !codec
+        .capabilities()
+        .contains(Capabilities::VARIABLE_FRAME_SIZE)
+    {
+        sink.sink().set_frame_size(encoder.frame_size());
+    }
+
+    octx.set_metadata(metadata);
+    let mut muxer_options = Dictionary::new();
+    if let Some(id3) = target_type.id3() {
+        muxer_options.set("id3v2_version", id3.id3_version.ffmpeg_value());
+        if id3.id3v1 {
+            muxer_options.set("write_id3v1", "1");
+        }
+    }
+    octx.write_header_with(muxer_options)?;
+
+    let mut pipeline = Pipeline {
+        filter_time_base: sink.sink().time_base(),
+        encoder_time_base: Rational::new(1, rate),
+        // The muxer can change the time base of the stream when writing the header.
+        output_time_base: octx
+            .stream(0)
+            .ok_or(ffmpeg_next::Error::StreamNotFound)?
+            .time_base(),
+        _graph: graph,
+        source,
+        sink,
+        decoder,
+        encoder,
+    };
+    for (stream, packet) in ictx.packets() {
+        if stream.index() == input_index {
+            pipeline.decoder.send_packet(&packet)?;
+            pipeline.decode(&mut octx)?;
+        }
+    }
+    pipeline.decoder.send_eof()?;
+    pipeline.decode(&mut octx)?;
+    pipeline.source.source().flush()?;
+    pipeline.filter(&mut octx)?;
+    pipeline.encoder.send_eof()?;
+    pipeline.encode(&mut octx)?;
+    octx.write_trailer()
+}
+
+/// Passes the audio from the decoder through the filters to the encoder, and writes it.
+struct Pipeline {
+    decoder: codec::decoder::Audio,
+    /// Owns the filters below, so it has to be kept around for as long as they are used.
+    _graph: filter::Graph,
+    source: filter::Context,
+    sink: filter::Context,
+    encoder: codec::encoder::Audio,
+    filter_time_base: Rational,
+    encoder_time_base: Rational,
+    output_time_base: Rational,
+}
+
+impl Pipeline {
+    fn decode(&mut self, octx: &mut format::context::Output) -> Result<(), ffmpeg_next::Error> {
+        let mut decoded = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            decoded.set_pts(decoded.timestamp());
+            self.source.source().add(&decoded)?;
+            self.filter(octx)?;
+        }
+        Ok(())
+    }
+
+    fn filter(&mut self, octx: &mut format::context::Output) -> Result<(), ffmpeg_next::Error> {
+        let mut filtered = frame::Audio::empty();
+        while self.sink.sink().frame(&mut filtered).is_ok() {
+            filtered.set_pts(
+                filtered
+                    .pts()
+                    .map(|pts| pts.rescale(self.filter_time_base, self.encoder_time_base)),
+            );
+            self.encoder.send_frame(&filtered)?;
+            self.encode(octx)?;
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, octx: &mut format::context::Output) -> Result<(), ffmpeg_next::Error> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.set_stream(0);
+            encoded.rescale_ts(self.encoder_time_base, self.output_time_base);
+            encoded.write_interleaved(octx)?;
+        }
+        Ok(())
+    }
+}
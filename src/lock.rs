@@ -0,0 +1,160 @@
+use crate::music_library::MusicLibraryError;
+use fs2::FileExt;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of the lock file in the target library.
+const LOCK_FILENAME: &str = ".syncbops.lock";
+
+/// Makes sure only one sync at a time writes to a target library. Otherwise two runs (e.g. one
+/// started by cron while the other is still going) write to the same files and records.
+/// The lock is an advisory lock of the OS on the lock file, so it is released when the run ends,
+/// also when it crashes or is killed. The lock file only holds the process id, to tell who has it.
+/// The lock is released when this is dropped.
+#[derive(Debug)]
+pub struct TargetLibraryLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl TargetLibraryLock {
+    /// Locks the target library. If another run has it locked already, either fails, or waits
+    /// until the other run is done.
+    pub fn acquire(target_library: &Path, wait: bool) -> Result<Self, MusicLibraryError> {
+        let path = target_library.join(LOCK_FILENAME);
+        let lock_error = |source| MusicLibraryError::Lock {
+            path: path.clone(),
+            source,
+        };
+        let mut told_waiting = false;
+        loop {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(lock_error)?;
+            match file.try_lock_exclusive() {
+                Ok(()) => (),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    let pid = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|pid| pid.trim().parse::<u32>().ok());
+                    if !wait {
+                        return Err(MusicLibraryError::TargetLibraryLocked { pid });
+                    }
+                    if !told_waiting {
+                        match pid {
+                            Some(pid) => say!("Waiting for the other sync to the target library (process {pid}) to finish..."),
+                            None => say!("Waiting for the other sync to the target library to finish..."),
+                        }
+                        told_waiting = true;
+                    }
+                    file.lock_exclusive().map_err(lock_error)?;
+                }
+                Err(e) => return Err(lock_error(e)),
+            }
+            // The run that had the lock removes the lock file when it is done. If that happened
+            // while this one was waiting, it locked a file that is gone, and another run may
+            // have locked a new one already. So start over.
+            if !is_same_file(&file, &path) {
+                continue;
+            }
+            let mut lock = TargetLibraryLock {
+                path: path.clone(),
+                file,
+            };
+            lock.write_pid().map_err(lock_error)?;
+            return Ok(lock);
+        }
+    }
+
+    fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        write!(self.file, "{}", std::process::id())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for TargetLibraryLock {
+    fn drop(&mut self) {
+        // Removed while it is still locked, so nobody locks the file before it is gone.
+        let _ = fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// Whether the open file is still the one at the path.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Whether the open file is still the one at the path. Windows can't remove files that are open,
+/// so it is enough that it is still there.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TargetLibraryLock, LOCK_FILENAME};
+    use crate::music_library::MusicLibraryError;
+    use std::path::PathBuf;
+
+    #[test]
+    /// A second lock is refused while the first is held, and lock files that are left behind by
+    /// a run that crashed are taken over.
+    fn lock_target_library() {
+        let target_library: PathBuf = format!(
+            "/tmp/syncbops/lock_test_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        )
+        .into();
+        std::fs::create_dir_all(&target_library).unwrap();
+
+        let lock = TargetLibraryLock::acquire(&target_library, false).unwrap();
+        assert!(matches!(
+            TargetLibraryLock::acquire(&target_library, false),
+            Err(MusicLibraryError::TargetLibraryLocked { pid: Some(pid) }) if pid == std::process::id()
+        ));
+        drop(lock);
+        assert!(!target_library.join(LOCK_FILENAME).exists());
+
+        // Nobody has a lock on it, whatever process id is in it.
+        std::fs::write(target_library.join(LOCK_FILENAME), "4294967295").unwrap();
+        let lock = TargetLibraryLock::acquire(&target_library, false);
+        assert!(lock.is_ok());
+    }
+
+    #[test]
+    /// A run that waits gets the lock once the other run is done.
+    fn wait_for_lock() {
+        let target_library: PathBuf = format!(
+            "/tmp/syncbops/lock_test_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        )
+        .into();
+        std::fs::create_dir_all(&target_library).unwrap();
+
+        let lock = TargetLibraryLock::acquire(&target_library, false).unwrap();
+        let waiting = {
+            let target_library = target_library.clone();
+            std::thread::spawn(move || TargetLibraryLock::acquire(&target_library, true))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        drop(lock);
+        let lock = waiting.join().unwrap().unwrap();
+        assert!(target_library.join(LOCK_FILENAME).exists());
+        drop(lock);
+    }
+}
@@ -1,31 +1,124 @@
+/// Like `println!`, but prints nothing with --quiet. For telling what is going on; failures and
+/// warnings are always printed.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod adb;
+mod adopt;
+mod artist_images;
+mod audiobooks;
+mod backup;
+mod bench;
+mod completions;
+mod cue;
+mod doctor;
+mod empty_dirs;
+mod estimate;
 mod ffmpeg_interface;
+mod fill;
 mod hashing;
+mod journal;
+#[cfg(feature = "libav")]
+mod libav;
+mod lock;
+mod metadata_cache;
 mod music_library;
+mod native_metadata;
+mod notify;
+mod path_template;
+mod permissions;
+mod plan;
+mod presets;
+mod priority;
+mod replaygain;
+mod report;
+mod sidecars;
 mod song;
+mod sqlite_records;
 mod sync_song;
+mod tag_filter;
+mod tag_map;
+mod tag_rules;
+mod target_path;
 #[cfg(test)]
 mod test_data;
-use clap::{arg, Parser};
+mod transcode_rules;
+mod verify;
+use adb::{AdbError, AdbTarget};
+use adopt::adopt_shadows;
+use artist_images::{copy_artist_images, find_artist_folders};
+use audiobooks::{Audiobooks, Chapters};
+use backup::{Backup, Disposal, Retention, BACKUP_DIR};
+use bench::{bench, summarize_bench, BenchCli};
+use clap::{arg, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use dialoguer::Confirm;
+use doctor::{doctor, DoctorCli};
+use empty_dirs::remove_empty_dirs;
+use estimate::estimate_sync;
+use fill::{estimated_size, pick_filler, FillOrder};
 use hashing::{
+    find_duplicate_songs, normalize_record_keys, prune_stale_records,
     read_records_of_previous_sync, register_record_to_previous_sync_db,
-    write_records_of_current_sync, SyncRecord,
+    write_records_of_current_sync, RecordsFormat, SyncRecord,
 };
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+use journal::{undo, UndoCli};
+use lock::TargetLibraryLock;
+use metadata_cache::MetadataCache;
 use music_library::{
-    copy_dedicated_cover_art_for_song, find_songs_in_library, ArtStrategy, ArtworkType,
-    MusicFileType, MusicLibraryError, UpdateType,
+    copy_dedicated_cover_art_for_song, find_listed_songs, find_songs_in_library, parse_date,
+    read_song_list, write_song_list, ArtDeduplication, ArtFormat, ArtStrategy, ArtworkType,
+    CodecPolicy, CopiedArt, Downmix, LinkMode, MusicFileType, MusicLibraryError, OversizedArt,
+    SongDeduplication, UpdateType, DEFAULT_ART_NAME_PREFERENCE,
+};
+use notify::{Notification, RunStats};
+use path_template::PathTemplate;
+use permissions::{parse_mode, parse_owner, set_umask, Owner, Permissions};
+use plan::{
+    format_plan, plan_from_results, Action, ApplyCli, PlanFormat, PlannedAction, PlannedSong,
+    Removal, SyncPlan,
+};
+use presets::{Device, Preset};
+use priority::{lower_priority, IoPriority};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use replaygain::scan_loudness;
+use report::{report_rows, target_sizes, write_report, ReportFormat};
+use sidecars::{
+    copy_sidecars, find_lyrics, find_sidecars, remove_stale_sidecars, DEFAULT_SIDECAR_EXTENSIONS,
 };
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use song::Song;
+use sqlite_records::SqliteRecords;
 use std::fmt::Write;
+use std::io::IsTerminal;
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
-    process::exit,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use sync_song::{
+    journal_created, sync_duplicate_song, sync_song, sync_song_as_planned, SyncSettings,
 };
-use sync_song::sync_song;
+use tag_filter::{rated_at_least, TagFilter, UnratedSongs};
+use tag_map::{read_tag_map_file, TagMap};
+use tag_rules::{read_tag_rules_file, TagRules};
+use target_path::{
+    plan_target_paths, CollisionResolution, Flatten, MergeDiscs, NormalizationForm,
+    TargetPathOptions,
+};
+use transcode_rules::{parse_target_filetype, read_rules_file, TranscodeRules};
+use verify::{check_source_songs, summarize_verification, verify_library, VerifyCli};
 
-use crate::ffmpeg_interface::ensure_ffmpeg_capable;
+use crate::ffmpeg_interface::{ensure_ffmpeg_capable, ffmpeg_version, FfmpegError};
 
 /// What all the individual attempts at syncing are collected into.
 type SyncResults<'a> = Vec<(&'a Song, Result<SyncRecord, MusicLibraryError>)>;
@@ -34,17 +127,47 @@ const PREVIOUS_SYNC_DB_FILENAME: &str = ".syncbops";
 
 #[derive(clap::Parser)]
 #[command(version, about, long_about = None)] // Read from cargo.toml
+#[command(
+    after_help = "To check a target library for corrupt or truncated files, run `syncbops verify <TARGET_LIBRARY>`.\n\
+    To take over a target library that was not made by syncbops (or of which the records were lost) without transcoding everything again, run `syncbops adopt` with the same arguments as a regular sync.\n\
+    To see how large and how fast transcoding some of your own songs is with several settings, run `syncbops bench <SOURCE_LIBRARY>`.\n\
+    To carry out a plan saved by a dry run with `--plan <FILE>`, run `syncbops apply <FILE>`.\n\
+    To complete the arguments in your shell, run `syncbops completions <SHELL>`.\n\
+    To check whether ffmpeg and its encoders are installed, run `syncbops doctor [TARGET_LIBRARY]`.\n\n\
+    Exits with 0 if everything went fine, 1 if some songs failed, 2 if nothing could be synchronised (e.g. because of wrong arguments), 3 if aborted when asked for confirmation, and 4 if --check finds that a sync would change something."
+)]
 struct Cli {
+    /// Can be left out if --device is given, to use the one that suits the device.
     #[command(subcommand)]
-    target_filetype: MusicFileType,
+    target_filetype: Option<MusicFileType>,
 
     /// The directory to be scanned for music files to synchronise
     source_library: PathBuf,
 
-    /// The directory that a transcoded copy of the library provided will be put into.
+    /// The directory that a transcoded copy of the library provided will be put into. Can also
+    /// be an Android phone, like `adb://<serial>/sdcard/Music`, to push the songs to with adb.
     target_library: PathBuf,
 
+    /// For a phone as the target library: the directory on this computer that the songs are
+    /// synchronised to before they are pushed, which also holds the records. Defaults to one in
+    /// the local data directory of the user.
+    #[arg(long, value_name = "DIR")]
+    adb_staging: Option<PathBuf>,
+
+    /// Settings for what the target library is used for. Settings that are also given
+    /// separately take precedence over the ones of the preset.
+    #[arg(long, value_name = "PRESET")]
+    preset: Option<Preset>,
+
+    /// Sensible settings for the kind of device the target library is for, including the
+    /// target filetype. Settings that are also given separately take precedence over the ones
+    /// of the device.
+    #[arg(long, value_name = "DEVICE", conflicts_with = "preset")]
+    device: Option<Device>,
+
     /// Force overwriting existing music files. Does not affect external album art files.
+    /// Songs are not synchronised again just because ffmpeg was updated, so this is also how to
+    /// transcode them with the new version.
     #[arg(short, long, default_value_t = false)]
     force: bool,
 
@@ -53,12 +176,98 @@ struct Cli {
     art_strategy: ArtStrategy,
 
     /// Don't actually make any changes to the filesystem, just report on what it would look like after the operation. Makes most sense to run together with verbose option.
+    /// Lists what would be done to every song, and why. To save time, songs that were not
+    /// synchronised by syncbops before are assumed to be unchanged if they are newer than their
+    /// source, instead of comparing their metadata.
     #[arg(short, long, default_value_t = false)]
     dry_run: bool,
 
-    /// Display more info.
+    /// Do a dry run, and exit with code 4 if a sync would change anything. For scripts that
+    /// want to know whether the target library is up to date.
+    #[arg(long, default_value_t = false)]
+    check: bool,
+
+    /// How to list what a dry run would do. JSON is printed as a single line at the end.
+    #[arg(long, value_name = "FORMAT", default_value = "human")]
+    plan_format: PlanFormat,
+
+    /// Also save what a dry run would do to this file, so that exactly that can be done later
+    /// with `syncbops apply <FILE>`.
+    #[arg(long, value_name = "FILE", requires = "dry_run")]
+    plan: Option<PathBuf>,
+
+    /// After the run, write the songs that failed to this file, one per line. Retry them with
+    /// `--only-from-file <FILE>`.
+    #[arg(long, value_name = "FILE")]
+    failures_out: Option<PathBuf>,
+
+    /// Write a report of what was done to every song to this file, with the sizes before and
+    /// after and any errors. To look back at what a scheduled run did.
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// What to write the report as.
+    #[arg(long, value_name = "FORMAT", default_value = "csv")]
+    report_format: ReportFormat,
+
+    /// Also write the report into the target library, as syncbops_report.csv (or .html).
+    #[arg(long, default_value_t = false)]
+    report_in_target: bool,
+
+    /// Show a desktop notification when the run is done, or when it fails.
+    #[arg(long, default_value_t = false)]
+    notify_desktop: bool,
+
+    /// Run this command (with the shell) when the run is done, or when it fails. How it went is
+    /// in the environment variables SYNCBOPS_OUTCOME, SYNCBOPS_MESSAGE, SYNCBOPS_SYNCHRONISED,
+    /// SYNCBOPS_UNCHANGED, SYNCBOPS_FAILED and SYNCBOPS_DURATION_SECS.
+    #[arg(long, value_name = "COMMAND")]
+    notify_command: Option<String>,
+
+    /// POST how the run went as JSON to this URL when it is done, or when it fails. Uses curl.
+    #[arg(long, value_name = "URL")]
+    notify_webhook: Option<String>,
+
+    /// Ping this URL when the run starts (at <URL>/start), and when it is done with a summary
+    /// of how it went (at <URL>/fail if it failed), like healthchecks.io expects. So that a
+    /// scheduled sync that stops working is noticed. Uses curl.
+    #[arg(long, value_name = "URL")]
+    healthcheck_url: Option<String>,
+
+    /// Only synchronise the songs in this file (one per line, relative to the source library),
+    /// without going through the whole library. E.g. to retry the songs written by
+    /// `--failures-out`.
+    #[arg(long, value_name = "FILE")]
+    only_from_file: Option<PathBuf>,
+
+    /// Only synchronise songs that were added to the source library or modified on or after
+    /// this date, like 2024-01-31. Quick for pushing only new purchases, as the metadata of the
+    /// other songs is not even read.
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    since: Option<SystemTime>,
+
+    /// Like --since, but for the songs added or modified in the last this many days.
+    #[arg(long, value_name = "DAYS", conflicts_with = "since")]
+    since_days: Option<u64>,
+
+    /// Display more info. Once lists every changed song, twice also explains per song why it
+    /// is (not) synchronised, and shows the ffmpeg commands.
+    #[arg(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print a single line at the end, and failures. For scheduled runs.
     #[arg(short, long, default_value_t = false)]
-    verbose: bool,
+    quiet: bool,
+
+    /// Colour the summary and the list of changes: new songs green, overwritten ones yellow
+    /// and failures red.
+    #[arg(long, value_name = "WHEN", default_value = "auto")]
+    color: ColorChoice,
+
+    /// In the verbose summary, leave out the albums in which nothing changed, instead of
+    /// listing each of them.
+    #[arg(long, default_value_t = false, requires = "verbose")]
+    collapse_unchanged: bool,
 
     /// Automatically say 'yes' to any prompts that show up.
     /// Use this flag if you use syncbops non-interactively, e.g. in a script.
@@ -69,40 +278,955 @@ struct Cli {
     #[arg(short, long)]
     thread_count: Option<usize>,
 
+    /// Maximum amount of songs to transcode at the same time. Every transcode starts an ffmpeg
+    /// process, which uses multiple threads itself. Lower this if syncing makes the machine too
+    /// busy; reading and hashing the library still uses all threads. Defaults to no limit.
+    #[arg(long)]
+    max_encoders: Option<usize>,
+
+    /// Synchronise one song at a time, in alphabetical order, so that two runs on the same
+    /// libraries print the same things in the same order, make the same plan, and break ties
+    /// (like which of two identical art files is kept) the same way. Slower, as only one song
+    /// is transcoded at a time. Timings still differ.
+    #[arg(long, default_value_t = false, conflicts_with = "thread_count")]
+    deterministic: bool,
+
+    /// If ffmpeg doesn't have the encoder for the target filetype (e.g. libopus or libvorbis),
+    /// use the encoder of ffmpeg itself instead of failing. These don't sound as good at the
+    /// same bitrate.
+    #[arg(long, default_value_t = false)]
+    allow_fallback: bool,
+
+    /// Stop at the first song that fails, printing the full error right away, instead of
+    /// listing all failures at the end. The ffmpeg command and its output are part of the error.
+    #[arg(long, default_value_t = false)]
+    fail_fast: bool,
+
+    /// Stop after this many songs are transcoded or copied. Songs that are up to date don't
+    /// count. Useful to try out settings on a few songs before synchronising everything.
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Lower the CPU priority of the sync and the ffmpeg processes it starts, so that it doesn't
+    /// slow down other programs. Like `nice`, from 1 (a bit lower) to 19 (lowest). On Windows,
+    /// 15 and up is the idle priority class, anything lower is below normal.
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..=19))]
+    nice: Option<i32>,
+
+    /// Lower the disk priority of the sync and the ffmpeg processes it starts. Linux only.
+    #[arg(long, value_name = "CLASS")]
+    ionice: Option<IoPriority>,
+
     /// Disable writing of records of the current synchronisation run to the target library.
     /// future synchronising runs can be performed much faster if these are present, as file
     /// changes can be checked based on hashes.
     /// Disabling them makes updating much slower, but does not contaminate the target dir.
     #[arg(long, default_value_t = false)]
     dont_save_records: bool,
-    // TODO: Maximum resolution for embedded art. Works like a threshold: Files larger than this resolution will be scaled, files lower in resolution will not be touched. 0 will not do any scaling, and embed everything at their actual resolution.
 
-    // #[arg(short, long, value_name = "RESOLUTION", default_value_t = 0)]
-    // embed_art_resolution: u64,
+    /// How to store the records. An SQLite database is faster for large libraries, and keeps
+    /// the records of an interrupted sync. Existing JSON records are moved into it. The binary
+    /// format is a lot quicker to read than JSON on slow machines.
+    #[arg(long, value_name = "FORMAT", default_value = "json")]
+    records_format: RecordsFormat,
+
+    /// After synchronising, remove the folders in the target library that are empty, like the
+    /// album and artist folders left behind after reorganising the source library. With
+    /// --dry-run, only lists them.
+    #[arg(long, default_value_t = false)]
+    remove_empty_dirs: bool,
+
+    /// After synchronising, give the files in the target library this mode, in octal like
+    /// chmod, e.g. 644. Folders get execute on top of it for whoever may read them, so 644
+    /// makes them 755. Only on Unix.
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    chmod: Option<u32>,
+
+    /// After synchronising, give the files and folders in the target library to this user and
+    /// group, like chown: USER, USER:GROUP or :GROUP, by name or id. Usually needs root. Only
+    /// on Unix.
+    #[arg(long, value_name = "OWNER", value_parser = parse_owner)]
+    chown: Option<Owner>,
+
+    /// The umask to create files with, in octal, e.g. 022 or 002. Unlike --chmod, this also
+    /// applies to files while they are being written. Only on Unix.
+    #[arg(long, value_name = "MASK", value_parser = parse_mode)]
+    umask: Option<u32>,
+
+    /// Instead of destroying the files in the target library that are overwritten or removed,
+    /// move them into .syncbops-backup in the target library, in a folder per sync (named after
+    /// when it started, in seconds since 1970). Every change the sync makes is noted there as
+    /// well, so that `syncbops undo TARGET_LIBRARY` can revert the last sync.
+    #[arg(long, default_value_t = false, conflicts_with = "use_trash")]
+    backup: bool,
+
+    /// Keep the backups of this many syncs. Older ones are removed.
+    #[arg(long, value_name = "SYNCS", default_value_t = 5, requires = "backup")]
+    backup_keep: usize,
+
+    /// Remove the oldest backups until they take up at most this much space together, like
+    /// "2G". The backup of the current sync is always kept.
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, requires = "backup")]
+    backup_max_size: Option<u64>,
+
+    /// Instead of destroying the files in the target library that are overwritten or removed,
+    /// move them to the trash of the desktop, from where they can be restored.
+    #[arg(long, default_value_t = false, conflicts_with = "backup")]
+    use_trash: bool,
+
+    /// If another sync to the same target library is still running, wait for it to finish,
+    /// instead of stopping right away.
+    #[arg(long, default_value_t = false)]
+    wait_for_lock: bool,
+
+    /// Don't use the cache of the metadata of the songs in the source library, but read the
+    /// metadata of every song again. The cache is kept in your cache directory (like
+    /// ~/.cache/syncbops), and only songs of which the size or modification time changed are
+    /// read again.
+    #[arg(long, default_value_t = false)]
+    no_metadata_cache: bool,
+
+    /// Maximum resolution for embedded art. Works like a threshold: Art larger than this
+    /// resolution (in either width or height) will be scaled down, art lower in resolution
+    /// will not be touched. 0 will not do any scaling, and embed everything at their actual
+    /// resolution. Changing this re-generates files with embedded art on the next sync.
+    #[arg(short, long, value_name = "RESOLUTION", default_value_t = 0)]
+    embed_art_resolution: u32,
+
+    /// Embed all the pictures that are embedded in the source files (like booklet scans),
+    /// instead of only the front cover.
+    #[arg(long, default_value_t = false)]
+    keep_all_pictures: bool,
+
+    /// Maximum size for embedded art, like "500k" or "2M". Art that is larger (e.g. a 4 MB PNG
+    /// in a 3 MB song) is recompressed or dropped, see --oversized-art.
+    #[arg(long, value_name = "BYTES", value_parser = parse_byte_size)]
+    max_embedded_art_bytes: Option<u64>,
+
+    /// What to do with embedded art that is larger than --max-embedded-art-bytes.
+    #[arg(long, value_name = "ACTION", default_value = "recompress")]
+    oversized_art: OversizedArt,
+
+    /// Measure the loudness (EBU R128) of songs without ReplayGain tags, and write ReplayGain
+    /// tags into their transcoded files, so players can level the volume. The measurements
+    /// are saved in the records, so songs are only measured again if they change. Songs that
+    /// are already synchronised only get the tags when they are transcoded again (see --force).
+    #[arg(long, default_value_t = false)]
+    scan_loudness: bool,
+
+    /// Leave these tags out of the synchronised songs, separated by commas, like
+    /// "comment,encoder,purchase_owner", so the files don't tell who bought or ripped them.
+    /// Songs with any of these tags are remuxed or transcoded instead of copied as they are.
+    #[arg(long, value_name = "TAGS", value_delimiter = ',')]
+    strip_tags: Vec<String>,
+
+    /// File with rules to clean up the tags of the synchronised songs, one per line. The source
+    /// files are not changed. "genre: Hip Hop -> Hip-Hop" replaces text in a tag,
+    /// "feat-to-artist" moves "(feat. Someone)" from the title to the artist, and
+    /// "title-case title" capitalises the words of a tag. Songs of which tags change are
+    /// remuxed or transcoded instead of copied as they are.
+    #[arg(long, value_name = "FILE", value_parser = read_tag_rules_file)]
+    tag_rules: Option<TagRules>,
+
+    /// File with the names to write tags under in transcoded songs, one per line, like
+    /// "mp3: discnumber -> disc". On the left is the target filetype (mp3, opus, ogg or flac),
+    /// or * for all of them. These come on top of built-in mappings for tags like ALBUMARTIST
+    /// and DISCNUMBER, which ffmpeg would otherwise write under a name players don't know.
+    #[arg(long, value_name = "FILE", value_parser = read_tag_map_file)]
+    tag_map: Option<TagMap>,
+
+    /// Resample songs with a higher sample rate than this (in Hz, e.g. 48000) down to it, for
+    /// devices that can't play e.g. 96 kHz files. Songs with a lower sample rate are left as
+    /// they are.
+    #[arg(long, value_name = "HZ")]
+    max_sample_rate: Option<u32>,
+
+    /// Mix songs with more channels (like 5.1 surround) down to stereo or mono. Songs that
+    /// already have this amount of channels or fewer are left as they are.
+    #[arg(long, value_name = "CHANNELS")]
+    downmix: Option<Downmix>,
+
+    /// When transcoding to FLAC, reduce songs with 24 or 32 bits per sample to 16 bits, for
+    /// devices that can't play hi-res files. 16-bit songs are left as they are.
+    #[arg(long, default_value_t = false)]
+    reduce_bit_depth: bool,
+
+    /// Dither when reducing the bit depth (see --reduce-bit-depth). This masks the distortion
+    /// of the lost bits with a bit of noise.
+    #[arg(long, default_value_t = false, requires = "reduce_bit_depth")]
+    dither: bool,
+
+    /// Which songs to transcode. By default, songs with a lower bitrate than the target are
+    /// copied. With transcode-lossless, only lossless songs (FLAC, ALAC, WAV) are transcoded,
+    /// and all lossy songs (MP3, AAC, Ogg, Opus) are copied, so they don't lose quality by
+    /// being encoded again.
+    #[arg(long, value_name = "POLICY", default_value = "bitrate")]
+    codec_policy: CodecPolicy,
+
+    /// Copy songs with a lower bitrate than this (in kbps) instead of transcoding them. By
+    /// default, this is about the bitrate of the target filetype, because transcoding songs
+    /// below it would only lose quality. 0 never copies, and always transcodes.
+    #[arg(long, value_name = "KBPS")]
+    copy_threshold_kbps: Option<u32>,
+
+    /// How to put songs that are copied (not transcoded) in the target library. Hard links and
+    /// reflinks take no extra space, but only work if the target library is on the same
+    /// filesystem; otherwise songs are copied normally.
+    #[arg(long, value_name = "MODE", default_value = "copy")]
+    link_mode: LinkMode,
+
+    /// What to do with songs that are bit-identical to another song in the source library, like
+    /// a single that is also on a compilation. Instead of transcoding them twice, hard link them
+    /// to the other song in the target library, or skip them.
+    #[arg(long, value_name = "ACTION")]
+    dedupe_songs: Option<SongDeduplication>,
+
+    /// Also compare songs on only their audio, leaving out the tags. Songs of which only the
+    /// tags (or embedded art) changed are then retagged, instead of transcoded again. Only works
+    /// for MP3 and FLAC files; other songs are transcoded again like usual.
+    #[arg(long, default_value_t = false)]
+    hash_audio_only: bool,
+
+    /// Also compare the duration of songs that the records say are up to date with that of the
+    /// target files, and transcode them again if they differ. Finds targets that were cut off,
+    /// like by a full disk or an interrupted copy, but has to read every target file.
+    #[arg(long, default_value_t = false)]
+    check_durations: bool,
+
+    /// Give the synchronised songs the modification time of their source, instead of the time
+    /// they were transcoded or copied, for players that sort on "recently added" by it.
+    #[arg(long, default_value_t = false)]
+    preserve_mtime: bool,
+
+    /// Give songs that are copied instead of transcoded the extended attributes (like tags of
+    /// the file manager), permissions and modification time of their source, like rsync -X -p
+    /// -t. Attributes that the target library doesn't support are left out.
+    #[arg(long, default_value_t = false)]
+    preserve_xattrs: bool,
+
+    /// Also synchronise the audio of video files (mkv, mp4, webm, mov), like recordings of live
+    /// sets. The video is left out; a frame of it is used as cover art if there is no other art.
+    #[arg(long, default_value_t = false)]
+    include_videos: bool,
+
+    /// Skip songs that are shorter than this (in seconds), like sound effects or the silent gap
+    /// files before hidden tracks.
+    #[arg(long, value_name = "SECONDS")]
+    min_duration: Option<u64>,
+
+    /// Only synchronise songs of which the tag matches, like "genre=Metal". Use `~` instead of
+    /// `=` to match a part of the tag, like "album~live". Can be given multiple times: songs
+    /// have to match one of the filters on each tag, e.g. "genre=Metal" or "genre=Rock", and
+    /// "year=1999". Case is ignored.
+    #[arg(long, value_name = "TAG=VALUE")]
+    filter: Vec<TagFilter>,
+
+    /// Don't synchronise songs of which the tag matches, written like for --filter. Can be
+    /// given multiple times.
+    #[arg(long, value_name = "TAG=VALUE")]
+    exclude: Vec<TagFilter>,
+
+    /// Don't synchronise songs of this genre, like "Audiobook". Short for
+    /// `--exclude genre=<GENRE>`. Can be given multiple times.
+    #[arg(long, value_name = "GENRE")]
+    exclude_genre: Vec<String>,
+
+    /// Only synchronise songs that are rated at least this many stars (0 to 5, like 3.5).
+    /// Ratings are read from FMPS_Rating tags, ID3v2 popularimeter (POPM) frames, and RATING
+    /// tags.
+    #[arg(long, value_name = "STARS", value_parser = parse_stars)]
+    min_rating: Option<f64>,
+
+    /// Whether songs that are not rated are synchronised when selecting by --min-rating.
+    #[arg(
+        long,
+        value_name = "ACTION",
+        default_value = "exclude",
+        requires = "min_rating"
+    )]
+    unrated: UnratedSongs,
+
+    /// Fill the target library up to this size (like 32G) with a random selection of the songs
+    /// that were not selected by --filter, --exclude, --exclude-genre and --min-rating, after
+    /// the ones that were. Without any of those, the whole library is picked from. The same
+    /// songs are picked every run. Songs that were picked before but are not anymore are removed
+    /// from the target library.
+    #[arg(long, value_name = "BYTES", value_parser = parse_byte_size)]
+    fill: Option<u64>,
+
+    /// Pick different songs to fill the target library with than the default.
+    #[arg(long, value_name = "SEED", default_value_t = 0, requires = "fill")]
+    fill_seed: u64,
+
+    /// Every song gets a new chance to be picked for --fill once every this many days, each on
+    /// a different day, so that some of the filler is swapped out every day.
+    #[arg(long, value_name = "DAYS", requires = "fill")]
+    fill_rotate_days: Option<u64>,
+
+    /// Split albums that are one big file with a .cue sheet next to it into a song per track,
+    /// each with the tags of its track and named like "03 Title". Only sheets for a single file
+    /// with multiple tracks are used.
+    #[arg(long, default_value_t = false)]
+    split_cue: bool,
+
+    /// Synchronise audiobooks (.m4b files, and songs in an --audiobook-folder) like audiobooks
+    /// instead of like songs: as --audiobook-target, in mono, and without art.
+    #[arg(long, default_value_t = false)]
+    audiobooks: bool,
+
+    /// Folder in the source library (relative to it) with audiobooks. Can be given multiple
+    /// times.
+    #[arg(long, value_name = "DIR", requires = "audiobooks")]
+    audiobook_folder: Vec<PathBuf>,
+
+    /// The filetype for audiobooks, written like on the command line.
+    #[arg(
+        long,
+        value_name = "FILETYPE",
+        default_value = "opus --bitrate 32",
+        value_parser = parse_target_filetype,
+        requires = "audiobooks"
+    )]
+    audiobook_target: MusicFileType,
+
+    /// What to do with the chapters of audiobooks.
+    #[arg(long, value_enum, default_value_t = Chapters::Keep, requires = "audiobooks")]
+    chapters: Chapters,
+
+    /// Decode every song in the source library before synchronising, and skip the ones that are
+    /// corrupt or truncated. They are listed in the summary. Slow, because every song is
+    /// decoded completely.
+    #[arg(long, default_value_t = false)]
+    check_source: bool,
+
+    /// File with rules to pick the target filetype per song, one per line, like
+    /// "flac -> opus --bitrate 128" or "mp3 <256k -> copy". On the left is the extension of
+    /// the song (or * for any), optionally with a condition on its bitrate (">=256k" or
+    /// "<256k"). On the right is the filetype, written like on the command line. The first
+    /// matching rule is used; songs that don't match any use the filetype given on the command
+    /// line.
+    #[arg(long, value_name = "FILE", value_parser = read_rules_file)]
+    rules: Option<TranscodeRules>,
+
+    /// Maximum resolution for external album art files (cover.jpg etc.) that are copied to the
+    /// target library. Works like --embed-art-resolution: larger art is scaled down, smaller
+    /// art is not touched. 0 copies the art as it is.
+    #[arg(long, value_name = "RESOLUTION", default_value_t = 0)]
+    art_file_resolution: u32,
+
+    /// Re-encode copied JPEG (or WebP) album art files with this quality, from 1 to 100 (higher
+    /// is better quality, but larger). If not given, the quality of the source art is kept, or
+    /// 90 is used if the art has to be converted or scaled anyway.
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+    art_file_quality: Option<u8>,
+
+    /// Convert all copied album art files to this format, regardless of the format in the
+    /// source library. Use this if your device can't show e.g. PNG art, or to save space.
+    #[arg(long, value_name = "FORMAT")]
+    art_format: Option<ArtFormat>,
+
+    /// Give all copied album art files this name (without extension), regardless of what they
+    /// are called in the source library. For example, with "cover", both "folder.png" and
+    /// "Front.jpeg" become "cover.png" and "cover.jpg". For players that only recognise one
+    /// specific name.
+    #[arg(long, value_name = "NAME")]
+    art_filename: Option<String>,
+
+    /// If a folder has multiple album art files, the one with the highest resolution is used.
+    /// If they have the same resolution and file size, this decides which one is used: a comma
+    /// separated list of names (without extension), most preferred first.
+    #[arg(long, value_name = "NAMES", value_delimiter = ',', default_value = DEFAULT_ART_NAME_PREFERENCE)]
+    art_name_preference: Vec<String>,
+
+    /// Save space on album art files that are identical to art in a folder next to it, like
+    /// the same cover.jpg in the CD1 and CD2 folders of an album.
+    #[arg(long, value_name = "MODE")]
+    dedupe_art: Option<ArtDeduplication>,
+
+    /// Also copy artist images (artist.jpg, fanart.jpg or folder.jpg in an artist folder, like
+    /// Artist/folder.jpg next to Artist/Album/) into the artist folders in the target library,
+    /// and report artists without one. Some players and media servers show these.
+    #[arg(long, default_value_t = false)]
+    artist_images: bool,
+
+    /// Also copy .nfo files, with extra information for media servers (like album.nfo next to
+    /// the songs, and artist.nfo in an artist folder), into the matching folders in the target
+    /// library.
+    #[arg(long, default_value_t = false)]
+    copy_nfo: bool,
+
+    /// Also copy the files next to the songs that belong with them, like .cue sheets, rip logs
+    /// and booklets, into the folder in the target library that the songs of their folder go
+    /// to. They are kept in the records, so they are copied again when they change, and removed
+    /// from the target library when they are gone from the source library.
+    #[arg(long, default_value_t = false)]
+    copy_sidecars: bool,
+
+    /// Extensions of the files that --copy-sidecars copies, separated by commas.
+    #[arg(
+        long,
+        value_name = "EXTENSIONS",
+        value_delimiter = ',',
+        default_value = DEFAULT_SIDECAR_EXTENSIONS,
+        requires = "copy_sidecars"
+    )]
+    sidecar_extensions: Vec<String>,
+
+    /// Also copy the .lrc files with the (synced) lyrics of songs, which have the same name as
+    /// the song. They are renamed to match the synchronised song, and removed from the target
+    /// library when their song is gone.
+    #[arg(long, default_value_t = false)]
+    copy_lyrics: bool,
+
+    /// Maximum length of a single file or directory name in the target library, in bytes.
+    /// Longer names are shortened, keeping the extension and adding a short hash so they stay
+    /// unique. 0 disables the limit.
+    #[arg(long, value_name = "BYTES", default_value_t = 255)]
+    max_filename_bytes: usize,
+
+    /// Maximum length of a full path in the target library (including the path to the target
+    /// library itself), in characters. Some devices and Windows can't handle paths longer
+    /// than 260 characters. If not given, paths are not limited.
+    #[arg(long, value_name = "CHARS")]
+    max_path_length: Option<usize>,
+
+    /// Normalise all unicode names in the target library (and in the records) to this form.
+    /// Use this when syncing between filesystems that store accented characters differently
+    /// (e.g. from Linux to a macOS-formatted drive), so files are not seen as new every time.
+    #[arg(long, value_name = "FORM")]
+    unicode_normalization: Option<NormalizationForm>,
+
+    /// Replace non-ASCII characters in file and folder names in the target library by an ASCII
+    /// approximation, e.g. "Björk" becomes "Bjork". For devices that can't display them.
+    /// The tags inside the music files are not changed.
+    #[arg(long, default_value_t = false)]
+    ascii_filenames: bool,
+
+    /// Replace the characters that FAT32 and exFAT don't allow in file and folder names (like
+    /// `?`, `:` and `"`) by `_`, and drop the dots and spaces at the end of names that they
+    /// would drop themselves. For devices and SD cards with these filesystems, and Android.
+    #[arg(long, default_value_t = false)]
+    fat_safe_filenames: bool,
+
+    /// What to do when different songs would end up at the same place in the target library,
+    /// e.g. "Song.flac" and "Song.mp3" both becoming "Song.opus". All such collisions are
+    /// reported before anything is synchronised.
+    #[arg(long, value_name = "RESOLUTION", default_value = "suffix")]
+    on_collision: CollisionResolution,
+
+    /// The target library is on a filesystem that does not tell upper and lower case apart,
+    /// like FAT32, exFAT, NTFS or (by default) APFS. Names that only differ in case, like
+    /// "Remix.mp3" and "remix.mp3", are then treated as colliding.
+    #[arg(long, default_value_t = false)]
+    case_insensitive_target: bool,
+
+    /// Instead of mirroring the folder structure of the source library, put songs in the target
+    /// library based on their tags. For example:
+    /// "{albumartist}/{album}/{disc}-{track:02} {title}.{ext}".
+    /// Available fields: albumartist, artist, album, title, track, disc, year, genre, ext.
+    /// Numbers can be zero-padded like {track:02}.
+    #[arg(long, value_name = "TEMPLATE")]
+    layout: Option<PathTemplate>,
+
+    /// Separate layout for songs on compilation albums (flagged as compilation, or with
+    /// "Various Artists" as album artist), so they don't get scattered over artist folders.
+    /// For example: "Compilations/{album}/{track:02} {artist} - {title}". Takes the same
+    /// fields as --layout. Songs that are not on a compilation keep using --layout (or mirror
+    /// the source library if there is none).
+    #[arg(long, value_name = "TEMPLATE")]
+    compilation_layout: Option<PathTemplate>,
+
+    /// Don't create nested folders in the target library, for devices that can only read files
+    /// one level deep. Folder names are joined into the file names instead.
+    /// `--flatten` puts everything directly in the target library, `--flatten=artist` keeps one
+    /// folder per artist.
+    /// External album art files are not copied when flattening, so use an art strategy that
+    /// embeds the art.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "all")]
+    flatten: Option<Flatten>,
+
+    /// Put the songs of albums that are split into disc folders, like "CD1" and "CD2" or
+    /// "Disc 1", in the album folder itself, for devices that can't browse nested folders.
+    /// `--merge-discs` puts the disc number in front of the file names ("2-03 Song.mp3"),
+    /// `--merge-discs=renumber` numbers the songs of all discs in a row ("15 Song.mp3").
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "prefix")]
+    merge_discs: Option<MergeDiscs>,
 }
 
-fn main() -> Result<(), MusicLibraryError> {
-    let cli = Cli::parse();
+/// Parses a rating in stars, from 0 to 5.
+fn parse_stars(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(stars) if (0. ..=5.).contains(&stars) => Ok(stars),
+        _ => Err(format!("'{s}' is not a rating from 0 to 5 stars")),
+    }
+}
+
+/// Parses sizes like "500000", "500k" or "2M" (powers of 1000).
+fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let lowercase = s.trim().to_lowercase();
+    let without_unit = lowercase.strip_suffix('b').unwrap_or(&lowercase);
+    let (number, multiplier) = match without_unit.chars().last() {
+        Some('k') => (&without_unit[..without_unit.len() - 1], 1_000),
+        Some('m') => (&without_unit[..without_unit.len() - 1], 1_000_000),
+        Some('g') => (&without_unit[..without_unit.len() - 1], 1_000_000_000),
+        _ => (without_unit, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("'{s}' is not a size. Use something like 500k or 2M."))
+}
+
+/// With --quiet, only the summary line and failures are printed.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// How much is printed.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    /// Lists every changed song.
+    ChangeLog,
+    /// Also why songs are (not) synchronised, and the ffmpeg commands.
+    Debug,
+}
+
+/// Whether to colour the output.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+enum ColorChoice {
+    /// Only when printing to a terminal, and NO_COLOR is not set.
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn apply(self) {
+        let enabled = match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty())
+                    && console::colors_enabled()
+            }
+        };
+        console::set_colors_enabled(enabled);
+    }
+}
+
+/// What the exit code means, so that scripts and scheduled jobs can tell how a run went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    /// Everything went fine.
+    Clean = 0,
+    /// The run completed, but some songs failed.
+    FileErrors = 1,
+    /// Nothing was synchronised, because of e.g. wrong arguments or a missing target library.
+    /// Also what clap exits with on wrong arguments.
+    Fatal = 2,
+    /// The user said no to a confirmation.
+    Aborted = 3,
+    /// With --check: a sync would change something.
+    ChangesPending = 4,
+}
+
+fn main() -> ExitCode {
+    let started = Instant::now();
+    let mut notification = Notification::default();
+    let result = run(&mut notification);
+    notification.send(&result, started.elapsed());
+    let outcome = result.unwrap_or_else(|e| {
+        // Like returning the error from main would.
+        eprintln!("Error: {e:?}");
+        Outcome::Fatal
+    });
+    ExitCode::from(outcome as u8)
+}
+
+/// Parses the arguments of a sync, with the settings of the preset filled in. Exits on invalid
+/// arguments, like `Cli::parse_from()`.
+fn parse_sync_cli<T: Into<std::ffi::OsString> + Clone>(args: impl IntoIterator<Item = T>) -> Cli {
+    let matches = Cli::command().get_matches_from(args);
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(preset) = cli.preset {
+        preset.apply(&mut cli, &matches);
+    }
+    if let Some(device) = cli.device {
+        device.apply(&mut cli, &matches);
+    }
+    if cli.target_filetype.is_none() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a target filetype is required, unless --device is given",
+            )
+            .exit();
+    }
+    cli
+}
+
+/// `notification` is filled in with how to notify the user, and what to tell them.
+fn run(notification: &mut Notification) -> Result<Outcome, MusicLibraryError> {
+    // The subcommand of the regular invocation is the target filetype, so `verify` and `bench`
+    // can't be one of them.
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "verify")
+    {
+        let cli = VerifyCli::parse_from(std::env::args_os().skip(1));
+        let failures = verify_library(&cli)?;
+        summarize_verification(&failures, cli.verbose);
+        if !failures.is_empty() {
+            return Ok(Outcome::FileErrors);
+        }
+        return Ok(Outcome::Clean);
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "completions")
+    {
+        completions::print_completions(std::env::args_os().skip(1));
+        return Ok(Outcome::Clean);
+    }
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "undo") {
+        undo(&UndoCli::parse_from(std::env::args_os().skip(1)))?;
+        return Ok(Outcome::Clean);
+    }
+    if std::env::args_os()
+        .nth(1)
+        .is_some_and(|arg| arg == "doctor")
+    {
+        let cli = DoctorCli::parse_from(std::env::args_os().skip(1));
+        return Ok(if doctor(&cli) {
+            Outcome::Clean
+        } else {
+            Outcome::FileErrors
+        });
+    }
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "bench") {
+        let cli = BenchCli::parse_from(std::env::args_os().skip(1));
+        summarize_bench(&bench(&cli)?);
+        return Ok(Outcome::Clean);
+    }
+
+    // Takes the same arguments as a regular sync, so that the target paths are planned the same.
+    let adopt = std::env::args_os().nth(1).is_some_and(|arg| arg == "adopt");
+    // A plan is carried out with the arguments of the dry run that made it.
+    let applying = if std::env::args_os().nth(1).is_some_and(|arg| arg == "apply") {
+        let apply_cli = ApplyCli::parse_from(std::env::args_os().skip(1));
+        let plan = SyncPlan::read(&apply_cli.plan)?;
+        let cli = plan.cli().map_err(|e| MusicLibraryError::Plan {
+            path: apply_cli.plan.clone(),
+            msg: format!("Could not go to the directory the plan was made in: {e}"),
+        })?;
+        Some((plan, cli))
+    } else {
+        None
+    };
+    let (applying, applied_cli) = applying.unzip();
+    let mut cli = if let Some(cli) = applied_cli {
+        cli
+    } else if adopt {
+        parse_sync_cli(
+            std::env::args_os()
+                .enumerate()
+                .filter_map(|(i, arg)| (i != 1).then_some(arg)),
+        )
+    } else {
+        parse_sync_cli(std::env::args_os())
+    };
+    notification.desktop = cli.notify_desktop;
+    notification.command = cli.notify_command.clone();
+    notification.webhook = cli.notify_webhook.clone();
+    notification.healthcheck = cli.healthcheck_url.clone();
+    notification.ping_start();
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Normal,
+        (false, 1) => Verbosity::ChangeLog,
+        (false, _) => Verbosity::Debug,
+    };
+    QUIET.store(verbosity == Verbosity::Quiet, Ordering::Relaxed);
+    cli.color.apply();
+    if verbosity == Verbosity::Debug {
+        ffmpeg_interface::PRINT_COMMANDS.store(true, Ordering::Relaxed);
+    }
+    if applying.is_some() {
+        // These were already taken into account when making the plan.
+        cli.dry_run = false;
+        cli.check = false;
+        cli.plan = None;
+        cli.min_duration = None;
+        cli.filter.clear();
+        cli.exclude.clear();
+        cli.exclude_genre.clear();
+        cli.min_rating = None;
+        cli.since = None;
+        cli.since_days = None;
+        cli.fill = None;
+        cli.split_cue = false;
+        cli.chapters = Chapters::Keep;
+        cli.check_source = false;
+    }
+    if let Some(umask) = cli.umask {
+        set_umask(umask).map_err(MusicLibraryError::Umask)?;
+    }
     let source_library = cli.source_library;
-    let target_library = cli.target_library;
+    let adb_target = AdbTarget::parse(&cli.target_library).transpose()?;
+    let target_library = match &adb_target {
+        Some(adb_target) => {
+            let staging = cli
+                .adb_staging
+                .clone()
+                .or_else(|| adb_target.default_staging())
+                .ok_or(AdbError::NoStagingDirectory)?;
+            std::fs::create_dir_all(&staging).map_err(|e| AdbError::StagingDirectory {
+                path: staging.clone(),
+                source: e,
+            })?;
+            staging
+        }
+        None => cli.target_library,
+    };
 
+    if cli.check {
+        cli.dry_run = true;
+    }
     if cli.dry_run {
-        println!("Performing a dry run, so no actual changes will be made to the filesystem.")
+        say!("Performing a dry run, so no actual changes will be made to the filesystem.")
     }
 
-    if let Some(x) = cli.thread_count {
+    lower_priority(cli.nice, cli.ionice);
+    // With a single thread, rayon goes through the songs in order.
+    if let Some(x) = cli.thread_count.or(cli.deterministic.then_some(1)) {
         rayon::ThreadPoolBuilder::new()
             .num_threads(x)
             .build_global()
             .unwrap_or_else(|_| panic!("Cannot set amount of threads to {}. Exiting.", x));
     }
+    if let Some(max) = cli.max_encoders {
+        ffmpeg_interface::ENCODER_LIMIT.set_max(max);
+    }
+    ffmpeg_interface::ALLOW_FALLBACK.store(cli.allow_fallback, Ordering::Relaxed);
+    if let Some(limit) = cli.limit {
+        sync_song::CHANGE_LIMIT.set_max(limit);
+    }
+
+    let mut songs = if let Some(plan) = &applying {
+        say!("Applying a plan of {} songs.", plan.songs.len());
+        plan.songs(&source_library)
+    } else {
+        say!("Discovering files in {}", source_library.display());
+        let metadata_cache = if cli.no_metadata_cache {
+            None
+        } else {
+            MetadataCache::open(&source_library)
+        };
+        let since = cli.since.or_else(|| {
+            let days = Duration::from_secs(cli.since_days? * 24 * 60 * 60);
+            SystemTime::now().checked_sub(days)
+        });
+        if let Some(since) = since {
+            say!(
+                "Only looking at songs added or modified in the last {}.",
+                HumanDuration(SystemTime::now().duration_since(since).unwrap_or_default())
+            );
+        }
+        let songs = match &cli.only_from_file {
+            Some(list) => find_listed_songs(
+                &source_library,
+                &read_song_list(list, &source_library)?,
+                &cli.art_name_preference,
+                cli.include_videos,
+                metadata_cache.as_ref(),
+                since,
+            ),
+            None => find_songs_in_library(
+                &source_library,
+                &cli.art_name_preference,
+                cli.include_videos,
+                metadata_cache.as_ref(),
+                since,
+            )?,
+        };
+        if let Some(metadata_cache) = metadata_cache {
+            metadata_cache.save(cli.only_from_file.is_none() && since.is_none());
+        }
+        songs
+    };
+    let total_duration: Duration = songs.iter().filter_map(|song| song.metadata.duration).sum();
+    say!(
+        "Discovered {} songs, with {} of audio.",
+        songs.len(),
+        HumanDuration(total_duration)
+    );
+    let untitled = songs
+        .iter()
+        .filter(|song| song.metadata.title.is_none())
+        .collect::<Vec<_>>();
+    if !untitled.is_empty() {
+        eprintln!(
+            "{}",
+            console::style(format!(
+                "{} songs don't have a title tag, so they are named after their file instead.",
+                untitled.len()
+            ))
+            .yellow()
+        );
+        if verbosity == Verbosity::Debug {
+            for song in untitled {
+                eprintln!("  {}", song.library_relative_path.display());
+            }
+        }
+    }
+    if cli.split_cue {
+        let (split, n_split) = cue::split_cue_sheets(songs);
+        songs = split;
+        if n_split > 0 {
+            say!(
+                "Split {n_split} albums with a .cue sheet into their tracks, making {} songs.",
+                songs.len()
+            );
+        }
+    }
+    let audiobooks = cli.audiobooks.then(|| Audiobooks {
+        folders: cli.audiobook_folder.clone(),
+        filetype: cli.audiobook_target.clone(),
+        chapters: cli.chapters,
+    });
+    if let Some(audiobooks) = audiobooks
+        .as_ref()
+        .filter(|a| a.chapters == Chapters::Split)
+    {
+        let (split, n_split) = audiobooks.split_chapters(songs);
+        songs = split;
+        if n_split > 0 {
+            say!("Split {n_split} audiobooks into their chapters.");
+        }
+    }
+    // Also the ones that are skipped later on, as their records are still useful.
+    let discovered = songs
+        .iter()
+        .map(|song| match cli.unicode_normalization {
+            Some(form) => form.normalize_path(&song.library_relative_path),
+            None => song.library_relative_path.clone(),
+        })
+        .collect::<HashSet<_>>();
+
+    // Sound effects and gap files are not worth synchronising. Songs of which the duration is
+    // unknown are kept.
+    let too_short = match cli.min_duration {
+        Some(min_duration) => {
+            let min_duration = Duration::from_secs(min_duration);
+            let (kept, too_short) = songs
+                .into_iter()
+                .partition(|song| song.metadata.duration.is_none_or(|d| d >= min_duration));
+            songs = kept;
+            too_short
+        }
+        None => Vec::new(),
+    };
+    if !too_short.is_empty() {
+        say!(
+            "Skipping {} songs that are shorter than {} seconds.",
+            too_short.len(),
+            cli.min_duration.unwrap_or_default()
+        );
+    }
 
-    println!("Discovering files in {}", source_library.display());
-    let songs = find_songs_in_library(&source_library)?;
-    println!("Discovered {} songs.", songs.len());
+    // The songs that are left out are not listed in the summary, as they are left out on purpose.
+    let exclusions = cli
+        .exclude_genre
+        .iter()
+        .map(|genre| {
+            format!("genre={genre}")
+                .parse()
+                .expect("genre filter should always be valid")
+        })
+        .chain(cli.exclude.iter().cloned())
+        .collect::<Vec<TagFilter>>();
+    // Songs that are not selected can still be picked to fill up the target library with.
+    let mut not_selected = Vec::new();
+    if !cli.filter.is_empty() || !exclusions.is_empty() {
+        let before = songs.len();
+        let (selected, rest): (Vec<_>, Vec<_>) = songs
+            .into_iter()
+            .partition(|song| tag_filter::selected(&song.metadata, &cli.filter, &exclusions));
+        songs = selected;
+        not_selected.extend(rest);
+        say!(
+            "Selected {} of {} songs by their tags.",
+            songs.len(),
+            before
+        );
+    }
+    if let Some(min_rating) = cli.min_rating {
+        let before = songs.len();
+        let (selected, rest): (Vec<_>, Vec<_>) = songs
+            .into_iter()
+            .partition(|song| rated_at_least(&song.metadata, min_rating, cli.unrated));
+        songs = selected;
+        not_selected.extend(rest);
+        say!(
+            "Selected {} of {} songs that are rated at least {min_rating} stars.",
+            songs.len(),
+            before
+        );
+    }
+    let selecting = !cli.filter.is_empty() || !exclusions.is_empty() || cli.min_rating.is_some();
+    if cli.fill.is_none() {
+        not_selected.clear();
+    } else if !selecting {
+        not_selected = std::mem::take(&mut songs);
+    }
+
+    let corrupt = if cli.check_source {
+        say!("Checking whether all songs can be decoded...");
+        let (fine, corrupt) = check_source_songs(songs);
+        songs = fine;
+        if !corrupt.is_empty() {
+            say!("Skipping {} songs that can't be decoded.", corrupt.len());
+        }
+        corrupt
+    } else {
+        Vec::new()
+    };
+
+    // Records are keyed on the library relative path, so those need to be normalised too.
+    // Otherwise the same song could look like a new one, depending on the filesystem it was
+    // read from.
+    if let Some(form) = cli.unicode_normalization {
+        for song in songs.iter_mut().chain(not_selected.iter_mut()) {
+            song.library_relative_path = form.normalize_path(&song.library_relative_path);
+        }
+    }
+
+    // Check capabilities of ffmpeg once, instead of failing the same way for every song.
+    let filetypes = cli
+        .target_filetype
+        .iter()
+        .chain(cli.rules.iter().flat_map(|rules| rules.target_filetypes()))
+        .chain(audiobooks.iter().map(|audiobooks| &audiobooks.filetype));
+    let mut fallbacks = BTreeSet::new();
+    for filetype in filetypes {
+        if let Some(encoder) = ensure_ffmpeg_capable(filetype)? {
+            if let Some(preferred) = encoder.fallback_for {
+                fallbacks.insert((preferred, encoder.name));
+            }
+        }
+    }
+    for (preferred, fallback) in fallbacks {
+        eprintln!(
+            "{}",
+            console::style(format!(
+                "ffmpeg does not have the {preferred} encoder, using {fallback} instead."
+            ))
+            .yellow()
+        );
+    }
 
-    // Check capabilities of ffmpeg
-    ensure_ffmpeg_capable(&cli.target_filetype)?;
+    let ffmpeg_version = ffmpeg_version();
 
     // It would really suck to accidentally overwrite your main library with your transcoded
     // stuff by mixing up the source dir and target dir. So, here are some guardrails to make
@@ -127,10 +1251,10 @@ fn main() -> Result<(), MusicLibraryError> {
                 .unwrap();
 
             if confirmation {
-                println!("Continuing anyway!");
+                say!("Continuing anyway!");
             } else {
-                println!("Aborting. Saved you from overwriting your source music library!");
-                return Ok(());
+                say!("Aborting. Saved you from overwriting your source music library!");
+                return Ok(Outcome::Aborted);
             }
         }
 
@@ -158,10 +1282,10 @@ fn main() -> Result<(), MusicLibraryError> {
                     .unwrap();
 
                 if confirmation {
-                    println!("Continuing anyway!");
+                    say!("Continuing anyway!");
                 } else {
-                    println!("Aborting. Saved your music library!");
-                    exit(0);
+                    say!("Aborting. Saved your music library!");
+                    return Ok(Outcome::Aborted);
                 }
             }
         }
@@ -183,9 +1307,9 @@ fn main() -> Result<(), MusicLibraryError> {
         //         .unwrap();
         //
         //     if confirmation {
-        //         println!("Continuing anyway!");
+        //         say!("Continuing anyway!");
         //     } else {
-        //         println!("Aborting. Saved your music library!");
+        //         say!("Aborting. Saved your music library!");
         //         exit(0);
         //     }
         // }
@@ -194,12 +1318,12 @@ fn main() -> Result<(), MusicLibraryError> {
     }
 
     // Report if there are songs without album art.
-    println!("Checking for songs without album art...");
+    say!("Checking for songs without album art...");
     let songs_without_album_art = songs_without_album_art(&songs);
     if !songs_without_album_art.is_empty() {
-        println!("Warning! There are songs without any album art (either embedded or found in Cover.jpg, folder.png, etc:");
+        say!("Warning! There are songs without any album art (either embedded or found in Cover.jpg, folder.png, etc:");
         for x in songs_without_album_art {
-            println!("\t- {}", x)
+            say!("\t- {}", x)
         }
     }
 
@@ -211,46 +1335,677 @@ fn main() -> Result<(), MusicLibraryError> {
         });
     }
 
-    let art_strategy = cli.art_strategy;
+    // Two runs writing to the same target library at the same time would mess up its files and
+    // records. Released at the end of main.
+    let _lock = if !cli.dry_run {
+        Some(TargetLibraryLock::acquire(
+            &target_library,
+            cli.wait_for_lock,
+        )?)
+    } else {
+        None
+    };
+
+    let settings = SyncSettings {
+        target_filetype: cli
+            .target_filetype
+            .clone()
+            .expect("parse_sync_cli() makes sure there is a target filetype"),
+        transcode_rules: cli.rules.clone().unwrap_or_default(),
+        art_strategy: cli.art_strategy,
+        force: cli.force,
+        dry_run: cli.dry_run,
+        verbose: verbosity >= Verbosity::Debug,
+        target_paths: TargetPathOptions {
+            layout: cli.layout.clone(),
+            compilation_layout: cli.compilation_layout.clone(),
+            flatten: cli.flatten,
+            merge_discs: cli.merge_discs,
+            max_component_bytes: (cli.max_filename_bytes > 0).then_some(cli.max_filename_bytes),
+            max_path_length: cli.max_path_length,
+            unicode_normalization: cli.unicode_normalization,
+            transliterate_to_ascii: cli.ascii_filenames,
+            fat_safe: cli.fat_safe_filenames,
+            on_collision: cli.on_collision,
+            case_insensitive: cli.case_insensitive_target,
+        },
+        embed_art_resolution: cli.embed_art_resolution,
+        art_file_resolution: cli.art_file_resolution,
+        art_file_quality: cli.art_file_quality,
+        art_file_format: cli.art_format,
+        art_file_name: cli.art_filename.clone(),
+        art_deduplication: cli.dedupe_art,
+        keep_all_pictures: cli.keep_all_pictures,
+        max_embedded_art_bytes: cli.max_embedded_art_bytes,
+        oversized_art: cli.oversized_art,
+        max_sample_rate: cli.max_sample_rate,
+        downmix: cli.downmix,
+        reduce_bit_depth: cli.reduce_bit_depth,
+        dither: cli.dither,
+        codec_policy: cli.codec_policy,
+        copy_threshold_kbps: cli.copy_threshold_kbps,
+        link_mode: cli.link_mode,
+        song_deduplication: cli.dedupe_songs,
+        hash_audio_only: cli.hash_audio_only,
+        ffmpeg_version,
+        audiobooks,
+        strip_tags: cli
+            .strip_tags
+            .iter()
+            .map(|tag| tag.trim().to_lowercase())
+            .collect(),
+        tag_rules: cli.tag_rules.clone().unwrap_or_default(),
+        tag_map: cli.tag_map.clone().unwrap_or_default(),
+        check_durations: cli.check_durations,
+        preserve_mtime: cli.preserve_mtime,
+        preserve_xattrs: cli.preserve_xattrs,
+        disposal: if cli.backup {
+            Disposal::Backup(Backup::new(
+                &target_library,
+                Retention {
+                    keep: cli.backup_keep,
+                    max_bytes: cli.backup_max_size,
+                },
+            ))
+        } else if cli.use_trash {
+            Disposal::Trash
+        } else {
+            Disposal::Delete
+        },
+    };
+
+    // The records are changed in place, so undoing the sync needs a copy of them.
+    if let Some(backup) = settings.disposal.backup().filter(|_| !cli.dry_run) {
+        for format in RecordsFormat::value_variants() {
+            let records = target_library.join(format.filename());
+            backup
+                .snapshot(&records)
+                .map_err(|source| MusicLibraryError::Journal {
+                    path: records,
+                    source,
+                })?;
+        }
+    }
+
+    // Fill up the space that the selected songs leave with a random pick of the other ones.
+    let left_out_of_fill = match cli.fill {
+        Some(capacity) => {
+            let selected_size = songs
+                .iter()
+                .map(|song| estimated_size(song, &settings))
+                .sum::<u64>();
+            if selected_size > capacity {
+                say!(
+                    "Warning! The selected songs alone take about {} MB, more than the {} MB to fill.",
+                    selected_size / 1_000_000,
+                    capacity / 1_000_000
+                );
+            }
+            let (filler, left_out) = pick_filler(
+                std::mem::take(&mut not_selected),
+                capacity.saturating_sub(selected_size),
+                FillOrder::new(cli.fill_seed, cli.fill_rotate_days),
+                |song| estimated_size(song, &settings),
+            );
+            say!(
+                "Filling up the target library with {} of the {} other songs.",
+                filler.len(),
+                filler.len() + left_out.len()
+            );
+            songs.extend(filler);
+            left_out
+        }
+        None => Vec::new(),
+    };
+
+    // Decide where everything goes up front, so that songs that would end up at the same place
+    // don't overwrite each other.
+    let (target_plan, collisions) = match &applying {
+        Some(plan) => (plan.target_plan(&target_library), Vec::new()),
+        None => plan_target_paths(
+            &songs,
+            &target_library,
+            |song| settings.shadow_filetype_for(song),
+            &settings.target_paths,
+        ),
+    };
+    if !collisions.is_empty() {
+        say!("Warning! Some songs would end up at the same place in the target library:");
+        for collision in &collisions {
+            say!("\t- {}", collision.shadow.display());
+            for song in &collision.songs {
+                say!("\t\t{}", song.display());
+            }
+        }
+        match cli.on_collision {
+            CollisionResolution::Suffix => {
+                say!("All but the first song of each get a number added to their name.")
+            }
+            CollisionResolution::Skip => {
+                say!("Only the first song of each is synchronised.");
+                songs.retain(|song| target_plan.contains_key(&song.library_relative_path));
+            }
+            CollisionResolution::Error => {
+                return Err(MusicLibraryError::TargetPathCollisions {
+                    count: collisions.len(),
+                })
+            }
+        }
+    }
 
-    // Load the results from the last hash.
-    let previous_sync_db = read_records_of_previous_sync(&target_library);
+    // Load the results from the last hash. Nothing is written to the target library until the
+    // sync is started, so the database of records is only opened for writing after that.
+    let saves_records = !cli.dry_run && !cli.dont_save_records;
+    let mut previous_sync_db = match cli.records_format {
+        // Once the database is opened, its records are the ones to go by, even if there are none.
+        RecordsFormat::Sqlite if saves_records => {
+            Some(SqliteRecords::read_only(&target_library)?.unwrap_or_default())
+        }
+        RecordsFormat::Sqlite => SqliteRecords::read_only(&target_library)?
+            .or_else(|| read_records_of_previous_sync(&target_library, RecordsFormat::Json, false)),
+        format => read_records_of_previous_sync(&target_library, format, saves_records),
+    }
+    .map(|db| match cli.unicode_normalization {
+        Some(form) => normalize_record_keys(db, form),
+        None => db,
+    });
     let records_found = previous_sync_db.is_some();
 
-    // Do the synchronising on a per-file basis, so that it can be parallelised. Each one starting
-    // with its own ffmpeg thread.
-    println!("Synchronising music files...");
+    // Planned links already know what they link to.
+    let duplicates = if settings.song_deduplication.is_some() && applying.is_none() {
+        say!("Looking for duplicate songs...");
+        find_duplicate_songs(&songs)
+    } else {
+        HashMap::new()
+    };
+
+    let parallel = ffmpeg_interface::ENCODER_LIMIT
+        .max()
+        .map_or(rayon::current_num_threads(), |max| {
+            max.min(rayon::current_num_threads())
+        });
+    // Everything in a plan is to be done, so there is nothing to estimate. The estimate comes
+    // before anything is written, so that it can still be decided to synchronise later.
+    if applying.is_none() && !adopt {
+        let estimate = estimate_sync(
+            &songs,
+            &target_plan,
+            previous_sync_db.as_ref(),
+            &duplicates,
+            &settings,
+            parallel,
+        );
+        if verbosity > Verbosity::Quiet {
+            print!("{estimate}");
+        }
+        let something_to_do = estimate.to_transcode + estimate.to_copy > 0;
+        // Only ask when someone is there to answer, so that scheduled syncs don't get stuck.
+        if something_to_do && !cli.yes && !cli.dry_run && std::io::stdin().is_terminal() {
+            let confirmation = Confirm::new()
+                .with_prompt("Start synchronising?")
+                .default(true)
+                .interact()
+                .unwrap();
+            if !confirmation {
+                say!("Aborting.");
+                return Ok(Outcome::Aborted);
+            }
+        }
+    }
+
+    // The database is written to while synchronising, so it is only opened if that is allowed.
+    let records_db = match cli.records_format {
+        RecordsFormat::Sqlite if saves_records => Some(SqliteRecords::open(&target_library)?),
+        _ => None,
+    };
+
+    // What would be removed besides songs, for the plan of a dry run.
+    let mut removals = Vec::new();
+    // A plan only removes what it says, which was found when it was made.
+    if let (Some(plan), Some(db)) = (&applying, previous_sync_db.as_mut()) {
+        let removed = plan.apply_removals(db, &target_library, &settings.disposal);
+        if !removed.is_empty() {
+            if let Some(records_db) = &records_db {
+                records_db.remove(&removed)?;
+            }
+            say!("Removed {} files and records, as planned.", removed.len());
+        }
+    }
+
+    // Records of songs that were removed from the source library are no longer needed.
+    if let Some(db) = previous_sync_db.as_mut().filter(|_| applying.is_none()) {
+        let stale = prune_stale_records(db, &source_library, &discovered);
+        removals.extend(
+            stale.iter().map(|record| {
+                PlannedAction::removal(record, Removal::StaleRecord, &target_library)
+            }),
+        );
+        let stale = stale
+            .into_iter()
+            .map(|record| record.library_relative_path)
+            .collect::<Vec<_>>();
+        if !stale.is_empty() {
+            if let Some(records_db) = &records_db {
+                records_db.remove(&stale)?;
+            }
+            if cli.dry_run || cli.dont_save_records {
+                say!(
+                    "{} records are of songs that are no longer in the source library.",
+                    stale.len()
+                );
+            } else {
+                say!(
+                    "Dropped {} records of songs that are no longer in the source library.",
+                    stale.len()
+                );
+            }
+            if verbosity >= Verbosity::ChangeLog {
+                for path in &stale {
+                    say!("\t- {}", path.display());
+                }
+            }
+        }
+    }
+
+    // Songs that were picked to fill up the target library with before, but aren't anymore, make
+    // room for the ones that are. Only the ones that syncbops synchronised itself are removed.
+    if let Some(db) = previous_sync_db
+        .as_mut()
+        .filter(|_| !adopt && !left_out_of_fill.is_empty() && applying.is_none())
+    {
+        let (left_out_plan, _) = plan_target_paths(
+            &left_out_of_fill,
+            &target_library,
+            |song| settings.shadow_filetype_for(song),
+            &settings.target_paths,
+        );
+        let in_use = target_plan.values().collect::<HashSet<_>>();
+        let mut evicted = left_out_plan
+            .iter()
+            .filter(|(path, shadow)| db.contains_key(*path) && !in_use.contains(shadow))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        evicted.sort();
+        removals.extend(evicted.iter().map(|path| {
+            let record = SyncRecord {
+                target_relative_path: left_out_plan[path]
+                    .strip_prefix(&target_library)
+                    .ok()
+                    .map(Path::to_path_buf),
+                ..db[path].clone()
+            };
+            PlannedAction::removal(&record, Removal::LeftOutOfFill, &target_library)
+        }));
+        if !evicted.is_empty() {
+            if !cli.dry_run {
+                for path in &evicted {
+                    let _ = settings.disposal.remove_file(&left_out_plan[path]);
+                }
+                if let Some(records_db) = &records_db {
+                    records_db.remove(&evicted)?;
+                }
+            }
+            for path in &evicted {
+                db.remove(path);
+            }
+            if cli.dry_run {
+                say!(
+                    "{} songs that were picked to fill up the target library would be removed.",
+                    evicted.len()
+                );
+            } else {
+                say!(
+                    "Removed {} songs that were picked to fill up the target library before.",
+                    evicted.len()
+                );
+            }
+            if verbosity >= Verbosity::ChangeLog {
+                for path in &evicted {
+                    say!("\t- {}", path.display());
+                }
+            }
+        }
+    }
+
+    // .nfo files are sidecars too, so that they are also kept up to date.
+    let mut sidecar_extensions = if cli.copy_sidecars {
+        cli.sidecar_extensions.clone()
+    } else {
+        Vec::new()
+    };
+    if cli.copy_nfo && !sidecar_extensions.iter().any(|ext| ext == "nfo") {
+        sidecar_extensions.push("nfo".to_owned());
+    }
+    // Those are renamed to match their song instead.
+    if cli.copy_lyrics {
+        sidecar_extensions.retain(|ext| !ext.trim_start_matches('.').eq_ignore_ascii_case("lrc"));
+    }
+    let copying_sidecars = !sidecar_extensions.is_empty() || cli.copy_lyrics;
+    if let Some(db) = previous_sync_db
+        .as_mut()
+        .filter(|_| !adopt && copying_sidecars && applying.is_none())
+    {
+        let stale = remove_stale_sidecars(
+            db,
+            &source_library,
+            &target_library,
+            &sidecar_extensions,
+            cli.copy_lyrics,
+            cli.dry_run,
+            &settings.disposal,
+        );
+        removals.extend(
+            stale.iter().map(|record| {
+                PlannedAction::removal(record, Removal::StaleSidecar, &target_library)
+            }),
+        );
+        let stale = stale
+            .into_iter()
+            .map(|record| record.library_relative_path)
+            .collect::<Vec<_>>();
+        if !stale.is_empty() {
+            if let Some(records_db) = &records_db {
+                records_db.remove(&stale)?;
+            }
+            if cli.dry_run {
+                say!(
+                    "{} sidecar files would be removed, as they are not copied anymore.",
+                    stale.len()
+                );
+            } else {
+                say!(
+                    "Removed {} sidecar files that are not copied anymore.",
+                    stale.len()
+                );
+            }
+            if verbosity >= Verbosity::ChangeLog {
+                for path in &stale {
+                    say!("\t- {}", path.display());
+                }
+            }
+        }
+    }
+
+    if adopt {
+        say!("Matching songs with the files already in the target library...");
+        let (adopted, not_adopted) = adopt_shadows(
+            &songs,
+            &target_plan,
+            &target_library,
+            &settings,
+            previous_sync_db.as_ref(),
+        );
+        say!("Adopted {} songs.", adopted.len());
+        if !not_adopted.is_empty() {
+            say!(
+                "Could not adopt {} songs, these are synchronised with the next sync:",
+                not_adopted.len()
+            );
+            for (song, e) in &not_adopted {
+                if verbosity >= Verbosity::ChangeLog {
+                    say!("\t- {}: {}", song.library_relative_path.display(), e);
+                } else {
+                    say!("\t- {}", song.library_relative_path.display());
+                }
+            }
+        }
+        if cli.dry_run || cli.dont_save_records {
+            return Ok(Outcome::Clean);
+        }
+        match &records_db {
+            Some(db) => {
+                for record in &adopted {
+                    db.upsert(record)?;
+                }
+            }
+            None => {
+                let mut new_records = previous_sync_db.unwrap_or_default();
+                for record in adopted {
+                    register_record_to_previous_sync_db(&mut new_records, record);
+                }
+                write_records_of_current_sync(&new_records, &target_library, cli.records_format);
+            }
+        }
+        return Ok(Outcome::Clean);
+    }
+
+    // The loudness only ends up in the tags, so it doesn't change what a dry run would do.
+    if cli.scan_loudness && !cli.dry_run {
+        say!("Measuring the loudness of songs without ReplayGain tags...");
+        scan_loudness(&mut songs, previous_sync_db.as_ref());
+    }
+
+    let report_paths = cli
+        .report
+        .iter()
+        .cloned()
+        .chain(cli.report_in_target.then(|| {
+            target_library.join(format!("syncbops_report.{}", cli.report_format.extension()))
+        }))
+        .collect::<Vec<_>>();
+    let sizes_before = if report_paths.is_empty() {
+        HashMap::new()
+    } else {
+        target_sizes(&target_plan)
+    };
+
+    // Do the synchronising on a per-album basis, so that it can be parallelised. Each song
+    // starting with its own ffmpeg thread.
+    say!("Synchronising music files...");
     if cli.force {
-        println!("Forced re-writing every music file.")
+        say!("Forced re-writing every music file.")
     }
-    let pb = ProgressBar::new(songs.len() as u64);
+    // The bar advances by the duration of the songs instead of by song, because long songs take
+    // a lot longer to transcode. Otherwise the ETA is useless for libraries with both.
+    let fallback_weight = average_duration_secs(&songs);
+    let synced = Arc::new(AtomicUsize::new(0));
+    let n_songs = songs.len();
+    let pb = ProgressBar::new(
+        songs
+            .iter()
+            .map(|song| sync_weight(song, fallback_weight))
+            .sum(),
+    );
     pb.set_style(
         ProgressStyle::default_bar()
-            .template("[{elapsed}] [{bar:60.cyan/blue}] {pos}/{len} [ETA: {eta}] {msg}")
+            .with_key("songs", {
+                let synced = synced.clone();
+                move |_: &ProgressState, w: &mut dyn std::fmt::Write| {
+                    let _ = write!(w, "{}/{n_songs}", synced.load(Ordering::Relaxed));
+                }
+            })
+            .template("[{elapsed}] [{bar:60.cyan/blue}] {songs} [ETA: {eta}] {msg}")
             .unwrap()
             .progress_chars("#>-"),
     );
-    let sync_results: SyncResults = songs
-        .par_iter()
-        .progress_with(pb.clone())
-        .map(|song| {
-            pb.set_message(format!("{}", song.library_relative_path.display()));
-            (
-                song,
-                sync_song(
-                    song,
-                    &target_library,
-                    cli.target_filetype.clone(),
-                    art_strategy,
-                    previous_sync_db.as_ref(),
-                    cli.force,
-                    cli.dry_run,
-                    Some(&pb),
-                    cli.verbose,
+    let failed = AtomicBool::new(false);
+    let song_done = |song: &Song, result: &Result<SyncRecord, MusicLibraryError>| {
+        save_record(records_db.as_ref(), result, Some(&pb));
+        synced.fetch_add(1, Ordering::Relaxed);
+        pb.inc(sync_weight(song, fallback_weight));
+        let Err(e) = result else {
+            return;
+        };
+        let counts = !matches!(e, MusicLibraryError::ChangeLimitReached { .. });
+        if cli.fail_fast && counts && !failed.swap(true, Ordering::Relaxed) {
+            log_failure(
+                format!(
+                    "Stopping, because synchronising {} failed: {}",
+                    song.library_relative_path.display(),
+                    error_chain(e)
                 ),
-            )
+                Some(&pb),
+            );
+        }
+    };
+    // With --fail-fast, no new songs are started after the first failure.
+    let keep_going = || !failed.load(Ordering::Relaxed);
+    let planned = applying
+        .iter()
+        .flat_map(|plan| &plan.songs)
+        .map(|planned| (planned.action.source.as_path(), planned))
+        .collect::<HashMap<&Path, &PlannedSong>>();
+    let is_planned_link = |song: &Song| {
+        planned
+            .get(song.library_relative_path.as_path())
+            .is_some_and(|planned| planned.action.action == Action::Link)
+    };
+    // The work is split up per album folder, so that the songs of an album are synchronised
+    // together, one after the other.
+    let albums = songs
+        .iter()
+        .filter(|song| {
+            !duplicates.contains_key(&song.library_relative_path) && !is_planned_link(song)
+        })
+        .fold(BTreeMap::<&Path, Vec<&Song>>::new(), |mut albums, song| {
+            let folder = song.library_relative_path.parent().unwrap_or(Path::new(""));
+            albums.entry(folder).or_default().push(song);
+            albums
         })
-        .collect::<SyncResults>();
+        .into_iter()
+        .collect::<Vec<_>>();
+    let n_albums = albums.len();
+    // The art is copied along with the songs, also in a dry run. Each cover is claimed by the first
+    // song that gets to it, so two albums in the same folder (or disc folders of the same album)
+    // never race to write the same one.
+    let copied_art = Mutex::new(CopiedArt::new(previous_sync_db.as_ref(), &source_library));
+    let new_cover_arts = Mutex::new(Vec::new());
+    let art_failed = AtomicBool::new(false);
+    // Songs that could not be synchronised don't get art either.
+    let copy_art = |song: &Song, result: &Result<SyncRecord, MusicLibraryError>| {
+        if result.is_err() {
+            return;
+        }
+        let copied = copy_dedicated_cover_art_for_song(
+            song,
+            &target_plan[&song.library_relative_path],
+            &source_library,
+            &target_library,
+            &settings,
+            &copied_art,
+        )
+        .and_then(|shadow| {
+            if let Some(shadow) = &shadow {
+                journal_created(shadow, &settings)?;
+            }
+            Ok(shadow)
+        });
+        match copied {
+            Ok(Some(shadow)) => {
+                // Extracted art has no source of its own to keep a record of.
+                let record = song
+                    .external_album_art
+                    .as_deref()
+                    .filter(|_| !cli.dry_run)
+                    .and_then(|art| {
+                        Some(
+                            SyncRecord::from_art(
+                                art.strip_prefix(&source_library).ok()?,
+                                art,
+                                shadow.strip_prefix(&target_library).ok()?,
+                            )
+                            .set_update_type(UpdateType::Copied),
+                        )
+                    });
+                if let Some(record) = &record {
+                    save_record(records_db.as_ref(), &Ok(record.clone()), Some(&pb));
+                }
+                new_cover_arts
+                    .lock()
+                    .expect("art lock should not be poisoned")
+                    .push((shadow, record));
+            }
+            Ok(None) => (),
+            Err(e) => {
+                art_failed.store(true, Ordering::Relaxed);
+                log_failure(
+                    format!(
+                        "Could not copy the album art of {}: {}",
+                        song.library_relative_path.display(),
+                        error_chain(&e)
+                    ),
+                    Some(&pb),
+                );
+            }
+        }
+    };
+    let mut sync_results: SyncResults = albums
+        .par_iter()
+        .enumerate()
+        .flat_map_iter(|(i, (folder, album))| {
+            // Like "Artist — Album 3/120".
+            let name = folder
+                .iter()
+                .map(|part| part.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" — ");
+            pb.set_message(format!("{name} {}/{n_albums}", i + 1));
+            let mut results = Vec::with_capacity(album.len());
+            for &song in album.iter().take_while(|_| keep_going()) {
+                let result = match planned.get(song.library_relative_path.as_path()) {
+                    Some(planned) => {
+                        sync_song_as_planned(song, planned, &target_library, &settings, Some(&pb))
+                    }
+                    None => sync_song(
+                        song,
+                        &target_plan[&song.library_relative_path],
+                        &target_library,
+                        &settings,
+                        previous_sync_db.as_ref(),
+                        Some(&pb),
+                    ),
+                };
+                song_done(song, &result);
+                copy_art(song, &result);
+                results.push((song, result));
+            }
+            results
+        })
+        .collect();
+    // Only after the originals are synchronised, they can be linked to.
+    for song in songs
+        .iter()
+        .filter(|song| duplicates.contains_key(&song.library_relative_path) && keep_going())
+    {
+        let original = &duplicates[&song.library_relative_path];
+        let result = sync_duplicate_song(
+            song,
+            &target_plan[&song.library_relative_path],
+            &target_plan[original],
+            &target_library,
+            &settings,
+            previous_sync_db.as_ref(),
+            Some(&pb),
+        );
+        song_done(song, &result);
+        copy_art(song, &result);
+        sync_results.push((song, result));
+    }
+    for song in songs
+        .iter()
+        .filter(|song| is_planned_link(song) && keep_going())
+    {
+        let result = sync_song_as_planned(
+            song,
+            planned[song.library_relative_path.as_path()],
+            &target_library,
+            &settings,
+            Some(&pb),
+        );
+        song_done(song, &result);
+        copy_art(song, &result);
+        sync_results.push((song, result));
+    }
+    pb.finish();
+    let mut new_cover_arts = new_cover_arts
+        .into_inner()
+        .expect("art lock should not be poisoned");
+    new_cover_arts.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let (new_cover_arts, art_records): (Vec<_>, Vec<_>) = new_cover_arts.into_iter().unzip();
+    let aborted = failed.into_inner();
+    if aborted {
+        say!("Stopped at the first failure, because of --fail-fast.");
+    }
 
     // Might be sorted differently because of parallel execution, so put in alphabetic order again.
     let sync_results = {
@@ -258,39 +2013,160 @@ fn main() -> Result<(), MusicLibraryError> {
         unsorted.sort_by(|(i_a, _), (i_b, _)| i_a.absolute_path.cmp(&i_b.absolute_path));
         unsorted
     };
+    // Songs over the limit are left for the next run, so they are not failures.
+    let (sync_results, over_limit): (SyncResults, SyncResults) =
+        sync_results.into_iter().partition(|(_, result)| {
+            !matches!(result, Err(MusicLibraryError::ChangeLimitReached { .. }))
+        });
+    if !over_limit.is_empty() {
+        say!(
+            "Stopped after {} songs. {} more songs would be changed by the next run.",
+            cli.limit.unwrap_or_default(),
+            over_limit.len()
+        );
+    }
 
-    // Go over all the dedicated album art.
-    // If there is a dedicated art file for the music file, add it. If it already exists, it is probably already added by another file
-    let new_cover_arts = if !cli.dry_run {
-        println!("Checking and copying external cover art...");
-        Some(
-            songs
-                .iter()
-                .map(|song| {
-                    copy_dedicated_cover_art_for_song(
-                        song,
-                        &source_library,
-                        &target_library,
-                        cli.dry_run,
-                    )
-                })
-                .collect::<Result<Vec<_>, _>>()?
-                .iter()
-                .filter_map(|o| o.to_owned())
-                .collect::<Vec<_>>(),
-        )
+    if cli.artist_images && !cli.dry_run {
+        say!("Checking and copying artist images...");
+        let artist_folders =
+            find_artist_folders(&songs, &target_plan, &source_library, &target_library);
+        let new_artist_images = copy_artist_images(&artist_folders, &target_library, &settings);
+        for image in &new_artist_images {
+            journal_created(image, &settings)?;
+        }
+        let without_image = artist_folders
+            .iter()
+            .filter(|folder| folder.image.is_none())
+            .collect::<Vec<_>>();
+        if !without_image.is_empty() {
+            say!("There are artists without an artist image:");
+            for folder in without_image {
+                say!("\t- {}", folder.source.display())
+            }
+        }
+        say!("New artist images: {}", new_artist_images.len());
+    }
+    let sidecar_records = if copying_sidecars {
+        say!("Checking and copying sidecar files...");
+        let artist_folders =
+            find_artist_folders(&songs, &target_plan, &source_library, &target_library);
+        let mut sidecars = find_sidecars(
+            &songs,
+            &target_plan,
+            &source_library,
+            &target_library,
+            &artist_folders,
+            &sidecar_extensions,
+            &settings,
+        );
+        if cli.copy_lyrics {
+            sidecars.extend(find_lyrics(&songs, &target_plan));
+        }
+        let records = copy_sidecars(
+            &sidecars,
+            previous_sync_db.as_ref(),
+            &target_library,
+            &settings,
+        );
+        for record in &records {
+            save_record(records_db.as_ref(), &Ok(record.clone()), None);
+        }
+        say!("New or updated sidecar files: {}", records.len());
+        records
     } else {
-        None
+        Vec::new()
     };
 
-    print!("{}", summarize(&sync_results, new_cover_arts, cli.verbose));
-    if !cli.dry_run {
-        print_library_size_reduction(&source_library, &target_library);
+    if verbosity > Verbosity::Quiet {
+        print!(
+            "{}",
+            summarize(
+                &sync_results,
+                new_cover_arts,
+                &too_short,
+                &corrupt,
+                verbosity >= Verbosity::ChangeLog,
+                cli.collapse_unchanged
+            )
+        );
+        if !cli.dry_run {
+            print_library_size_reduction(&source_library, &target_library);
+        }
+    }
+    let any_failed =
+        sync_results.iter().any(|(_, result)| result.is_err()) || art_failed.into_inner();
+    notification.stats = Some(RunStats::from_results(&sync_results));
+    if !report_paths.is_empty() {
+        let rows = report_rows(
+            &sync_results,
+            &too_short,
+            &corrupt,
+            &target_library,
+            &target_plan,
+            &sizes_before,
+        );
+        for path in &report_paths {
+            write_report(path, &rows, cli.report_format)?;
+            say!("Wrote a report to {}.", path.display());
+        }
+    }
+    if let Some(path) = &cli.failures_out {
+        let failed = sync_results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(song, _)| *song)
+            .chain(corrupt.iter().map(|(song, _)| song))
+            // As they are in the source library, so that --only-from-file finds them again.
+            .map(|song| song.source_relative_path(&source_library))
+            .collect::<BTreeSet<_>>();
+        write_song_list(
+            path,
+            &failed.iter().map(PathBuf::as_path).collect::<Vec<_>>(),
+        )?;
+        if !failed.is_empty() {
+            say!(
+                "Wrote the {} songs that failed to {}.",
+                failed.len(),
+                path.display()
+            );
+        }
     }
+    let mut changes_pending = false;
+    // Printed last, so that JSON can be taken from the end of the output.
+    let plan = if cli.dry_run {
+        let plan = plan_from_results(
+            &sync_results,
+            &source_library,
+            &target_library,
+            &settings,
+            &duplicates,
+            &target_plan,
+        );
+        changes_pending = !plan.is_empty() || !removals.is_empty();
+        let formatted = format_plan(&plan, &removals, cli.plan_format);
+        if let Some(path) = &cli.plan {
+            SyncPlan {
+                arguments: std::env::args_os()
+                    .skip(1)
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+                working_directory: std::env::current_dir()
+                    .and_then(|dir| dir.canonicalize())
+                    .ok(),
+                songs: plan,
+                removals,
+            }
+            .write(path)?;
+            say!("Saved the plan to {}.", path.display());
+        }
+        Some(formatted)
+    } else {
+        None
+    };
 
-    // Update the PreviousSyncDB with the newly added items.
-    if !cli.dont_save_records && !cli.dry_run {
-        println!("Writing new records so the next sync can be done faster");
+    // Update the PreviousSyncDB with the newly added items. The database is already up to date.
+    if !cli.dont_save_records && !cli.dry_run && records_db.is_none() {
+        say!("Writing new records so the next sync can be done faster");
         // Carry over any previous records (files that are not touched retain their original data).
         let mut new_records = previous_sync_db.unwrap_or_default();
 
@@ -305,20 +2181,127 @@ fn main() -> Result<(), MusicLibraryError> {
             // Not the case, so a .clone() is necessary here.
             register_record_to_previous_sync_db(&mut new_records, record)
         }
+        for record in sidecar_records
+            .into_iter()
+            .chain(art_records.into_iter().flatten())
+        {
+            register_record_to_previous_sync_db(&mut new_records, record);
+        }
         // TODO: Also handle deleting songs. Right now it only adds one-way lol. For every filename in
         // the target directory, check if the same filename -prefix exists in the source dir, otherwise
         // delete it. can re-use find_albums_in_directory()
-        write_records_of_current_sync(&new_records, &target_library);
+        write_records_of_current_sync(&new_records, &target_library, cli.records_format);
+    }
+    if let Some(backup) = settings.disposal.backup().filter(|_| !cli.dry_run) {
+        let removed = backup.prune().map_err(|source| MusicLibraryError::Backup {
+            path: target_library.join(BACKUP_DIR),
+            source,
+        })?;
+        if !removed.is_empty() && verbosity >= Verbosity::ChangeLog {
+            say!("Removed the {} oldest backups.", removed.len());
+        }
+    }
+    if cli.remove_empty_dirs && !aborted {
+        let removed = remove_empty_dirs(&target_library, cli.dry_run).map_err(|source| {
+            MusicLibraryError::EmptyDirs {
+                path: target_library.clone(),
+                source,
+            }
+        })?;
+        if !removed.is_empty() {
+            if cli.dry_run {
+                say!("{} empty folders would be removed.", removed.len());
+            } else {
+                say!("Removed {} empty folders.", removed.len());
+            }
+            if verbosity >= Verbosity::ChangeLog {
+                for dir in &removed {
+                    say!("\t- {}", dir.display());
+                }
+            }
+        }
+    }
+    let permissions = Permissions {
+        mode: cli.chmod,
+        owner: cli.chown,
+    };
+    if !permissions.is_empty() && !cli.dry_run {
+        // Also after an abort, for what was written until then.
+        let changed = permissions.apply(&target_library).map_err(|source| {
+            MusicLibraryError::Permissions {
+                path: target_library.clone(),
+                source,
+            }
+        })?;
+        if changed > 0 && verbosity >= Verbosity::ChangeLog {
+            say!("Changed the mode or owner of {changed} files and folders.");
+        }
+    }
+    let mut push_failed = false;
+    if let Some(adb_target) = adb_target.as_ref().filter(|_| !cli.dry_run) {
+        say!("Pushing the changes to the phone");
+        let stats = adb_target.mirror(&target_library)?;
+        say!(
+            "Pushed {} files to the phone, and removed {} that are not synchronised anymore.",
+            stats.pushed,
+            stats.removed
+        );
+        for (path, e) in &stats.failed {
+            eprintln!("{}: {e}", console::style(path.display()).red());
+        }
+        push_failed = !stats.failed.is_empty();
     }
 
     // If not writing any records, but there are records present, the synchronisation state in
     // those is no longer up to date. Warn the user of this.
     if cli.dont_save_records && records_found {
-        println!("Writing records is disabled, but there are already records present in the target directory (from a previous run?). This means that the next synchronisation will use this data, and not update everything. It is therefore recommended to delete the existing records file from the target library.")
+        say!("Writing records is disabled, but there are already records present in the target directory (from a previous run?). This means that the next synchronisation will use this data, and not update everything. It is therefore recommended to delete the existing records file from the target library.")
+    }
+    if verbosity == Verbosity::Quiet {
+        let stats = notification.stats.unwrap_or_default();
+        println!(
+            "{} songs {}, {} unchanged, {} failed.",
+            stats.synchronised,
+            if cli.dry_run {
+                "would be synchronised"
+            } else {
+                "synchronised"
+            },
+            stats.unchanged,
+            stats.failed + corrupt.len()
+        );
     }
-    Ok(())
-    // TODO: Separately search for "albumname.jpg" everywhere. Match this to the albums by
-    // reading their tags, and link it if the album does not yet have art set.
+    if let Some(plan) = plan {
+        print!("{plan}");
+    }
+    Ok(if aborted || any_failed || push_failed {
+        Outcome::FileErrors
+    } else if cli.check && changes_pending {
+        Outcome::ChangesPending
+    } else {
+        Outcome::Clean
+    })
+}
+
+/// The average duration of the songs of which it is known, in seconds.
+fn average_duration_secs(songs: &[Song]) -> u64 {
+    let durations = songs
+        .iter()
+        .filter_map(|song| song.metadata.duration)
+        .collect::<Vec<_>>();
+    if durations.is_empty() {
+        return 1;
+    }
+    durations.iter().sum::<Duration>().as_secs() / durations.len() as u64
+}
+
+/// How much synchronising the song counts towards the progress bar: its duration in seconds.
+/// Songs of which the duration is unknown count as an average song.
+fn sync_weight(song: &Song, fallback: u64) -> u64 {
+    song.metadata
+        .duration
+        .map_or(fallback, |duration| duration.as_secs())
+        .max(1)
 }
 
 pub fn songs_without_album_art(songs: &[Song]) -> Vec<&Song> {
@@ -331,10 +2314,14 @@ pub fn songs_without_album_art(songs: &[Song]) -> Vec<&Song> {
 
 fn summarize(
     sync_results: &SyncResults,
-    new_cover_arts: Option<Vec<PathBuf>>,
+    new_cover_arts: Vec<PathBuf>,
+    // Songs that are not synchronised, because they are shorter than the minimum duration.
+    too_short: &[Song],
+    // Songs that are not synchronised, because they can't be decoded.
+    corrupt: &[(Song, FfmpegError)],
     verbose: bool,
+    collapse_unchanged: bool,
 ) -> String {
-    let mut changed_buf = String::new();
     let mut error_buf = String::new();
     let mut n_unchanged = 0;
     let mut n_new = 0;
@@ -342,34 +2329,35 @@ fn summarize(
     let mut n_err = 0;
     let mut n_missing_target = 0;
     let mut n_copied = 0;
+    let mut n_duplicate = 0;
+    let mut n_retagged = 0;
+    let mut total_duration = Duration::ZERO;
+    let mut changed_duration = Duration::ZERO;
     for (song, r) in sync_results {
         match r {
             Ok(sync_record) => {
                 let update_type = sync_record
                     .update_type
                     .expect("Empty update type. Implementation error");
+                let duration = song.metadata.duration.unwrap_or_default();
+                total_duration += duration;
+                if !matches!(
+                    update_type,
+                    UpdateType::NoChange | UpdateType::Adopted | UpdateType::Duplicate
+                ) {
+                    changed_duration += duration;
+                }
                 use UpdateType as U;
                 match update_type {
-                    U::NoChange => {
-                        n_unchanged += 1;
-                        // If not changed, don't log anything extra.
-                        continue;
-                    }
+                    U::NoChange | U::Adopted => n_unchanged += 1,
                     U::NewTranscode => n_new += 1,
                     U::Overwrite => n_overwritten += 1,
+                    U::Retag => n_retagged += 1,
                     U::ForceOverwrite => n_overwritten += 1,
                     U::TranscodeMissingTarget => n_missing_target += 1,
                     U::Copied => n_copied += 1,
+                    U::Duplicate => n_duplicate += 1,
                 };
-                if verbose {
-                    writeln!(
-                        changed_buf,
-                        "[{:?}] {}",
-                        update_type,
-                        song.library_relative_path.display()
-                    )
-                    .unwrap();
-                }
             }
             Err(e) => {
                 n_err += 1;
@@ -377,7 +2365,7 @@ fn summarize(
                     error_buf,
                     // debug format also displays source error
                     "{}: {}",
-                    song.library_relative_path.display(),
+                    console::style(song.library_relative_path.display()).red(),
                     e
                 )
                 .unwrap();
@@ -387,28 +2375,127 @@ fn summarize(
     let mut summary = String::new();
     writeln!(summary, "====== Summary of synchronisation ======").unwrap();
     summary.push_str(&format!("Unchanged: {}\n", n_unchanged));
-    summary.push_str(&format!("New songs: {}\n", n_new));
-    summary.push_str(&format!("Changed songs (overwritten): {}\n", n_overwritten));
+    let new = UpdateType::NewTranscode.style(format!("New songs: {}", n_new));
+    writeln!(summary, "{new}").unwrap();
+    let overwritten =
+        UpdateType::Overwrite.style(format!("Changed songs (overwritten): {}", n_overwritten));
+    writeln!(summary, "{overwritten}").unwrap();
+    if n_retagged > 0 {
+        let retagged = UpdateType::Retag.style(format!("Changed tags (retagged): {}", n_retagged));
+        writeln!(summary, "{retagged}").unwrap();
+    }
     summary.push_str(&format!("Re-added missing: {}\n", n_missing_target));
     summary.push_str(&format!("Copied (not transcoded): {}\n", n_copied));
-    if let Some(art_files) = new_cover_arts {
-        summary.push_str(&format!("New album art: {}\n", art_files.len()));
+    if n_duplicate > 0 {
+        summary.push_str(&format!(
+            "Duplicates (linked or skipped): {}\n",
+            n_duplicate
+        ));
+    }
+    if !too_short.is_empty() {
+        summary.push_str(&format!("Skipped (too short): {}\n", too_short.len()));
     }
+    if !corrupt.is_empty() {
+        summary.push_str(&format!("Skipped (corrupt): {}\n", corrupt.len()));
+    }
+    summary.push_str(&format!(
+        "New or updated album art: {}\n",
+        new_cover_arts.len()
+    ));
+    writeln!(
+        summary,
+        "Music: {} hours, of which {} hours changed",
+        hours(total_duration),
+        hours(changed_duration)
+    )
+    .unwrap();
     if n_err == 0 {
         summary.push_str("No Errors :D\n");
     } else {
-        summary.push_str(&format!("Files with errors: {}\n", n_err));
+        let errors = console::style(format!("Files with errors: {}", n_err)).red();
+        writeln!(summary, "{errors}").unwrap();
         summary.push_str("The following errors occurred:\n");
         summary += &error_buf;
     }
+    if !corrupt.is_empty() {
+        summary.push_str("The following songs were skipped, because they can't be decoded:\n");
+        for (song, e) in corrupt {
+            writeln!(summary, "{}: {}", song.library_relative_path.display(), e).unwrap();
+        }
+    }
     if verbose {
-        summary.push_str("Changed files\n");
-        summary += &changed_buf;
+        summary.push_str("Changed files, per album:\n");
+        summary += &changes_per_album(sync_results, collapse_unchanged);
+        if !too_short.is_empty() {
+            summary.push_str("Skipped files (too short)\n");
+            for song in too_short {
+                writeln!(summary, "{}", song.library_relative_path.display()).unwrap();
+            }
+        }
     }
 
     summary
 }
 
+/// Like "12.3".
+fn hours(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() / 3600.)
+}
+
+/// Lists the changed songs grouped by the folder they are in, which is usually the album. With
+/// hundreds of changes, a flat list is hard to read.
+fn changes_per_album(sync_results: &SyncResults, collapse_unchanged: bool) -> String {
+    #[derive(Default)]
+    struct Album<'a> {
+        changed: Vec<(UpdateType, &'a Path)>,
+        unchanged: usize,
+    }
+    let mut albums: BTreeMap<&Path, Album> = BTreeMap::new();
+    for (song, result) in sync_results {
+        let Ok(record) = result else {
+            // Errors are listed separately.
+            continue;
+        };
+        let path = song.library_relative_path.as_path();
+        let album = albums
+            .entry(path.parent().unwrap_or(Path::new("")))
+            .or_default();
+        match record.update_type {
+            Some(UpdateType::NoChange | UpdateType::Adopted) | None => album.unchanged += 1,
+            Some(update_type) => album.changed.push((update_type, path)),
+        }
+    }
+    let mut buf = String::new();
+    let mut n_unchanged_albums = 0;
+    for (folder, album) in albums {
+        if album.changed.is_empty() {
+            if collapse_unchanged {
+                n_unchanged_albums += 1;
+            } else {
+                writeln!(buf, "{}: {} unchanged", folder.display(), album.unchanged).unwrap();
+            }
+            continue;
+        }
+        writeln!(
+            buf,
+            "{} ({} changed, {} unchanged)",
+            folder.display(),
+            album.changed.len(),
+            album.unchanged
+        )
+        .unwrap();
+        for (update_type, path) in album.changed {
+            let name = path.file_name().unwrap_or(path.as_os_str());
+            let line = format!("[{:?}] {}", update_type, Path::new(name).display());
+            writeln!(buf, "\t{}", update_type.style(line)).unwrap();
+        }
+    }
+    if n_unchanged_albums > 0 {
+        writeln!(buf, "{n_unchanged_albums} albums are unchanged.").unwrap();
+    }
+    buf
+}
+
 fn print_library_size_reduction(source_library: &Path, target_library: &Path) {
     use fs_extra::dir::get_size;
     let source_lib_size = get_size(source_library).unwrap();
@@ -422,6 +2509,37 @@ fn print_library_size_reduction(source_library: &Path, target_library: &Path) {
     )
 }
 
+/// Writes the record to the records database right away, if it is used.
+fn save_record(
+    records_db: Option<&SqliteRecords>,
+    result: &Result<SyncRecord, MusicLibraryError>,
+    pb: Option<&ProgressBar>,
+) {
+    let (Some(db), Ok(record)) = (records_db, result) else {
+        return;
+    };
+    if let Err(e) = db.upsert(record) {
+        log_failure(
+            format!(
+                "Could not save the record of {}: {e}",
+                record.library_relative_path.display()
+            ),
+            pb,
+        );
+    }
+}
+
+/// The error and everything that caused it, e.g. including the ffmpeg command and its output.
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut chain = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        let _ = write!(chain, "\n\tcaused by: {e}");
+        source = e.source();
+    }
+    chain
+}
+
 /// Called to log whenever an operation has failed on a music file, but the program is allowed to
 /// continue running.
 /// To death with silent errors!
@@ -0,0 +1,168 @@
+use crate::ffmpeg_interface::{FfmpegError, SongMetaData};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// At the start of every cache file. The last byte is the version, which has to be increased
+/// whenever `SongMetaData` changes. Caches of another version are thrown away.
+const METADATA_CACHE_HEADER: &[u8; 4] = b"SBM\x01";
+
+/// The metadata of the songs in a source library, as it was read at the last sync. Reading the
+/// metadata with ffprobe takes long, so it is only done again for files that changed since.
+/// Kept in the cache directory of the user, not in the source library, so that the source
+/// library is not touched.
+pub struct MetadataCache {
+    path: PathBuf,
+    /// Keyed on the absolute path of the song.
+    previous: HashMap<PathBuf, CachedMetadata>,
+    /// Everything that was looked up during this run. When the whole library was read, only
+    /// these are saved, so that songs that were removed from the library are dropped from the
+    /// cache.
+    current: Mutex<HashMap<PathBuf, CachedMetadata>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMetadata {
+    size: u64,
+    modified: SystemTime,
+    metadata: SongMetaData,
+}
+
+impl MetadataCache {
+    /// Opens the cache of the given source library. Starts with an empty cache if there is none
+    /// yet, or if it can't be read. None if the user has no cache directory.
+    pub fn open(source_library: &Path) -> Option<MetadataCache> {
+        // One cache per source library, so that syncing one library doesn't drop the cache of
+        // another one.
+        let library = source_library
+            .canonicalize()
+            .unwrap_or_else(|_| source_library.to_path_buf());
+        let path = dirs::cache_dir()?.join("syncbops").join(format!(
+            "metadata_{:016x}.bin",
+            rapidhash::rapidhash(library.as_os_str().as_encoded_bytes())
+        ));
+        let previous = match read_cache(&path) {
+            Ok(previous) => previous,
+            Err(e) => {
+                if path.exists() {
+                    eprintln!(
+                        "Cannot read the metadata cache in {}, because {e}. Reading the \
+                        metadata of every song again.",
+                        path.display()
+                    );
+                }
+                HashMap::new()
+            }
+        };
+        Some(MetadataCache {
+            path,
+            previous,
+            current: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The metadata of the song, from the cache if the file did not change since it was cached.
+    pub fn parse_file(&self, path: &Path) -> Result<SongMetaData, FfmpegError> {
+        let Some((size, modified)) = size_and_modified(path) else {
+            return SongMetaData::parse_file(path);
+        };
+        let cached = self
+            .previous
+            .get(path)
+            .filter(|cached| cached.size == size && cached.modified == modified);
+        let metadata = match cached {
+            Some(cached) => cached.metadata.clone(),
+            None => SongMetaData::parse_file(path)?,
+        };
+        self.current.lock().unwrap().insert(
+            path.to_path_buf(),
+            CachedMetadata {
+                size,
+                modified,
+                metadata: metadata.clone(),
+            },
+        );
+        Ok(metadata)
+    }
+
+    /// Writes the metadata that was looked up during this run to the cache. If only a part of
+    /// the library was read, the cached metadata of the rest is kept as well.
+    pub fn save(self, whole_library: bool) {
+        let mut current = self.current.into_inner().unwrap();
+        if !whole_library {
+            for (path, cached) in self.previous {
+                current.entry(path).or_insert(cached);
+            }
+        }
+        if let Err(e) = write_cache(&self.path, &current) {
+            eprintln!(
+                "Could not write the metadata cache to {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+fn size_and_modified(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+fn read_cache(path: &Path) -> Result<HashMap<PathBuf, CachedMetadata>, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let mut header = [0; METADATA_CACHE_HEADER.len()];
+    reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+    if &header != METADATA_CACHE_HEADER {
+        return Err("it was written by a different version of syncbops".to_owned());
+    }
+    bincode::deserialize_from(reader).map_err(|e| e.to_string())
+}
+
+fn write_cache(path: &Path, cache: &HashMap<PathBuf, CachedMetadata>) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    let mut writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+    writer
+        .write_all(METADATA_CACHE_HEADER)
+        .map_err(|e| e.to_string())?;
+    bincode::serialize_into(&mut writer, cache).map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_cache, write_cache, CachedMetadata};
+    use crate::song::Song;
+    use std::{collections::HashMap, path::PathBuf, time::SystemTime};
+
+    #[test]
+    /// Metadata survives being written to and read from the cache.
+    fn metadata_cache_roundtrip() {
+        let path = std::env::temp_dir().join(format!(
+            "syncbops_metadata_{}.bin",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let song = Song::new_fake("a.flac", &[("title", "A"), ("artist", "B")]);
+        let cache = HashMap::from([(
+            PathBuf::from("/music/a.flac"),
+            CachedMetadata {
+                size: 42,
+                modified: SystemTime::now(),
+                metadata: song.metadata,
+            },
+        )]);
+        write_cache(&path, &cache).unwrap();
+        let read = read_cache(&path).unwrap();
+        let cached = &read[&PathBuf::from("/music/a.flac")];
+        assert_eq!(cached.size, 42);
+        assert_eq!(cached.metadata.title.as_deref(), Some("A"));
+        assert_eq!(cached.metadata.artist(), Some("B"));
+    }
+}
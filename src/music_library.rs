@@ -1,18 +1,30 @@
+use crate::adb::AdbError;
+use crate::ffmpeg_interface::convert_art;
+use crate::ffmpeg_interface::image_resolution;
 use crate::ffmpeg_interface::FfmpegCapabilityError;
 use crate::ffmpeg_interface::FfmpegError;
+use crate::ffmpeg_interface::SongMetaData;
+use crate::hashing::{hash_file, PreviousSyncDb};
 use crate::log_failure;
+use crate::metadata_cache::MetadataCache;
 use crate::song::Song;
+use crate::sqlite_records::RecordsError;
+use crate::sync_song::SyncSettings;
+use crate::target_path::{disc_number, target_relative_path, NormalizationForm, TargetPathOptions};
 use indicatif::ParallelProgressIterator;
 use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
 use itertools::Itertools;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::Display;
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 use walkdir::WalkDir;
 
 /// How should the file be updated? (or how was it updated last time)
@@ -24,6 +36,9 @@ pub enum UpdateType {
     NewTranscode,
     /// Updated because it was modified more recently than the shadow copy
     Overwrite,
+    /// Only the tags of the source changed, so the audio of the shadow copy is kept, and only
+    /// the tags (and art) are written again.
+    Retag,
     /// Actually unchanged, but forced into being overwritten.
     ForceOverwrite,
     /// The song is present in the SyncDB (It has been synced before),
@@ -33,6 +48,27 @@ pub enum UpdateType {
     /// The target file does not yet exist, and the source file already has a low bitrate.
     /// It should just be copied, and not transcoded.
     Copied,
+    /// The source file is identical to another song in the library, so it is linked to the
+    /// shadow of that song, or skipped.
+    Duplicate,
+    /// The shadow was already there, not made by syncbops, and was taken over as it is by
+    /// `syncbops adopt`.
+    Adopted,
+}
+
+impl UpdateType {
+    /// Colours the text for how the song changed, like a diff: new songs green, overwritten
+    /// ones yellow. Unchanged songs are not coloured.
+    pub fn style<D>(&self, text: D) -> console::StyledObject<D> {
+        use UpdateType as U;
+        let styled = console::style(text);
+        match self {
+            U::NewTranscode | U::TranscodeMissingTarget | U::Copied => styled.green(),
+            U::Overwrite | U::ForceOverwrite | U::Retag => styled.yellow(),
+            U::Duplicate => styled.cyan(),
+            U::NoChange | U::Adopted => styled,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -48,13 +84,16 @@ impl ArtworkType {
     }
 }
 
-#[derive(Clone, Debug, clap::Subcommand)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, clap::Subcommand)]
 pub enum MusicFileType {
     /// Constant bitrate MP3. Very widely supported, not very good.
     Mp3CBR {
         /// The constant bitrate in kbps
         #[arg(short, long, value_name = "BITRATE", default_value_t = 180)]
         bitrate: u32,
+        #[command(flatten)]
+        #[serde(default)]
+        id3: Id3Tags,
     },
     /// Variable bitrate MP3. A decent bit smaller than MP3 CBR, usually at negligible qualtiy
     /// degredation.
@@ -62,6 +101,9 @@ pub enum MusicFileType {
         /// quality factor. From 0 to 9. Lower is higher quality, but larger filesize. See https://trac.ffmpeg.org/wiki/Encode/MP3
         #[arg(short, long, default_value_t = 3)]
         quality: usize,
+        #[command(flatten)]
+        #[serde(default)]
+        id3: Id3Tags,
     },
     /// Transcode to Opus. Nichely supported, but highest quality audio codec. This might not be supported by your ffmpeg build.
     /// You need to explicitly configure the ffmpeg build with --enable-libopus.
@@ -72,6 +114,10 @@ pub enum MusicFileType {
         /// Compression algorithm complexity. 0-10. Trades quality for encoding time. higher is best quality. Does not affect filesize
         #[arg(short, long, default_value_t = 3)]
         compression_level: usize,
+        /// Whether the bitrate may vary. Variable bitrate sounds better for the same filesize,
+        /// but some devices (like DJ controllers) need a constant bitrate.
+        #[arg(long, value_name = "MODE", default_value = "on")]
+        vbr: OpusVbr,
     },
     /// Transcode to Vorbis. Good support, high quality. Not always supported by ffmpeg
     /// You need to explicitly configure the build with --enable-libvorbis.
@@ -86,14 +132,26 @@ pub enum MusicFileType {
         #[arg(short, long, default_value_t = 10)]
         quality: u64,
     },
+    /// Don't transcode at all: mirror the music files as they are. Songs are only remuxed
+    /// (without re-encoding the audio) if the art strategy needs the embedded art to change.
+    /// Options that change the audio, like --max-sample-rate, don't apply.
+    Copy,
 }
 
 impl MusicFileType {
+    /// How tags are written, for MP3 files. None for other filetypes.
+    pub fn id3(&self) -> Option<Id3Tags> {
+        match self {
+            MusicFileType::Mp3CBR { id3, .. } | MusicFileType::Mp3VBR { id3, .. } => Some(*id3),
+            _ => None,
+        }
+    }
+
     /// To be able to compare quality and file sizes of different file types.
     pub fn equivalent_bitrate(&self) -> u32 {
         match self {
-            MusicFileType::Mp3CBR { bitrate } => *bitrate,
-            MusicFileType::Mp3VBR { quality } => match quality {
+            MusicFileType::Mp3CBR { bitrate, .. } => *bitrate,
+            MusicFileType::Mp3VBR { quality, .. } => match quality {
                 // Values obtained from https://trac.ffmpeg.org/wiki/Encode/MP3
                 0 => 245,
                 1 => 225,
@@ -107,10 +165,7 @@ impl MusicFileType {
                 9 => 65,
                 _ => panic!("Invalid MP3 VBR quality number."),
             },
-            MusicFileType::Opus {
-                bitrate,
-                compression_level: _,
-            } => *bitrate,
+            MusicFileType::Opus { bitrate, .. } => *bitrate,
             MusicFileType::Vorbis { quality } => {
                 let q = *quality;
                 // Equation obtained from https://trac.ffmpeg.org/wiki/TheoraVorbisEncodingGuide#VariableBitrateVBR
@@ -125,6 +180,8 @@ impl MusicFileType {
             }
             // Sorry man but if you want to transcode into flac you are using the wrong software.
             MusicFileType::Flac { .. } => 800,
+            // Everything is copied.
+            MusicFileType::Copy => u32::MAX,
         }
     }
 
@@ -160,15 +217,101 @@ impl Display for MusicFileType {
                 MusicFileType::Opus { .. } => "opus",
                 MusicFileType::Vorbis { .. } => "ogg",
                 MusicFileType::Flac { .. } => "flac",
+                MusicFileType::Copy => "copy",
             }
         )
     }
 }
 
-// pub enum ImageType {
-//     Png,
-//     Jpg,
-// }
+/// How tags are written to MP3 files.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::Args, Debug)]
+pub struct Id3Tags {
+    /// Which version of ID3v2 tags to write. 2.3 is supported by about every player, and writes
+    /// text that is all ASCII as Latin-1. 2.4 writes text as UTF-8, and dates in full (TDRC),
+    /// which modern players prefer.
+    #[arg(long, value_name = "VERSION", default_value = "2.3")]
+    pub id3_version: Id3Version,
+    /// Also add ID3v1 tags to the end of the files, for very old players. These only have room
+    /// for 30 characters per tag.
+    #[arg(long, default_value_t = false)]
+    pub id3v1: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum, Debug)]
+pub enum Id3Version {
+    #[default]
+    #[value(name = "2.3")]
+    #[serde(rename = "2.3")]
+    V2_3,
+    #[value(name = "2.4")]
+    #[serde(rename = "2.4")]
+    V2_4,
+}
+
+impl Id3Version {
+    /// The value of the `-id3v2_version` option of ffmpeg's MP3 muxer.
+    pub fn ffmpeg_value(&self) -> &'static str {
+        match self {
+            Id3Version::V2_3 => "3",
+            Id3Version::V2_4 => "4",
+        }
+    }
+}
+
+/// Bitrate mode of the Opus encoder.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum, Debug)]
+pub enum OpusVbr {
+    /// Variable bitrate.
+    On,
+    /// Hard constant bitrate.
+    Off,
+    /// Variable bitrate, but it doesn't deviate much from the target bitrate.
+    Constrained,
+}
+
+impl OpusVbr {
+    /// The value of the `-vbr` option of ffmpeg's libopus encoder.
+    pub fn ffmpeg_value(&self) -> &'static str {
+        match self {
+            OpusVbr::On => "on",
+            OpusVbr::Off => "off",
+            OpusVbr::Constrained => "constrained",
+        }
+    }
+}
+
+/// Image format to convert external album art files to.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum ArtFormat {
+    /// Supported by pretty much everything.
+    Jpeg,
+    /// Lossless, so usually quite large.
+    Png,
+    /// Smaller than JPEG at the same quality, but not supported by all devices.
+    Webp,
+}
+
+impl ArtFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArtFormat::Jpeg => "jpg",
+            ArtFormat::Png => "png",
+            ArtFormat::Webp => "webp",
+        }
+    }
+
+    /// Whether the file at the path is already in this format.
+    fn is_format_of(&self, path: &Path) -> bool {
+        let Some(ext) = path.extension().map(|e| e.to_ascii_lowercase()) else {
+            return false;
+        };
+        match self {
+            ArtFormat::Jpeg => ext == "jpg" || ext == "jpeg",
+            ArtFormat::Png => ext == "png",
+            ArtFormat::Webp => ext == "webp",
+        }
+    }
+}
 
 #[derive(PartialEq, Eq)]
 pub enum FileType {
@@ -178,27 +321,48 @@ pub enum FileType {
     // Things like cue files, etc
     Meta,
     Playlist,
+    /// Like recordings of live sets. Only the audio is used.
+    Video,
 }
 
 /// Returns None if the file does not exist or is not identifiable.
-fn identify_file_type(path: &Path) -> Option<FileType> {
+/// Files are identified by their extension. If that doesn't work (e.g. rips without extension),
+/// by their contents.
+pub fn identify_file_type(path: &Path) -> Option<FileType> {
     if !path.exists() {
         return None;
     }
     if path.is_dir() {
         return Some(FileType::Folder);
     };
-    let ext = path.extension()?.to_ascii_lowercase();
+    path.extension()
+        .and_then(|ext| file_type_from_extension(&ext.to_string_lossy().to_ascii_lowercase()))
+        .or_else(|| sniff_file_type(path))
+}
 
+fn file_type_from_extension(ext: &str) -> Option<FileType> {
     use FileType as F;
-    Some(match ext.as_os_str().to_str()? {
+    Some(match ext {
         "mp3" => F::Music,
         "m4a" => F::Music,
+        "m4b" => F::Music,
         "ogg" => F::Music,
         "flac" => F::Music,
+        "opus" => F::Music,
+        "wav" => F::Music,
+        "aiff" => F::Music,
+        "aif" => F::Music,
+        "ape" => F::Music,
+        "wv" => F::Music,
+        "wma" => F::Music,
+        "mpc" => F::Music,
+        "dsf" => F::Music,
+        "dff" => F::Music,
         "png" => F::Art,
         "jpg" => F::Art,
         "jpeg" => F::Art,
+        "bmp" => F::Art,
+        "webp" => F::Art,
         "cue" => F::Meta,
         "nfo" => F::Meta,
         "log" => F::Meta,
@@ -208,10 +372,75 @@ fn identify_file_type(path: &Path) -> Option<FileType> {
         "sfv" => F::Meta,
         "m3u" => F::Playlist,
         "m3u8" => F::Playlist,
+        "mkv" => F::Video,
+        "mp4" => F::Video,
+        "m4v" => F::Video,
+        "webm" => F::Video,
+        "mov" => F::Video,
         _ => return None,
     })
 }
 
+/// Identifies a file by the magic bytes at the start of it.
+fn sniff_file_type(path: &Path) -> Option<FileType> {
+    let mut header = [0u8; 12];
+    let mut file = fs::File::open(path).ok()?;
+    let n = file.read(&mut header).ok()?;
+    file_type_from_magic_bytes(&header[..n])
+}
+
+fn file_type_from_magic_bytes(header: &[u8]) -> Option<FileType> {
+    use FileType as F;
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+    // Container formats have the type of their contents a bit further in.
+    if at(0, b"RIFF") {
+        return match header.get(8..12)? {
+            b"WAVE" => Some(F::Music),
+            b"WEBP" => Some(F::Art),
+            _ => None,
+        };
+    }
+    if at(0, b"FORM") {
+        return (at(8, b"AIFF") || at(8, b"AIFC")).then_some(F::Music);
+    }
+    if at(4, b"ftyp") {
+        // MP4 files with only audio are called M4A (or M4B for audiobooks).
+        return Some(match header.get(8..11)? {
+            b"M4A" | b"M4B" => F::Music,
+            _ => F::Video,
+        });
+    }
+    const MUSIC: [&[u8]; 10] = [
+        b"ID3",
+        b"fLaC",
+        b"OggS",
+        b"MAC ",
+        b"wvpk",
+        b"MPCK",
+        b"MP+",
+        b"DSD ",
+        b"FRM8",
+        // ASF, for WMA
+        &[0x30, 0x26, 0xB2, 0x75],
+    ];
+    const ART: [&[u8]; 2] = [&[0xFF, 0xD8, 0xFF], b"\x89PNG"];
+    if MUSIC.iter().any(|magic| at(0, magic)) {
+        return Some(F::Music);
+    }
+    // MP3 without ID3 tag starts with a frame sync: 11 set bits.
+    if header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 && header[1] != 0xFF {
+        return Some(F::Music);
+    }
+    if ART.iter().any(|magic| at(0, magic)) {
+        return Some(F::Art);
+    }
+    // Matroska, for MKV and WebM.
+    if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some(F::Video);
+    }
+    None
+}
+
 /// Checks if the file meets the criteria to be considered dedicated album art: is it named
 /// cover.jpg or something?
 fn is_image_file_album_art(path: &Path) -> bool {
@@ -235,8 +464,71 @@ fn is_image_file_album_art(path: &Path) -> bool {
     stem_is_allowed && has_right_extension
 }
 
-pub fn find_songs_in_library(library_root: &Path) -> Result<Vec<Song>, MusicLibraryError> {
+/// The order in which art files are preferred if they are of the same quality, by default.
+pub const DEFAULT_ART_NAME_PREFERENCE: &str = "cover,folder,front,album,cover_art,cover_image";
+
+/// An image file that might be used as the album art for the songs in its folder.
+#[derive(Debug)]
+struct ArtCandidate {
+    path: PathBuf,
+    /// Width and height in pixels, if they could be determined.
+    resolution: Option<(u32, u32)>,
+    file_size: u64,
+}
+
+impl ArtCandidate {
+    fn new(path: PathBuf) -> ArtCandidate {
+        ArtCandidate {
+            resolution: image_resolution(&path).ok(),
+            file_size: fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+            path,
+        }
+    }
+}
+
+/// Picks the art with the highest resolution. If they are the same, the one with the largest
+/// file (so least compressed) is used. If that's also the same, it goes by the order of
+/// `name_preference` (file stems, like "cover").
+fn best_album_art(candidates: Vec<ArtCandidate>, name_preference: &[String]) -> Option<PathBuf> {
+    let name_rank = |path: &Path| {
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        name_preference
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(&stem))
+            .unwrap_or(name_preference.len())
+    };
+    candidates
+        .into_iter()
+        .max_by(|a, b| {
+            let pixels = |c: &ArtCandidate| c.resolution.map_or(0, |(w, h)| w as u64 * h as u64);
+            pixels(a)
+                .cmp(&pixels(b))
+                .then(a.file_size.cmp(&b.file_size))
+                // A lower rank is better.
+                .then(name_rank(&b.path).cmp(&name_rank(&a.path)))
+                // Make sure it doesn't depend on the order the files were found in.
+                .then(b.path.cmp(&a.path))
+        })
+        .map(|c| c.path)
+}
+
+/// `art_name_preference` decides which art file is used if there are multiple of the same
+/// quality in a folder. See `best_album_art()`.
+pub fn find_songs_in_library(
+    library_root: &Path,
+    art_name_preference: &[String],
+    // Also use the audio of video files as songs.
+    include_videos: bool,
+    metadata_cache: Option<&MetadataCache>,
+    // Only songs that were added or modified since then.
+    since: Option<SystemTime>,
+) -> Result<Vec<Song>, MusicLibraryError> {
+    // In the same order every time, so that ties between songs are broken the same way.
     let filenames = WalkDir::new(library_root)
+        .sort_by_file_name()
         .into_iter()
         .filter_map(|direntry_res| {
             let item = match direntry_res {
@@ -254,23 +546,161 @@ pub fn find_songs_in_library(library_root: &Path) -> Result<Vec<Song>, MusicLibr
             Some(item)
         })
         .collect_vec();
+    Ok(songs_from_files(
+        library_root,
+        &filenames,
+        |path| since.is_none_or(|since| changed_since(path, since)),
+        art_name_preference,
+        include_videos,
+        metadata_cache,
+    ))
+}
+
+/// Like `find_songs_in_library()`, but only finds the given songs (relative to the library),
+/// without going through the whole library. Only the folders the songs are in are read, to
+/// find their album art.
+pub fn find_listed_songs(
+    library_root: &Path,
+    listed: &HashSet<PathBuf>,
+    art_name_preference: &[String],
+    include_videos: bool,
+    metadata_cache: Option<&MetadataCache>,
+    since: Option<SystemTime>,
+) -> Vec<Song> {
+    let directories = listed
+        .iter()
+        .filter_map(|path| Some(library_root.join(path).parent()?.to_path_buf()))
+        .collect::<HashSet<_>>();
+    let filenames = directories
+        .iter()
+        .filter_map(|directory| fs::read_dir(directory).ok())
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| !path.is_dir())
+        .collect_vec();
+    songs_from_files(
+        library_root,
+        &filenames,
+        |path| {
+            listed.contains(&library_relative_path(path, library_root))
+                && since.is_none_or(|since| changed_since(path, since))
+        },
+        art_name_preference,
+        include_videos,
+        metadata_cache,
+    )
+}
 
+/// Whether the file was modified, or added to the library (created), at or after the given time.
+/// Copying a file into the library often keeps its modification time, so both are looked at.
+fn changed_since(path: &Path, since: SystemTime) -> bool {
+    let Ok(metadata) = fs::metadata(path) else {
+        return true;
+    };
+    // Not every filesystem keeps the creation time.
+    [metadata.modified(), metadata.created()]
+        .into_iter()
+        .filter_map(Result::ok)
+        .any(|time| time >= since)
+}
+
+/// Parses a date like "2024-01-31", as the start of that day in UTC. For use as a clap value
+/// parser.
+pub fn parse_date(s: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("'{s}' is not a date like 2024-01-31");
+    let mut parts = s.trim().splitn(3, '-');
+    let mut next = |range: std::ops::RangeInclusive<i64>| {
+        parts
+            .next()
+            .and_then(|part| part.parse::<i64>().ok())
+            .filter(|n| range.contains(n))
+            .ok_or_else(invalid)
+    };
+    let (year, month, day) = (next(1970..=9999)?, next(1..=12)?, next(1..=31)?);
+    // Days since 1970-01-01, counting years from March so that the leap day comes last. See
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(days as u64 * 24 * 60 * 60))
+}
+
+/// Reads a list of songs, one per line, as written by `write_song_list()`. Paths may also be
+/// absolute, as long as they are in the library.
+pub fn read_song_list(
+    path: &Path,
+    library_root: &Path,
+) -> Result<HashSet<PathBuf>, MusicLibraryError> {
+    let list = fs::read_to_string(path).map_err(|e| MusicLibraryError::SongList {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let song = Path::new(line);
+            song.strip_prefix(library_root)
+                .unwrap_or(song)
+                .to_path_buf()
+        })
+        .collect())
+}
+
+/// Writes the songs (relative to the library) to a file, one per line.
+pub fn write_song_list(path: &Path, songs: &[&Path]) -> Result<(), MusicLibraryError> {
+    let list = songs
+        .iter()
+        .map(|song| song.to_string_lossy() + "\n")
+        .collect::<String>();
+    fs::write(path, list).map_err(|e| MusicLibraryError::SongList {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+/// Reads the songs among the files, and finds their album art among the others. Only files
+/// for which `wanted` is true are read as songs.
+fn songs_from_files(
+    library_root: &Path,
+    filenames: &[PathBuf],
+    wanted: impl Fn(&Path) -> bool + Sync,
+    art_name_preference: &[String],
+    include_videos: bool,
+    metadata_cache: Option<&MetadataCache>,
+) -> Vec<Song> {
     // Create an easy-to-access way to find external album art
     let external_album_arts: HashMap<PathBuf, PathBuf> = {
-        let mut m = HashMap::with_capacity(20);
+        let mut per_directory: HashMap<PathBuf, Vec<PathBuf>> = HashMap::with_capacity(20);
         for image_file in filenames
             .iter()
             .filter(|path| is_image_file_album_art(path))
         {
-            // TODO: Instead of picking the first one, sort by quality and prefer the highest
-            // quality one.
             let containing_directory = image_file
                 .parent()
                 .expect("should be able to get containing directory of image file.");
-            m.entry(containing_directory.to_path_buf())
-                .or_insert(image_file.to_path_buf());
+            per_directory
+                .entry(containing_directory.to_path_buf())
+                .or_default()
+                .push(image_file.to_path_buf());
         }
-        m
+        per_directory
+            .into_par_iter()
+            .filter_map(|(directory, mut images)| {
+                // Only bother looking into the images if there is actually a choice.
+                let best = if images.len() == 1 {
+                    images.pop()
+                } else {
+                    let candidates = images.into_iter().map(ArtCandidate::new).collect();
+                    best_album_art(candidates, art_name_preference)
+                };
+                best.map(|image| (directory, image))
+            })
+            .collect()
     };
 
     // Since we are also checking the files for metadata, it is worth doing this in parallel.
@@ -281,13 +711,14 @@ pub fn find_songs_in_library(library_root: &Path) -> Result<Vec<Song>, MusicLibr
             .unwrap()
             .progress_chars("#>-"),
     );
-    let songs = filenames
+    let mut songs = filenames
         .par_iter()
         // If it is a song file, the processing might take a while because metadata needs to be
         // parsed. If it is not a music file, it will be done very quickly though. Maybe set up
         // some sort of chunking here? Realistically that shouldn't be necessary, because the
         // majority of files in a directory should be music files.
         .progress_with(pb.clone())
+        .filter(|path| wanted(path))
         .filter_map(|path| {
             let Some(filetype) = identify_file_type(path) else {
                 log_failure(
@@ -306,8 +737,10 @@ pub fn find_songs_in_library(library_root: &Path) -> Result<Vec<Song>, MusicLibr
                 FileType::Art => return None,
                 FileType::Meta => return None,
                 FileType::Playlist => return None,
+                FileType::Video if include_videos => (),
+                FileType::Video => return None,
             };
-            match process_song_file(path, library_root, &external_album_arts) {
+            match process_song_file(path, library_root, &external_album_arts, metadata_cache) {
                 Ok(song) => Some(song),
                 Err(e) => {
                     log_failure(
@@ -319,13 +752,60 @@ pub fn find_songs_in_library(library_root: &Path) -> Result<Vec<Song>, MusicLibr
             }
         })
         .collect::<Vec<_>>();
-    Ok(songs)
+
+    // Some libraries keep art in separate files named after the album, instead of in the album
+    // folder.
+    let loose_album_arts = filenames
+        .iter()
+        .filter(|path| {
+            identify_file_type(path) == Some(FileType::Art) && !is_image_file_album_art(path)
+        })
+        .collect_vec();
+    link_loose_album_art(&mut songs, &loose_album_arts);
+    songs
+}
+
+/// Album names are written slightly differently in file names than in tags, e.g. because of
+/// characters that are not allowed in file names.
+fn album_key(album: &str) -> String {
+    album
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Finds image files named after the album ("Album Name.jpg") for songs that don't have any art,
+/// and uses those as their external album art.
+fn link_loose_album_art(songs: &mut [Song], loose_album_arts: &[&PathBuf]) {
+    if loose_album_arts.is_empty() {
+        return;
+    }
+    let by_album: HashMap<String, &PathBuf> = loose_album_arts
+        .iter()
+        .filter_map(|path| {
+            let key = album_key(&path.file_stem()?.to_string_lossy());
+            (!key.is_empty()).then_some((key, *path))
+        })
+        .collect();
+    for song in songs
+        .iter_mut()
+        .filter(|song| song.has_artwork() == ArtworkType::None)
+    {
+        let Some(album) = song.metadata.album() else {
+            continue;
+        };
+        if let Some(art) = by_album.get(&album_key(album)) {
+            song.external_album_art = Some(art.to_path_buf());
+        }
+    }
 }
 
 fn process_song_file(
     song_path: &Path,
     source_library: &Path,
     external_album_arts: &HashMap<PathBuf, PathBuf>,
+    metadata_cache: Option<&MetadataCache>,
 ) -> Result<Song, MusicLibraryError> {
     debug_assert!(matches!(
         identify_file_type(song_path).unwrap(),
@@ -344,11 +824,16 @@ fn process_song_file(
             external_album_arts.get(one_folder_up)
         })
         .cloned();
-    Song::new(
+    let metadata = match metadata_cache {
+        Some(cache) => cache.parse_file(song_path)?,
+        None => SongMetaData::parse_file(song_path)?,
+    };
+    Ok(Song::with_metadata(
         song_path.to_path_buf(),
-        source_library.to_path_buf(),
+        source_library,
         external_album_art,
-    )
+        metadata,
+    ))
 }
 
 /// Where to put the synchronised copy
@@ -357,12 +842,22 @@ pub fn get_shadow_filename(
     target_library: &Path,
     // TODO: Change to FileType, so I can re-use the same code for images.
     filetype: &MusicFileType,
+    path_options: &TargetPathOptions,
 ) -> PathBuf {
-    target_library.join(library_relative_path.with_extension(filetype.to_string()))
+    let with_new_extension = match filetype {
+        // The song keeps its format, so also its extension.
+        MusicFileType::Copy => library_relative_path.to_path_buf(),
+        _ => library_relative_path.with_extension(filetype.to_string()),
+    };
+    target_library.join(target_relative_path(
+        &with_new_extension,
+        target_library,
+        path_options,
+    ))
 }
 
 /// How to handle album art
-#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum, Debug)]
 pub enum ArtStrategy {
     /// Remove all embedded album art, and don't copy album art files.
     None,
@@ -372,6 +867,61 @@ pub enum ArtStrategy {
     PreferFile,
     /// Do not embed any cover art: Discard all existing embedded art, only keep cover.jpg if it exists.
     FileOnly,
+    /// Do not embed any cover art, but save the embedded art to a cover.jpg next to the songs if
+    /// there is no cover.jpg (or similar) yet. Makes the songs smaller, and works for players
+    /// that only look at art files.
+    ExtractToFile,
+}
+
+/// Amount of channels to mix down to.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum Downmix {
+    Stereo,
+    /// For e.g. audiobooks.
+    Mono,
+}
+
+impl Downmix {
+    pub fn channels(&self) -> u32 {
+        match self {
+            Downmix::Stereo => 2,
+            Downmix::Mono => 1,
+        }
+    }
+}
+
+/// How songs that don't need to be transcoded end up in the target library.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum LinkMode {
+    /// A normal copy.
+    Copy,
+    /// Takes no extra space, but the target is the same file as the source: changing the tags
+    /// of one changes the other too. Only works if the target is on the same filesystem.
+    Hardlink,
+    /// A copy that shares its data with the source until either is changed, so it takes no
+    /// extra space. Only works on filesystems that support it, like Btrfs, XFS and APFS.
+    Reflink,
+}
+
+/// Which songs are transcoded, and which are copied as they are.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum CodecPolicy {
+    /// Copy songs with a lower bitrate than the target, because transcoding them would only
+    /// lose quality without making them smaller.
+    Bitrate,
+    /// Only transcode lossless songs (like FLAC, ALAC or WAV), and copy songs that are already
+    /// lossy (like MP3 or AAC) regardless of their bitrate, because every lossy encode loses
+    /// quality. Songs of which the codec is unknown fall back to the bitrate.
+    TranscodeLossless,
+}
+
+/// What to do with embedded art that takes up too much space.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum OversizedArt {
+    /// Re-encode it as a JPEG, scaled down if no --embed-art-resolution is given.
+    Recompress,
+    /// Don't embed it at all.
+    Drop,
 }
 
 /// gets the path relative to the library.
@@ -382,28 +932,366 @@ pub fn library_relative_path(full_path: &Path, source_library: &Path) -> PathBuf
         .to_path_buf()
 }
 
-/// Returns the path to the new cover art if the file is copied over.
+/// Finds the file at the library relative path, also if its name is in a different unicode
+/// normalisation form on the filesystem than in the path, like the normalised keys of the
+/// records. None if it is not there.
+pub fn find_in_library(library: &Path, library_relative_path: &Path) -> Option<PathBuf> {
+    let exact = library.join(library_relative_path);
+    if exact.exists() {
+        return Some(exact);
+    }
+    let same_name = |a: &Path, b: &Path| {
+        NormalizationForm::Nfc.normalize_path(a) == NormalizationForm::Nfc.normalize_path(b)
+    };
+    let mut found = library.to_path_buf();
+    for component in library_relative_path.components() {
+        let name = Path::new(component.as_os_str());
+        found = if found.join(name).exists() {
+            found.join(name)
+        } else {
+            fs::read_dir(&found)
+                .ok()?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .find(|path| {
+                    path.file_name()
+                        .is_some_and(|n| same_name(Path::new(n), name))
+                })?
+        };
+    }
+    Some(found)
+}
+
+/// What to do with album art that is byte-identical to art in a folder next to it, like
+/// the same cover.jpg in the CD1 and CD2 folders of an album.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum ArtDeduplication {
+    /// Make a hard link to the art that is already there, so it only takes up space once.
+    /// Needs a target filesystem that supports hard links.
+    Hardlink,
+    /// Don't copy the art. Only use this if your player also looks for art in parent folders!
+    Skip,
+}
+
+/// What to do with songs that are identical to another song in the source library, like a
+/// single that is also on a compilation.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum, Debug)]
+pub enum SongDeduplication {
+    /// Make a hard link to the shadow of the other song, so it only takes up space once.
+    /// Needs a target filesystem that supports hard links.
+    Hardlink,
+    /// Don't synchronise the song at all.
+    Skip,
+}
+
+/// Keeps track of the album art in the target library, so duplicates can be found, and every art
+/// file is only written once per sync.
+#[derive(Default)]
+pub struct CopiedArt {
+    /// Hashes of the source art files, so they don't need to be re-hashed for every song.
+    source_hashes: HashMap<PathBuf, Option<u64>>,
+    /// Where art with the given hash was put in the given album tree.
+    by_hash: HashMap<(u64, PathBuf), PathBuf>,
+    /// The art files in the target library that were handled in this sync, also in a dry run.
+    handled: HashSet<PathBuf>,
+    /// Hashes of the source art files when they were last copied, according to the records.
+    previous_hashes: HashMap<PathBuf, u64>,
+}
+
+impl CopiedArt {
+    /// Also knows from the records which art was copied before, to find art of which the source
+    /// changed since.
+    pub fn new(previous_sync_db: Option<&PreviousSyncDb>, source_library: &Path) -> CopiedArt {
+        CopiedArt {
+            previous_hashes: previous_sync_db
+                .iter()
+                .flat_map(|db| db.values())
+                .filter(|record| record.art)
+                .filter_map(|record| {
+                    Some((
+                        find_in_library(source_library, &record.library_relative_path)?,
+                        record.hash?,
+                    ))
+                })
+                .collect(),
+            ..CopiedArt::default()
+        }
+    }
+
+    /// Whether the art that is already at `shadow` is older than its source. Without a record,
+    /// only the modification times can tell.
+    fn is_outdated(&mut self, source: &Path, shadow: &Path) -> bool {
+        match self.previous_hashes.get(source).copied() {
+            Some(previous) => self.hash_of(source).is_some_and(|hash| hash != previous),
+            None => {
+                let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+                modified(source) > modified(shadow)
+            }
+        }
+    }
+
+    fn hash_of(&mut self, source: &Path) -> Option<u64> {
+        *self
+            .source_hashes
+            .entry(source.to_path_buf())
+            .or_insert_with(|| hash_file(source))
+    }
+
+    /// Disc folders of a multi-disc album, like "CD1" and "CD2", are in the same album tree.
+    /// Any other folder is an album tree of its own, so albums that are next to each other (like
+    /// the albums of an artist) don't share art.
+    fn key(hash: u64, shadow: &Path) -> Option<(u64, PathBuf)> {
+        let folder = shadow.parent()?;
+        let is_disc_folder = folder
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(disc_number)
+            .is_some();
+        let album_tree = match folder.parent() {
+            Some(album) if is_disc_folder => album,
+            _ => folder,
+        };
+        Some((hash, album_tree.to_path_buf()))
+    }
+
+    /// Where art with the same contents was already put in this album tree, if anywhere.
+    fn find(&mut self, source: &Path, shadow: &Path) -> Option<PathBuf> {
+        let key = Self::key(self.hash_of(source)?, shadow)?;
+        self.by_hash.get(&key).cloned()
+    }
+
+    fn register(&mut self, source: &Path, shadow: &Path) {
+        let Some(key) = self
+            .hash_of(source)
+            .and_then(|hash| Self::key(hash, shadow))
+        else {
+            return;
+        };
+        self.by_hash.entry(key).or_insert(shadow.to_path_buf());
+    }
+}
+
+/// Returns the path to the new cover art if the file is copied over (or would be, in a dry run).
+/// Art that is already there is copied again if its source changed.
+/// `song_shadow` is where the song itself ends up in the target library.
+/// `copied_art` should be the same for all songs. It is only locked to look up and register
+/// art, so songs of different albums can copy their art at the same time.
 pub fn copy_dedicated_cover_art_for_song(
     song: &Song,
+    song_shadow: &Path,
     source_library: &Path,
     target_library: &Path,
-    dry_run: bool,
+    settings: &SyncSettings,
+    copied_art: &Mutex<CopiedArt>,
 ) -> Result<Option<PathBuf>, MusicLibraryError> {
+    let path_options = &settings.target_paths;
+    // There are no album folders anymore to put the art in, and audiobooks don't get any art.
+    if path_options.flatten.is_some() || settings.is_audiobook(song) {
+        return Ok(None);
+    }
     let Some(path) = &song.external_album_art else {
+        if settings.art_strategy == ArtStrategy::ExtractToFile
+            && song.metadata.has_embedded_album_art
+        {
+            return extract_embedded_art_for_song(
+                song,
+                song_shadow,
+                target_library,
+                settings,
+                copied_art,
+            );
+        }
         return Ok(None);
     };
 
-    let relative_path = path.strip_prefix(source_library).unwrap();
-    let shadow = target_library.join(relative_path);
-    // TODO: Return error on something that is not a "file already exists"
-    if !fs::exists(&shadow).unwrap() {
-        if !dry_run {
-            let _ = std::fs::copy(path, &shadow);
+    // Only convert if it is not already in the right format.
+    // Art that is not in the folder of the song (or the one above it) was matched on the album
+    // name. It can't mirror where it is in the source library, because it would not end up
+    // with the songs.
+    let is_loose = !song
+        .absolute_path
+        .ancestors()
+        .skip(1)
+        .take(2)
+        .any(|dir| path.parent() == Some(dir));
+    let convert_to = settings
+        .art_file_format
+        .filter(|format| !format.is_format_of(path));
+    let with_extension = |p: &Path| {
+        let extension = match convert_to {
+            Some(format) => format.extension().to_owned(),
+            None => path
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
+        };
+        // Loose art is named after the album, which players won't recognise as album art.
+        let art_file_name = settings
+            .art_file_name
+            .as_deref()
+            .or(is_loose.then_some("cover"));
+        match art_file_name {
+            // All art gets the same name, so whatever was found in the source library (Front.jpeg,
+            // folder.png, ...) ends up as one file. If multiple songs in the same target folder
+            // have different art, the first one that is copied wins.
+            Some(name) => {
+                // Players looking for cover.jpg don't always recognise cover.jpeg.
+                let extension = if extension == "jpeg" {
+                    "jpg"
+                } else {
+                    &extension
+                };
+                p.with_file_name(name).with_extension(extension)
+            }
+            None if convert_to.is_some() => p.with_extension(extension),
+            None => p.to_path_buf(),
         }
-        Ok(Some(shadow))
+    };
+
+    let shadow = if path_options.uses_layout() || path_options.merge_discs.is_some() || is_loose {
+        // The song can end up anywhere, so the art can't mirror where it is in the source
+        // library. Put it right next to the song instead.
+        let art_name = with_extension(Path::new(
+            path.file_name().expect("art should have a file name"),
+        ));
+        song_shadow
+            .parent()
+            .expect("Cannot get parent dir of shadow")
+            .join(target_relative_path(
+                &art_name,
+                target_library,
+                path_options,
+            ))
     } else {
-        Ok(None)
+        let relative_path = with_extension(path.strip_prefix(source_library).unwrap());
+        target_library.join(target_relative_path(
+            &relative_path,
+            target_library,
+            path_options,
+        ))
+    };
+    let lock = || copied_art.lock().expect("art lock should not be poisoned");
+    let (outdated, missing, existing_duplicate) = {
+        let mut copied_art = lock();
+        // Most songs share their art with the rest of the album. Claiming the shadow here also
+        // makes sure that no other song writes to it.
+        if !copied_art.handled.insert(shadow.clone()) {
+            return Ok(None);
+        }
+        let exists = fs::exists(&shadow).unwrap();
+        let outdated = exists && copied_art.is_outdated(path, &shadow);
+        let missing = !exists || outdated;
+        let existing_duplicate = match settings.art_deduplication {
+            Some(_) if missing => copied_art
+                .find(path, &shadow)
+                .filter(|original| *original != shadow),
+            _ => None,
+        };
+        // Art that still has to be copied is only registered once it is there, so that nothing
+        // is linked to it before.
+        if settings.art_deduplication.is_some() && !missing {
+            copied_art.register(path, &shadow);
+        }
+        (outdated, missing, existing_duplicate)
+    };
+    if outdated && !settings.dry_run {
+        settings
+            .disposal
+            .set_aside(&shadow)
+            .map_err(|source| MusicLibraryError::SetAside {
+                path: shadow.clone(),
+                source,
+            })?;
+    }
+    let art_error = |source| MusicLibraryError::AlbumArt {
+        path: shadow.clone(),
+        source,
+    };
+    if let Some(original) = existing_duplicate {
+        return match settings.art_deduplication {
+            Some(ArtDeduplication::Hardlink) => {
+                if !settings.dry_run {
+                    // Can't link over an existing file.
+                    let _ = fs::remove_file(&shadow);
+                    fs::hard_link(&original, &shadow).map_err(art_error)?;
+                }
+                Ok(Some(shadow))
+            }
+            _ => Ok(None),
+        };
     }
+
+    if !missing {
+        return Ok(None);
+    }
+    if !settings.dry_run {
+        let _ = fs::create_dir_all(shadow.parent().expect("Cannot get parent dir of art"));
+        let needs_reencode = convert_to.is_some()
+            || settings.art_file_resolution > 0
+            || settings.art_file_quality.is_some();
+        if !needs_reencode {
+            fs::copy(path, &shadow).map_err(art_error)?;
+        } else {
+            convert_art(
+                path,
+                &shadow,
+                settings.art_file_resolution,
+                settings.art_file_quality,
+            )
+            .map_err(|source| MusicLibraryError::ConvertAlbumArt {
+                path: path.clone(),
+                source,
+            })?;
+        }
+    }
+    if settings.art_deduplication.is_some() {
+        lock().register(path, &shadow);
+    }
+    Ok(Some(shadow))
+}
+
+/// Saves the art embedded in the song as a separate file next to the song in the target library.
+/// Returns the path to the new art file, if it was not there yet.
+fn extract_embedded_art_for_song(
+    song: &Song,
+    song_shadow: &Path,
+    target_library: &Path,
+    settings: &SyncSettings,
+    copied_art: &Mutex<CopiedArt>,
+) -> Result<Option<PathBuf>, MusicLibraryError> {
+    let format = settings.art_file_format.unwrap_or(ArtFormat::Jpeg);
+    let name = settings.art_file_name.as_deref().unwrap_or("cover");
+    let art_name = PathBuf::from(name).with_extension(format.extension());
+    let shadow = song_shadow
+        .parent()
+        .expect("Cannot get parent dir of shadow")
+        .join(target_relative_path(
+            &art_name,
+            target_library,
+            &settings.target_paths,
+        ));
+    // All the songs in an album usually have the same art, so only the first one is used.
+    let is_new = copied_art
+        .lock()
+        .expect("art lock should not be poisoned")
+        .handled
+        .insert(shadow.clone());
+    if !is_new || fs::exists(&shadow).unwrap() {
+        return Ok(None);
+    }
+    if !settings.dry_run {
+        let _ = fs::create_dir_all(shadow.parent().expect("Cannot get parent dir of art"));
+        convert_art(
+            &song.absolute_path,
+            &shadow,
+            settings.art_file_resolution,
+            settings.art_file_quality,
+        )
+        .map_err(|source| MusicLibraryError::ConvertAlbumArt {
+            path: song.absolute_path.clone(),
+            source,
+        })?;
+    }
+    Ok(Some(shadow))
 }
 
 #[derive(thiserror::Error)]
@@ -414,7 +1302,9 @@ pub enum MusicLibraryError {
     #[error("Could not get last modified time for the source file")]
     SourceModifiedTime(#[source] std::io::Error),
 
-    #[error("Could not get the file creation time for the already existing shadow copy")]
+    #[error(
+        "Could not get the file creation or modification time for the already existing shadow copy"
+    )]
     TargetCreatedTime(#[source] std::io::Error),
 
     #[error("Tried to discover albums in directory '{path}', but that is not a directory.")]
@@ -437,6 +1327,133 @@ pub enum MusicLibraryError {
 
     #[error("ffmpeg does not have the required capabilities.")]
     Capability(#[from] FfmpegCapabilityError),
+
+    #[error("Could not synchronise to the phone.")]
+    Adb(#[from] AdbError),
+
+    #[error("Could not read or write the records of previous syncs.")]
+    Records(#[from] RecordsError),
+
+    #[error("{count} places in the target library would be claimed by more than one song. Rename the songs, or pick a different --on-collision.")]
+    TargetPathCollisions { count: usize },
+
+    #[error("Another sync to this target library is still running{}. Wait for it to finish, or use --wait-for-lock.", pid.map(|pid| format!(" (process {pid})")).unwrap_or_default())]
+    TargetLibraryLocked { pid: Option<u32> },
+
+    #[error("Could not lock the target library with {path}")]
+    Lock {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("{path} is not synchronised, because the limit of songs to change is reached")]
+    ChangeLimitReached { path: PathBuf },
+
+    #[error("Could not read or write the list of songs {path}")]
+    SongList {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Could not write the report to {path}")]
+    Report {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Could not use the plan {path}: {msg}")]
+    Plan { path: PathBuf, msg: String },
+
+    #[error("{path} changed since the plan was made, so it is skipped. Make a new plan to synchronise it.")]
+    ChangedSincePlan { path: PathBuf },
+
+    #[error("Could not copy the song to {path}")]
+    CopySong {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not copy album art to {path}")]
+    AlbumArt {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not convert the album art of {path}")]
+    ConvertAlbumArt {
+        path: PathBuf,
+        #[source]
+        source: FfmpegError,
+    },
+
+    #[error("Could not give {path} the extended attributes and permissions of its source")]
+    PreserveAttributes {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not give {path} the modification time of its source")]
+    PreserveModifiedTime {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not move {path} into the backup or the trash before replacing it")]
+    SetAside {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not note a change to {path} in the journal")]
+    Journal {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not undo the last sync to {path}")]
+    Undo {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not remove the oldest backups in {path}")]
+    Backup {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not set the mode or owner of the files in {path}")]
+    Permissions {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not set the umask")]
+    Umask(#[source] std::io::Error),
+
+    #[error("Could not remove the empty folders in {path}")]
+    EmptyDirs {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Could not create the directory {path} to transcode to")]
+    ScratchDirectory {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
 }
 
 // Show the error that caused this error (chain) when debug formatting.
@@ -452,8 +1469,360 @@ impl std::fmt::Debug for MusicLibraryError {
 
 #[cfg(test)]
 mod tests {
-    use super::MusicLibraryError;
+    use super::{
+        best_album_art, link_loose_album_art, ArtCandidate, CopiedArt, MusicLibraryError,
+        DEFAULT_ART_NAME_PREFERENCE,
+    };
+    use crate::{song::Song, test_data::TestFile};
+    use std::path::{Path, PathBuf};
 
     // miette::Diagnostic/ miette::Result is only used in tests, so can't use the derive macro.
     impl miette::Diagnostic for MusicLibraryError {}
+
+    #[test]
+    fn parse_since_date() {
+        use super::parse_date;
+        use std::time::{Duration, SystemTime};
+        let days = |days: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(days * 86_400);
+        assert_eq!(parse_date("1970-01-01"), Ok(days(0)));
+        assert_eq!(parse_date("2000-03-01"), Ok(days(11_017)));
+        assert_eq!(parse_date("2024-01-01"), Ok(days(19_723)));
+        assert!(parse_date("2024-13-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn sniff_magic_bytes() {
+        use super::{file_type_from_magic_bytes, FileType};
+        let music = [
+            &b"ID3\x04\x00"[..],
+            b"fLaC\x00\x00\x00\x22",
+            b"OggS\x00\x02",
+            b"RIFF\x24\x08\x00\x00WAVEfmt ",
+            b"FORM\x00\x00\x45\x2eAIFFCOMM",
+            b"\x00\x00\x00\x20ftypM4A ",
+            &[0xFF, 0xFB, 0x90, 0x64],
+        ];
+        for header in music {
+            assert!(
+                file_type_from_magic_bytes(header) == Some(FileType::Music),
+                "{header:?} should be music"
+            );
+        }
+        assert!(file_type_from_magic_bytes(&[0xFF, 0xD8, 0xFF, 0xE0]) == Some(FileType::Art));
+        assert!(file_type_from_magic_bytes(b"\x00\x00\x00\x20ftypisom") == Some(FileType::Video));
+        assert!(file_type_from_magic_bytes(b"just some text").is_none());
+        assert!(file_type_from_magic_bytes(b"").is_none());
+    }
+
+    #[test]
+    /// Rips without extension are recognised by their contents.
+    fn identify_without_extension() {
+        use super::{identify_file_type, FileType};
+        let dir = PathBuf::from(format!(
+            "/tmp/syncbops/sniff_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Track 01");
+        std::fs::copy(TestFile::FlacWithoutArt.path(), &path).unwrap();
+        assert!(identify_file_type(&path) == Some(FileType::Music));
+        let path = dir.join("Track 01.MP3");
+        std::fs::copy(TestFile::Mp3CBRWithoutArt.path(), &path).unwrap();
+        assert!(identify_file_type(&path) == Some(FileType::Music));
+    }
+
+    #[test]
+    /// ffmpeg can decode all of these, so they can all be synchronised.
+    fn identify_music_formats() {
+        use super::{identify_file_type, FileType};
+        let dir = PathBuf::from(format!(
+            "/tmp/syncbops/formats_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        for extension in [
+            "mp3", "m4a", "ogg", "flac", "opus", "wav", "aiff", "aif", "ape", "wv", "wma", "mpc",
+            "dsf", "dff",
+        ] {
+            let path = dir.join(format!("song.{extension}"));
+            std::fs::write(&path, []).unwrap();
+            assert!(
+                identify_file_type(&path) == Some(FileType::Music),
+                "{extension} should be a music file"
+            );
+        }
+    }
+
+    fn candidate(path: &str, resolution: Option<(u32, u32)>, file_size: u64) -> ArtCandidate {
+        ArtCandidate {
+            path: path.into(),
+            resolution,
+            file_size,
+        }
+    }
+
+    fn preference() -> Vec<String> {
+        DEFAULT_ART_NAME_PREFERENCE
+            .split(',')
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn best_art_by_resolution() {
+        let best = best_album_art(
+            vec![
+                candidate("a/cover.jpg", Some((600, 600)), 100_000),
+                candidate("a/folder.png", Some((1200, 1200)), 50_000),
+                candidate("a/front.jpg", None, 900_000),
+            ],
+            &preference(),
+        );
+        assert_eq!(best, Some(PathBuf::from("a/folder.png")));
+    }
+
+    #[test]
+    /// Same resolution, so the least compressed one wins.
+    fn best_art_by_file_size() {
+        let best = best_album_art(
+            vec![
+                candidate("a/cover.jpg", Some((600, 600)), 100_000),
+                candidate("a/folder.png", Some((600, 600)), 400_000),
+            ],
+            &preference(),
+        );
+        assert_eq!(best, Some(PathBuf::from("a/folder.png")));
+    }
+
+    #[test]
+    fn best_art_by_name_preference() {
+        let candidates = || {
+            vec![
+                candidate("a/Folder.jpg", Some((600, 600)), 100_000),
+                candidate("a/cover.jpg", Some((600, 600)), 100_000),
+            ]
+        };
+        assert_eq!(
+            best_album_art(candidates(), &preference()),
+            Some(PathBuf::from("a/cover.jpg"))
+        );
+        assert_eq!(
+            best_album_art(candidates(), &["folder".to_owned()]),
+            Some(PathBuf::from("a/Folder.jpg"))
+        );
+    }
+
+    #[test]
+    /// The same art in disc folders is a duplicate, but not in another album.
+    fn duplicate_art_in_album_tree() {
+        let art = TestFile::Jpg600.path();
+        let mut copied_art = CopiedArt::default();
+        copied_art.register(&art, Path::new("/target/Album/CD1/cover.jpg"));
+        assert_eq!(
+            copied_art.find(&art, Path::new("/target/Album/CD2/cover.jpg")),
+            Some(PathBuf::from("/target/Album/CD1/cover.jpg"))
+        );
+        assert_eq!(
+            copied_art.find(&art, Path::new("/target/Other/CD1/cover.jpg")),
+            None
+        );
+
+        // Two albums of the same artist.
+        copied_art.register(&art, Path::new("/target/Artist/First/cover.jpg"));
+        assert_eq!(
+            copied_art.find(&art, Path::new("/target/Artist/Second/cover.jpg")),
+            None
+        );
+        // Songs right in the target library, without album folders.
+        copied_art.register(&art, Path::new("/target/cover.jpg"));
+        assert_eq!(
+            copied_art.find(&art, Path::new("/target/Artist/cover.jpg")),
+            None
+        );
+    }
+
+    #[test]
+    /// Art is only copied once for an album, also in a dry run, and again when its source
+    /// changed since it was copied according to the records.
+    fn copy_album_art_once_and_when_changed() {
+        use super::{
+            copy_dedicated_cover_art_for_song, ArtStrategy, Id3Tags, MusicFileType, UpdateType,
+        };
+        use crate::{
+            hashing::{register_record_to_previous_sync_db, PreviousSyncDb, SyncRecord},
+            sync_song::SyncSettings,
+        };
+        use std::{fs, sync::Mutex};
+        let root = PathBuf::from(format!(
+            "/tmp/syncbops/test_album_art_{}",
+            random_string::generate(24, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let (source_library, target_library) = (root.join("source"), root.join("target"));
+        let art = source_library.join("Album/cover.jpg");
+        fs::create_dir_all(art.parent().unwrap()).unwrap();
+        fs::create_dir_all(target_library.join("Album")).unwrap();
+        fs::copy(TestFile::Jpg600.path(), &art).unwrap();
+        let songs = ["Album/1.mp3", "Album/2.mp3"].map(|path| {
+            let mut song = Song::new_fake(path, &[]);
+            song.absolute_path = source_library.join(path);
+            song.external_album_art = Some(art.clone());
+            song
+        });
+        let mut settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::FileOnly,
+        );
+        let copy_all = |settings: &SyncSettings, copied_art: CopiedArt| {
+            let copied_art = Mutex::new(copied_art);
+            songs
+                .iter()
+                .filter_map(|song| {
+                    copy_dedicated_cover_art_for_song(
+                        song,
+                        &target_library.join(&song.library_relative_path),
+                        &source_library,
+                        &target_library,
+                        settings,
+                        &copied_art,
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let shadow = target_library.join("Album/cover.jpg");
+
+        settings.dry_run = true;
+        assert_eq!(
+            copy_all(&settings, CopiedArt::default()),
+            [shadow.as_path()]
+        );
+        assert!(!shadow.exists());
+
+        settings.dry_run = false;
+        assert_eq!(
+            copy_all(&settings, CopiedArt::default()),
+            [shadow.as_path()]
+        );
+        let mut records = PreviousSyncDb::new();
+        register_record_to_previous_sync_db(
+            &mut records,
+            SyncRecord::from_art(
+                Path::new("Album/cover.jpg"),
+                &art,
+                Path::new("Album/cover.jpg"),
+            )
+            .set_update_type(UpdateType::Copied),
+        );
+        assert!(copy_all(&settings, CopiedArt::new(Some(&records), &source_library)).is_empty());
+
+        fs::write(&art, b"new cover").unwrap();
+        assert_eq!(
+            copy_all(&settings, CopiedArt::new(Some(&records), &source_library)),
+            [shadow.as_path()]
+        );
+        assert_eq!(fs::read(&shadow).unwrap(), b"new cover");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn loose_art_matched_on_album() {
+        let mut songs = vec![
+            Song::new_fake("a/1.mp3", &[("album", "AC/DC: Live!")]),
+            Song::new_fake("b/1.mp3", &[("album", "Something else")]),
+        ];
+        let art = PathBuf::from("/library/Artwork/ACDC Live.jpg");
+        link_loose_album_art(&mut songs, &[&art]);
+        assert_eq!(songs[0].external_album_art, Some(art));
+        assert_eq!(songs[1].external_album_art, None);
+    }
+
+    #[test]
+    /// A list of failed songs can be read back, also with absolute paths in it.
+    fn song_list_roundtrip() -> miette::Result<()> {
+        use super::{read_song_list, write_song_list};
+        let path = std::env::temp_dir().join(format!(
+            "syncbops_songs_{}.txt",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let library = Path::new("/library");
+        write_song_list(&path, &[Path::new("a/1.flac"), Path::new("b/2.flac")])?;
+        let mut list = std::fs::read_to_string(&path).unwrap();
+        list.push_str("\n/library/c/3.flac\n");
+        std::fs::write(&path, list).unwrap();
+        let songs = read_song_list(&path, library)?;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(songs.len(), 3);
+        assert!(songs.contains(Path::new("c/3.flac")));
+        Ok(())
+    }
+
+    #[test]
+    /// A song of which the path is normalised for its record is listed as it is on the
+    /// filesystem, so that it is found again.
+    fn song_list_of_normalised_song() -> miette::Result<()> {
+        use super::{find_listed_songs, read_song_list, write_song_list};
+        use crate::target_path::NormalizationForm;
+        let root = std::env::temp_dir().join(format!(
+            "syncbops_songs_nfd_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let folder = root.join("Bjo\u{308}rk");
+        std::fs::create_dir_all(&folder).unwrap();
+        let file = folder.join("01.flac");
+        std::fs::copy(TestFile::FlacWithoutArt.path(), &file).unwrap();
+        let mut song = Song::new(file.clone(), root.clone(), None)?;
+        song.library_relative_path =
+            NormalizationForm::Nfc.normalize_path(&song.library_relative_path);
+        assert_eq!(song.library_relative_path, Path::new("Bj\u{f6}rk/01.flac"));
+
+        let list = root.join("failed.txt");
+        write_song_list(&list, &[&song.source_relative_path(&root)])?;
+        let listed = read_song_list(&list, &root)?;
+        let songs = find_listed_songs(&root, &listed, &[], false, None, None);
+        let _ = std::fs::remove_dir_all(&root);
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].absolute_path, file);
+        Ok(())
+    }
+
+    #[test]
+    /// Changes are coloured like a diff, unchanged songs are left alone.
+    fn update_type_colours() {
+        use super::UpdateType;
+        let styled = |update_type: UpdateType| {
+            update_type
+                .style("song.flac")
+                .force_styling(true)
+                .to_string()
+        };
+        assert!(styled(UpdateType::NewTranscode).starts_with("\u{1b}[32m"));
+        assert!(styled(UpdateType::Overwrite).starts_with("\u{1b}[33m"));
+        assert_eq!(styled(UpdateType::NoChange), "song.flac");
+    }
+
+    #[test]
+    /// MP3s get ID3v2.3 tags unless asked otherwise, also in records from before it could be.
+    fn id3_options() {
+        use super::{Id3Tags, Id3Version, MusicFileType};
+        use crate::transcode_rules::parse_target_filetype;
+        let id3 = |filetype: &str| parse_target_filetype(filetype).unwrap().id3();
+        assert_eq!(id3("mp3-vbr"), Some(Id3Tags::default()));
+        assert_eq!(
+            id3("mp3-cbr --bitrate 192 --id3-version 2.4 --id3v1"),
+            Some(Id3Tags {
+                id3_version: Id3Version::V2_4,
+                id3v1: true
+            })
+        );
+        assert_eq!(id3("flac"), None);
+        assert!(parse_target_filetype("mp3-vbr --id3-version 2.2").is_err());
+
+        let recorded: MusicFileType =
+            serde_json::from_str(r#"{"Mp3CBR":{"bitrate":192}}"#).unwrap();
+        assert_eq!(recorded.id3(), Some(Id3Tags::default()));
+    }
 }
@@ -0,0 +1,229 @@
+use crate::ffmpeg_interface::{title_from_tags, EmbeddedPicture, SongMetaData};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+use symphonia::core::{
+    codecs::{CODEC_TYPE_FLAC, CODEC_TYPE_MP3},
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardVisualKey},
+    probe::Hint,
+};
+
+/// What ffmpeg calls the most common ID3v2 frames. Frames that are not in here keep their ID.
+const ID3V2_FRAME_NAMES: [(&str, &str); 15] = [
+    ("TIT2", "title"),
+    ("TPE1", "artist"),
+    ("TPE2", "album_artist"),
+    ("TALB", "album"),
+    ("TRCK", "track"),
+    ("TPOS", "disc"),
+    ("TCON", "genre"),
+    ("TYER", "date"),
+    ("TDRC", "date"),
+    ("TCMP", "compilation"),
+    ("TCOM", "composer"),
+    ("TPUB", "publisher"),
+    ("TCOP", "copyright"),
+    ("TSSE", "encoder"),
+    ("TIT1", "grouping"),
+];
+
+/// Reads the metadata of FLAC and MP3 files in-process, which is a lot faster than starting
+/// ffprobe for every song. The result is the same as what ffprobe would give. None for other
+/// formats, or if something can't be determined, so that ffprobe can be used instead.
+pub fn parse_natively(path: &Path) -> Option<SongMetaData> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    if extension != "flac" && extension != "mp3" {
+        return None;
+    }
+    let file_size = std::fs::metadata(path).ok()?.len();
+    let source = MediaSourceStream::new(Box::new(File::open(path).ok()?), Default::default());
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            Hint::new().with_extension(&extension),
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()?;
+
+    let params = &probed.format.default_track()?.codec_params;
+    let codec = match params.codec {
+        CODEC_TYPE_FLAC => "flac",
+        CODEC_TYPE_MP3 => "mp3",
+        _ => return None,
+    };
+    let sample_rate = params.sample_rate;
+    let channels = params.channels.map(|channels| channels.count() as u32);
+    // Lossy formats don't have a bit depth.
+    let bit_depth = params.bits_per_sample.filter(|_| codec == "flac");
+    let duration =
+        Duration::try_from_secs_f64(params.n_frames? as f64 / sample_rate? as f64).ok()?;
+
+    // Tags can be in front of the audio (ID3v2), or in the audio container itself (FLAC).
+    let mut tags = HashMap::new();
+    let mut embedded_pictures = Vec::new();
+    let mut read_revision = |revision: &MetadataRevision| {
+        for tag in revision.tags() {
+            tags.insert(ffmpeg_tag_name(&tag.key), tag.value.to_string());
+        }
+        for visual in revision.visuals() {
+            embedded_pictures.push(EmbeddedPicture {
+                // ffmpeg puts the pictures in the streams after the audio, in the same order.
+                stream_index: embedded_pictures.len() + 1,
+                resolution: imagesize::blob_size(&visual.data)
+                    .ok()
+                    .map(|size| (size.width as u32, size.height as u32)),
+                is_front_cover: visual.usage == Some(StandardVisualKey::FrontCover),
+            });
+        }
+    };
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        read_revision(revision);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        read_revision(revision);
+    }
+    // ID3v1 tags (or APE tags) are not read, so leave those to ffprobe.
+    if tags.is_empty() {
+        return None;
+    }
+
+    let bitrate_kbps = match codec {
+        "mp3" => mp3_bitrate(path, duration)?,
+        // ffprobe gives the bitrate of the whole file, including the art.
+        _ => (file_size as f64 * 8. / duration.as_secs_f64()) as u32 / 1000,
+    };
+
+    let has_embedded_album_art = !embedded_pictures.is_empty();
+    let embedded_art_resolution = embedded_pictures
+        .iter()
+        .find(|p| p.is_front_cover)
+        .or_else(|| embedded_pictures.first())
+        .and_then(|p| p.resolution);
+    Some(SongMetaData {
+        title: title_from_tags(&tags),
+        bitrate_kbps,
+        sample_rate,
+        channels,
+        bit_depth,
+        codec: Some(codec.to_owned()),
+        has_embedded_album_art,
+        has_video: false,
+        embedded_art_resolution,
+        embedded_pictures,
+        tags,
+        duration: Some(duration),
+    })
+}
+
+/// The name ffmpeg uses for a tag, lowercased like in `SongMetaData::tags`. Vorbis comments
+/// (FLAC) are mostly used as they are, ID3v2 frames get a readable name.
+fn ffmpeg_tag_name(key: &str) -> String {
+    if let Some((_, name)) = ID3V2_FRAME_NAMES.iter().find(|(id, _)| *id == key) {
+        return (*name).to_owned();
+    }
+    // User defined frames are named after their description.
+    if let Some(description) = key.strip_prefix("TXXX:") {
+        return description.to_lowercase();
+    }
+    // Comments have their language after the ID, like "COMM!eng".
+    if key.starts_with("COMM") {
+        return "comment".to_owned();
+    }
+    key.to_lowercase()
+}
+
+/// Bitrates of MPEG-1 and MPEG-2 layer III frames in kbps, by the index in the frame header.
+const MP3_BITRATES: [[u32; 15]; 2] = [
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// The bitrate of the audio in an MP3 file, like ffprobe gives it: the bitrate of the frames
+/// for constant bitrate files, and the average for variable bitrate files.
+fn mp3_bitrate(path: &Path, duration: Duration) -> Option<u32> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0; 10];
+    file.read_exact(&mut header).ok()?;
+    let id3v2 = if header.starts_with(b"ID3") {
+        // The size is stored in 4 bytes of 7 bits, and excludes the header (and footer).
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |size, byte| (size << 7) | (*byte & 0x7F) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        10 + size + footer
+    } else {
+        0
+    };
+
+    let mut frame = [0; 200];
+    file.seek(SeekFrom::Start(id3v2)).ok()?;
+    file.read_exact(&mut frame).ok()?;
+    // Every frame starts with 11 set bits.
+    if frame[0] != 0xFF || frame[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    // Variable bitrate files start with a frame that says so.
+    let vbr = frame
+        .windows(4)
+        .any(|window| window == b"Xing" || window == b"VBRI");
+    if !vbr {
+        let mpeg1 = (frame[1] >> 3) & 0b11 == 0b11;
+        let bitrate = *MP3_BITRATES[usize::from(!mpeg1)].get(usize::from(frame[2] >> 4))?;
+        return (bitrate > 0).then_some(bitrate);
+    }
+
+    let mut trailer = [0; 3];
+    let id3v1 = match file.seek(SeekFrom::End(-128)) {
+        Ok(_) if file.read_exact(&mut trailer).is_ok() && &trailer == b"TAG" => 128,
+        _ => 0,
+    };
+    let audio_bytes = file.metadata().ok()?.len().saturating_sub(id3v2 + id3v1);
+    Some((audio_bytes as f64 * 8. / duration.as_secs_f64() / 1000.).round() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_natively;
+    use crate::test_data::TestFile;
+
+    #[test]
+    /// MP3 and FLAC files are read without ffprobe, other formats are not.
+    fn parse_common_formats_natively() {
+        let mp3 = parse_natively(&TestFile::Mp3CBRWithArt.path()).unwrap();
+        assert_eq!(mp3.title.as_deref(), Some("mp3 with art"));
+        assert_eq!(mp3.artist(), Some("a silly developer"));
+        assert_eq!(mp3.codec.as_deref(), Some("mp3"));
+        assert_eq!(mp3.bit_depth, None);
+        assert_eq!(mp3.embedded_art_resolution, Some((600, 600)));
+        assert_eq!(mp3.embedded_pictures[0].stream_index, 1);
+        assert!(mp3.embedded_pictures[0].is_front_cover);
+
+        let rotterdam = parse_natively(&TestFile::Rotterdam128kbpsMp3.path()).unwrap();
+        assert_eq!(rotterdam.bitrate_kbps, 128);
+        assert_eq!(rotterdam.track_number(), Some(1));
+
+        let flac = parse_natively(&TestFile::FlacWithArt.path()).unwrap();
+        assert_eq!(flac.title.as_deref(), Some("flac with art"));
+        assert_eq!(flac.bit_depth, Some(16));
+        assert_eq!(flac.sample_rate, Some(44100));
+        assert_eq!(flac.channels, Some(2));
+        assert!(flac.has_embedded_album_art);
+        assert!(
+            !parse_natively(&TestFile::FlacWithoutArt.path())
+                .unwrap()
+                .has_embedded_album_art
+        );
+
+        assert!(parse_natively(&TestFile::OggWithArt.path()).is_none());
+        assert!(parse_natively(&TestFile::M4aWithArt.path()).is_none());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{
+    music_library::{MusicLibraryError, UpdateType},
+    Outcome, SyncResults,
+};
+use indicatif::HumanDuration;
+use serde::Serialize;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// Tells how a run went once it is done, so that e.g. a sync that runs overnight on a NAS can
+/// send a message to a phone.
+#[derive(Default)]
+pub struct Notification {
+    /// Show a desktop notification.
+    pub desktop: bool,
+    /// Run this command with the shell. How the run went is in environment variables.
+    pub command: Option<String>,
+    /// POST how the run went to this URL, as JSON.
+    pub webhook: Option<String>,
+    /// Ping this healthchecks.io style URL when the run starts, and when it ends with how it
+    /// went. Runs that fail ping `/fail`, so that a sync that stops working is noticed.
+    pub healthcheck: Option<String>,
+    /// Filled in once the songs are synchronised. None if it didn't get that far.
+    pub stats: Option<RunStats>,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct RunStats {
+    /// Songs that were written or linked.
+    pub synchronised: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+}
+
+impl RunStats {
+    pub fn from_results(sync_results: &SyncResults) -> RunStats {
+        let mut stats = RunStats::default();
+        for (_, result) in sync_results {
+            match result.as_ref().map(|record| record.update_type) {
+                Ok(Some(UpdateType::NoChange | UpdateType::Adopted) | None) => stats.unchanged += 1,
+                Ok(Some(_)) => stats.synchronised += 1,
+                Err(_) => stats.failed += 1,
+            }
+        }
+        stats
+    }
+}
+
+/// What is sent to the webhook.
+#[derive(Serialize)]
+struct Message<'a> {
+    outcome: &'a str,
+    message: &'a str,
+    synchronised: usize,
+    unchanged: usize,
+    failed: usize,
+    duration_secs: u64,
+}
+
+fn outcome_name(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Clean => "clean",
+        Outcome::FileErrors => "file_errors",
+        Outcome::Fatal => "fatal",
+        Outcome::Aborted => "aborted",
+        Outcome::ChangesPending => "changes_pending",
+    }
+}
+
+impl Notification {
+    /// Tells the healthcheck that the run started, so it can also tell when the run hangs.
+    pub fn ping_start(&self) {
+        if let Some(url) = &self.healthcheck {
+            let url = format!("{}/start", url.trim_end_matches('/'));
+            if let Err(e) = curl(&url, None) {
+                eprintln!("Could not ping {url}: {e}");
+            }
+        }
+    }
+
+    /// Sends the notifications that are asked for. Failing to send one is only reported, as the
+    /// run itself is already done.
+    pub fn send(&self, result: &Result<Outcome, MusicLibraryError>, duration: Duration) {
+        if !self.desktop
+            && self.command.is_none()
+            && self.webhook.is_none()
+            && self.healthcheck.is_none()
+        {
+            return;
+        }
+        let outcome = *result.as_ref().unwrap_or(&Outcome::Fatal);
+        let stats = self.stats.unwrap_or_default();
+        let text = message(result, stats, duration);
+
+        if self.desktop {
+            if let Err(e) = notify_rust::Notification::new()
+                .summary("syncbops")
+                .body(&text)
+                .show()
+            {
+                eprintln!("Could not show a desktop notification: {e}");
+            }
+        }
+        if let Some(command) = &self.command {
+            let mut shell = if cfg!(windows) {
+                let mut shell = Command::new("cmd");
+                shell.arg("/C");
+                shell
+            } else {
+                let mut shell = Command::new("sh");
+                shell.arg("-c");
+                shell
+            };
+            let status = shell
+                .arg(command)
+                .env("SYNCBOPS_OUTCOME", outcome_name(outcome))
+                .env("SYNCBOPS_MESSAGE", &text)
+                .env("SYNCBOPS_SYNCHRONISED", stats.synchronised.to_string())
+                .env("SYNCBOPS_UNCHANGED", stats.unchanged.to_string())
+                .env("SYNCBOPS_FAILED", stats.failed.to_string())
+                .env("SYNCBOPS_DURATION_SECS", duration.as_secs().to_string())
+                .status();
+            match status {
+                Ok(status) if status.success() => (),
+                Ok(status) => eprintln!("The notification command exited with {status}"),
+                Err(e) => eprintln!("Could not run the notification command: {e}"),
+            }
+        }
+        if let Some(url) = &self.webhook {
+            let body = serde_json::to_string(&Message {
+                outcome: outcome_name(outcome),
+                message: &text,
+                synchronised: stats.synchronised,
+                unchanged: stats.unchanged,
+                failed: stats.failed,
+                duration_secs: duration.as_secs(),
+            })
+            .expect("message should always be serialisable");
+            if let Err(e) = curl(url, Some(("application/json", &body))) {
+                eprintln!("Could not send the notification to {url}: {e}");
+            }
+        }
+        if let Some(url) = &self.healthcheck {
+            let url = healthcheck_ping(url, outcome);
+            // The message ends up in the log of the check.
+            if let Err(e) = curl(&url, Some(("text/plain", &text))) {
+                eprintln!("Could not ping {url}: {e}");
+            }
+        }
+    }
+}
+
+/// Where to ping the healthcheck at the end of a run.
+fn healthcheck_ping(url: &str, outcome: Outcome) -> String {
+    match outcome {
+        Outcome::Clean | Outcome::ChangesPending => url.to_owned(),
+        Outcome::FileErrors | Outcome::Fatal | Outcome::Aborted => {
+            format!("{}/fail", url.trim_end_matches('/'))
+        }
+    }
+}
+
+/// Uses curl, like transcoding uses ffmpeg, instead of building in an HTTP client. GETs the URL,
+/// or POSTs the body if there is one, given with its content type.
+fn curl(url: &str, body: Option<(&str, &str)>) -> std::io::Result<()> {
+    let mut command = Command::new("curl");
+    command
+        .args([
+            "--silent",
+            "--show-error",
+            "--fail",
+            "--max-time",
+            "30",
+            "--retry",
+            "3",
+        ])
+        .stdout(Stdio::null());
+    if let Some((content_type, _)) = body {
+        command
+            .args(["-X", "POST", "-H"])
+            .arg(format!("Content-Type: {content_type}"))
+            .args(["--data-binary", "@-"])
+            .stdin(Stdio::piped());
+    }
+    let mut curl = command.arg(url).spawn()?;
+    if let Some((_, body)) = body {
+        curl.stdin
+            .take()
+            .expect("stdin should be piped")
+            .write_all(body.as_bytes())?;
+    }
+    let status = curl.wait()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("curl exited with {status}")));
+    }
+    Ok(())
+}
+
+/// A single line about how the run went.
+fn message(
+    result: &Result<Outcome, MusicLibraryError>,
+    stats: RunStats,
+    duration: Duration,
+) -> String {
+    match result {
+        Err(e) => format!("The sync failed: {e}"),
+        Ok(Outcome::Aborted) => "The sync was aborted.".to_owned(),
+        Ok(Outcome::ChangesPending) => format!(
+            "The target library is not up to date: {} songs would change.",
+            stats.synchronised
+        ),
+        Ok(_) => {
+            let mut text = format!(
+                "Synchronised {} songs in {}, {} were unchanged.",
+                stats.synchronised,
+                HumanDuration(duration),
+                stats.unchanged
+            );
+            if stats.failed > 0 {
+                text += &format!(" {} songs failed.", stats.failed);
+            }
+            text
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{healthcheck_ping, message, RunStats};
+    use crate::{music_library::MusicLibraryError, Outcome};
+    use std::time::Duration;
+
+    #[test]
+    fn notification_message() {
+        let stats = RunStats {
+            synchronised: 12,
+            unchanged: 300,
+            failed: 2,
+        };
+        let duration = Duration::from_secs(125);
+        assert_eq!(
+            message(&Ok(Outcome::FileErrors), stats, duration),
+            "Synchronised 12 songs in 2 minutes, 300 were unchanged. 2 songs failed."
+        );
+        let fatal = Err(MusicLibraryError::OutputCodecNotYetImplemented);
+        assert!(message(&fatal, stats, duration).starts_with("The sync failed"));
+
+        let url = "https://hc-ping.com/abc/";
+        assert_eq!(healthcheck_ping(url, Outcome::Clean), url);
+        assert_eq!(
+            healthcheck_ping(url, Outcome::FileErrors),
+            "https://hc-ping.com/abc/fail"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::song::Song;
+use std::{path::PathBuf, str::FromStr};
+
+/// A layout for the target library that is filled in with the tags of each song, like
+/// `{albumartist}/{album}/{disc}-{track:02} {title}.{ext}`. This makes it possible to get a
+/// tidy target library, even if the source library is a mess.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field {
+        field: Field,
+        /// Numbers are zero-padded to this width, e.g. `{track:02}`.
+        width: Option<usize>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    /// Falls back to the artist.
+    AlbumArtist,
+    Artist,
+    Album,
+    /// Falls back to the name of the source file.
+    Title,
+    Track,
+    Disc,
+    Year,
+    Genre,
+    /// The extension of the target filetype.
+    Ext,
+}
+
+impl Field {
+    const ALL: [(&'static str, Field); 9] = [
+        ("albumartist", Field::AlbumArtist),
+        ("artist", Field::Artist),
+        ("album", Field::Album),
+        ("title", Field::Title),
+        ("track", Field::Track),
+        ("disc", Field::Disc),
+        ("year", Field::Year),
+        ("genre", Field::Genre),
+        ("ext", Field::Ext),
+    ];
+
+    /// The value to fill in for this song. Numbers are returned as numbers, so they can be
+    /// padded.
+    fn value(&self, song: &Song) -> FieldValue {
+        let md = &song.metadata;
+        let text = |value: Option<&str>, fallback: &str| {
+            FieldValue::Text(value.unwrap_or(fallback).to_owned())
+        };
+        match self {
+            Field::AlbumArtist => text(md.album_artist(), "Unknown Artist"),
+            Field::Artist => text(md.artist(), "Unknown Artist"),
+            Field::Album => text(md.album(), "Unknown Album"),
+            Field::Title => FieldValue::Text(song.title().unwrap_or_default()),
+            Field::Track => FieldValue::Number(md.track_number()),
+            Field::Disc => FieldValue::Number(md.disc_number()),
+            Field::Year => text(md.year(), "Unknown Year"),
+            Field::Genre => text(md.genre(), "Unknown Genre"),
+            Field::Ext => FieldValue::Text(
+                song.library_relative_path
+                    .extension()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+enum FieldValue {
+    Text(String),
+    Number(Option<u32>),
+}
+
+impl PathTemplate {
+    /// Fills in the template for the song. The result is relative to the target library. Its
+    /// extension is the extension of the source file; it still needs to be replaced by the one
+    /// of the target filetype.
+    pub fn render(&self, song: &Song) -> PathBuf {
+        let mut rendered = String::new();
+        let mut has_extension = false;
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => rendered.push_str(s),
+                Segment::Field { field, width } => {
+                    has_extension |= *field == Field::Ext;
+                    let value = match field.value(song) {
+                        FieldValue::Text(s) => s,
+                        FieldValue::Number(Some(n)) => match width {
+                            Some(w) => format!("{n:0w$}"),
+                            None => n.to_string(),
+                        },
+                        FieldValue::Number(None) => String::new(),
+                    };
+                    rendered.push_str(&sanitize(&value));
+                }
+            }
+        }
+        // Without an extension, a title like "Mr. Blue Sky" would get cut off at the dot once
+        // the extension is replaced.
+        if !has_extension {
+            if let Some(extension) = song.library_relative_path.extension() {
+                rendered.push('.');
+                rendered.push_str(&extension.to_string_lossy());
+            }
+        }
+        rendered.into()
+    }
+}
+
+/// Tags can contain characters that would mess up the path, like "AC/DC".
+fn sanitize(value: &str) -> String {
+    let replaced = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // A tag that is just dots would otherwise be interpreted as the current or parent dir.
+    let trimmed = replaced.trim().trim_start_matches('.');
+    trimmed.to_owned()
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_owned()));
+            }
+            let Some(length) = rest[start..].find('}') else {
+                return Err(format!("unclosed '{{' in layout template '{s}'"));
+            };
+            let placeholder = &rest[start + 1..start + length];
+            let (name, width) = match placeholder.split_once(':') {
+                Some((name, width)) => {
+                    let width = width
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid padding '{width}' for field '{name}'"))?;
+                    (name, Some(width))
+                }
+                None => (placeholder, None),
+            };
+            let Some((_, field)) = Field::ALL.iter().find(|(n, _)| *n == name) else {
+                return Err(format!(
+                    "unknown field '{name}' in layout template. Available fields are: {}",
+                    Field::ALL.map(|(n, _)| n).join(", ")
+                ));
+            };
+            segments.push(Segment::Field {
+                field: *field,
+                width,
+            });
+            rest = &rest[start + length + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_owned()));
+        }
+        if segments.is_empty() {
+            return Err("layout template can't be empty".to_owned());
+        }
+        Ok(PathTemplate { segments })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathTemplate;
+    use crate::song::Song;
+    use std::path::PathBuf;
+
+    #[test]
+    fn render_full_template() {
+        let template: PathTemplate = "{albumartist}/{album}/{disc}-{track:02} {title}.{ext}"
+            .parse()
+            .unwrap();
+        let song = Song::new_fake(
+            "messy/track3.flac",
+            &[
+                ("album_artist", "AC/DC"),
+                ("artist", "AC/DC"),
+                ("album", "Back in Black"),
+                ("title", "Hells Bells"),
+                ("track", "1/10"),
+                ("disc", "1"),
+            ],
+        );
+        assert_eq!(
+            template.render(&song),
+            PathBuf::from("AC_DC/Back in Black/1-01 Hells Bells.flac")
+        );
+    }
+
+    #[test]
+    /// Missing tags get a placeholder, and the extension is added even if not in the template.
+    fn render_with_missing_tags() {
+        let template: PathTemplate = "{albumartist}/{album}/{title}".parse().unwrap();
+        let song = Song::new_fake("some/Mr. Blue Sky.mp3", &[("artist", "ELO")]);
+        assert_eq!(
+            template.render(&song),
+            PathBuf::from("ELO/Unknown Album/Mr. Blue Sky.mp3")
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!("{albumartist".parse::<PathTemplate>().is_err());
+        assert!("{nonsense}/{title}".parse::<PathTemplate>().is_err());
+        assert!("{track:xx}".parse::<PathTemplate>().is_err());
+        assert!("".parse::<PathTemplate>().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::{io, path::Path};
+
+/// Who owns the files and folders in the target library, and who may read and write them. For
+/// when the sync runs as a different user than the one that plays the music, like a cron job of
+/// root and a media server. Only supported on Unix.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Permissions {
+    /// The mode of files, like 0o644. Folders get the same mode, plus execute for whoever may
+    /// read them, so they can be opened.
+    pub mode: Option<u32>,
+    pub owner: Option<Owner>,
+}
+
+/// The user and group to give the files to. None leaves them as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Permissions {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none()
+    }
+
+    /// The mode that a folder gets.
+    fn dir_mode(mode: u32) -> u32 {
+        mode | ((mode & 0o444) >> 2)
+    }
+
+    /// Gives every file and folder in the target library (the library itself included) the
+    /// mode and owner, leaving the ones that already have them alone. Symlinks are skipped.
+    /// Returns how many were changed.
+    #[cfg(unix)]
+    pub fn apply(&self, target_library: &Path) -> io::Result<usize> {
+        use std::{
+            fs,
+            os::unix::fs::{chown, MetadataExt, PermissionsExt},
+        };
+        let mut changed = 0;
+        for entry in walkdir::WalkDir::new(target_library) {
+            let entry = entry?;
+            if entry.file_type().is_symlink() {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let mut is_changed = false;
+            if let Some(owner) = self.owner {
+                let uid = owner.uid.filter(|uid| *uid != metadata.uid());
+                let gid = owner.gid.filter(|gid| *gid != metadata.gid());
+                if uid.is_some() || gid.is_some() {
+                    chown(entry.path(), uid, gid)?;
+                    is_changed = true;
+                }
+            }
+            if let Some(mode) = self.mode {
+                let mode = if metadata.is_dir() {
+                    Self::dir_mode(mode)
+                } else {
+                    mode
+                };
+                if metadata.mode() & 0o7777 != mode {
+                    fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))?;
+                    is_changed = true;
+                }
+            }
+            changed += usize::from(is_changed);
+        }
+        Ok(changed)
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _target_library: &Path) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "setting the mode and owner of files is only supported on Unix",
+        ))
+    }
+}
+
+/// Parses a mode in octal, like chmod does: `644` or `0644`. For use as a clap value parser.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim(), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("'{s}' is not a mode. Use octal digits, like 644."))
+}
+
+/// Parses an owner like chown does: `user`, `user:group` or `:group`, by name or by id. For
+/// use as a clap value parser.
+pub fn parse_owner(s: &str) -> Result<Owner, String> {
+    let (user, group) = match s.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (s, None),
+    };
+    let owner = Owner {
+        uid: Some(user)
+            .filter(|user| !user.is_empty())
+            .map(|user| user_id(user).ok_or_else(|| format!("there is no user '{user}'")))
+            .transpose()?,
+        gid: group
+            .filter(|group| !group.is_empty())
+            .map(|group| group_id(group).ok_or_else(|| format!("there is no group '{group}'")))
+            .transpose()?,
+    };
+    if owner == Owner::default() {
+        return Err(format!(
+            "'{s}' is not an owner. Use something like media or media:media."
+        ));
+    }
+    Ok(owner)
+}
+
+#[cfg(unix)]
+fn user_id(user: &str) -> Option<u32> {
+    user.parse().ok().or_else(|| {
+        let user = nix::unistd::User::from_name(user).ok()??;
+        Some(user.uid.as_raw())
+    })
+}
+
+#[cfg(unix)]
+fn group_id(group: &str) -> Option<u32> {
+    group.parse().ok().or_else(|| {
+        let group = nix::unistd::Group::from_name(group).ok()??;
+        Some(group.gid.as_raw())
+    })
+}
+
+#[cfg(not(unix))]
+fn user_id(user: &str) -> Option<u32> {
+    user.parse().ok()
+}
+
+#[cfg(not(unix))]
+fn group_id(group: &str) -> Option<u32> {
+    group.parse().ok()
+}
+
+/// Sets the umask of this process, which the files that it (and the ffmpeg processes it starts)
+/// creates get. Has to be done before anything is written.
+#[cfg(unix)]
+pub fn set_umask(mask: u32) -> io::Result<()> {
+    use nix::sys::stat::{umask, Mode};
+    umask(Mode::from_bits_truncate(mask as nix::libc::mode_t));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_umask(_mask: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the umask is only supported on Unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mode, parse_owner, Owner};
+
+    #[test]
+    fn parse_mode_and_owner() {
+        assert_eq!(parse_mode("644"), Ok(0o644));
+        assert_eq!(parse_mode("2775"), Ok(0o2775));
+        assert!(parse_mode("9").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert_eq!(
+            parse_owner("1000:100"),
+            Ok(Owner {
+                uid: Some(1000),
+                gid: Some(100)
+            })
+        );
+        assert_eq!(
+            parse_owner(":100"),
+            Ok(Owner {
+                uid: None,
+                gid: Some(100)
+            })
+        );
+        assert!(parse_owner(":").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// Files get the mode, and folders get execute on top of it where they can be read.
+    fn apply_mode() {
+        use super::Permissions;
+        use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+        let target_library = PathBuf::from(format!(
+            "/tmp/syncbops/test_permissions_{}",
+            random_string::generate(24, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let song = target_library.join("Artist/01 Song.opus");
+        fs::create_dir_all(song.parent().unwrap()).unwrap();
+        fs::write(&song, b"").unwrap();
+        fs::set_permissions(&song, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let permissions = Permissions {
+            mode: Some(0o640),
+            owner: None,
+        };
+        // The library, the artist folder and the song.
+        assert_eq!(permissions.apply(&target_library).unwrap(), 3);
+        let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&song), 0o640);
+        assert_eq!(mode(&target_library.join("Artist")), 0o750);
+        assert_eq!(permissions.apply(&target_library).unwrap(), 0);
+        fs::remove_dir_all(&target_library).unwrap();
+    }
+}
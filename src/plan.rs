@@ -0,0 +1,560 @@
+use crate::{
+    backup::Disposal,
+    cue::{whole_file, Segment},
+    estimate::transcoded_size,
+    ffmpeg_interface::SongMetaData,
+    hashing::{PreviousSyncDb, SyncRecord},
+    music_library::{MusicLibraryError, UpdateType},
+    parse_sync_cli,
+    song::Song,
+    sync_song::SyncSettings,
+    target_path::TargetPlan,
+    Cli, SyncResults,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+/// How to show what a dry run would do.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum PlanFormat {
+    /// One line per song, to read through.
+    Human,
+    /// A single line of JSON at the end of the output, for scripts.
+    Json,
+}
+
+/// What would be done to a song.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Transcode,
+    Copy,
+    /// Only write the tags (and art) again, keeping the audio.
+    Retag,
+    /// Link to the synchronised copy of an identical song.
+    Link,
+    /// Remove the file from the target library, and its record.
+    Remove,
+    /// Only drop the record, the file it is about is gone already or left alone.
+    Forget,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Transcode => "transcode",
+            Action::Copy => "copy",
+            Action::Retag => "retag",
+            Action::Link => "link",
+            Action::Remove => "remove",
+            Action::Forget => "forget",
+        }
+    }
+}
+
+/// Why something would be done: how a song would be synchronised, or why a file would be
+/// removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum Reason {
+    Update(UpdateType),
+    Removal(Removal),
+}
+
+/// Why a file (or only its record) would be removed from the target library.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Removal {
+    /// The song is no longer in the source library.
+    StaleRecord,
+    /// The song was picked to fill up the target library before, but isn't anymore.
+    LeftOutOfFill,
+    /// The source of the sidecar is gone, or its extension is not copied anymore.
+    StaleSidecar,
+}
+
+/// What a dry run found would be done to a single song.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedAction {
+    /// Relative to the source library.
+    pub source: PathBuf,
+    /// Relative to the target library.
+    pub target: PathBuf,
+    pub action: Action,
+    pub reason: Reason,
+    /// Roughly how many bytes the target library grows by (or shrinks, if negative).
+    pub size_delta: i64,
+}
+
+impl PlannedAction {
+    /// Removing the record, and the file in the target library that it is about if there is one.
+    /// Records of songs that are gone from the source library are only dropped, as a sync does.
+    pub fn removal(record: &SyncRecord, removal: Removal, target_library: &Path) -> PlannedAction {
+        let target = record.target_relative_path.clone().unwrap_or_default();
+        let size = std::fs::metadata(target_library.join(&target))
+            .ok()
+            .filter(|_| removal != Removal::StaleRecord)
+            .filter(|m| m.is_file())
+            .map(|m| m.len());
+        PlannedAction {
+            source: record.library_relative_path.clone(),
+            target,
+            action: match size {
+                Some(_) => Action::Remove,
+                None => Action::Forget,
+            },
+            reason: Reason::Removal(removal),
+            size_delta: -(size.unwrap_or(0) as i64),
+        }
+    }
+}
+
+/// Synchronises exactly what a dry run saved with `--plan` said it would, with the same
+/// settings, without scanning the library again. Songs that changed since are skipped.
+#[derive(clap::Parser)]
+#[command(bin_name = "syncbops apply", version)]
+pub struct ApplyCli {
+    /// The plan, as saved by a dry run with `--plan`.
+    pub plan: PathBuf,
+}
+
+/// A dry run saved with `--plan`, to be carried out later with `syncbops apply`.
+#[derive(Serialize, Deserialize)]
+pub struct SyncPlan {
+    /// What the dry run was started with, so that the plan is carried out with the same
+    /// settings.
+    pub arguments: Vec<String>,
+    /// Where the dry run was started, which relative paths in the arguments are relative to.
+    #[serde(default)]
+    pub working_directory: Option<PathBuf>,
+    pub songs: Vec<PlannedSong>,
+    /// Files and records that are removed, apart from the songs.
+    #[serde(default)]
+    pub removals: Vec<PlannedAction>,
+}
+
+/// Everything needed to synchronise a song, without scanning the library again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlannedSong {
+    #[serde(flatten)]
+    pub action: PlannedAction,
+    /// Hash of the source when the plan was made. If it changed since, the song is skipped.
+    pub hash: Option<u64>,
+    pub external_album_art: Option<PathBuf>,
+    /// For links: the target of the song it is identical to, relative to the target library.
+    pub linked_to: Option<PathBuf>,
+    pub metadata: SongMetaData,
+    /// The file to read, relative to the source library, if that is not `source`: for the tracks
+    /// of a single-file album the file they are a part of, and for songs of which `source` is
+    /// normalised the file as it is on the filesystem.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    /// Which part of `file` the track is.
+    #[serde(default)]
+    pub segment: Option<Segment>,
+}
+
+impl PlannedSong {
+    /// How the song is to be synchronised.
+    pub fn update_type(&self) -> UpdateType {
+        match self.action.reason {
+            Reason::Update(update_type) => update_type,
+            Reason::Removal(_) => unreachable!("songs in a plan are never removals"),
+        }
+    }
+}
+
+impl SyncPlan {
+    pub fn read(path: &Path) -> Result<SyncPlan, MusicLibraryError> {
+        let plan_error = |msg: String| MusicLibraryError::Plan {
+            path: path.to_path_buf(),
+            msg,
+        };
+        let json = std::fs::read_to_string(path).map_err(|e| plan_error(e.to_string()))?;
+        serde_json::from_str(&json).map_err(|e| plan_error(e.to_string()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), MusicLibraryError> {
+        let plan_error = |msg: String| MusicLibraryError::Plan {
+            path: path.to_path_buf(),
+            msg,
+        };
+        let json = serde_json::to_string_pretty(self).map_err(|e| plan_error(e.to_string()))?;
+        std::fs::write(path, json).map_err(|e| plan_error(e.to_string()))
+    }
+
+    /// The arguments of the dry run, parsed in the directory it was started in. So that relative
+    /// paths in them mean the same, wherever the plan is applied from.
+    pub fn cli(&self) -> std::io::Result<Cli> {
+        if let Some(dir) = &self.working_directory {
+            std::env::set_current_dir(dir)?;
+        }
+        Ok(parse_sync_cli(
+            std::iter::once("syncbops".to_owned()).chain(self.arguments.clone()),
+        ))
+    }
+
+    /// The songs in the plan, as they were when it was made.
+    pub fn songs(&self, source_library: &Path) -> Vec<Song> {
+        self.songs
+            .iter()
+            .map(|planned| {
+                let file = planned.file.as_ref().unwrap_or(&planned.action.source);
+                let mut song = Song::with_metadata(
+                    source_library.join(file),
+                    source_library,
+                    planned.external_album_art.clone(),
+                    planned.metadata.clone(),
+                );
+                song.library_relative_path
+                    .clone_from(&planned.action.source);
+                song.segment = planned.segment;
+                song
+            })
+            .collect()
+    }
+
+    /// Removes the files and records that the plan says to, and nothing else. Files of which the
+    /// record is gone since the plan was made are left alone. Returns the library relative
+    /// paths of the removed records.
+    pub fn apply_removals(
+        &self,
+        previous_sync_db: &mut PreviousSyncDb,
+        target_library: &Path,
+        disposal: &Disposal,
+    ) -> Vec<PathBuf> {
+        self.removals
+            .iter()
+            .filter(|removal| previous_sync_db.remove(&removal.source).is_some())
+            .map(|removal| {
+                if removal.action == Action::Remove {
+                    let _ = disposal.remove_file(&target_library.join(&removal.target));
+                }
+                removal.source.clone()
+            })
+            .collect()
+    }
+
+    /// Where every song in the plan goes, keyed on its library relative path.
+    pub fn target_plan(&self, target_library: &Path) -> TargetPlan {
+        self.songs
+            .iter()
+            .map(|planned| {
+                (
+                    planned.action.source.clone(),
+                    target_library.join(&planned.action.target),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Why something would be done, in words.
+fn reason(reason: Reason) -> &'static str {
+    use UpdateType as U;
+    let update_type = match reason {
+        Reason::Update(update_type) => update_type,
+        Reason::Removal(Removal::StaleRecord) => return "no longer in the source library",
+        Reason::Removal(Removal::LeftOutOfFill) => return "not picked to fill up anymore",
+        Reason::Removal(Removal::StaleSidecar) => return "sidecar is not copied anymore",
+    };
+    match update_type {
+        U::NewTranscode => "new song",
+        U::Overwrite => "source or settings changed",
+        U::Retag => "only the tags changed",
+        U::ForceOverwrite => "forced",
+        U::TranscodeMissingTarget => "synchronised copy is missing",
+        U::Copied => "not worth transcoding",
+        U::Duplicate => "identical to another song",
+        U::NoChange | U::Adopted => "unchanged",
+    }
+}
+
+/// What the results of a dry run would do. Songs that would not be touched are left out.
+/// `duplicates` maps songs to the song they are identical to.
+pub fn plan_from_results(
+    sync_results: &SyncResults,
+    source_library: &Path,
+    target_library: &Path,
+    settings: &SyncSettings,
+    duplicates: &HashMap<PathBuf, PathBuf>,
+    target_plan: &TargetPlan,
+) -> Vec<PlannedSong> {
+    sync_results
+        .iter()
+        .filter_map(|(song, result)| {
+            let record = result.as_ref().ok()?;
+            let target = record.target_relative_path.clone()?;
+            use UpdateType as U;
+            let action = match record.update_type? {
+                U::NoChange | U::Adopted => return None,
+                U::NewTranscode | U::Overwrite | U::ForceOverwrite | U::TranscodeMissingTarget => {
+                    Action::Transcode
+                }
+                U::Retag => Action::Retag,
+                U::Copied => Action::Copy,
+                U::Duplicate => Action::Link,
+            };
+            let existing = std::fs::metadata(target_library.join(&target)).map_or(0, |m| m.len());
+            let size_delta = new_size(song, action, existing, settings) as i64 - existing as i64;
+            let linked_to = duplicates
+                .get(&song.library_relative_path)
+                .and_then(|original| target_plan.get(original))
+                .and_then(|shadow| shadow.strip_prefix(target_library).ok())
+                .map(Path::to_path_buf);
+            Some(PlannedSong {
+                action: PlannedAction {
+                    source: song.library_relative_path.clone(),
+                    target,
+                    action,
+                    reason: Reason::Update(record.update_type?),
+                    size_delta,
+                },
+                hash: record.hash,
+                external_album_art: song.external_album_art.clone(),
+                linked_to,
+                metadata: song.metadata.clone(),
+                file: whole_file(song, source_library).or_else(|| {
+                    Some(song.source_relative_path(source_library))
+                        .filter(|file| *file != song.library_relative_path)
+                }),
+                segment: song.segment,
+            })
+        })
+        .collect()
+}
+
+/// Roughly how large the synchronised copy would be after the action.
+fn new_size(song: &Song, action: Action, existing: u64, settings: &SyncSettings) -> u64 {
+    let source_size = std::fs::metadata(&song.absolute_path).map_or(0, |m| m.len());
+    match action {
+        Action::Transcode => transcoded_size(song, source_size, settings),
+        Action::Copy => source_size,
+        // The audio is kept, the tags are hardly any of the size.
+        Action::Retag => existing,
+        Action::Link | Action::Remove | Action::Forget => 0,
+    }
+}
+
+pub fn format_plan(plan: &[PlannedSong], removals: &[PlannedAction], format: PlanFormat) -> String {
+    let plan = plan
+        .iter()
+        .map(|planned| &planned.action)
+        .chain(removals)
+        .collect::<Vec<_>>();
+    match format {
+        PlanFormat::Json => {
+            serde_json::to_string(&plan).expect("plan should always be serialisable") + "\n"
+        }
+        PlanFormat::Human => {
+            let mut buf = String::new();
+            if plan.is_empty() {
+                let _ = writeln!(buf, "Nothing would be changed.");
+                return buf;
+            }
+            let _ = writeln!(buf, "Would do the following:");
+            for action in &plan {
+                let line = format!(
+                    "{:<9} {:>+9.1} MB  {} ({})",
+                    action.action.name(),
+                    action.size_delta as f64 / 1_000_000.,
+                    action.source.display(),
+                    reason(action.reason)
+                );
+                let line = match action.reason {
+                    Reason::Update(update_type) => update_type.style(line),
+                    Reason::Removal(_) => console::style(line).red(),
+                };
+                let _ = writeln!(buf, "\t{line}");
+            }
+            let total: i64 = plan.iter().map(|action| action.size_delta).sum();
+            let _ = writeln!(
+                buf,
+                "The target library would change by about {:+.1} MB.",
+                total as f64 / 1_000_000.
+            );
+            buf
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        format_plan, plan_from_results, Action, PlanFormat, PlannedAction, Removal, SyncPlan,
+    };
+    use crate::{
+        backup::Disposal,
+        hashing::{PreviousSyncDb, SyncRecord},
+        music_library::{ArtStrategy, Id3Tags, MusicFileType, UpdateType},
+        song::Song,
+        sync_song::SyncSettings,
+        SyncResults,
+    };
+    use std::{collections::HashMap, path::Path, time::Duration};
+
+    #[test]
+    /// Only songs that would be changed are in the plan, and it survives being written as JSON.
+    fn plan_of_dry_run() {
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3CBR {
+                bitrate: 128,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::None,
+        );
+        let mut new = Song::new_fake("new.flac", &[]);
+        new.metadata.duration = Some(Duration::from_secs(10));
+        let old = Song::new_fake("old.flac", &[]);
+        let record = |song: &Song, update_type| {
+            Ok(SyncRecord::from_song(song, &settings)
+                .set_target_relative_path(song.library_relative_path.with_extension("mp3"))
+                .set_update_type(update_type))
+        };
+        let results: SyncResults = vec![
+            (&new, record(&new, UpdateType::NewTranscode)),
+            (&old, record(&old, UpdateType::NoChange)),
+        ];
+
+        let target_library = Path::new("/nonexistent_target");
+        let plan = plan_from_results(
+            &results,
+            Path::new("/source_library"),
+            target_library,
+            &settings,
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+        assert_eq!(plan.len(), 1);
+        let action = &plan[0].action;
+        assert_eq!(action.action, Action::Transcode);
+        assert_eq!(action.target, Path::new("new.mp3"));
+        // 10 seconds of 128 kbps.
+        assert_eq!(action.size_delta, 160_000);
+
+        let json = format_plan(&plan, &[], PlanFormat::Json);
+        let read: Vec<PlannedAction> = serde_json::from_str(&json).unwrap();
+        assert_eq!(&read[0], action);
+        assert!(format_plan(&plan, &[], PlanFormat::Human).contains("transcode"));
+
+        // A saved plan has what is needed to synchronise the songs without reading them again.
+        let path = std::env::temp_dir().join(format!(
+            "syncbops_plan_{}.json",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        SyncPlan {
+            arguments: vec!["mp3-cbr".to_owned()],
+            working_directory: None,
+            songs: plan,
+            removals: Vec::new(),
+        }
+        .write(&path)
+        .unwrap();
+        let read = SyncPlan::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let songs = read.songs(Path::new("/source_library"));
+        assert_eq!(
+            songs[0].absolute_path,
+            Path::new("/source_library/new.flac")
+        );
+        assert_eq!(songs[0].metadata.duration, Some(Duration::from_secs(10)));
+        assert_eq!(
+            read.target_plan(target_library)[Path::new("new.flac")],
+            Path::new("/nonexistent_target/new.mp3")
+        );
+    }
+
+    #[test]
+    /// Applying a plan only removes what is in it, even if more would be removed by now.
+    fn only_planned_removals() {
+        let settings =
+            SyncSettings::new_debug(MusicFileType::Flac { quality: 8 }, ArtStrategy::None);
+        let target_library = std::env::temp_dir().join(format!(
+            "syncbops_plan_removals_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        std::fs::create_dir_all(&target_library).unwrap();
+        let mut db = PreviousSyncDb::new();
+        for name in ["planned.flac", "unplanned.flac", "gone.flac"] {
+            std::fs::write(target_library.join(name), "audio").unwrap();
+            let song = Song::new_fake(name, &[]);
+            db.insert(
+                song.library_relative_path.clone(),
+                SyncRecord::from_song(&song, &settings).set_target_relative_path(name.into()),
+            );
+        }
+
+        let removals = vec![
+            PlannedAction::removal(
+                &db[Path::new("planned.flac")],
+                Removal::LeftOutOfFill,
+                &target_library,
+            ),
+            // Only its record is dropped, like a sync would.
+            PlannedAction::removal(
+                &db[Path::new("gone.flac")],
+                Removal::StaleRecord,
+                &target_library,
+            ),
+        ];
+        assert_eq!(removals[0].action, Action::Remove);
+        assert_eq!(removals[0].size_delta, -5);
+        assert_eq!(removals[1].action, Action::Forget);
+        let human = format_plan(&[], &removals, PlanFormat::Human);
+        assert!(human.contains("remove") && human.contains("forget"));
+
+        let plan = SyncPlan {
+            arguments: Vec::new(),
+            working_directory: None,
+            songs: Vec::new(),
+            removals,
+        };
+        let removed = plan.apply_removals(&mut db, &target_library, &Disposal::Delete);
+        assert_eq!(
+            removed,
+            vec![Path::new("planned.flac"), Path::new("gone.flac")]
+        );
+        assert!(!target_library.join("planned.flac").exists());
+        assert!(target_library.join("gone.flac").exists());
+        assert!(target_library.join("unplanned.flac").exists());
+        assert!(db.contains_key(Path::new("unplanned.flac")));
+        let _ = std::fs::remove_dir_all(&target_library);
+    }
+
+    #[test]
+    /// Relative paths given to the dry run still lead to the same libraries when the plan is
+    /// applied from somewhere else.
+    fn apply_from_other_directory() {
+        let dir = std::env::temp_dir().join(format!(
+            "syncbops_plan_dir_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        std::fs::create_dir_all(dir.join("source")).unwrap();
+        std::fs::write(dir.join("source/song.flac"), "audio").unwrap();
+        let path = dir.join("plan.json");
+        SyncPlan {
+            arguments: ["source", "target", "flac"].map(str::to_owned).to_vec(),
+            working_directory: Some(dir.canonicalize().unwrap()),
+            songs: Vec::new(),
+            removals: Vec::new(),
+        }
+        .write(&path)
+        .unwrap();
+
+        let before = std::env::current_dir().unwrap();
+        std::env::set_current_dir(std::env::temp_dir()).unwrap();
+        let source_library = SyncPlan::read(&path)
+            .unwrap()
+            .cli()
+            .map(|cli| cli.source_library.canonicalize());
+        std::env::set_current_dir(before).unwrap();
+        let source_library = source_library.unwrap().unwrap();
+        assert_eq!(source_library, dir.canonicalize().unwrap().join("source"));
+        assert!(source_library.join("song.flac").is_file());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
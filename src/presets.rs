@@ -0,0 +1,225 @@
+use crate::{
+    music_library::{ArtFormat, ArtStrategy, Id3Tags, MusicFileType, OpusVbr},
+    target_path::Flatten,
+    Cli,
+};
+use clap::{parser::ValueSource, ArgMatches};
+
+/// Settings for what the target library is used for, so that they don't all have to be given
+/// one by one. Settings that are given on the command line take precedence.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum Preset {
+    /// For pointing Jellyfin or Navidrome at the target library:
+    /// `{albumartist}/{album}/{track:02} {title}` folders, compilations in their own folder, the
+    /// album art in a cover.jpg next to the songs instead of embedded, artist images, and .nfo
+    /// files copied along.
+    MediaServer,
+}
+
+/// Sensible settings for a kind of device, so that a sync works on it without having to know
+/// what it supports. Settings that are given on the command line take precedence.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum Device {
+    /// An iPod running Rockbox: MP3 (easiest on the battery), a small cover.jpg next to the
+    /// songs instead of embedded art, and names and paths that fit FAT32.
+    RockboxIpod,
+    /// An Android phone: Opus, embedded art, and names that Android storage allows.
+    Android,
+    /// A Garmin watch: MP3, small embedded art, and plain ASCII names that fit FAT32.
+    Garmin,
+    /// A car stereo reading a FAT32 USB stick: constant bitrate MP3 (which every head unit can
+    /// play and seek in), small embedded art, plain ASCII names, and one folder per artist, as
+    /// many can't go more than a few folders deep.
+    CarFat32,
+}
+
+/// Whether the argument was given on the command line, instead of having its default value.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+impl Device {
+    /// Fills in the settings for the device that were not given on the command line.
+    pub fn apply(&self, cli: &mut Cli, matches: &ArgMatches) {
+        let given = |id: &str| given(matches, id);
+        let filetype = match self {
+            Device::RockboxIpod => MusicFileType::Mp3VBR {
+                quality: 2,
+                id3: Id3Tags::default(),
+            },
+            Device::Android => MusicFileType::Opus {
+                bitrate: 128,
+                compression_level: 10,
+                vbr: OpusVbr::On,
+            },
+            Device::Garmin => MusicFileType::Mp3VBR {
+                quality: 4,
+                id3: Id3Tags::default(),
+            },
+            Device::CarFat32 => MusicFileType::Mp3CBR {
+                bitrate: 192,
+                id3: Id3Tags::default(),
+            },
+        };
+        cli.target_filetype.get_or_insert(filetype);
+        // All of them use FAT32 or exFAT, or forbid the same characters.
+        if !given("fat_safe_filenames") {
+            cli.fat_safe_filenames = true;
+        }
+        if *self != Device::Android && !given("case_insensitive_target") {
+            cli.case_insensitive_target = true;
+        }
+        match self {
+            Device::RockboxIpod => {
+                if !given("art_strategy") {
+                    cli.art_strategy = ArtStrategy::FileOnly;
+                }
+                // Rockbox only looks for a few names, and can't show PNG or progressive JPEG.
+                if !given("art_filename") {
+                    cli.art_filename = Some("cover".to_owned());
+                }
+                if !given("art_format") {
+                    cli.art_format = Some(ArtFormat::Jpeg);
+                }
+                if !given("art_file_resolution") {
+                    cli.art_file_resolution = 200;
+                }
+                if !given("max_path_length") {
+                    cli.max_path_length = Some(260);
+                }
+            }
+            Device::Android => {
+                if !given("art_strategy") {
+                    cli.art_strategy = ArtStrategy::EmbedAll;
+                }
+                if !given("embed_art_resolution") {
+                    cli.embed_art_resolution = 600;
+                }
+            }
+            Device::Garmin | Device::CarFat32 => {
+                if !given("art_strategy") {
+                    cli.art_strategy = ArtStrategy::EmbedAll;
+                }
+                if !given("embed_art_resolution") {
+                    cli.embed_art_resolution = 300;
+                }
+                if !given("ascii_filenames") {
+                    cli.ascii_filenames = true;
+                }
+                if !given("max_path_length") {
+                    cli.max_path_length = Some(255);
+                }
+                if *self == Device::CarFat32 && !given("flatten") {
+                    cli.flatten = Some(Flatten::Artist);
+                }
+            }
+        }
+    }
+}
+
+impl Preset {
+    /// Fills in the settings of the preset that were not given on the command line.
+    pub fn apply(&self, cli: &mut Cli, matches: &ArgMatches) {
+        let given = |id: &str| given(matches, id);
+        match self {
+            Preset::MediaServer => {
+                if !given("layout") {
+                    cli.layout = Some(
+                        "{albumartist}/{album}/{track:02} {title}.{ext}"
+                            .parse()
+                            .expect("preset layout should be valid"),
+                    );
+                }
+                if !given("compilation_layout") {
+                    cli.compilation_layout = Some(
+                        "Compilations/{album}/{track:02} {artist} - {title}.{ext}"
+                            .parse()
+                            .expect("preset layout should be valid"),
+                    );
+                }
+                // Both look for a cover.jpg in the album folder first.
+                if !given("art_strategy") {
+                    cli.art_strategy = ArtStrategy::ExtractToFile;
+                }
+                if !given("art_filename") {
+                    cli.art_filename = Some("cover".to_owned());
+                }
+                if !given("art_format") {
+                    cli.art_format = Some(ArtFormat::Jpeg);
+                }
+                if !given("artist_images") {
+                    cli.artist_images = true;
+                }
+                if !given("copy_nfo") {
+                    cli.copy_nfo = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        music_library::{ArtStrategy, Id3Tags, MusicFileType},
+        parse_sync_cli,
+        target_path::Flatten,
+    };
+
+    #[test]
+    /// The preset fills in what is not given, and what is given stays.
+    fn media_server_preset() {
+        let cli = parse_sync_cli([
+            "syncbops",
+            "--preset",
+            "media-server",
+            "--art-filename",
+            "folder",
+            "/source",
+            "/target",
+            "mp3-vbr",
+        ]);
+        assert_eq!(cli.art_strategy, ArtStrategy::ExtractToFile);
+        assert_eq!(cli.art_filename.as_deref(), Some("folder"));
+        assert!(cli.layout.is_some());
+        assert!(cli.copy_nfo);
+
+        let cli = parse_sync_cli(["syncbops", "/source", "/target", "mp3-vbr"]);
+        assert_eq!(cli.art_strategy, ArtStrategy::PreferFile);
+        assert!(!cli.copy_nfo);
+    }
+
+    #[test]
+    /// A device also picks the target filetype, unless one is given.
+    fn device_preset() {
+        let cli = parse_sync_cli(["syncbops", "--device", "car-fat32", "/source", "/target"]);
+        assert_eq!(
+            cli.target_filetype,
+            Some(MusicFileType::Mp3CBR {
+                bitrate: 192,
+                id3: Id3Tags::default()
+            })
+        );
+        assert_eq!(cli.flatten, Some(Flatten::Artist));
+        assert!(cli.fat_safe_filenames && cli.ascii_filenames);
+
+        let cli = parse_sync_cli([
+            "syncbops",
+            "--device",
+            "car-fat32",
+            "--embed-art-resolution",
+            "500",
+            "/source",
+            "/target",
+            "mp3-vbr",
+        ]);
+        assert_eq!(
+            cli.target_filetype,
+            Some(MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default()
+            })
+        );
+        assert_eq!(cli.embed_art_resolution, 500);
+    }
+}
@@ -0,0 +1,64 @@
+use std::process::Command;
+
+/// How much disk access the sync gets compared to other programs.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum IoPriority {
+    /// Lowest priority of the normal class. Other programs go first, but the sync still
+    /// progresses when the disk is busy.
+    BestEffort,
+    /// Only touch the disk when no other program needs it. Can stall the sync for a long time
+    /// if the disk is always busy.
+    Idle,
+}
+
+/// Lowers the priority of this process, so that a sync doesn't make the rest of the machine
+/// unresponsive. Has to be done before the worker threads are started: those and the ffmpeg
+/// processes inherit the priority of the thread that starts them.
+/// Failing to do so is not a reason to stop the sync, so only warns about it.
+pub fn lower_priority(nice: Option<i32>, ionice: Option<IoPriority>) {
+    let pid = std::process::id().to_string();
+    if let Some(nice) = nice {
+        let mut command = if cfg!(windows) {
+            // Windows has no niceness, only a few priority classes. Processes that are started
+            // by a process in one of the two lowest classes get the same class.
+            let class = if nice >= 15 { "Idle" } else { "BelowNormal" };
+            let mut command = Command::new("powershell");
+            command
+                .arg("-NoProfile")
+                .arg("-Command")
+                .arg(format!("(Get-Process -Id {pid}).PriorityClass = '{class}'"));
+            command
+        } else {
+            let mut command = Command::new("renice");
+            command.arg("-n").arg(nice.to_string()).arg("-p").arg(&pid);
+            command
+        };
+        run(&mut command, "the priority");
+    }
+    if let Some(ionice) = ionice {
+        if cfg!(target_os = "linux") {
+            let mut command = Command::new("ionice");
+            match ionice {
+                IoPriority::BestEffort => command.arg("-c").arg("2").arg("-n").arg("7"),
+                IoPriority::Idle => command.arg("-c").arg("3"),
+            };
+            run(command.arg("-p").arg(&pid), "the I/O priority");
+        } else {
+            eprintln!("Setting the I/O priority is only supported on Linux, so ignoring --ionice.");
+        }
+    }
+}
+
+fn run(command: &mut Command, what: &str) {
+    match command.output() {
+        Ok(output) if output.status.success() => (),
+        Ok(output) => eprintln!(
+            "Could not lower {what} of syncbops: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => eprintln!(
+            "Could not lower {what} of syncbops, because `{}` could not be run: {e}",
+            command.get_program().to_string_lossy()
+        ),
+    }
+}
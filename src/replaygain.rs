@@ -0,0 +1,415 @@
+use crate::{
+    ffmpeg_interface::{measure_loudness, SongMetaData},
+    hashing::{hash_file, PreviousSyncDb},
+    log_failure,
+    music_library::MusicFileType,
+    song::Song,
+};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// ReplayGain 2.0 levels everything to this loudness.
+const REFERENCE_LOUDNESS_LUFS: f64 = -18.0;
+
+/// Loudness of a song, as measured according to EBU R128.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    pub integrated_lufs: f64,
+    pub true_peak_dbfs: f64,
+}
+
+impl Loudness {
+    fn gain_db(&self) -> f64 {
+        REFERENCE_LOUDNESS_LUFS - self.integrated_lufs
+    }
+
+    /// As a linear amplitude, like ReplayGain expects it.
+    fn peak(&self) -> f64 {
+        10f64.powf(self.true_peak_dbfs / 20.0)
+    }
+}
+
+/// The loudness of an album as a whole. Songs are weighted by their duration, as if they were
+/// played after each other.
+fn album_loudness(tracks: &[(Loudness, Option<Duration>)]) -> Option<Loudness> {
+    if tracks.is_empty() {
+        return None;
+    }
+    let weight = |duration: &Option<Duration>| duration.map_or(1.0, |d| d.as_secs_f64());
+    let total_weight: f64 = tracks.iter().map(|(_, duration)| weight(duration)).sum();
+    let energy = tracks
+        .iter()
+        .map(|(loudness, duration)| weight(duration) * 10f64.powf(loudness.integrated_lufs / 10.0))
+        .sum::<f64>()
+        / total_weight;
+    let true_peak_dbfs = tracks
+        .iter()
+        .map(|(loudness, _)| loudness.true_peak_dbfs)
+        .fold(f64::NEG_INFINITY, f64::max);
+    Some(Loudness {
+        integrated_lufs: 10.0 * energy.log10(),
+        true_peak_dbfs,
+    })
+}
+
+/// Measures the loudness of the songs that don't have ReplayGain tags, and adds ReplayGain tags
+/// to their metadata, so they end up in the transcoded files. Measurements in the records of a
+/// previous sync are re-used if the song did not change.
+pub fn scan_loudness(songs: &mut [Song], previous_sync_db: Option<&PreviousSyncDb>) {
+    // Tracks of a single-file album can't be measured on their own yet.
+    let to_scan =
+        |song: &Song| gain_db(&song.metadata, "track").is_none() && song.segment.is_none();
+    let n_to_scan = songs.iter().filter(|song| to_scan(song)).count();
+    let pb = ProgressBar::new(n_to_scan as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed}] [{bar:60.cyan/blue}] {pos}/{len} [ETA: {eta}] {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    songs
+        .par_iter_mut()
+        .filter(|song| to_scan(song))
+        .progress_with(pb.clone())
+        .for_each(|song| {
+            let measured_before = previous_sync_db
+                .and_then(|db| db.get(&song.library_relative_path))
+                .filter(|record| {
+                    record.hash.is_some() && record.hash == hash_file(&song.absolute_path)
+                })
+                .and_then(|record| record.loudness);
+            song.loudness = measured_before.or_else(|| {
+                measure_loudness(&song.absolute_path)
+                    .map_err(|e| {
+                        log_failure(
+                            format!(
+                                "Could not measure loudness of {}: {}",
+                                song.library_relative_path.display(),
+                                e
+                            ),
+                            Some(&pb),
+                        )
+                    })
+                    .ok()
+            });
+        });
+
+    // The album gain can only be determined if all songs of the album are measured.
+    let mut albums: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    for (i, song) in songs.iter().enumerate() {
+        let Some(album) = song.metadata.album() else {
+            continue;
+        };
+        let album_artist = song.metadata.album_artist().unwrap_or_default();
+        albums
+            .entry((album_artist.to_owned(), album.to_owned()))
+            .or_default()
+            .push(i);
+    }
+    let mut album_loudnesses = HashMap::new();
+    for indices in albums.values() {
+        let tracks = indices
+            .iter()
+            .map(|i| Some((songs[*i].loudness?, songs[*i].metadata.duration)))
+            .collect::<Option<Vec<_>>>();
+        if let Some(album) = tracks.and_then(|tracks| album_loudness(&tracks)) {
+            for i in indices {
+                album_loudnesses.insert(*i, album);
+            }
+        }
+    }
+
+    for (i, song) in songs.iter_mut().enumerate() {
+        let Some(track) = song.loudness else {
+            continue;
+        };
+        let tags = &mut song.metadata.tags;
+        tags.insert(
+            "replaygain_track_gain".to_owned(),
+            format!("{:.2} dB", track.gain_db()),
+        );
+        tags.insert(
+            "replaygain_track_peak".to_owned(),
+            format!("{:.6}", track.peak()),
+        );
+        if let Some(album) = album_loudnesses.get(&i) {
+            tags.insert(
+                "replaygain_album_gain".to_owned(),
+                format!("{:.2} dB", album.gain_db()),
+            );
+            tags.insert(
+                "replaygain_album_peak".to_owned(),
+                format!("{:.6}", album.peak()),
+            );
+        }
+    }
+}
+
+/// The ReplayGain tags, as they are named in Vorbis comments. ID3v2 stores them in TXXX frames,
+/// with the name as description.
+const REPLAYGAIN_TAGS: [&str; 5] = [
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_PEAK",
+    "REPLAYGAIN_ALBUM_GAIN",
+    "REPLAYGAIN_ALBUM_PEAK",
+    "REPLAYGAIN_REFERENCE_LOUDNESS",
+];
+
+/// R128 gains (used by Opus) are relative to -23 LUFS instead of the -18 LUFS of ReplayGain.
+const R128_OFFSET_DB: f64 = -5.0;
+
+/// The ReplayGain tags of the source, written the way the target filetype expects them.
+/// ffmpeg carries over most tags by itself, but it doesn't know which name to use for ReplayGain
+/// in each format, and leaves the values as they are, so players can't always read them.
+/// An empty value means the tag should be removed.
+pub fn replaygain_tags(
+    metadata: &SongMetaData,
+    target_filetype: &MusicFileType,
+) -> Vec<(String, String)> {
+    match target_filetype {
+        MusicFileType::Opus { .. } => return r128_tags(metadata),
+        // The song keeps its format, so the tags are already right.
+        MusicFileType::Copy => return Vec::new(),
+        _ => (),
+    }
+    REPLAYGAIN_TAGS
+        .iter()
+        .filter_map(|name| {
+            let value = match *name {
+                "REPLAYGAIN_TRACK_GAIN" => format!("{:.2} dB", gain_db(metadata, "track")?),
+                "REPLAYGAIN_ALBUM_GAIN" => format!("{:.2} dB", gain_db(metadata, "album")?),
+                name if name.ends_with("_PEAK") => {
+                    format!(
+                        "{:.6}",
+                        parse_number(metadata.tag(&[&name.to_lowercase()])?)?
+                    )
+                }
+                name => format!(
+                    "{:.2} dB",
+                    parse_number(metadata.tag(&[&name.to_lowercase()])?)?
+                ),
+            };
+            Some((tag_name(name, target_filetype), value))
+        })
+        .collect()
+}
+
+/// Opus players only look at the R128 tags (and ReplayGain tags in Opus files are not supposed
+/// to be there), so convert them. Opus doesn't have peak tags, because clipping can't happen
+/// when decoding to floats.
+fn r128_tags(metadata: &SongMetaData) -> Vec<(String, String)> {
+    let mut tags = Vec::new();
+    for kind in ["track", "album"] {
+        if let Some(gain) = gain_db(metadata, kind) {
+            tags.push((
+                format!("R128_{}_GAIN", kind.to_uppercase()),
+                to_r128(gain).to_string(),
+            ));
+        }
+    }
+    // ffmpeg would carry over the ReplayGain tags from the source otherwise.
+    for name in REPLAYGAIN_TAGS {
+        if metadata.tag(&[&name.to_lowercase()]).is_some() {
+            tags.push((name.to_owned(), String::new()));
+        }
+    }
+    tags
+}
+
+/// The ReplayGain gain in dB for the "track" or the "album". Uses the R128 tags if the source is
+/// an Opus file.
+fn gain_db(metadata: &SongMetaData, kind: &str) -> Option<f64> {
+    metadata
+        .tag(&[&format!("replaygain_{kind}_gain")])
+        .and_then(parse_number)
+        .or_else(|| {
+            let r128 = metadata.tag(&[&format!("r128_{kind}_gain")])?;
+            Some(from_r128(r128.trim().parse().ok()?))
+        })
+}
+
+/// R128 gains are written as a Q7.8 fixed point number: the gain in dB times 256.
+fn to_r128(replaygain_db: f64) -> i16 {
+    ((replaygain_db + R128_OFFSET_DB) * 256.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+fn from_r128(r128: i16) -> f64 {
+    r128 as f64 / 256.0 - R128_OFFSET_DB
+}
+
+/// Vorbis comments are conventionally uppercase, most taggers write ID3v2 descriptions in
+/// lowercase.
+fn tag_name(name: &str, target_filetype: &MusicFileType) -> String {
+    match target_filetype {
+        MusicFileType::Mp3CBR { .. } | MusicFileType::Mp3VBR { .. } => name.to_lowercase(),
+        MusicFileType::Opus { .. }
+        | MusicFileType::Vorbis { .. }
+        | MusicFileType::Flac { .. }
+        | MusicFileType::Copy => name.to_owned(),
+    }
+}
+
+/// Gains are written like "-6.54 dB", or sometimes without unit.
+fn parse_number(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{album_loudness, replaygain_tags, Loudness};
+    use crate::{
+        ffmpeg_interface::SongMetaData,
+        music_library::{Id3Tags, MusicFileType, OpusVbr},
+        song::Song,
+    };
+    use std::time::Duration;
+
+    fn tags(song: &Song, target_filetype: &MusicFileType) -> Vec<(String, String)> {
+        replaygain_tags(&song.metadata, target_filetype)
+    }
+
+    #[test]
+    fn vorbis_comments_to_id3() {
+        let song = Song::new_fake(
+            "a.flac",
+            &[
+                ("replaygain_track_gain", "-7.3 dB"),
+                ("replaygain_track_peak", "0.98"),
+                ("replaygain_album_gain", "garbage"),
+            ],
+        );
+        assert_eq!(
+            tags(
+                &song,
+                &MusicFileType::Mp3VBR {
+                    quality: 3,
+                    id3: Id3Tags::default()
+                }
+            ),
+            vec![
+                ("replaygain_track_gain".to_owned(), "-7.30 dB".to_owned()),
+                ("replaygain_track_peak".to_owned(), "0.980000".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn id3_to_vorbis_comments() {
+        let song = Song::new_fake("a.mp3", &[("replaygain_album_gain", "+1.20")]);
+        assert_eq!(
+            tags(&song, &MusicFileType::Vorbis { quality: 5.0 }),
+            vec![("REPLAYGAIN_ALBUM_GAIN".to_owned(), "1.20 dB".to_owned())]
+        );
+    }
+
+    #[test]
+    fn opus_uses_r128() {
+        let song = Song::new_fake(
+            "a.flac",
+            &[
+                ("replaygain_track_gain", "-7.3 dB"),
+                ("replaygain_track_peak", "0.98"),
+            ],
+        );
+        assert_eq!(
+            tags(
+                &song,
+                &MusicFileType::Opus {
+                    bitrate: 128,
+                    compression_level: 3,
+                    vbr: OpusVbr::On,
+                }
+            ),
+            vec![
+                // (-7.3 - 5) * 256
+                ("R128_TRACK_GAIN".to_owned(), "-3149".to_owned()),
+                ("REPLAYGAIN_TRACK_GAIN".to_owned(), String::new()),
+                ("REPLAYGAIN_TRACK_PEAK".to_owned(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn r128_to_replaygain() {
+        let song = Song::new_fake("a.opus", &[("r128_album_gain", "-3149")]);
+        assert_eq!(
+            tags(
+                &song,
+                &MusicFileType::Mp3VBR {
+                    quality: 3,
+                    id3: Id3Tags::default()
+                }
+            ),
+            vec![("replaygain_album_gain".to_owned(), "-7.30 dB".to_owned())]
+        );
+    }
+
+    #[test]
+    /// Longer songs count more towards the loudness of the album.
+    fn album_loudness_weighted_by_duration() {
+        let loudness = |integrated_lufs, true_peak_dbfs| Loudness {
+            integrated_lufs,
+            true_peak_dbfs,
+        };
+        let same = album_loudness(&[
+            (loudness(-20.0, -3.0), Some(Duration::from_secs(100))),
+            (loudness(-20.0, -1.0), Some(Duration::from_secs(300))),
+        ])
+        .unwrap();
+        assert!((same.integrated_lufs - -20.0).abs() < 1e-9);
+        assert_eq!(same.true_peak_dbfs, -1.0);
+
+        let weighted = album_loudness(&[
+            (loudness(-10.0, -1.0), Some(Duration::from_secs(10))),
+            (loudness(-30.0, -1.0), Some(Duration::from_secs(1000))),
+        ])
+        .unwrap();
+        assert!(weighted.integrated_lufs < -20.0);
+        assert!(album_loudness(&[]).is_none());
+    }
+
+    #[test]
+    /// The tags actually end up in the transcoded file.
+    fn replaygain_survives_transcode() -> miette::Result<()> {
+        use crate::{
+            ffmpeg_interface::{transcode_song, ArtEmbedding, AudioConversion, PictureSelection},
+            test_data::TestFile,
+        };
+        let target_filetype = MusicFileType::Mp3VBR {
+            quality: 6,
+            id3: Id3Tags::default(),
+        };
+        let random_string = random_string::generate(16, "abcdefghijklmnopqrstuvwxyz");
+        let target =
+            std::path::PathBuf::from(format!("/tmp/syncbops/replaygain_test_{random_string}.mp3"));
+        let _ = std::fs::create_dir_all(target.parent().unwrap());
+        let song = Song::new_fake("a.flac", &[("replaygain_track_gain", "-7.3 dB")]);
+        transcode_song(
+            &TestFile::FlacWithoutArt.path(),
+            &target,
+            target_filetype.clone(),
+            AudioConversion::default(),
+            ArtEmbedding {
+                embed: false,
+                external_art: None,
+                max_resolution: 0,
+                pictures: PictureSelection::All,
+                recompress_quality: None,
+            },
+            &tags(&song, &target_filetype),
+            None,
+        )?;
+        let target_md = SongMetaData::parse_file(&target)?;
+        assert_eq!(target_md.tag(&["replaygain_track_gain"]), Some("-7.30 dB"));
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{
+    error_chain, ffmpeg_interface::FfmpegError, music_library::MusicLibraryError, song::Song,
+    target_path::TargetPlan, SyncResults,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+/// How to write the report of a sync.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum ReportFormat {
+    /// A table to open in a spreadsheet, or to read with scripts.
+    Csv,
+    /// A simple page to look at in a browser.
+    Html,
+}
+
+impl ReportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Html => "html",
+        }
+    }
+}
+
+/// What happened to a single song.
+#[derive(Debug, PartialEq)]
+pub struct ReportRow {
+    /// Relative to the source library.
+    pub source: PathBuf,
+    /// Relative to the target library.
+    pub target: Option<PathBuf>,
+    pub action: String,
+    /// Size of the synchronised copy before and after the sync, in bytes.
+    pub size_before: Option<u64>,
+    pub size_after: Option<u64>,
+    pub error: Option<String>,
+}
+
+const COLUMNS: [&str; 6] = [
+    "source",
+    "target",
+    "action",
+    "size_before",
+    "size_after",
+    "error",
+];
+
+/// Sizes of the synchronised copies that are already there, taken before synchronising so that
+/// the report can show what changed.
+pub fn target_sizes(target_plan: &TargetPlan) -> HashMap<PathBuf, u64> {
+    target_plan
+        .values()
+        .filter_map(|shadow| Some((shadow.clone(), std::fs::metadata(shadow).ok()?.len())))
+        .collect()
+}
+
+/// A row for every song, including the ones that were skipped before synchronising.
+pub fn report_rows(
+    sync_results: &SyncResults,
+    too_short: &[Song],
+    corrupt: &[(Song, FfmpegError)],
+    target_library: &Path,
+    target_plan: &TargetPlan,
+    sizes_before: &HashMap<PathBuf, u64>,
+) -> Vec<ReportRow> {
+    let mut rows = sync_results
+        .iter()
+        .map(|(song, result)| {
+            let shadow = target_plan.get(&song.library_relative_path);
+            let target = shadow
+                .and_then(|shadow| shadow.strip_prefix(target_library).ok())
+                .map(Path::to_path_buf);
+            let size_before = shadow.and_then(|shadow| sizes_before.get(shadow).copied());
+            let size_after = shadow.and_then(|shadow| Some(std::fs::metadata(shadow).ok()?.len()));
+            let (action, error) = match result {
+                Ok(record) => (
+                    record
+                        .update_type
+                        .map_or_else(String::new, |update_type| format!("{update_type:?}")),
+                    None,
+                ),
+                Err(e) => ("Failed".to_owned(), Some(error_chain(e))),
+            };
+            ReportRow {
+                source: song.library_relative_path.clone(),
+                target,
+                action,
+                size_before,
+                size_after,
+                error,
+            }
+        })
+        .collect::<Vec<_>>();
+    let skipped = too_short
+        .iter()
+        .map(|song| (song, "SkippedTooShort", None))
+        .chain(
+            corrupt
+                .iter()
+                .map(|(song, e)| (song, "SkippedCorrupt", Some(e.to_string()))),
+        );
+    for (song, action, error) in skipped {
+        rows.push(ReportRow {
+            source: song.library_relative_path.clone(),
+            target: None,
+            action: action.to_owned(),
+            size_before: None,
+            size_after: None,
+            error,
+        });
+    }
+    rows
+}
+
+pub fn format_report(rows: &[ReportRow], format: ReportFormat) -> String {
+    let cells = |row: &ReportRow| {
+        [
+            row.source.display().to_string(),
+            row.target
+                .as_ref()
+                .map_or_else(String::new, |target| target.display().to_string()),
+            row.action.clone(),
+            row.size_before.map_or_else(String::new, |s| s.to_string()),
+            row.size_after.map_or_else(String::new, |s| s.to_string()),
+            row.error.clone().unwrap_or_default(),
+        ]
+    };
+    let mut buf = String::new();
+    match format {
+        ReportFormat::Csv => {
+            let _ = writeln!(buf, "{}", COLUMNS.join(","));
+            for row in rows {
+                let line = cells(row).map(|cell| csv_field(&cell)).join(",");
+                let _ = writeln!(buf, "{line}");
+            }
+        }
+        ReportFormat::Html => {
+            let failed = rows.iter().filter(|row| row.error.is_some()).count();
+            let _ = writeln!(
+                buf,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+                <title>syncbops report</title>\n<style>\n\
+                body {{ font-family: sans-serif; }}\n\
+                td, th {{ padding: 2px 8px; text-align: left; }}\n\
+                tr.failed {{ background: #fdd; }}\n\
+                </style>\n</head>\n<body>\n\
+                <h1>syncbops report</h1>\n<p>{} songs, {} failed.</p>\n<table>",
+                rows.len(),
+                failed
+            );
+            let header = COLUMNS.map(|column| format!("<th>{column}</th>")).join("");
+            let _ = writeln!(buf, "<tr>{header}</tr>");
+            for row in rows {
+                let class = if row.error.is_some() {
+                    " class=\"failed\""
+                } else {
+                    ""
+                };
+                let line = cells(row)
+                    .map(|cell| format!("<td>{}</td>", html_escape(&cell)))
+                    .join("");
+                let _ = writeln!(buf, "<tr{class}>{line}</tr>");
+            }
+            let _ = writeln!(buf, "</table>\n</body>\n</html>");
+        }
+    }
+    buf
+}
+
+pub fn write_report(
+    path: &Path,
+    rows: &[ReportRow],
+    format: ReportFormat,
+) -> Result<(), MusicLibraryError> {
+    std::fs::write(path, format_report(rows, format)).map_err(|e| MusicLibraryError::Report {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+/// Quotes the field if it would otherwise break the row up.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_report, ReportFormat, ReportRow};
+    use std::path::PathBuf;
+
+    #[test]
+    /// Fields with commas or quotes stay one field, and nothing ends up as HTML.
+    fn report_escapes_fields() {
+        let rows = [ReportRow {
+            source: PathBuf::from("Artist/Album, \"Live\"/01 <Intro>.flac"),
+            target: None,
+            action: "Failed".to_owned(),
+            size_before: Some(1000),
+            size_after: None,
+            error: Some("ffmpeg failed".to_owned()),
+        }];
+        let csv = format_report(&rows, ReportFormat::Csv);
+        assert_eq!(
+            csv.lines().nth(1),
+            Some("\"Artist/Album, \"\"Live\"\"/01 <Intro>.flac\",,Failed,1000,,ffmpeg failed")
+        );
+        let html = format_report(&rows, ReportFormat::Html);
+        assert!(html.contains("01 &lt;Intro&gt;.flac"));
+        assert!(html.contains("<tr class=\"failed\">"));
+    }
+}
@@ -0,0 +1,463 @@
+use crate::{
+    artist_images::ArtistFolder,
+    backup::Disposal,
+    hashing::{PreviousSyncDb, SyncRecord},
+    log_failure,
+    music_library::{find_in_library, identify_file_type, FileType, UpdateType},
+    song::Song,
+    sync_song::SyncSettings,
+    target_path::{target_relative_path, TargetPlan},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// What --copy-sidecars copies if no extensions are given.
+pub const DEFAULT_SIDECAR_EXTENSIONS: &str = "cue,log,nfo,pdf";
+
+/// A file that is not music, but belongs with the songs next to it, like the .cue sheet and rip
+/// .log of an album, a booklet, or an .nfo file with extra information for media servers like
+/// Jellyfin and Kodi.
+#[derive(Debug, PartialEq)]
+pub struct Sidecar {
+    /// Path relative to the source library, which its record is keyed on.
+    pub library_relative_path: PathBuf,
+    /// Absolute path in the source library.
+    pub source: PathBuf,
+    /// Absolute path in the target library.
+    pub target: PathBuf,
+}
+
+/// Whether the file has one of the extensions, which are compared ignoring case and a leading
+/// dot.
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension().is_some_and(|ext| {
+        extensions
+            .iter()
+            .any(|wanted| ext.eq_ignore_ascii_case(wanted.trim_start_matches('.')))
+    })
+}
+
+/// Finds the sidecars (like album.cue) in the folders with songs, and the ones (like
+/// artist.nfo) in the artist folders. They go in the folder in the target library that the
+/// songs end up in.
+pub fn find_sidecars(
+    songs: &[Song],
+    target_plan: &TargetPlan,
+    source_library: &Path,
+    target_library: &Path,
+    artist_folders: &[ArtistFolder],
+    extensions: &[String],
+    settings: &SyncSettings,
+) -> Vec<Sidecar> {
+    let mut folders: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+    for song in songs {
+        let source = song
+            .source_relative_path(source_library)
+            .parent()
+            .expect("song should be in a folder")
+            .to_path_buf();
+        let target = target_plan[&song.library_relative_path]
+            .parent()
+            .expect("shadow should be in a folder")
+            .to_path_buf();
+        // Like for artist images: always pick the same one if the songs of a folder end up in
+        // different places.
+        folders
+            .entry(source)
+            .and_modify(|existing| {
+                if target < *existing {
+                    existing.clone_from(&target)
+                }
+            })
+            .or_insert(target);
+    }
+    for folder in artist_folders {
+        folders
+            .entry(folder.source.clone())
+            .or_insert_with(|| target_library.join(&folder.target));
+    }
+
+    let mut sidecars = Vec::new();
+    for (source, target) in folders {
+        let Ok(entries) = fs::read_dir(source_library.join(&source)) else {
+            continue;
+        };
+        for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+            if !has_extension(&path, extensions) || !path.is_file() {
+                continue;
+            }
+            let name = Path::new(path.file_name().expect("sidecar should have a file name"));
+            let library_relative_path = source.join(name);
+            sidecars.push(Sidecar {
+                // Keyed like the records of songs.
+                library_relative_path: match settings.target_paths.unicode_normalization {
+                    Some(form) => form.normalize_path(&library_relative_path),
+                    None => library_relative_path,
+                },
+                target: target.join(target_relative_path(
+                    name,
+                    target_library,
+                    &settings.target_paths,
+                )),
+                source: path,
+            });
+        }
+    }
+    sidecars
+}
+
+/// Finds the .lrc files with the lyrics of the songs, which have the same name as the song. They
+/// get the same name as the synchronised song, as that is how players find them.
+pub fn find_lyrics(songs: &[Song], target_plan: &TargetPlan) -> Vec<Sidecar> {
+    songs
+        .iter()
+        .filter_map(|song| {
+            let source = ["lrc", "LRC"]
+                .iter()
+                .map(|ext| song.absolute_path.with_extension(ext))
+                .find(|path| path.is_file())?;
+            Some(Sidecar {
+                library_relative_path: song
+                    .library_relative_path
+                    .with_extension(source.extension()?),
+                target: target_plan[&song.library_relative_path].with_extension("lrc"),
+                source,
+            })
+        })
+        .collect()
+}
+
+/// Whether there is still a song (or video) next to the lyrics with the same name.
+fn has_song(lyrics: &Path) -> bool {
+    let (Some(folder), Some(stem)) = (lyrics.parent(), lyrics.file_stem()) else {
+        return false;
+    };
+    let Ok(entries) = fs::read_dir(folder) else {
+        return false;
+    };
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        let path = entry.path();
+        path.file_stem() == Some(stem)
+            && matches!(
+                identify_file_type(&path),
+                Some(FileType::Music | FileType::Video)
+            )
+    })
+}
+
+/// Copies the sidecars that are not in the target library yet, that changed since they were
+/// copied, or that go somewhere else now. Returns the records of the ones that were copied.
+pub fn copy_sidecars(
+    sidecars: &[Sidecar],
+    previous_sync_db: Option<&PreviousSyncDb>,
+    target_library: &Path,
+    settings: &SyncSettings,
+) -> Vec<SyncRecord> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut copied = Vec::new();
+    for sidecar in sidecars {
+        let target_relative = sidecar
+            .target
+            .strip_prefix(target_library)
+            .expect("sidecar should go in the target library");
+        let previous = previous_sync_db
+            .and_then(|db| db.get(&sidecar.library_relative_path))
+            .filter(|record| record.sidecar);
+        let moved = previous.and_then(|record| {
+            record
+                .target_relative_path
+                .as_deref()
+                .filter(|previous_target| *previous_target != target_relative)
+        });
+        let up_to_date = match (modified(&sidecar.source), modified(&sidecar.target)) {
+            (Some(source), Some(target)) => target >= source,
+            (_, target) => target.is_some(),
+        };
+        if previous.is_some() && moved.is_none() && up_to_date {
+            continue;
+        }
+        if !settings.dry_run {
+            let _ = fs::create_dir_all(
+                sidecar
+                    .target
+                    .parent()
+                    .expect("Cannot get parent dir of sidecar"),
+            );
+            let copied = settings
+                .disposal
+                .set_aside(&sidecar.target)
+                .and_then(|_| settings.disposal.created(&sidecar.target))
+                .and_then(|_| fs::copy(&sidecar.source, &sidecar.target));
+            if let Err(e) = copied {
+                log_failure(
+                    format!("Could not copy {}: {}", sidecar.source.display(), e),
+                    None,
+                );
+                continue;
+            }
+            if let Some(moved) = moved {
+                let _ = settings.disposal.remove_file(&target_library.join(moved));
+            }
+        }
+        let update_type = match previous {
+            Some(_) => UpdateType::Overwrite,
+            None => UpdateType::Copied,
+        };
+        copied.push(
+            SyncRecord::from_sidecar(
+                &sidecar.library_relative_path,
+                &sidecar.source,
+                target_relative,
+            )
+            .set_update_type(update_type),
+        );
+    }
+    copied
+}
+
+/// Removes the copies of sidecars of which the source is gone, or of which the extension is not
+/// copied anymore, and their records. With `lyrics`, .lrc files are copied too, and removed
+/// when their song is gone. Returns the removed records.
+pub fn remove_stale_sidecars(
+    previous_sync_db: &mut PreviousSyncDb,
+    source_library: &Path,
+    target_library: &Path,
+    extensions: &[String],
+    lyrics: bool,
+    dry_run: bool,
+    disposal: &Disposal,
+) -> Vec<SyncRecord> {
+    let lrc = ["lrc".to_owned()];
+    let mut stale = previous_sync_db
+        .values()
+        .filter(|record| record.sidecar)
+        .filter(|record| {
+            let Some(source) = find_in_library(source_library, &record.library_relative_path)
+            else {
+                return true;
+            };
+            let is_lyrics = lyrics && has_extension(&source, &lrc);
+            !(is_lyrics || has_extension(&source, extensions)) || is_lyrics && !has_song(&source)
+        })
+        .map(|record| record.library_relative_path.clone())
+        .collect::<Vec<_>>();
+    stale.sort();
+    let stale = stale
+        .iter()
+        .map(|path| {
+            previous_sync_db
+                .remove(path)
+                .expect("stale record should be in the records")
+        })
+        .collect::<Vec<_>>();
+    for target in stale
+        .iter()
+        .filter_map(|record| record.target_relative_path.as_ref())
+        .filter(|_| !dry_run)
+    {
+        let _ = disposal.remove_file(&target_library.join(target));
+    }
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_sidecars, find_lyrics, find_sidecars, remove_stale_sidecars};
+    use crate::{
+        backup::Disposal,
+        hashing::{register_record_to_previous_sync_db, PreviousSyncDb},
+        music_library::{ArtStrategy, Id3Tags, MusicFileType},
+        song::Song,
+        sync_song::SyncSettings,
+        target_path::NormalizationForm,
+    };
+    use std::{collections::HashMap, fs};
+
+    #[test]
+    /// Sidecars are copied next to the songs of their folder once, and removed again when they
+    /// are gone from the source library.
+    fn sidecar_files() {
+        let root = std::env::temp_dir().join(format!(
+            "syncbops_sidecars_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let source_library = root.join("source");
+        let target_library = root.join("target");
+        fs::create_dir_all(source_library.join("Album")).unwrap();
+        for name in ["album.cue", "rip.LOG", "cover.jpg"] {
+            fs::write(source_library.join("Album").join(name), name).unwrap();
+        }
+        let mut song = Song::new_fake("Album/01.flac", &[]);
+        song.absolute_path = source_library.join("Album/01.flac");
+        let target_plan = HashMap::from([(
+            song.library_relative_path.clone(),
+            target_library.join("Artist/Album/01.mp3"),
+        )]);
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::None,
+        );
+        let extensions = ["cue".to_owned(), ".log".to_owned()];
+
+        let sidecars = find_sidecars(
+            &[song],
+            &target_plan,
+            &source_library,
+            &target_library,
+            &[],
+            &extensions,
+            &settings,
+        );
+        assert_eq!(sidecars.len(), 2);
+        let mut db = PreviousSyncDb::new();
+        for record in copy_sidecars(&sidecars, Some(&db), &target_library, &settings) {
+            register_record_to_previous_sync_db(&mut db, record);
+        }
+        assert!(target_library.join("Artist/Album/album.cue").is_file());
+        assert!(!target_library.join("Artist/Album/cover.jpg").exists());
+        assert!(copy_sidecars(&sidecars, Some(&db), &target_library, &settings).is_empty());
+
+        fs::remove_file(source_library.join("Album/album.cue")).unwrap();
+        let stale = remove_stale_sidecars(
+            &mut db,
+            &source_library,
+            &target_library,
+            &extensions,
+            false,
+            false,
+            &Disposal::Delete,
+        );
+        assert_eq!(
+            stale
+                .iter()
+                .map(|record| record.library_relative_path.as_path())
+                .collect::<Vec<_>>(),
+            [std::path::Path::new("Album/album.cue")]
+        );
+        assert!(!target_library.join("Artist/Album/album.cue").exists());
+        assert!(target_library.join("Artist/Album/rip.LOG").is_file());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    /// The sidecars of a folder of which the name is decomposed on the filesystem are found,
+    /// while their records are keyed on the normalised name like those of the songs.
+    fn sidecars_of_normalised_songs() {
+        let root = std::env::temp_dir().join(format!(
+            "syncbops_sidecars_nfd_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let source_library = root.join("source");
+        let target_library = root.join("target");
+        let decomposed = source_library.join("Bjo\u{308}rk");
+        fs::create_dir_all(&decomposed).unwrap();
+        fs::write(decomposed.join("album.cue"), "cue").unwrap();
+        let mut song = Song::new_fake("Bj\u{f6}rk/01.flac", &[]);
+        song.absolute_path = decomposed.join("01.flac");
+        let target_plan = HashMap::from([(
+            song.library_relative_path.clone(),
+            target_library.join("Bj\u{f6}rk/01.flac"),
+        )]);
+        let mut settings = SyncSettings::new_debug(MusicFileType::Copy, ArtStrategy::None);
+        settings.target_paths.unicode_normalization = Some(NormalizationForm::Nfc);
+        let extensions = ["cue".to_owned()];
+
+        let sidecars = find_sidecars(
+            &[song],
+            &target_plan,
+            &source_library,
+            &target_library,
+            &[],
+            &extensions,
+            &settings,
+        );
+        assert_eq!(sidecars.len(), 1);
+        assert_eq!(
+            sidecars[0].library_relative_path,
+            std::path::Path::new("Bj\u{f6}rk/album.cue")
+        );
+        let mut db = PreviousSyncDb::new();
+        for record in copy_sidecars(&sidecars, Some(&db), &target_library, &settings) {
+            register_record_to_previous_sync_db(&mut db, record);
+        }
+        assert!(target_library.join("Bj\u{f6}rk/album.cue").is_file());
+
+        // It is still there, under its decomposed name.
+        let stale = remove_stale_sidecars(
+            &mut db,
+            &source_library,
+            &target_library,
+            &extensions,
+            false,
+            false,
+            &Disposal::Delete,
+        );
+        assert!(stale.is_empty());
+        assert!(target_library.join("Bj\u{f6}rk/album.cue").is_file());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    /// Lyrics get the name of the synchronised song, and go when the song goes.
+    fn lyrics_files() {
+        let root = std::env::temp_dir().join(format!(
+            "syncbops_lyrics_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        ));
+        let source_library = root.join("source");
+        let target_library = root.join("target");
+        fs::create_dir_all(source_library.join("Album")).unwrap();
+        for name in ["01 Song.flac", "01 Song.lrc", "02 Other.flac"] {
+            fs::write(source_library.join("Album").join(name), name).unwrap();
+        }
+        let songs = ["01 Song.flac", "02 Other.flac"].map(|name| {
+            let mut song = Song::new_fake(&format!("Album/{name}"), &[]);
+            song.absolute_path = source_library.join("Album").join(name);
+            song
+        });
+        let target_plan = HashMap::from([
+            (
+                songs[0].library_relative_path.clone(),
+                target_library.join("Artist/Album/01 - Song.opus"),
+            ),
+            (
+                songs[1].library_relative_path.clone(),
+                target_library.join("Artist/Album/02 - Other.opus"),
+            ),
+        ]);
+        let settings = SyncSettings::new_debug(MusicFileType::Copy, ArtStrategy::None);
+
+        let lyrics = find_lyrics(&songs, &target_plan);
+        assert_eq!(lyrics.len(), 1);
+        let mut db = PreviousSyncDb::new();
+        for record in copy_sidecars(&lyrics, Some(&db), &target_library, &settings) {
+            register_record_to_previous_sync_db(&mut db, record);
+        }
+        let copied = target_library.join("Artist/Album/01 - Song.lrc");
+        assert!(copied.is_file());
+
+        // Still there while the song is.
+        let stale = |db: &mut PreviousSyncDb| {
+            remove_stale_sidecars(
+                db,
+                &source_library,
+                &target_library,
+                &[],
+                true,
+                false,
+                &Disposal::Delete,
+            )
+        };
+        assert!(stale(&mut db).is_empty());
+        fs::remove_file(source_library.join("Album/01 Song.flac")).unwrap();
+        assert_eq!(stale(&mut db).len(), 1);
+        assert!(!copied.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,20 +1,34 @@
 use crate::{
+    cue::Segment,
     ffmpeg_interface::SongMetaData,
     music_library::{library_relative_path, ArtworkType, MusicLibraryError},
+    replaygain::Loudness,
+};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
 };
-use std::{fmt::Display, path::PathBuf};
 
 #[derive(Debug)]
 pub struct Song {
     /// Where the original song file can be found
     pub absolute_path: PathBuf,
-    /// The location of the song file relative to the source library.
+    /// The location of the song file relative to the source library. Records are keyed on it,
+    /// so it is normalised with --unicode-normalization: use `source_relative_path()` to find
+    /// things in the source library.
     pub library_relative_path: PathBuf,
 
     /// Where the external album art is, if it exists.
     pub external_album_art: Option<PathBuf>,
 
     pub metadata: SongMetaData,
+
+    /// The measured loudness, if it has been scanned.
+    pub loudness: Option<Loudness>,
+
+    /// For the tracks of an album that is one big file with a .cue sheet: which part of the file
+    /// this song is. None for songs that are a file of their own.
+    pub segment: Option<Segment>,
 }
 
 impl Song {
@@ -25,15 +39,49 @@ impl Song {
         external_album_art: Option<PathBuf>,
     ) -> Result<Song, MusicLibraryError> {
         let metadata = SongMetaData::parse_file(&path)?;
-        let library_relative_path = library_relative_path(&path, &source_library);
-        Ok(Song {
+        Ok(Song::with_metadata(
+            path,
+            &source_library,
+            external_album_art,
+            metadata,
+        ))
+    }
+
+    /// Like `new()`, for when the metadata is already known.
+    pub fn with_metadata(
+        path: PathBuf,
+        source_library: &Path,
+        external_album_art: Option<PathBuf>,
+        metadata: SongMetaData,
+    ) -> Song {
+        let library_relative_path = library_relative_path(&path, source_library);
+        Song {
             absolute_path: path,
             external_album_art,
             metadata,
             library_relative_path,
+            loudness: None,
+            segment: None,
+        }
+    }
+
+    /// The title from the tags, or else the name of the file. None only if the file has no
+    /// name either.
+    pub fn title(&self) -> Option<String> {
+        self.metadata.title.clone().or_else(|| {
+            self.library_relative_path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
         })
     }
 
+    /// Where the file of the song really is, relative to the source library. Unlike
+    /// `library_relative_path` it is never normalised, and for the tracks of a single-file album
+    /// it is the whole file.
+    pub fn source_relative_path(&self, source_library: &Path) -> PathBuf {
+        library_relative_path(&self.absolute_path, source_library)
+    }
+
     // Does the song have artwork information? Can use a
     pub fn has_artwork(&self) -> ArtworkType {
         if self.external_album_art.is_some() {
@@ -59,6 +107,39 @@ impl Song {
             .to_path_buf();
         Song::new(path, parent_directory, external_album_art)
     }
+
+    /// Creates a song with the given tags, without there being an actual file.
+    /// For testing things that only depend on the metadata.
+    #[cfg(test)]
+    pub fn new_fake(library_relative_path: &str, tags: &[(&str, &str)]) -> Song {
+        Song {
+            absolute_path: PathBuf::from("/source_library").join(library_relative_path),
+            library_relative_path: library_relative_path.into(),
+            external_album_art: None,
+            metadata: SongMetaData {
+                title: tags
+                    .iter()
+                    .find(|(k, _)| *k == "title")
+                    .map(|(_, v)| v.to_string()),
+                bitrate_kbps: 320,
+                sample_rate: Some(44100),
+                channels: Some(2),
+                bit_depth: Some(16),
+                codec: None,
+                has_embedded_album_art: false,
+                has_video: false,
+                embedded_art_resolution: None,
+                embedded_pictures: Vec::new(),
+                tags: tags
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                duration: None,
+            },
+            loudness: None,
+            segment: None,
+        }
+    }
 }
 
 impl Display for Song {
@@ -0,0 +1,248 @@
+use crate::{
+    hashing::{read_records_from_file, PreviousSyncDb, RecordsFormat, SyncRecord},
+    music_library::UpdateType,
+    PREVIOUS_SYNC_DB_FILENAME,
+};
+use rusqlite::{params, Connection, OpenFlags};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Name of the records database in the target library.
+pub const SQLITE_RECORDS_FILENAME: &str = ".syncbops.sqlite";
+/// Version of the database layout, stored as its `user_version`. Increase it when the tables
+/// change, and migrate older databases in `SqliteRecords::open()`. Changes to the records
+/// themselves don't need this, as they are stored as JSON.
+const SCHEMA_VERSION: u32 = 1;
+/// What the JSON records are renamed to after they are moved into the database, so they are
+/// not read again.
+const MIGRATED_JSON_RECORDS_FILENAME: &str = ".syncbops.migrated";
+
+/// Records of previous syncs in an SQLite database. Unlike the JSON records, every record is
+/// written as soon as its song is synchronised, instead of rewriting all records at the end.
+/// An interrupted sync then only loses the song it was working on.
+pub struct SqliteRecords {
+    // Songs are synchronised in parallel, but only one can write at a time.
+    connection: Mutex<Connection>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RecordsError {
+    #[error("Could not use the records database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("Could not (de)serialise a record: {0}")]
+    Serialise(#[from] serde_json::Error),
+
+    #[error("The records database is of version {version}, which is newer than what this version of syncbops can use (version {SCHEMA_VERSION}). Update syncbops, or delete the database to start over.")]
+    NewerVersion { version: u32 },
+}
+
+impl SqliteRecords {
+    /// Opens the records database in the target library, creating it if it does not exist yet.
+    /// If there are JSON records in the target library, they are moved into the new database.
+    pub fn open(target_library: &Path) -> Result<SqliteRecords, RecordsError> {
+        let connection = Connection::open(target_library.join(SQLITE_RECORDS_FILENAME))?;
+        check_schema_version(&connection)?;
+        // Write-ahead logging keeps the database intact if syncbops is interrupted halfway
+        // through a write, and is a lot faster for many small writes.
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        // The records are stored as JSON, so that new fields can be added to them without
+        // having to migrate the database.
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS records (
+                library_relative_path TEXT PRIMARY KEY,
+                record TEXT NOT NULL
+            )",
+            (),
+        )?;
+        connection.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        let records = SqliteRecords {
+            connection: Mutex::new(connection),
+        };
+        records.migrate_json_records(target_library)?;
+        Ok(records)
+    }
+
+    /// Reads the records in the target library without changing anything, e.g. for dry runs.
+    /// None if there is no database yet.
+    pub fn read_only(target_library: &Path) -> Result<Option<PreviousSyncDb>, RecordsError> {
+        let path = target_library.join(SQLITE_RECORDS_FILENAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        check_schema_version(&connection)?;
+        read_all(&connection).map(Some)
+    }
+
+    /// Saves the record right away, replacing the earlier record of the same song. Like the
+    /// JSON records, records of songs that were not changed are not written, apart from how
+    /// they are encoded if that was not recorded yet.
+    pub fn upsert(&self, record: &SyncRecord) -> Result<(), RecordsError> {
+        if record.update_type == Some(UpdateType::NoChange) {
+            let encoder = serde_json::to_string(&record.encoder)?;
+            self.connection
+                .lock()
+                .unwrap()
+                .prepare_cached(
+                    "UPDATE records SET record = json_set(record, '$.encoder', json(?2))
+                    WHERE library_relative_path = ?1
+                    AND json_extract(record, '$.encoder') IS NULL",
+                )?
+                .execute(params![
+                    record.library_relative_path.to_string_lossy(),
+                    encoder
+                ])?;
+            return Ok(());
+        }
+        let json = serde_json::to_string(record)?;
+        let connection = self.connection.lock().unwrap();
+        connection
+            .prepare_cached(
+                "INSERT INTO records (library_relative_path, record) VALUES (?1, ?2)
+                ON CONFLICT(library_relative_path) DO UPDATE SET record = excluded.record",
+            )?
+            .execute(params![
+                record.library_relative_path.to_string_lossy(),
+                json
+            ])?;
+        Ok(())
+    }
+
+    /// Removes the records of these songs, e.g. because they are no longer in the source library.
+    pub fn remove(&self, library_relative_paths: &[PathBuf]) -> Result<(), RecordsError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut statement =
+                transaction.prepare("DELETE FROM records WHERE library_relative_path = ?1")?;
+            for path in library_relative_paths {
+                statement.execute(params![path.to_string_lossy()])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Moves the records of an earlier sync with JSON records into the database, all at once.
+    fn migrate_json_records(&self, target_library: &Path) -> Result<(), RecordsError> {
+        let json_path = target_library.join(PREVIOUS_SYNC_DB_FILENAME);
+        if !json_path.exists() {
+            return Ok(());
+        }
+        let Some(json_records) = read_records_from_file(&json_path, RecordsFormat::Json, true)
+        else {
+            return Ok(());
+        };
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            // Records that are already in the database are newer.
+            let mut statement = transaction.prepare(
+                "INSERT OR IGNORE INTO records (library_relative_path, record) VALUES (?1, ?2)",
+            )?;
+            for (path, record) in &json_records {
+                statement.execute(params![
+                    path.to_string_lossy(),
+                    serde_json::to_string(record)?
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        let migrated = target_library.join(MIGRATED_JSON_RECORDS_FILENAME);
+        if let Err(e) = std::fs::rename(&json_path, &migrated) {
+            eprintln!(
+                "Could not rename {} after moving its records into the database: {e}",
+                json_path.display()
+            );
+        }
+        say!(
+            "Moved {} records from {} into {}",
+            json_records.len(),
+            json_path.display(),
+            SQLITE_RECORDS_FILENAME
+        );
+        Ok(())
+    }
+}
+
+/// A new database has version 0.
+fn check_schema_version(connection: &Connection) -> Result<(), RecordsError> {
+    let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(RecordsError::NewerVersion { version });
+    }
+    Ok(())
+}
+
+fn read_all(connection: &Connection) -> Result<PreviousSyncDb, RecordsError> {
+    let mut statement = connection.prepare("SELECT record FROM records")?;
+    let rows = statement.query_map((), |row| row.get::<_, String>(0))?;
+    let mut db = PreviousSyncDb::new();
+    for json in rows {
+        let record: SyncRecord = serde_json::from_str(&json?)?;
+        db.insert(record.library_relative_path.clone(), record);
+    }
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteRecords;
+    use crate::{
+        hashing::{write_records_of_current_sync, PreviousSyncDb, RecordsFormat, SyncRecord},
+        music_library::UpdateType,
+        PREVIOUS_SYNC_DB_FILENAME,
+    };
+    use std::{path::PathBuf, time::SystemTime};
+
+    fn record(path: &str, hash: u64) -> SyncRecord {
+        SyncRecord {
+            library_relative_path: path.into(),
+            update_type: Some(UpdateType::NewTranscode),
+            date: SystemTime::now(),
+            hash: Some(hash),
+            target_relative_path: None,
+            embed_art_resolution: 0,
+            loudness: None,
+            target_hash: None,
+            target_stamp: None,
+            audio_hash: None,
+            encoder: None,
+            encode_speed: None,
+            sidecar: false,
+            art: false,
+        }
+    }
+
+    #[test]
+    /// JSON records are moved into the database, and later records replace earlier ones.
+    fn migrate_and_upsert() {
+        let target_library: PathBuf = format!(
+            "/tmp/syncbops/sqlite_records_{}",
+            random_string::generate(16, "abcdefghijklmnopqrstuvwxyz")
+        )
+        .into();
+        std::fs::create_dir_all(&target_library).unwrap();
+        let mut json_records = PreviousSyncDb::new();
+        json_records.insert("a.flac".into(), record("a.flac", 1));
+        write_records_of_current_sync(&json_records, &target_library, RecordsFormat::Json);
+
+        let records = SqliteRecords::open(&target_library).unwrap();
+        assert!(!target_library.join(PREVIOUS_SYNC_DB_FILENAME).exists());
+        records.upsert(&record("a.flac", 2)).unwrap();
+        records.upsert(&record("b.flac", 3)).unwrap();
+        let mut unchanged = record("c.flac", 4);
+        unchanged.update_type = Some(UpdateType::NoChange);
+        records.upsert(&unchanged).unwrap();
+        drop(records);
+
+        let db = SqliteRecords::read_only(&target_library).unwrap().unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db[&PathBuf::from("a.flac")].hash, Some(2));
+        assert_eq!(db[&PathBuf::from("b.flac")].hash, Some(3));
+    }
+}
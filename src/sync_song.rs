@@ -1,94 +1,836 @@
 use crate::{
-    ffmpeg_interface::{transcode_song, SongMetaData},
-    hashing::{hash_file, PreviousSyncDb, SyncRecord},
+    audiobooks::Audiobooks,
+    backup::Disposal,
+    cue::track_tags,
+    ffmpeg_interface::{
+        chosen_encoder, embedded_picture_sizes, grab_video_frame, transcode_song, ArtEmbedding,
+        AudioConversion, PictureSelection, SongMetaData,
+    },
+    hashing::{hash_audio, hash_file, FileStamp, PreviousSyncDb, SyncRecord},
     log_failure,
     music_library::{
-        get_shadow_filename, ArtStrategy, MusicFileType, MusicLibraryError, UpdateType,
+        ArtDeduplication, ArtFormat, ArtStrategy, ArtworkType, CodecPolicy, Downmix, LinkMode,
+        MusicFileType, MusicLibraryError, OversizedArt, SongDeduplication, UpdateType,
     },
+    plan::{Action, PlannedSong},
+    replaygain::replaygain_tags,
     song::Song,
+    tag_map::TagMap,
+    tag_rules::TagRules,
+    target_path::TargetPathOptions,
+    transcode_rules::TranscodeRules,
 };
 use indicatif::ProgressBar;
-use std::{fs, path::Path};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 use UpdateType as U;
 
-/// Synchronises the file. Returns true if the file is updated, false it was not.
+/// Caps how many songs are written in a run, to try out settings on a few songs before
+/// synchronising everything. Songs that are up to date don't count.
+pub static CHANGE_LIMIT: ChangeLimit = ChangeLimit::new();
+
+pub struct ChangeLimit {
+    max: AtomicUsize,
+    written: AtomicUsize,
+}
+
+impl ChangeLimit {
+    const fn new() -> Self {
+        ChangeLimit {
+            max: AtomicUsize::new(usize::MAX),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::Relaxed);
+    }
+
+    /// Counts another song as written, if that is still allowed.
+    fn take(&self) -> bool {
+        let max = self.max.load(Ordering::Relaxed);
+        self.written
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |written| {
+                (written < max).then_some(written + 1)
+            })
+            .is_ok()
+    }
+}
+
+/// Everything about how songs should be synchronised that is the same for every song in a run.
+#[derive(Clone, Debug)]
+pub struct SyncSettings {
+    /// The filetype for songs that don't match any of the `transcode_rules`.
+    pub target_filetype: MusicFileType,
+    pub transcode_rules: TranscodeRules,
+    pub art_strategy: ArtStrategy,
+    /// Overwrite files even if they are up to date.
+    pub force: bool,
+    /// Don't make any changes to the filesystem.
+    pub dry_run: bool,
+    pub verbose: bool,
+    pub target_paths: TargetPathOptions,
+    /// Embedded art larger than this (in pixels, width or height) is scaled down. 0 means
+    /// no scaling.
+    pub embed_art_resolution: u32,
+    /// External art files larger than this (in pixels, width or height) are scaled down when
+    /// copied. 0 means no scaling.
+    pub art_file_resolution: u32,
+    /// Re-encode copied JPEG art files with this quality (1-100).
+    pub art_file_quality: Option<u8>,
+    /// Convert copied art files to this format.
+    pub art_file_format: Option<ArtFormat>,
+    /// Give all copied art files this name (without extension), e.g. "cover".
+    pub art_file_name: Option<String>,
+    /// What to do with copied art files that are identical to art next to it.
+    pub art_deduplication: Option<ArtDeduplication>,
+    /// Embed all pictures of the source file, instead of only the front cover.
+    pub keep_all_pictures: bool,
+    /// Embedded art that is larger than this (in bytes) is handled by `oversized_art`.
+    pub max_embedded_art_bytes: Option<u64>,
+    pub oversized_art: OversizedArt,
+    /// Resample songs with a higher sample rate (in Hz) than this.
+    pub max_sample_rate: Option<u32>,
+    /// Mix songs with more channels down to this.
+    pub downmix: Option<Downmix>,
+    /// Reduce songs with more than 16 bits per sample to 16 bits, when transcoding to a
+    /// lossless format.
+    pub reduce_bit_depth: bool,
+    /// Dither when reducing the bit depth.
+    pub dither: bool,
+    pub codec_policy: CodecPolicy,
+    /// Songs with a lower bitrate than this (in kbps) are copied instead of transcoded. If not
+    /// given, the equivalent bitrate of the target filetype is used.
+    pub copy_threshold_kbps: Option<u32>,
+    /// How to copy songs that don't need to be transcoded.
+    pub link_mode: LinkMode,
+    /// What to do with songs that are identical to another song.
+    pub song_deduplication: Option<SongDeduplication>,
+    /// Also compare songs on only their audio, so songs of which only the tags changed are
+    /// retagged instead of transcoded again.
+    pub hash_audio_only: bool,
+    /// Version of ffmpeg that is used, to store in the records.
+    pub ffmpeg_version: Option<String>,
+    /// Songs that are audiobooks, which are synchronised differently.
+    pub audiobooks: Option<Audiobooks>,
+    /// Tags (lowercase) that are left out of the synchronised songs.
+    pub strip_tags: Vec<String>,
+    /// Rules that clean up the tags of the synchronised songs.
+    pub tag_rules: TagRules,
+    /// Which tags of the source are written under which name in the target.
+    pub tag_map: TagMap,
+    /// Also compare the duration of songs that are up to date according to the records with
+    /// that of their source, to find ones that were cut off.
+    pub check_durations: bool,
+    /// What happens to the files in the target library that are overwritten.
+    pub disposal: Disposal,
+    /// Give the synchronised songs the modification (and access) time of their source, instead
+    /// of the time they were written.
+    pub preserve_mtime: bool,
+    /// Give copied songs the extended attributes, permissions and times of their source.
+    pub preserve_xattrs: bool,
+}
+
+impl SyncSettings {
+    /// Shorthand for settings with everything but the filetype and art strategy at their
+    /// defaults.
+    #[cfg(test)]
+    pub fn new_debug(target_filetype: MusicFileType, art_strategy: ArtStrategy) -> SyncSettings {
+        SyncSettings {
+            target_filetype,
+            transcode_rules: TranscodeRules::default(),
+            art_strategy,
+            force: false,
+            dry_run: false,
+            verbose: true,
+            target_paths: TargetPathOptions::default(),
+            embed_art_resolution: 0,
+            art_file_resolution: 0,
+            art_file_quality: None,
+            art_file_format: None,
+            art_file_name: None,
+            art_deduplication: None,
+            keep_all_pictures: false,
+            max_embedded_art_bytes: None,
+            oversized_art: OversizedArt::Recompress,
+            max_sample_rate: None,
+            downmix: None,
+            reduce_bit_depth: false,
+            dither: false,
+            codec_policy: CodecPolicy::Bitrate,
+            copy_threshold_kbps: None,
+            link_mode: LinkMode::Copy,
+            song_deduplication: None,
+            hash_audio_only: false,
+            ffmpeg_version: None,
+            audiobooks: None,
+            strip_tags: Vec::new(),
+            tag_rules: TagRules::default(),
+            tag_map: TagMap::default(),
+            check_durations: false,
+            disposal: Disposal::Delete,
+            preserve_mtime: false,
+            preserve_xattrs: false,
+        }
+    }
+
+    /// The filetype the song should be transcoded to.
+    pub fn target_filetype_for(&self, song: &Song) -> &MusicFileType {
+        match &self.audiobooks {
+            Some(audiobooks) if audiobooks.contains(song) => &audiobooks.filetype,
+            _ => self
+                .transcode_rules
+                .target_filetype_for(song, &self.target_filetype),
+        }
+    }
+
+    /// The filetype the shadow of the song gets. Songs that are copied keep their own.
+    pub fn shadow_filetype_for(&self, song: &Song) -> MusicFileType {
+        if would_copy(song, self) {
+            MusicFileType::Copy
+        } else {
+            self.target_filetype_for(song).clone()
+        }
+    }
+
+    pub fn is_audiobook(&self, song: &Song) -> bool {
+        self.audiobooks.as_ref().is_some_and(|a| a.contains(song))
+    }
+
+    /// The tags of the song that are left out of its synchronised copy.
+    pub fn tags_to_strip(&self, song: &Song) -> Vec<String> {
+        self.strip_tags
+            .iter()
+            .filter(|tag| song.metadata.tags.contains_key(*tag))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the tags of the synchronised copy of the song differ from the ones of the source,
+    /// other than for ReplayGain.
+    pub fn changes_tags(&self, song: &Song) -> bool {
+        !self.tags_to_strip(song).is_empty() || !self.tag_rules.rewrite(&song.metadata).is_empty()
+    }
+
+    /// Audiobooks don't get any art, as the cover of a book is of little use on a device.
+    pub fn art_strategy_for(&self, song: &Song) -> ArtStrategy {
+        if self.is_audiobook(song) {
+            ArtStrategy::None
+        } else {
+            self.art_strategy
+        }
+    }
+}
+
+/// Synchronises the file to the given shadow path (as planned with `plan_target_paths()`).
+/// Returns true if the file is updated, false it was not.
 pub fn sync_song(
     song: &Song,
+    shadow: &Path,
     target_library: &Path,
-    target_filetype: MusicFileType,
-    art_strategy: ArtStrategy,
+    settings: &SyncSettings,
     previous_sync_db: Option<&PreviousSyncDb>,
-    force: bool,
-    dry_run: bool,
     pb: Option<&ProgressBar>,
-    verbose: bool,
 ) -> Result<SyncRecord, MusicLibraryError> {
     // TODO:If it exists with a different filetype, give a warning
-    let shadow = get_shadow_filename(
-        &song.library_relative_path,
-        target_library,
-        &target_filetype,
-    );
-    let want_embedded_album_art = match art_strategy {
-        ArtStrategy::None => false,
-        ArtStrategy::EmbedAll => true,
-        ArtStrategy::PreferFile => song.external_album_art.is_none(),
-        ArtStrategy::FileOnly => false,
-    };
-    let desired_bitrate = target_filetype.equivalent_bitrate();
+    let want_embedded_album_art = wants_embedded_album_art(song, settings);
     let status = has_music_file_changed(
         song,
-        &shadow,
+        shadow,
         previous_sync_db,
         want_embedded_album_art,
-        desired_bitrate,
+        settings,
         pb,
-        verbose,
     );
-    let new_sync_record = SyncRecord::from_song(song);
+    let new_sync_record = SyncRecord::from_song(song, settings).set_target_relative_path(
+        shadow
+            .strip_prefix(target_library)
+            .expect("shadow should be in the target library")
+            .to_path_buf(),
+    );
 
     // Early exit if unchanged.
     // If force, don't early exit.
     // Instead, overwrite.
     let status = match status {
         U::NoChange => {
-            if force {
+            if settings.force {
                 U::ForceOverwrite
             } else {
-                return Ok(new_sync_record.set_update_type(status));
+                // If there is a hash from before, it was already checked to still match.
+                let target_hash = previous_sync_db
+                    .and_then(|db| db.get(&song.library_relative_path))
+                    .and_then(|record| record.target_hash)
+                    .or_else(|| (!settings.dry_run).then(|| hash_file(shadow)).flatten());
+                return Ok(new_sync_record
+                    .set_update_type(status)
+                    .set_target_hash(shadow, target_hash));
             }
         }
         // Don't touch the other statuses
         _ => status,
     };
+    write_song(song, shadow, settings, status, new_sync_record, pb)
+}
+
+/// Synchronises the song as a dry run planned it, without checking again whether it changed.
+/// Fails if the song itself changed since the plan was made.
+pub fn sync_song_as_planned(
+    song: &Song,
+    planned: &PlannedSong,
+    target_library: &Path,
+    settings: &SyncSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<SyncRecord, MusicLibraryError> {
+    let shadow = target_library.join(&planned.action.target);
+    let new_sync_record = SyncRecord::from_song(song, settings)
+        .set_target_relative_path(planned.action.target.clone());
+    if planned.hash.is_some() && new_sync_record.hash != planned.hash {
+        return Err(MusicLibraryError::ChangedSincePlan {
+            path: song.absolute_path.clone(),
+        });
+    }
+    if planned.action.action != Action::Link {
+        return write_song(
+            song,
+            &shadow,
+            settings,
+            planned.update_type(),
+            new_sync_record,
+            pb,
+        );
+    }
+    let linked_to = planned
+        .linked_to
+        .as_ref()
+        .map(|original| target_library.join(original));
+    let target_hash = match (settings.song_deduplication, linked_to) {
+        (Some(SongDeduplication::Hardlink), Some(original_shadow)) => {
+            let _ = fs::create_dir_all(shadow.parent().expect("Cannot get parent dir of shadow"));
+            set_aside(&shadow, settings)?;
+            journal_created(&shadow, settings)?;
+            copy_song(&original_shadow, &shadow, LinkMode::Hardlink, pb).map_err(|source| {
+                MusicLibraryError::CopySong {
+                    path: shadow.clone(),
+                    source,
+                }
+            })?;
+            hash_file(&shadow)
+        }
+        _ => None,
+    };
+    Ok(new_sync_record
+        .set_update_type(U::Duplicate)
+        .set_target_hash(&shadow, target_hash))
+}
 
-    let whether_to_embed_art = match art_strategy {
+/// Writes the shadow of the song, in the way that the status says it needs to be updated.
+fn write_song(
+    song: &Song,
+    shadow: &Path,
+    settings: &SyncSettings,
+    status: UpdateType,
+    new_sync_record: SyncRecord,
+    pb: Option<&ProgressBar>,
+) -> Result<SyncRecord, MusicLibraryError> {
+    if !CHANGE_LIMIT.take() {
+        return Err(MusicLibraryError::ChangeLimitReached {
+            path: song.absolute_path.clone(),
+        });
+    }
+    let whether_to_embed_art = match settings.art_strategy_for(song) {
         ArtStrategy::None => false,
         ArtStrategy::EmbedAll => true,
         ArtStrategy::PreferFile => song.external_album_art.is_none(),
         ArtStrategy::FileOnly => false,
+        ArtStrategy::ExtractToFile => false,
     };
 
+    // For videos, "all pictures" would include the video itself.
+    let pictures = match song.metadata.front_cover() {
+        Some(cover) if !settings.keep_all_pictures || song.metadata.has_video => {
+            PictureSelection::Stream(cover.stream_index)
+        }
+        _ => PictureSelection::All,
+    };
+
+    // Copies keep their audio as it is, but leave out the art that is extracted to a file.
+    let remux = matches!(status, U::Copied) && copy_strips_art(song, settings);
+    let copied_as_is = matches!(status, U::Copied) && !remux;
+    let mut encode_speed = None;
     // Can't change files in place with ffmpeg, so if we need to update then we need to
     // overwrite the file fully.
     // If the source directory does not yet exist, create it. ffmpeg will otherwise throw an error.
-    if !dry_run {
-        let _ = fs::create_dir_all(shadow.parent().expect("Cannot get parent dir of shadow"));
-        if matches!(status, U::Copied) {
-            std::fs::copy(&song.absolute_path, shadow).expect("could not copy!");
+    if !settings.dry_run {
+        let parent = shadow.parent().expect("Cannot get parent dir of shadow");
+        // Removed again if writing fails, so failures don't leave empty album folders.
+        let mut partial_write = PartialWrite {
+            shadow: None,
+            created_dirs: missing_dirs(parent),
+        };
+        let _ = fs::create_dir_all(parent);
+        let backed_up = set_aside(shadow, settings)?;
+        // From here on, whatever is at the shadow is of no use if writing fails.
+        partial_write.shadow = Some(shadow);
+        // Before it is written, so that undoing also removes what a failed write leaves.
+        journal_created(shadow, settings)?;
+        if copied_as_is {
+            copy_song(&song.absolute_path, shadow, settings.link_mode, pb).map_err(|source| {
+                MusicLibraryError::CopySong {
+                    path: shadow.to_path_buf(),
+                    source,
+                }
+            })?;
         } else {
-            transcode_song(
+            // A video without any other art gets a frame of the video as cover.
+            let video_frame = (whether_to_embed_art
+                && song.metadata.has_video
+                && song.has_artwork() == ArtworkType::None)
+                .then(|| grab_cover_from_video(song, shadow, pb))
+                .flatten();
+            let mut art = ArtEmbedding {
+                embed: whether_to_embed_art,
+                external_art: song
+                    .external_album_art
+                    .as_deref()
+                    .or(video_frame.as_deref()),
+                max_resolution: settings.embed_art_resolution,
+                pictures,
+                recompress_quality: None,
+            };
+            if song.metadata.has_video
+                && art.external_art.is_none()
+                && pictures == PictureSelection::All
+            {
+                // There is no picture to embed, only the video.
+                art.embed = false;
+            }
+            let art_too_large = whether_to_embed_art
+                && settings
+                    .max_embedded_art_bytes
+                    .is_some_and(|max| art_size(song, pictures) > max);
+            if art_too_large {
+                match settings.oversized_art {
+                    OversizedArt::Drop => art.embed = false,
+                    OversizedArt::Recompress => {
+                        art.recompress_quality = Some(OVERSIZED_ART_QUALITY);
+                        if art.max_resolution == 0 {
+                            art.max_resolution = OVERSIZED_ART_RESOLUTION;
+                        }
+                    }
+                }
+            }
+            // ffmpeg can't write to the file it is reading from, so move the audio to reuse
+            // out of the way first. A backup already is. One in the trash can't be reused, so
+            // that is transcoded again.
+            let old_shadow = shadow.with_extension("retag");
+            let reuse_audio = match &backed_up {
+                _ if status != U::Retag => None,
+                Some(backed_up) => Some(backed_up.as_path()),
+                None => fs::rename(shadow, &old_shadow)
+                    .is_ok()
+                    .then_some(old_shadow.as_path()),
+            };
+            // If the shadow is a hard link from an earlier sync, ffmpeg would overwrite the source.
+            let _ = fs::remove_file(shadow);
+            let started = Instant::now();
+            let transcoded = transcode_song(
                 &song.absolute_path,
-                &shadow,
-                target_filetype,
-                whether_to_embed_art,
-                song.external_album_art.as_deref(),
-            )?;
+                shadow,
+                if remux {
+                    MusicFileType::Copy
+                } else {
+                    settings.target_filetype_for(song).clone()
+                },
+                audio_conversion(song, settings),
+                art,
+                &target_tags(song, settings),
+                reuse_audio,
+            );
+            if let Some(video_frame) = video_frame {
+                let _ = fs::remove_file(video_frame);
+            }
+            if let Some(old_shadow) = reuse_audio.filter(|_| backed_up.is_none()) {
+                let _ = fs::remove_file(old_shadow);
+            }
+            transcoded?;
+            if reuse_audio.is_none() && !remux {
+                encode_speed = song
+                    .metadata
+                    .duration
+                    .map(|duration| duration.as_secs_f64() / started.elapsed().as_secs_f64());
+            }
         }
+        partial_write.finish();
     };
 
+    // A hard link already is the source, times and all.
+    let is_hardlink = copied_as_is && settings.link_mode == LinkMode::Hardlink;
+    let preserve_xattrs = settings.preserve_xattrs && matches!(status, U::Copied);
+    if preserve_xattrs && !settings.dry_run && !is_hardlink {
+        copy_file_attributes(&song.absolute_path, shadow).map_err(|source| {
+            MusicLibraryError::PreserveAttributes {
+                path: shadow.to_path_buf(),
+                source,
+            }
+        })?;
+    }
+    if (settings.preserve_mtime || preserve_xattrs) && !settings.dry_run && !is_hardlink {
+        copy_file_times(&song.absolute_path, shadow).map_err(|source| {
+            MusicLibraryError::PreserveModifiedTime {
+                path: shadow.to_path_buf(),
+                source,
+            }
+        })?;
+    }
+
     // The sync record needs to have its new status written to it still!
-    Ok(new_sync_record.set_update_type(status))
+    let target_hash = (!settings.dry_run).then(|| hash_file(shadow)).flatten();
+    Ok(new_sync_record
+        .set_update_type(status)
+        .set_target_hash(shadow, target_hash)
+        .set_encode_speed(encode_speed))
+}
+
+/// How the audio of the song should be changed when transcoding it. Sources with a lower
+/// sample rate, fewer channels or a lower bit depth are left as they are.
+fn audio_conversion(song: &Song, settings: &SyncSettings) -> AudioConversion {
+    let chapters = settings
+        .audiobooks
+        .as_ref()
+        .filter(|audiobooks| audiobooks.contains(song))
+        .map(|audiobooks| audiobooks.chapters);
+    // The audio is not re-encoded at all, so it can't be changed either. It can still be cut.
+    if matches!(settings.target_filetype_for(song), MusicFileType::Copy) {
+        return AudioConversion {
+            segment: song.segment,
+            chapters,
+            ..Default::default()
+        };
+    }
+    let md = &song.metadata;
+    let to_16_bit = settings.reduce_bit_depth
+        && matches!(
+            settings.target_filetype_for(song),
+            MusicFileType::Flac { .. }
+        )
+        && md.bit_depth.is_some_and(|bits| bits > 16);
+    AudioConversion {
+        sample_rate: settings
+            .max_sample_rate
+            .filter(|max| md.sample_rate.is_some_and(|rate| rate > *max)),
+        // Audiobooks are speech, which doesn't need more than one channel.
+        channels: chapters
+            .map(|_| 1)
+            .or(settings.downmix.map(|downmix| downmix.channels()))
+            .filter(|max| md.channels.is_some_and(|n| n > *max)),
+        to_16_bit,
+        dither: to_16_bit && settings.dither,
+        segment: song.segment,
+        chapters,
+    }
+}
+
+/// The tags to set in the synchronised copy of the song, on top of the ones of the source. Tags
+/// with an empty value are removed.
+fn target_tags(song: &Song, settings: &SyncSettings) -> Vec<(String, String)> {
+    let stripped = settings.tags_to_strip(song);
+    let filetype = settings.target_filetype_for(song);
+    settings
+        .tag_map
+        .map(&song.metadata, filetype, &stripped)
+        .into_iter()
+        .chain(replaygain_tags(&song.metadata, filetype))
+        .chain(track_tags(song))
+        .chain(settings.tag_rules.rewrite(&song.metadata))
+        .filter(|(key, _)| !settings.strip_tags.contains(&key.to_lowercase()))
+        .chain(stripped.into_iter().map(|key| (key, String::new())))
+        .collect()
+}
+
+fn wants_embedded_album_art(song: &Song, settings: &SyncSettings) -> bool {
+    match settings.art_strategy_for(song) {
+        ArtStrategy::None => false,
+        ArtStrategy::EmbedAll => true,
+        ArtStrategy::PreferFile => song.external_album_art.is_none(),
+        ArtStrategy::FileOnly => false,
+        ArtStrategy::ExtractToFile => false,
+    }
+}
+
+/// Whether the song is copied instead of transcoded, if it has to be synchronised.
+pub fn would_copy(song: &Song, settings: &SyncSettings) -> bool {
+    should_copy(song, wants_embedded_album_art(song, settings), settings)
+}
+
+/// Songs with a lower bitrate than the target would only lose quality by transcoding them, so
+/// they are copied instead (or lossy songs, depending on the codec policy). That is, unless the
+/// audio itself has to be changed.
+/// When not transcoding at all, songs are copied unless the embedded art has to be changed.
+/// Songs of which tags are stripped or rewritten are never copied as they are.
+fn should_copy(song: &Song, want_embedded_album_art: bool, settings: &SyncSettings) -> bool {
+    // The video has to be left out, only a part of the file is wanted, or the tags change.
+    if song.metadata.has_video || song.segment.is_some() || settings.changes_tags(song) {
+        return false;
+    }
+    if matches!(settings.target_filetype_for(song), MusicFileType::Copy) {
+        return !needs_remux_for_art(song, want_embedded_album_art, settings);
+    }
+    let lossless = match settings.codec_policy {
+        CodecPolicy::Bitrate => None,
+        CodecPolicy::TranscodeLossless => song.metadata.is_lossless(),
+    };
+    let not_worth_transcoding = match lossless {
+        Some(lossless) => !lossless,
+        None => {
+            let threshold = settings
+                .copy_threshold_kbps
+                .unwrap_or_else(|| settings.target_filetype_for(song).equivalent_bitrate());
+            song.metadata.bitrate_kbps < threshold
+        }
+    };
+    not_worth_transcoding && audio_conversion(song, settings) == AudioConversion::default()
+}
+
+/// Whether the art embedded in the song is not what the art strategy asks for.
+fn needs_remux_for_art(
+    song: &Song,
+    want_embedded_album_art: bool,
+    settings: &SyncSettings,
+) -> bool {
+    let md = &song.metadata;
+    if !want_embedded_album_art {
+        return md.has_embedded_album_art;
+    }
+    let external_art_to_embed = !md.has_embedded_album_art && song.external_album_art.is_some();
+    let art_too_large = md.embedded_art_resolution.is_some_and(|(w, h)| {
+        settings.embed_art_resolution > 0 && w.max(h) > settings.embed_art_resolution
+    });
+    external_art_to_embed || art_too_large
+}
+
+/// Whether a song that is copied has to be remuxed, because its embedded art is extracted to a
+/// file instead.
+fn copy_strips_art(song: &Song, settings: &SyncSettings) -> bool {
+    settings.art_strategy_for(song) == ArtStrategy::ExtractToFile
+        && needs_remux_for_art(song, false, settings)
+}
+
+/// Synchronises a song that is identical to another song in the source library, by linking its
+/// shadow to the shadow of that song (or not at all). If the other song has no shadow, it is
+/// synchronised like any other song.
+pub fn sync_duplicate_song(
+    song: &Song,
+    shadow: &Path,
+    original_shadow: &Path,
+    target_library: &Path,
+    settings: &SyncSettings,
+    previous_sync_db: Option<&PreviousSyncDb>,
+    pb: Option<&ProgressBar>,
+) -> Result<SyncRecord, MusicLibraryError> {
+    let Some(deduplication) = settings
+        .song_deduplication
+        .filter(|_| settings.dry_run || original_shadow.exists())
+    else {
+        return sync_song(song, shadow, target_library, settings, previous_sync_db, pb);
+    };
+    let new_sync_record = SyncRecord::from_song(song, settings).set_target_relative_path(
+        shadow
+            .strip_prefix(target_library)
+            .expect("shadow should be in the target library")
+            .to_path_buf(),
+    );
+    let status = match deduplication {
+        SongDeduplication::Skip => U::Duplicate,
+        SongDeduplication::Hardlink => {
+            let already_linked = shadow.exists()
+                && hash_file(shadow).is_some()
+                && hash_file(shadow) == hash_file(original_shadow);
+            if already_linked && !settings.force {
+                U::NoChange
+            } else {
+                if !settings.dry_run {
+                    let _ = fs::create_dir_all(
+                        shadow.parent().expect("Cannot get parent dir of shadow"),
+                    );
+                    set_aside(shadow, settings)?;
+                    journal_created(shadow, settings)?;
+                    copy_song(original_shadow, shadow, LinkMode::Hardlink, pb).map_err(
+                        |source| MusicLibraryError::CopySong {
+                            path: shadow.to_path_buf(),
+                            source,
+                        },
+                    )?;
+                }
+                U::Duplicate
+            }
+        }
+    };
+    let target_hash = match deduplication {
+        SongDeduplication::Skip => None,
+        SongDeduplication::Hardlink => hash_file(shadow),
+    };
+    Ok(new_sync_record
+        .set_update_type(status)
+        .set_target_hash(shadow, target_hash))
+}
+
+/// Moves the shadow into the backup or the trash, if it should be kept, before it is
+/// overwritten. Returns where it went, if that is known.
+fn set_aside(shadow: &Path, settings: &SyncSettings) -> Result<Option<PathBuf>, MusicLibraryError> {
+    settings
+        .disposal
+        .set_aside(shadow)
+        .map_err(|source| MusicLibraryError::SetAside {
+            path: shadow.to_path_buf(),
+            source,
+        })
+}
+
+/// Notes in the journal that the file in the target library is written, if there is a journal.
+pub(crate) fn journal_created(
+    shadow: &Path,
+    settings: &SyncSettings,
+) -> Result<(), MusicLibraryError> {
+    settings
+        .disposal
+        .created(shadow)
+        .map_err(|source| MusicLibraryError::Journal {
+            path: shadow.to_path_buf(),
+            source,
+        })
+}
+
+/// Copies the song to the shadow, or links it if possible. Falls back to a normal copy if
+/// linking doesn't work, e.g. because the target library is on another filesystem.
+fn copy_song(
+    source: &Path,
+    shadow: &Path,
+    link_mode: LinkMode,
+    pb: Option<&ProgressBar>,
+) -> std::io::Result<()> {
+    let linked = match link_mode {
+        LinkMode::Copy => return copy(source, shadow),
+        LinkMode::Hardlink => {
+            // Can't link over an existing file.
+            let _ = fs::remove_file(shadow);
+            fs::hard_link(source, shadow)
+        }
+        LinkMode::Reflink => reflink(source, shadow),
+    };
+    if let Err(e) = linked {
+        log_failure(
+            format!(
+                "Could not link {} to {} ({e}), copying it instead.",
+                shadow.display(),
+                source.display()
+            ),
+            pb,
+        );
+        return copy(source, shadow);
+    }
+    Ok(())
+}
+
+fn copy(source: &Path, shadow: &Path) -> std::io::Result<()> {
+    // Don't write through a hard link from an earlier sync into the source.
+    let _ = fs::remove_file(shadow);
+    fs::copy(source, shadow).map(|_| ())
+}
+
+/// Cleans up after writing a song that failed, when it is dropped before it is finished: removes
+/// what was written to the shadow, and the folders that were made for it.
+struct PartialWrite<'a> {
+    shadow: Option<&'a Path>,
+    created_dirs: Vec<PathBuf>,
+}
+
+impl PartialWrite<'_> {
+    /// The song is written, so there is nothing to clean up.
+    fn finish(mut self) {
+        self.shadow = None;
+        self.created_dirs.clear();
+    }
+}
+
+impl Drop for PartialWrite<'_> {
+    fn drop(&mut self) {
+        if let Some(shadow) = self.shadow {
+            let _ = fs::remove_file(shadow);
+        }
+        remove_empty_dirs(&self.created_dirs);
+    }
+}
+
+/// `dir` and the folders above it that don't exist yet, deepest first.
+fn missing_dirs(dir: &Path) -> Vec<PathBuf> {
+    dir.ancestors()
+        .take_while(|dir| !dir.exists())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// Removes the folders, deepest first, until one is not empty. Another song could have been
+/// written to it in the meantime.
+fn remove_empty_dirs(dirs: &[PathBuf]) {
+    for dir in dirs {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// The standard library can't make reflinks, so let `cp` do it.
+fn reflink(source: &Path, shadow: &Path) -> std::io::Result<()> {
+    let mut binding = std::process::Command::new("cp");
+    if cfg!(target_os = "macos") {
+        // Uses clonefile() on APFS.
+        binding.arg("-c");
+    } else {
+        binding.arg("--reflink=always");
+    }
+    let output = binding.arg(source).arg(shadow).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Saves a frame of the video next to the shadow, to embed as cover. Returns its path.
+fn grab_cover_from_video(song: &Song, shadow: &Path, pb: Option<&ProgressBar>) -> Option<PathBuf> {
+    let frame = shadow.with_extension("frame.jpg");
+    match grab_video_frame(&song.absolute_path, &frame) {
+        Ok(()) => Some(frame),
+        Err(e) => {
+            log_failure(format!("Could not grab a cover from video {song}: {e}"), pb);
+            None
+        }
+    }
+}
+
+/// JPEG quality (1-100) that art that is too large is recompressed with.
+const OVERSIZED_ART_QUALITY: u8 = 75;
+/// Art that is too large is also scaled down to this, if no resolution is set.
+const OVERSIZED_ART_RESOLUTION: u32 = 1000;
+
+/// The size in bytes of the art that would be embedded in the target file.
+fn art_size(song: &Song, pictures: PictureSelection) -> u64 {
+    if let Some(external_art) = &song.external_album_art {
+        return fs::metadata(external_art).map(|m| m.len()).unwrap_or(0);
+    }
+    let Ok(sizes) = embedded_picture_sizes(&song.absolute_path) else {
+        return 0;
+    };
+    match pictures {
+        PictureSelection::All => sizes.values().sum(),
+        PictureSelection::Stream(index) => sizes.get(&index).copied().unwrap_or(0),
+    }
 }
 
 /// Checks if the source music file has been changed since it has been transcoded.
@@ -98,12 +840,11 @@ pub fn has_music_file_changed(
     target: &Path,
     previous_sync_db: Option<&PreviousSyncDb>,
     want_embedded_album_art: bool,
-    // Any file that is above this bitrate will just be considered to be copied.
-    desired_bitrate: u32,
+    settings: &SyncSettings,
     pb: Option<&ProgressBar>,
-    verbose: bool,
 ) -> UpdateType {
     use UpdateType as U;
+    let verbose = settings.verbose;
 
     // We need to perform costly checks here:
     // Ideally, we'd only parse the metadata for the target file if it is truly necessary.
@@ -121,14 +862,7 @@ pub fn has_music_file_changed(
                 pb,
             );
         }
-        return compare_files_on_metadata(
-            song,
-            target,
-            want_embedded_album_art,
-            desired_bitrate,
-            pb,
-            verbose,
-        );
+        return compare_files_on_metadata(song, target, want_embedded_album_art, settings, pb);
     };
     // If a previous_sync_db is given, then we can use that to check if the hash is the same.
     if let Some(db) = previous_sync_db {
@@ -137,10 +871,9 @@ pub fn has_music_file_changed(
             source_hash,
             target,
             want_embedded_album_art,
-            desired_bitrate,
+            settings,
             db,
             pb,
-            verbose,
         );
     };
 
@@ -148,7 +881,7 @@ pub fn has_music_file_changed(
     // This is only done after checking the hash existence, because otherwise missing songs
     // (exists in recods, not as file) cannot be detected.
     if !target.exists() {
-        return if song.metadata.bitrate_kbps < desired_bitrate {
+        return if should_copy(song, want_embedded_album_art, settings) {
             U::Copied
         } else {
             U::NewTranscode
@@ -158,49 +891,43 @@ pub fn has_music_file_changed(
     // If you are here, no previous_sync_db is available, or checking for a previous sync didn't work.
     // See if the source file is newer than the destination file.
 
-    let target_is_outdated =
-        match has_source_changed_after_target_has_been_created(&song.absolute_path, target) {
-            Ok(x) => x,
-            Err(e) => {
-                if verbose {
-                    log_failure(
-                        format!(
-                            "Could not compare last changed time and \
+    let target_is_outdated = match has_source_changed_after_target_has_been_created(
+        &song.absolute_path,
+        target,
+        settings.preserve_mtime,
+    ) {
+        Ok(x) => x,
+        Err(e) => {
+            if verbose {
+                log_failure(
+                    format!(
+                        "Could not compare last changed time and \
                             created time of shadow copy of {song}: {e:?}. \
                             Falling back to comparing metadata.",
-                        ),
-                        pb,
-                    );
-                }
-                return compare_files_on_metadata(
-                    song,
-                    target,
-                    want_embedded_album_art,
-                    desired_bitrate,
+                    ),
                     pb,
-                    verbose,
                 );
             }
-        };
+            return compare_files_on_metadata(song, target, want_embedded_album_art, settings, pb);
+        }
+    };
     if target_is_outdated {
-        return if song.metadata.bitrate_kbps < desired_bitrate {
+        return if should_copy(song, want_embedded_album_art, settings) {
             U::Copied
         } else {
             U::NewTranscode
         };
     }
 
+    // Reading the metadata of every song in the target library makes a dry run take about as
+    // long as a real one, so a dry run trusts that a newer target is up to date.
+    if settings.dry_run {
+        return U::NoChange;
+    }
     // We cannot just hash the target file, since it will be encoded differently.
     // So, instead we can check if the metadata is the same, and if the album art has
     // not changed.
-    compare_files_on_metadata(
-        song,
-        target,
-        want_embedded_album_art,
-        desired_bitrate,
-        pb,
-        verbose,
-    )
+    compare_files_on_metadata(song, target, want_embedded_album_art, settings, pb)
 }
 
 /// Fallback, costly method: Comparing the metadata of the two files.
@@ -209,9 +936,8 @@ fn compare_files_on_metadata(
     source: &Song,
     target: &Path,
     want_embedded_album_art: bool,
-    desired_bitrate: u32,
+    settings: &SyncSettings,
     pb: Option<&ProgressBar>,
-    verbose: bool,
 ) -> UpdateType {
     match SongMetaData::parse_file(target) {
         Ok(shadow_metadata) => {
@@ -222,7 +948,17 @@ fn compare_files_on_metadata(
                 if want_embedded_album_art {
                     // You want artwork in the shadow, you have artwork available, and there
                     // wasn't any in the file yet.
-                    !shadow_metadata.has_embedded_album_art && source.has_artwork().is_some()
+                    let art_missing =
+                        !shadow_metadata.has_embedded_album_art && source.has_artwork().is_some();
+                    // Or the art that is in there is larger than allowed.
+                    let art_too_large =
+                        shadow_metadata
+                            .embedded_art_resolution
+                            .is_some_and(|(w, h)| {
+                                settings.embed_art_resolution > 0
+                                    && w.max(h) > settings.embed_art_resolution
+                            });
+                    art_missing || art_too_large
                 } else {
                     // You don't want artwork: re-encode if it already has artwork.
                     shadow_metadata.has_embedded_album_art
@@ -230,13 +966,15 @@ fn compare_files_on_metadata(
 
             // == shadow_metadata.has_embedded_album_art;
 
+            // A transcode that was cut off is shorter than the source.
             if source.metadata.title == shadow_metadata.title
+                && source.metadata.has_same_duration(&shadow_metadata)
                 && !should_re_encode_because_art_availability_or_desired_changed
             {
                 U::NoChange
             } else {
                 // Just copy a file if you'd just incur more encoding loss
-                if source.metadata.bitrate_kbps < desired_bitrate {
+                if should_copy(source, want_embedded_album_art, settings) {
                     U::Copied
                 } else {
                     U::Overwrite
@@ -245,7 +983,7 @@ fn compare_files_on_metadata(
         }
         Err(e) => {
             // If we also can't read the metadata of the existing song, then its pretty clear that we need to overwrite it.
-            if verbose {
+            if settings.verbose {
                 log_failure(
                     format!("Could not read metadata from shadow file, so overwriting it: {e}"),
                     pb,
@@ -257,15 +995,21 @@ fn compare_files_on_metadata(
     }
 }
 
+/// Whether the shadow has about the same duration as the song. Shadows that can't be read
+/// don't.
+fn has_same_duration(song: &Song, target: &Path) -> bool {
+    SongMetaData::parse_file(target)
+        .is_ok_and(|shadow_metadata| song.metadata.has_same_duration(&shadow_metadata))
+}
+
 fn has_music_file_changed_based_on_hash_and_records(
     song: &Song,
     source_hash: u64,
     target: &Path,
     want_embedded_album_art: bool,
-    desired_bitrate: u32,
+    settings: &SyncSettings,
     db: &PreviousSyncDb,
     pb: Option<&ProgressBar>,
-    verbose: bool,
 ) -> UpdateType {
     if let Some(previous_record) = db.get(&song.library_relative_path) {
         // If the file is in the previous_sync_db, but is not actually present,
@@ -276,9 +1020,84 @@ fn has_music_file_changed_based_on_hash_and_records(
         // Check if there is a saved hash, and if so, if they are the same.
         if let Some(hash_at_previous_sync) = previous_record.hash {
             if hash_at_previous_sync == source_hash {
+                // The source is the same, but the shadow was changed outside of syncbops.
+                // Hashing every copy takes long, so only the ones that look different are.
+                let tampered = previous_record.target_hash.is_some_and(|target_hash| {
+                    previous_record
+                        .target_stamp
+                        .is_none_or(|stamp| FileStamp::of(target) != Some(stamp))
+                        && hash_file(target) != Some(target_hash)
+                });
+                if tampered {
+                    if settings.verbose {
+                        log_failure(
+                            format!("The shadow of {song} was changed since the last sync, so replacing it."),
+                            pb,
+                        );
+                    }
+                    return if should_copy(song, want_embedded_album_art, settings) {
+                        U::Copied
+                    } else {
+                        U::Overwrite
+                    };
+                }
+                // The source is the same, but the art in it should be scaled differently now.
+                if want_embedded_album_art
+                    && previous_record.embed_art_resolution != settings.embed_art_resolution
+                {
+                    return U::Overwrite;
+                }
+                // The source is the same, but it should be encoded differently now.
+                if let Some(encoder) = &previous_record.encoder {
+                    let copy = should_copy(song, want_embedded_album_art, settings);
+                    // Copies are not encoded, so they don't depend on the target filetype.
+                    let still_copied = copy && previous_record.update_type == Some(U::Copied);
+                    let filetype = settings.target_filetype_for(song);
+                    let filetype_changed = encoder.filetype != *filetype;
+                    // Only if both are known, so that older records don't all look changed.
+                    let encoder_changed = encoder.encoder_name.as_deref().is_some_and(|name| {
+                        chosen_encoder(filetype).is_some_and(|chosen| chosen != name)
+                    });
+                    if encoder.art_strategy != settings.art_strategy_for(song)
+                        || encoder.strip_tags != settings.tags_to_strip(song)
+                        || encoder.rewritten_tags != settings.tag_rules.rewrite(&song.metadata)
+                        || ((filetype_changed || encoder_changed) && !still_copied)
+                    {
+                        if settings.verbose {
+                            log_failure(
+                                format!("{song} was synchronised with different settings, so synchronising it again."),
+                                pb,
+                            );
+                        }
+                        return if copy { U::Copied } else { U::Overwrite };
+                    }
+                }
+                if settings.check_durations && !has_same_duration(song, target) {
+                    if settings.verbose {
+                        log_failure(
+                            format!(
+                                "The shadow of {song} does not have the same duration, so it \
+                                was probably cut off. Replacing it."
+                            ),
+                            pb,
+                        );
+                    }
+                    return if should_copy(song, want_embedded_album_art, settings) {
+                        U::Copied
+                    } else {
+                        U::Overwrite
+                    };
+                }
                 return U::NoChange;
             } else {
-                // The hashes are not the same. Hence, the file must have changed.
+                // The hashes are not the same. Hence, the file must have changed. If it is only
+                // the tags, the audio in the shadow can be kept.
+                let only_tags_changed = settings.hash_audio_only
+                    && previous_record.audio_hash.is_some()
+                    && previous_record.audio_hash == hash_audio(&song.absolute_path);
+                if only_tags_changed && !should_copy(song, want_embedded_album_art, settings) {
+                    return U::Retag;
+                }
                 return U::Overwrite;
             }
         }
@@ -291,7 +1110,7 @@ fn has_music_file_changed_based_on_hash_and_records(
     // The file is not yet present, and it also does not yet appear in the records.
     // It has to be a new file, so transcode it or copy it.
     if !target.exists() {
-        if song.metadata.bitrate_kbps < desired_bitrate {
+        if should_copy(song, want_embedded_album_art, settings) {
             U::Copied
         } else {
             U::NewTranscode
@@ -302,26 +1121,67 @@ fn has_music_file_changed_based_on_hash_and_records(
         // knowing if it is still up to date. Hence, it should be checked.
         // It could also be that it could just not be inserted into the records; then too,
         // checking based on metadata is a good idea.
-        compare_files_on_metadata(
-            song,
-            target,
-            want_embedded_album_art,
-            desired_bitrate,
-            pb,
-            verbose,
-        )
+        compare_files_on_metadata(song, target, want_embedded_album_art, settings, pb)
+    }
+}
+
+/// How much the modification time of a target may be behind that of its source, because some
+/// filesystems store it less precisely. FAT, as on many SD cards, only stores it to 2 seconds.
+const MTIME_PRECISION: Duration = Duration::from_secs(2);
+
+/// Gives the target the extended attributes and permissions of the source, like `rsync -X -p`.
+/// Attributes that the target filesystem doesn't support, or that only root may set (like
+/// `security.selinux`), are left out.
+fn copy_file_attributes(source: &Path, target: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    for name in xattr::list(source)? {
+        let Some(value) = xattr::get(source, &name)? else {
+            continue;
+        };
+        match xattr::set(target, &name, &value) {
+            Ok(()) => (),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied
+                ) => {}
+            Err(e) => return Err(e),
+        }
     }
+    fs::set_permissions(target, fs::metadata(source)?.permissions())
 }
 
+/// Gives the target the modification and access time of the source.
+fn copy_file_times(source: &Path, target: &Path) -> std::io::Result<()> {
+    let source_md = fs::metadata(source)?;
+    let times = fs::FileTimes::new()
+        .set_modified(source_md.modified()?)
+        .set_accessed(source_md.accessed()?);
+    fs::File::options()
+        .write(true)
+        .open(target)?
+        .set_times(times)
+}
+
+/// With `preserve_mtime`, the modification time of the target is that of the source when it was
+/// synchronised, so the source has changed if it is newer than that. Targets that were written
+/// before are newer than their source, so they are not all transcoded again.
 fn has_source_changed_after_target_has_been_created(
     source: &Path,
     target: &Path,
+    preserve_mtime: bool,
 ) -> Result<bool, MusicLibraryError> {
     let source_filesystem_md =
         std::fs::metadata(source).map_err(MusicLibraryError::SourceModifiedTime)?;
     let source_last_modified = source_filesystem_md
         .modified()
         .map_err(MusicLibraryError::SourceModifiedTime)?;
+    if preserve_mtime {
+        let target_last_modified = std::fs::metadata(target)
+            .and_then(|md| md.modified())
+            .map_err(MusicLibraryError::TargetCreatedTime)?;
+        return Ok(source_last_modified > target_last_modified + MTIME_PRECISION);
+    }
     let target_filesystem_md =
         std::fs::metadata(target).map_err(MusicLibraryError::TargetCreatedTime)?;
     let target_created = target_filesystem_md
@@ -333,13 +1193,18 @@ fn has_source_changed_after_target_has_been_created(
 #[cfg(test)]
 mod tests {
     use crate::{
-        ffmpeg_interface::SongMetaData,
+        ffmpeg_interface::{image_resolution, SongMetaData},
         hashing::PreviousSyncDb,
-        music_library::{get_shadow_filename, ArtStrategy, ArtworkType, MusicFileType, UpdateType},
+        music_library::{
+            copy_dedicated_cover_art_for_song, get_shadow_filename, ArtStrategy, ArtworkType,
+            CodecPolicy, CopiedArt, Id3Tags, MusicFileType, MusicLibraryError, OpusVbr, UpdateType,
+        },
         song::Song,
+        sync_song::SyncSettings,
+        target_path::TargetPathOptions,
         test_data::TestFile,
     };
-    use std::path::PathBuf;
+    use std::{path::PathBuf, sync::Mutex};
 
     // TODO: Unit tests for changed artist, album artist, lyrics, album art, etc.
 
@@ -387,18 +1252,10 @@ mod tests {
             &song.library_relative_path,
             &target_library,
             &target_filetype,
+            &TargetPathOptions::default(),
         );
-        let updated_record = sync_song(
-            &song,
-            &target_library,
-            target_filetype.clone(),
-            art_strategy,
-            None,
-            false,
-            false,
-            None,
-            true,
-        )?;
+        let settings = SyncSettings::new_debug(target_filetype.clone(), art_strategy);
+        let updated_record = sync_song(&song, &target, &target_library, &settings, None, None)?;
         let output_metadata = SongMetaData::parse_file(&target)?;
 
         // The whole point of this program is to save space. The transcoded file should be
@@ -448,6 +1305,27 @@ mod tests {
                     "If File Only, should not have any embedded artwork."
                 )
             }
+            ArtStrategy::ExtractToFile => {
+                assert!(
+                    !output_metadata.has_embedded_album_art,
+                    "Embedded art should be extracted to a file, not kept in the song."
+                );
+                let cover = copy_dedicated_cover_art_for_song(
+                    &song,
+                    &target,
+                    song.absolute_path.parent().unwrap(),
+                    &target_library,
+                    &settings,
+                    &Mutex::new(CopiedArt::default()),
+                )?;
+                if song.external_album_art.is_none() && song.metadata.has_embedded_album_art {
+                    let cover = cover.expect("Embedded art should be extracted to a file.");
+                    assert!(
+                        image_resolution(&cover).is_ok(),
+                        "The extracted art should be an image."
+                    );
+                }
+            }
         }
 
         Ok(())
@@ -458,7 +1336,10 @@ mod tests {
     fn sync_mp3_to_mp3_with_higher_bitrate() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 320 },
+            MusicFileType::Mp3CBR {
+                bitrate: 320,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::None,
         )
@@ -471,7 +1352,10 @@ mod tests {
     fn sync_song_artstrat_none_embedded_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::None,
         )
@@ -482,7 +1366,10 @@ mod tests {
     fn sync_song_artstrat_none_external_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::None,
         )
@@ -493,7 +1380,10 @@ mod tests {
     fn sync_song_artstrat_none_no_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::None,
         )
@@ -504,7 +1394,10 @@ mod tests {
     fn sync_song_artstrat_none_both() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::None,
         )
@@ -518,7 +1411,10 @@ mod tests {
     fn sync_song_artstrat_embed_embedded_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::EmbedAll,
         )
@@ -529,7 +1425,10 @@ mod tests {
     fn sync_song_artstrat_embed_external_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::EmbedAll,
         )
@@ -540,7 +1439,10 @@ mod tests {
     fn sync_song_artstrat_embed_no_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::EmbedAll,
         )
@@ -551,7 +1453,10 @@ mod tests {
     fn sync_song_artstrat_embed_both() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::EmbedAll,
         )
@@ -565,7 +1470,10 @@ mod tests {
     fn sync_song_artstrat_prefer_file_embedded_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::PreferFile,
         )
@@ -576,7 +1484,10 @@ mod tests {
     fn sync_song_artstrat_prefer_file_external_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::PreferFile,
         )
@@ -587,7 +1498,10 @@ mod tests {
     fn sync_song_artstrat_prefer_file_no_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::PreferFile,
         )
@@ -598,7 +1512,10 @@ mod tests {
     fn sync_song_artstrat_prefer_file_both() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::PreferFile,
         )
@@ -612,7 +1529,10 @@ mod tests {
     fn sync_song_artstrat_file_only_embedded_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::FileOnly,
         )
@@ -623,7 +1543,10 @@ mod tests {
     fn sync_song_artstrat_file_only_external_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::FileOnly,
         )
@@ -634,7 +1557,10 @@ mod tests {
     fn sync_song_artstrat_file_only_no_art() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithoutArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             None,
             ArtStrategy::FileOnly,
         )
@@ -645,13 +1571,61 @@ mod tests {
     fn sync_song_artstrat_file_only_both() -> miette::Result<()> {
         sync_new_song_test(
             TestFile::Mp3CBRWithArt,
-            MusicFileType::Mp3CBR { bitrate: 60 },
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
             Some(TestFile::Jpg600),
             ArtStrategy::FileOnly,
         )
     }
 
     // END ART STRATEGY = FILE_ONLY
+    // ART STRATEGY = EXTRACT_TO_FILE
+
+    #[test]
+    /// Song with embedded album art, no external, art strategy = extract_to_file.
+    fn sync_song_artstrat_extract_to_file_embedded_art() -> miette::Result<()> {
+        sync_new_song_test(
+            TestFile::Mp3CBRWithArt,
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
+            None,
+            ArtStrategy::ExtractToFile,
+        )
+    }
+
+    #[test]
+    /// Song with both embedded and external art, art strategy = extract_to_file.
+    fn sync_song_artstrat_extract_to_file_both() -> miette::Result<()> {
+        sync_new_song_test(
+            TestFile::Mp3CBRWithArt,
+            MusicFileType::Mp3CBR {
+                bitrate: 60,
+                id3: Id3Tags::default(),
+            },
+            Some(TestFile::Jpg600),
+            ArtStrategy::ExtractToFile,
+        )
+    }
+
+    #[test]
+    /// Songs that are copied instead of transcoded don't keep the art that is extracted.
+    fn sync_song_artstrat_extract_to_file_copied() -> miette::Result<()> {
+        sync_new_song_test(
+            TestFile::Mp3CBRWithArt,
+            MusicFileType::Mp3CBR {
+                bitrate: 320,
+                id3: Id3Tags::default(),
+            },
+            None,
+            ArtStrategy::ExtractToFile,
+        )
+    }
+
+    // END ART STRATEGY = EXTRACT_TO_FILE
 
     #[test]
     /// Write a song that is present in the database, but is not actually physically in the
@@ -659,17 +1633,20 @@ mod tests {
     fn sync_missing_song() -> miette::Result<()> {
         let target_library = create_test_target_library();
         let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
-        let u = super::sync_song(
-            &song,
-            &target_library,
-            MusicFileType::Mp3VBR { quality: 6 },
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
             ArtStrategy::PreferFile,
-            None,
-            false,
-            false,
-            None,
-            true,
-        )?;
+        );
+        let target = get_shadow_filename(
+            &song.library_relative_path,
+            &target_library,
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
         assert_eq!(u.update_type.unwrap(), UpdateType::NewTranscode);
 
         let db = {
@@ -681,17 +1658,7 @@ mod tests {
         // Delete it. The record remains in db.
         std::fs::remove_file(target_library.join(song.library_relative_path.clone())).unwrap();
 
-        let u2 = super::sync_song(
-            &song,
-            &target_library,
-            MusicFileType::Mp3VBR { quality: 6 },
-            ArtStrategy::PreferFile,
-            Some(&db),
-            false,
-            false,
-            None,
-            true,
-        )?;
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
         assert_eq!(u2.update_type.unwrap(), UpdateType::TranscodeMissingTarget);
 
         Ok(())
@@ -702,17 +1669,20 @@ mod tests {
     fn sync_existing_song() -> miette::Result<()> {
         let target_library = create_test_target_library();
         let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
-        let u = super::sync_song(
-            &song,
-            &target_library,
-            MusicFileType::Mp3VBR { quality: 6 },
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
             ArtStrategy::PreferFile,
-            None,
-            false,
-            false,
-            None,
-            true,
-        )?;
+        );
+        let target = get_shadow_filename(
+            &song.library_relative_path,
+            &target_library,
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
         assert_eq!(u.update_type.unwrap(), UpdateType::NewTranscode);
 
         let db = {
@@ -721,19 +1691,153 @@ mod tests {
             a
         };
 
-        let u2 = super::sync_song(
-            &song,
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
+        assert_eq!(u2.update_type.unwrap(), UpdateType::NoChange);
+
+        Ok(())
+    }
+
+    #[test]
+    /// The source did not change, but the shadow was changed by something else. Should be
+    /// written again.
+    fn sync_tampered_song() -> miette::Result<()> {
+        let target_library = create_test_target_library();
+        let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::PreferFile,
+        );
+        let target = get_shadow_filename(
+            &song.library_relative_path,
             &target_library,
-            MusicFileType::Mp3VBR { quality: 6 },
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
+        assert!(u.target_hash.is_some());
+        let db = {
+            let mut a = PreviousSyncDb::default();
+            a.insert(song.library_relative_path.clone(), u);
+            a
+        };
+
+        let mut bytes = std::fs::read(&target).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        std::fs::write(&target, bytes).unwrap();
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
+        assert_eq!(u2.update_type.unwrap(), UpdateType::Overwrite);
+        assert_eq!(u2.target_hash, db[&song.library_relative_path].target_hash);
+
+        Ok(())
+    }
+
+    #[test]
+    /// The shadow is only hashed to see whether it was changed if its size or modification time
+    /// is different from when it was written.
+    fn tampering_checked_by_stamp_first() -> miette::Result<()> {
+        let target_library = create_test_target_library();
+        let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
             ArtStrategy::PreferFile,
-            Some(&db),
-            false,
-            false,
-            None,
-            true,
-        )?;
+        );
+        let target = get_shadow_filename(
+            &song.library_relative_path,
+            &target_library,
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
+        assert!(u.target_stamp.is_some());
+        // A hash that doesn't match, which is only noticed if the shadow is hashed.
+        let mut record = u.clone();
+        record.target_hash = record.target_hash.map(|hash| hash.wrapping_add(1));
+        let mut db = PreviousSyncDb::default();
+        db.insert(song.library_relative_path.clone(), record.clone());
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
+        assert_eq!(u2.update_type.unwrap(), UpdateType::NoChange);
+
+        record.target_stamp = None;
+        db.insert(song.library_relative_path.clone(), record);
+        let u3 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
+        assert_eq!(u3.update_type.unwrap(), UpdateType::Overwrite);
+
+        Ok(())
+    }
+
+    #[test]
+    /// The source and settings did not change, but ffmpeg uses another encoder for the filetype
+    /// now, like when it got libopus after its own fallback encoder was used. Should be
+    /// transcoded again.
+    fn sync_song_with_changed_encoder() -> miette::Result<()> {
+        let target_library = create_test_target_library();
+        let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::PreferFile,
+        );
+        crate::ffmpeg_interface::ensure_ffmpeg_capable(&settings.target_filetype).unwrap();
+        let target = get_shadow_filename(
+            &song.library_relative_path,
+            &target_library,
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let mut u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
+        let encoder = u.encoder.as_mut().unwrap();
+        assert_eq!(encoder.encoder_name.as_deref(), Some("libmp3lame"));
+        encoder.encoder_name = Some("mp3".to_owned());
+        let mut db = PreviousSyncDb::default();
+        db.insert(song.library_relative_path.clone(), u);
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
+        assert_eq!(u2.update_type.unwrap(), UpdateType::Overwrite);
+
+        Ok(())
+    }
+
+    #[test]
+    /// The source did not change, but the quality did. Should be transcoded again.
+    fn sync_song_with_changed_quality() -> miette::Result<()> {
+        let target_library = create_test_target_library();
+        let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
+        let mut settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::PreferFile,
+        );
+        let target = get_shadow_filename(
+            &song.library_relative_path,
+            &target_library,
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
+        let db = {
+            let mut a = PreviousSyncDb::default();
+            a.insert(song.library_relative_path.clone(), u);
+            a
+        };
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
         assert_eq!(u2.update_type.unwrap(), UpdateType::NoChange);
 
+        settings.target_filetype = MusicFileType::Mp3VBR {
+            quality: 7,
+            id3: Id3Tags::default(),
+        };
+        let u3 = super::sync_song(&song, &target, &target_library, &settings, Some(&db), None)?;
+        assert_eq!(u3.update_type.unwrap(), UpdateType::Overwrite);
+
         Ok(())
     }
 
@@ -742,32 +1846,365 @@ mod tests {
     fn sync_existing_song_no_record() -> miette::Result<()> {
         let target_library = create_test_target_library();
         let song = Song::new_debug(TestFile::Rotterdam128kbpsMp3.path(), None)?;
-        let u = super::sync_song(
-            &song,
-            &target_library,
-            MusicFileType::Mp3VBR { quality: 6 },
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 6,
+                id3: Id3Tags::default(),
+            },
             ArtStrategy::PreferFile,
-            None,
-            false,
-            false,
-            None,
-            true,
-        )?;
+        );
+        let target = get_shadow_filename(
+            &song.library_relative_path,
+            &target_library,
+            &settings.target_filetype,
+            &settings.target_paths,
+        );
+        let u = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
         assert_eq!(u.update_type.unwrap(), UpdateType::NewTranscode);
 
-        let u2 = super::sync_song(
-            &song,
-            &target_library,
-            MusicFileType::Mp3VBR { quality: 6 },
-            ArtStrategy::PreferFile,
-            None,
+        let u2 = super::sync_song(&song, &target, &target_library, &settings, None, None)?;
+        assert_eq!(u2.update_type.unwrap(), UpdateType::NoChange);
+
+        Ok(())
+    }
+
+    #[test]
+    /// Hi-res songs are transcoded when the bit depth is reduced, even if they'd otherwise be
+    /// copied. 16-bit songs are left alone.
+    fn reduce_bit_depth_only_for_hi_res() {
+        let mut settings =
+            SyncSettings::new_debug(MusicFileType::Flac { quality: 8 }, ArtStrategy::None);
+        settings.reduce_bit_depth = true;
+        settings.dither = true;
+
+        let cd = Song::new_fake("cd.flac", &[]);
+        assert!(super::should_copy(&cd, false, &settings));
+
+        let mut hi_res = Song::new_fake("hi_res.flac", &[]);
+        hi_res.metadata.bit_depth = Some(24);
+        assert!(!super::should_copy(&hi_res, false, &settings));
+        let audio = super::audio_conversion(&hi_res, &settings);
+        assert!(audio.to_16_bit && audio.dither);
+
+        // Lossy targets don't have a bit depth.
+        settings.target_filetype = MusicFileType::Mp3VBR {
+            quality: 3,
+            id3: Id3Tags::default(),
+        };
+        assert!(!super::audio_conversion(&hi_res, &settings).to_16_bit);
+    }
+
+    #[test]
+    /// When not transcoding, songs are only remuxed if the embedded art has to change.
+    fn copy_mode_remuxes_only_for_art() {
+        let settings = SyncSettings::new_debug(MusicFileType::Copy, ArtStrategy::None);
+        let mut song = Song::new_fake("a.m4a", &[]);
+        song.metadata.bitrate_kbps = 2000;
+        assert!(super::should_copy(&song, false, &settings));
+        assert!(super::should_copy(&song, true, &settings));
+
+        song.external_album_art = Some(PathBuf::from("/source_library/cover.jpg"));
+        assert!(!super::should_copy(&song, true, &settings));
+
+        song.metadata.has_embedded_album_art = true;
+        assert!(super::should_copy(&song, true, &settings));
+        assert!(!super::should_copy(&song, false, &settings));
+    }
+
+    #[test]
+    /// Lossy songs are copied regardless of their bitrate, lossless ones are transcoded.
+    fn transcode_lossless_policy() {
+        let mut settings = SyncSettings::new_debug(
+            MusicFileType::Opus {
+                bitrate: 128,
+                compression_level: 10,
+                vbr: OpusVbr::On,
+            },
+            ArtStrategy::None,
+        );
+        settings.codec_policy = CodecPolicy::TranscodeLossless;
+        let song = |codec: Option<&str>, bitrate_kbps| {
+            let mut song = Song::new_fake("a", &[]);
+            song.metadata.codec = codec.map(|c| c.to_owned());
+            song.metadata.bitrate_kbps = bitrate_kbps;
+            song
+        };
+        assert!(super::should_copy(
+            &song(Some("mp3"), 320),
             false,
+            &settings
+        ));
+        assert!(super::should_copy(
+            &song(Some("aac"), 256),
             false,
-            None,
-            true,
-        )?;
-        assert_eq!(u2.update_type.unwrap(), UpdateType::NoChange);
+            &settings
+        ));
+        assert!(!super::should_copy(
+            &song(Some("flac"), 900),
+            false,
+            &settings
+        ));
+        assert!(!super::should_copy(
+            &song(Some("pcm_s16le"), 1411),
+            false,
+            &settings
+        ));
+        // Unknown codecs fall back to the bitrate.
+        assert!(!super::should_copy(&song(None, 320), false, &settings));
+        assert!(super::should_copy(&song(None, 96), false, &settings));
+        // Copied songs keep their extension, transcoded ones get the one of the target.
+        let mp3 = song(Some("mp3"), 320);
+        let flac = song(Some("flac"), 900);
+        assert_eq!(settings.shadow_filetype_for(&mp3), MusicFileType::Copy);
+        assert_eq!(
+            settings.shadow_filetype_for(&flac),
+            *settings.target_filetype_for(&flac)
+        );
+    }
+
+    #[test]
+    fn copy_threshold_overrides_target_bitrate() {
+        let mut settings = SyncSettings::new_debug(
+            MusicFileType::Mp3CBR {
+                bitrate: 128,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::None,
+        );
+        let mut song = Song::new_fake("a.mp3", &[]);
+        song.metadata.bitrate_kbps = 192;
+        assert!(!super::should_copy(&song, false, &settings));
+        settings.copy_threshold_kbps = Some(200);
+        assert!(super::should_copy(&song, false, &settings));
+        settings.copy_threshold_kbps = Some(0);
+        song.metadata.bitrate_kbps = 64;
+        assert!(!super::should_copy(&song, false, &settings));
+    }
+
+    #[test]
+    /// Tags to strip are removed from the target, so songs that have them can't be copied.
+    fn strip_tags() {
+        let mut settings = SyncSettings::new_debug(
+            MusicFileType::Mp3CBR {
+                bitrate: 320,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::None,
+        );
+        settings.strip_tags = vec!["comment".to_owned(), "replaygain_track_gain".to_owned()];
+        let mut song = Song::new_fake("a.mp3", &[("title", "A")]);
+        song.metadata.bitrate_kbps = 128;
+        assert!(super::should_copy(&song, false, &settings));
+        assert!(super::target_tags(&song, &settings).is_empty());
+
+        for (key, value) in [
+            ("comment", "Ripped by someone@example.com"),
+            ("replaygain_track_gain", "-3 dB"),
+        ] {
+            song.metadata.tags.insert(key.to_owned(), value.to_owned());
+        }
+        assert!(!super::should_copy(&song, false, &settings));
+        let tags = super::target_tags(&song, &settings);
+        assert!(tags.contains(&("comment".to_owned(), String::new())));
+        assert!(!tags.iter().any(
+            |(key, value)| key.eq_ignore_ascii_case("replaygain_track_gain") && !value.is_empty()
+        ));
+        assert_eq!(settings.tags_to_strip(&song).len(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn hardlink_copied_song() {
+        use super::copy_song;
+        use crate::music_library::LinkMode;
+        use std::os::unix::fs::MetadataExt;
+        let target_library = create_test_target_library();
+        let source = target_library.join("source.mp3");
+        std::fs::copy(TestFile::Mp3CBRWithoutArt.path(), &source).unwrap();
+        let shadow = target_library.join("shadow.mp3");
+        copy_song(&source, &shadow, LinkMode::Hardlink, None).unwrap();
+        assert_eq!(
+            std::fs::metadata(&shadow).unwrap().ino(),
+            std::fs::metadata(&source).unwrap().ino()
+        );
+
+        // Copying again must not write into the source through the link.
+        copy_song(&source, &shadow, LinkMode::Copy, None).unwrap();
+        assert_ne!(
+            std::fs::metadata(&shadow).unwrap().ino(),
+            std::fs::metadata(&source).unwrap().ino()
+        );
+    }
+
+    #[test]
+    /// A target with the modification time of its source is up to date, until the source
+    /// changes.
+    fn preserved_mtime() {
+        use super::{copy_file_times, has_source_changed_after_target_has_been_created};
+        use std::{
+            fs::{File, FileTimes},
+            time::{Duration, SystemTime},
+        };
+        let target_library = create_test_target_library();
+        let source = target_library.join("source.mp3");
+        let shadow = target_library.join("shadow.mp3");
+        std::fs::copy(TestFile::Mp3CBRWithoutArt.path(), &source).unwrap();
+        std::fs::copy(&source, &shadow).unwrap();
+        let set_mtime = |path: &PathBuf, mtime: SystemTime| {
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_times(FileTimes::new().set_modified(mtime))
+                .unwrap();
+        };
+        let last_year = SystemTime::now() - Duration::from_secs(365 * 24 * 60 * 60);
+        set_mtime(&source, last_year);
+
+        copy_file_times(&source, &shadow).unwrap();
+        let mtime = |path: &PathBuf| std::fs::metadata(path).unwrap().modified().unwrap();
+        assert_eq!(mtime(&shadow), last_year);
+        assert!(!has_source_changed_after_target_has_been_created(&source, &shadow, true).unwrap());
+
+        set_mtime(&source, SystemTime::now());
+        assert!(has_source_changed_after_target_has_been_created(&source, &shadow, true).unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// Copied songs get the extended attributes and permissions of their source.
+    fn preserved_xattrs() {
+        use super::copy_file_attributes;
+        use std::os::unix::fs::PermissionsExt;
+        let target_library = create_test_target_library();
+        let source = target_library.join("source.mp3");
+        let shadow = target_library.join("shadow.mp3");
+        std::fs::copy(TestFile::Mp3CBRWithoutArt.path(), &source).unwrap();
+        std::fs::copy(&source, &shadow).unwrap();
+        xattr::set(&source, "user.xdg.tags", b"favourite").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        copy_file_attributes(&source, &shadow).unwrap();
+        assert_eq!(
+            xattr::get(&shadow, "user.xdg.tags").unwrap().as_deref(),
+            Some(b"favourite".as_slice())
+        );
+        assert_eq!(
+            std::fs::metadata(&shadow).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// Identical songs are found, and linked to the shadow of the first one.
+    fn hardlink_duplicate_song() -> miette::Result<()> {
+        use crate::{hashing::find_duplicate_songs, music_library::SongDeduplication};
+        use std::os::unix::fs::MetadataExt;
+        let target_library = create_test_target_library();
+        let song = |path: &str| {
+            let mut song = Song::new_fake(path, &[]);
+            song.absolute_path = TestFile::Mp3CBRWithoutArt.path();
+            song
+        };
+        let songs = [song("Single/song.mp3"), song("Album/song.mp3")];
+        let duplicates = find_duplicate_songs(&songs);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates[&PathBuf::from("Single/song.mp3")],
+            PathBuf::from("Album/song.mp3")
+        );
 
+        let original_shadow = target_library.join("Album/song.mp3");
+        std::fs::create_dir_all(original_shadow.parent().unwrap()).unwrap();
+        std::fs::write(&original_shadow, "transcoded").unwrap();
+        let shadow = target_library.join("Single/song.mp3");
+        let mut settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::None,
+        );
+        settings.song_deduplication = Some(SongDeduplication::Hardlink);
+        let sync = || {
+            super::sync_duplicate_song(
+                &songs[0],
+                &shadow,
+                &original_shadow,
+                &target_library,
+                &settings,
+                None,
+                None,
+            )
+        };
+        assert_eq!(sync()?.update_type, Some(UpdateType::Duplicate));
+        assert_eq!(
+            std::fs::metadata(&shadow).unwrap().ino(),
+            std::fs::metadata(&original_shadow).unwrap().ino()
+        );
+        assert_eq!(sync()?.update_type, Some(UpdateType::NoChange));
         Ok(())
     }
+
+    #[test]
+    /// A song that can't be transcoded leaves nothing behind, also not the folders that were
+    /// made for it. Folders that were already there are kept.
+    fn failed_transcode_leaves_no_dirs() {
+        let target_library = create_test_target_library();
+        let source = target_library.join("not a song.flac");
+        std::fs::write(&source, b"not audio").unwrap();
+        let mut song = Song::new_fake("Artist/Album/01 Song.flac", &[]);
+        song.absolute_path = source;
+        let settings = SyncSettings::new_debug(
+            MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default(),
+            },
+            ArtStrategy::None,
+        );
+        let shadow = target_library.join("Artist/Album/01 Song.mp3");
+        let synced = super::sync_song(&song, &shadow, &target_library, &settings, None, None);
+        assert!(synced.is_err());
+        assert!(!target_library.join("Artist").exists());
+        assert!(target_library.exists());
+    }
+
+    #[test]
+    /// A song that can't be copied leaves nothing behind either.
+    fn failed_copy_leaves_no_dirs() {
+        let target_library = create_test_target_library();
+        let source = target_library.join("song.mp3");
+        std::fs::write(&source, b"not audio").unwrap();
+        let mut song = Song::new_fake("Artist/Album/01 Song.mp3", &[]);
+        song.absolute_path = source;
+        let settings = SyncSettings::new_debug(MusicFileType::Copy, ArtStrategy::None);
+        // The name is too long for the filesystem, so the folders can be made but the song
+        // can't be copied.
+        let shadow = target_library.join(format!("Artist/Album/{}.mp3", "a".repeat(300)));
+        let synced = super::sync_song(&song, &shadow, &target_library, &settings, None, None);
+        assert!(matches!(synced, Err(MusicLibraryError::CopySong { .. })));
+        assert!(!target_library.join("Artist").exists());
+        assert!(target_library.exists());
+    }
+
+    #[test]
+    /// No more songs are written than the limit, even when synchronising in parallel.
+    fn change_limit() {
+        use super::ChangeLimit;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let limit = ChangeLimit::new();
+        limit.set_max(3);
+        let written = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    if limit.take() {
+                        written.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(written.into_inner(), 3);
+    }
 }
@@ -0,0 +1,154 @@
+use crate::ffmpeg_interface::SongMetaData;
+use std::str::FromStr;
+
+/// A condition on a tag of a song, like `genre=Metal` or `album~live`, to select which songs
+/// are synchronised by their metadata instead of by where they are in the library.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagFilter {
+    /// Lowercase, like the keys of the tags.
+    tag: String,
+    value: String,
+    /// Whether the tag only has to contain the value (`~`), instead of being equal to it (`=`).
+    contains: bool,
+}
+
+impl TagFilter {
+    /// Whether the tag of the song matches. Comparisons ignore case. Tags with multiple values,
+    /// like "Rock; Metal", match if one of the values does. Songs without the tag never match.
+    pub fn matches(&self, metadata: &SongMetaData) -> bool {
+        let Some(tag) = tag_value(metadata, &self.tag) else {
+            return false;
+        };
+        let tag = tag.to_lowercase();
+        if self.contains {
+            return tag.contains(&self.value);
+        }
+        tag.split([';', '\0'])
+            .any(|value| value.trim() == self.value)
+    }
+}
+
+/// Uses the same fallbacks as the layout templates, so that e.g. `albumartist` also works for
+/// songs that only have an artist.
+fn tag_value<'a>(metadata: &'a SongMetaData, tag: &str) -> Option<&'a str> {
+    match tag {
+        "albumartist" | "album_artist" => metadata.album_artist(),
+        "artist" => metadata.artist(),
+        "album" => metadata.album(),
+        "title" => metadata.title.as_deref(),
+        "genre" => metadata.genre(),
+        "year" => metadata.year(),
+        tag => metadata.tag(&[tag]),
+    }
+}
+
+impl FromStr for TagFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(split) = s.find(['=', '~']) else {
+            return Err(format!(
+                "filter '{s}' should look like 'genre=Metal', or 'album~live' to match a part"
+            ));
+        };
+        let tag = s[..split].trim().to_lowercase();
+        let value = s[split + 1..].trim().to_lowercase();
+        if tag.is_empty() {
+            return Err(format!("filter '{s}' does not say which tag it is for"));
+        }
+        Ok(TagFilter {
+            tag,
+            value,
+            contains: s[split..].starts_with('~'),
+        })
+    }
+}
+
+/// What to do with songs that are not rated, when selecting songs by their rating.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum UnratedSongs {
+    Include,
+    Exclude,
+}
+
+/// Whether the song is rated highly enough to be synchronised.
+pub fn rated_at_least(metadata: &SongMetaData, min_rating: f64, unrated: UnratedSongs) -> bool {
+    match metadata.rating() {
+        Some(rating) => rating >= min_rating,
+        None => unrated == UnratedSongs::Include,
+    }
+}
+
+/// Whether the song is synchronised. It has to match a filter for every tag that is filtered
+/// on (any of them, if there are several for the same tag), and none of the exclusions.
+pub fn selected(metadata: &SongMetaData, filters: &[TagFilter], exclusions: &[TagFilter]) -> bool {
+    let all_tags_match = filters.iter().all(|filter| {
+        filters
+            .iter()
+            .filter(|other| other.tag == filter.tag)
+            .any(|other| other.matches(metadata))
+    });
+    all_tags_match
+        && !exclusions
+            .iter()
+            .any(|exclusion| exclusion.matches(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rated_at_least, selected, TagFilter, UnratedSongs};
+    use crate::song::Song;
+
+    #[test]
+    /// Filters on the same tag are alternatives, filters on different tags all have to match.
+    fn tag_filters() {
+        let song = Song::new_fake(
+            "song.flac",
+            &[("genre", "Rock; Metal"), ("album", "Live at Wacken")],
+        );
+        let audiobook = Song::new_fake("book.m4b", &[("genre", "Audiobook")]);
+        let filters = |filters: &[&str]| {
+            filters
+                .iter()
+                .map(|f| f.parse::<TagFilter>().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let metal = filters(&["genre=metal"]);
+        assert!(selected(&song.metadata, &metal, &[]));
+        assert!(!selected(&audiobook.metadata, &metal, &[]));
+        // Only whole values match with '='.
+        assert!(!selected(&song.metadata, &filters(&["genre=meta"]), &[]));
+
+        let either = filters(&["genre=Audiobook", "genre=Metal", "album~live"]);
+        assert!(selected(&song.metadata, &either, &[]));
+        assert!(!selected(&audiobook.metadata, &either, &[]));
+
+        let no_audiobooks = filters(&["genre=audiobook"]);
+        assert!(selected(&song.metadata, &[], &no_audiobooks));
+        assert!(!selected(&audiobook.metadata, &[], &no_audiobooks));
+
+        assert!("genre".parse::<TagFilter>().is_err());
+        assert!("=Metal".parse::<TagFilter>().is_err());
+    }
+
+    #[test]
+    /// Ratings on different scales are all read as stars.
+    fn rating_selection() {
+        let rated = |tag, value| Song::new_fake("song.mp3", &[(tag, value)]).metadata;
+        let liked = rated("popm:no@email", "196");
+        assert_eq!(liked.rating(), Some(4.));
+        assert!(rated_at_least(&liked, 3., UnratedSongs::Exclude));
+        assert!(!rated_at_least(
+            &rated("fmps_rating", "0.4"),
+            3.,
+            UnratedSongs::Include
+        ));
+        assert_eq!(rated("rating", "60").rating(), Some(3.));
+        assert_eq!(rated("popm:no@email", "0").rating(), None);
+
+        let unrated = rated("genre", "Metal");
+        assert!(rated_at_least(&unrated, 3., UnratedSongs::Include));
+        assert!(!rated_at_least(&unrated, 3., UnratedSongs::Exclude));
+    }
+}
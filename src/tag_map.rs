@@ -0,0 +1,226 @@
+use crate::{ffmpeg_interface::SongMetaData, music_library::MusicFileType};
+use itertools::Itertools;
+use std::{fs, path::Path, str::FromStr};
+
+/// Which tags of the source are written under which name in the target, for tags that ffmpeg
+/// would otherwise carry over under a name that players don't understand, like ALBUMARTIST
+/// ending up as a TXXX frame in an MP3. Written one per line, like:
+///
+/// ```text
+/// # For MP3 targets, write DISCNUMBER as the disc number (TPOS).
+/// mp3: discnumber -> disc
+/// # For all targets.
+/// *: album artist -> album_artist
+/// ```
+///
+/// On the left is the filetype of the target (mp3, opus, ogg or flac), or `*` for all of them.
+/// The target names are the ones ffmpeg uses, which it writes as the right frame or comment for
+/// the format. Songs that are not transcoded keep their tags as they are.
+#[derive(Clone, Debug)]
+pub struct TagMap {
+    mappings: Vec<TagMapping>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct TagMapping {
+    /// Like the `Display` of `MusicFileType`. None for all filetypes.
+    filetype: Option<String>,
+    /// Lowercase, like the keys of the tags.
+    source: String,
+    target: String,
+}
+
+/// The mappings that are always used, unless the tag map overrides them.
+const DEFAULT_MAPPINGS: &str = "
+    *: albumartist -> album_artist
+    *: album artist -> album_artist
+    *: tracknumber -> track
+    *: discnumber -> disc
+    *: year -> date
+    *: organization -> publisher
+    *: label -> publisher
+";
+
+impl Default for TagMap {
+    fn default() -> Self {
+        DEFAULT_MAPPINGS
+            .parse()
+            .expect("The default tag mappings are valid")
+    }
+}
+
+impl TagMap {
+    /// Adds the mappings of `other`, which take precedence over these.
+    pub fn extend(&mut self, other: TagMap) {
+        self.mappings.splice(0..0, other.mappings);
+    }
+
+    /// The tags to set in the target to map the tags of the source. Tags that are mapped to a
+    /// different name get an empty value, which removes them. Tags in `skip` are not mapped.
+    pub fn map(
+        &self,
+        metadata: &SongMetaData,
+        filetype: &MusicFileType,
+        skip: &[String],
+    ) -> Vec<(String, String)> {
+        if matches!(filetype, MusicFileType::Copy) {
+            return Vec::new();
+        }
+        let filetype = filetype.to_string();
+        let mut tags = Vec::<(String, String)>::new();
+        let is_set = |tags: &[(String, String)], key: &str| {
+            metadata.tags.contains_key(key) || tags.iter().any(|(k, v)| k == key && !v.is_empty())
+        };
+        let sources = metadata
+            .tags
+            .iter()
+            .filter(|(source, _)| !skip.contains(source))
+            .sorted();
+        for (source, value) in sources {
+            // The first mapping for the tag wins.
+            let Some(mapping) = self.mappings.iter().find(|mapping| {
+                mapping.source == *source
+                    && mapping.filetype.as_ref().is_none_or(|f| *f == filetype)
+            }) else {
+                continue;
+            };
+            if mapping.target.eq_ignore_ascii_case(source) {
+                continue;
+            }
+            // Both could be there, like ALBUMARTIST and ALBUM_ARTIST.
+            if !is_set(&tags, &mapping.target) {
+                tags.push((mapping.target.clone(), value.clone()));
+            }
+            tags.push((source.clone(), String::new()));
+        }
+        tags.sort();
+        tags
+    }
+}
+
+/// Reads the mappings from a file, on top of the default ones. For use as a clap value parser.
+pub fn read_tag_map_file(path: &str) -> Result<TagMap, String> {
+    let text = fs::read_to_string(Path::new(path))
+        .map_err(|e| format!("could not read tag map file '{path}': {e}"))?;
+    let mut map = TagMap::default();
+    map.extend(text.parse()?);
+    Ok(map)
+}
+
+impl FromStr for TagMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mappings = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mapping = line
+                .parse::<TagMapping>()
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            mappings.push(mapping);
+        }
+        Ok(TagMap { mappings })
+    }
+}
+
+impl FromStr for TagMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mapping_error = || format!("mapping '{s}' should look like 'mp3: discnumber -> disc'");
+        let (filetype, mapping) = s.split_once(':').ok_or_else(mapping_error)?;
+        let (source, target) = mapping.split_once("->").ok_or_else(mapping_error)?;
+        let filetype = match filetype.trim().to_lowercase().as_str() {
+            "*" => None,
+            filetype @ ("mp3" | "opus" | "ogg" | "flac") => Some(filetype.to_owned()),
+            filetype => {
+                return Err(format!(
+                    "unknown filetype '{filetype}' in mapping '{s}', it should be mp3, opus, \
+                    ogg, flac or *"
+                ))
+            }
+        };
+        let (source, target) = (source.trim().to_lowercase(), target.trim().to_owned());
+        if source.is_empty() || target.is_empty() {
+            return Err(mapping_error());
+        }
+        Ok(TagMapping {
+            filetype,
+            source,
+            target,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TagMap;
+    use crate::{
+        music_library::{Id3Tags, MusicFileType},
+        song::Song,
+    };
+
+    #[test]
+    fn tag_mapping() {
+        let mut map = TagMap::default();
+        map.extend(
+            "
+            # Comments and empty lines are skipped.
+
+            mp3: discnumber -> TPOS
+            FLAC: comment -> description
+            "
+            .parse()
+            .unwrap(),
+        );
+        let mp3 = MusicFileType::Mp3VBR {
+            quality: 2,
+            id3: Id3Tags::default(),
+        };
+        let song = Song::new_fake(
+            "a.flac",
+            &[
+                ("albumartist", "Band"),
+                ("album_artist", "The Band"),
+                ("discnumber", "2"),
+                ("comment", "Ripped"),
+                ("title", "Song"),
+            ],
+        );
+        let owned = |tags: &[(&str, &str)]| {
+            tags.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        // The album artist that is already there is kept.
+        assert_eq!(
+            map.map(&song.metadata, &mp3, &[]),
+            owned(&[("TPOS", "2"), ("albumartist", ""), ("discnumber", "")])
+        );
+        assert_eq!(
+            map.map(&song.metadata, &MusicFileType::Flac { quality: 8 }, &[]),
+            owned(&[
+                ("albumartist", ""),
+                ("comment", ""),
+                ("description", "Ripped"),
+                ("disc", "2"),
+                ("discnumber", ""),
+            ])
+        );
+        assert!(map
+            .map(&song.metadata, &MusicFileType::Copy, &[])
+            .is_empty());
+        // Stripped tags are not carried over under another name either.
+        assert_eq!(
+            map.map(&song.metadata, &mp3, &["discnumber".to_owned()]),
+            owned(&[("albumartist", "")])
+        );
+
+        for invalid in ["discnumber -> disc", "wav: a -> b", "mp3: -> disc"] {
+            assert!(invalid.parse::<TagMap>().is_err(), "'{invalid}' parsed");
+        }
+    }
+}
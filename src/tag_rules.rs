@@ -0,0 +1,269 @@
+use crate::ffmpeg_interface::SongMetaData;
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
+
+/// Rules that clean up the tags of the synchronised songs, without changing the source files.
+/// Written one per line, like:
+///
+/// ```text
+/// # Find and replace in a tag.
+/// genre: Hip Hop -> Hip-Hop
+/// # Move "(feat. Someone)" from the title to the artist.
+/// feat-to-artist
+/// # Capitalise the words of a tag.
+/// title-case title
+/// ```
+///
+/// The rules are applied in order, so later rules see the changes of earlier ones.
+#[derive(Clone, Debug, Default)]
+pub struct TagRules {
+    rules: Vec<TagRule>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TagRule {
+    /// Tags are lowercase, like the keys of the tags.
+    Replace {
+        tag: String,
+        find: String,
+        replace: String,
+    },
+    FeatToArtist,
+    TitleCase {
+        tag: String,
+    },
+}
+
+/// How a featured artist is written in a title. Lowercase, and all ASCII so the positions are
+/// the same as in the title.
+const FEAT_MARKERS: &[&str] = &[
+    "(feat. ",
+    "[feat. ",
+    "(ft. ",
+    "[ft. ",
+    "(featuring ",
+    "[featuring ",
+    " feat. ",
+    " ft. ",
+    " featuring ",
+];
+
+/// Words that are not capitalised by `title-case`, unless they are the first or last word.
+const MINOR_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "but", "by", "for", "in", "nor", "of", "on", "or", "the", "to",
+    "vs.",
+];
+
+impl TagRules {
+    /// The tags that the rules change, with their new values. Empty if nothing changes.
+    pub fn rewrite(&self, metadata: &SongMetaData) -> Vec<(String, String)> {
+        if self.rules.is_empty() {
+            return Vec::new();
+        }
+        let original = |tag: &str| match tag {
+            "title" => metadata.title.as_deref().or(metadata.tag(&[tag])),
+            tag => metadata.tag(&[tag]),
+        };
+        let mut changed = BTreeMap::<String, String>::new();
+        for rule in &self.rules {
+            let current = |changed: &BTreeMap<String, String>, tag: &str| {
+                changed
+                    .get(tag)
+                    .map(String::as_str)
+                    .or_else(|| original(tag))
+                    .map(str::to_owned)
+            };
+            match rule {
+                TagRule::Replace { tag, find, replace } => {
+                    if let Some(value) = current(&changed, tag).filter(|v| v.contains(find)) {
+                        changed.insert(tag.clone(), value.replace(find, replace));
+                    }
+                }
+                TagRule::FeatToArtist => {
+                    let (Some(title), Some(artist)) =
+                        (current(&changed, "title"), current(&changed, "artist"))
+                    else {
+                        continue;
+                    };
+                    let Some((title, featured)) = split_feat(&title) else {
+                        continue;
+                    };
+                    changed.insert("title".to_owned(), title);
+                    if !artist.to_lowercase().contains(&featured.to_lowercase()) {
+                        changed.insert("artist".to_owned(), format!("{artist} feat. {featured}"));
+                    }
+                }
+                TagRule::TitleCase { tag } => {
+                    if let Some(value) = current(&changed, tag) {
+                        changed.insert(tag.clone(), title_case(&value));
+                    }
+                }
+            }
+        }
+        changed
+            .into_iter()
+            .filter(|(tag, value)| original(tag) != Some(value.as_str()))
+            .collect()
+    }
+}
+
+/// Splits "Title (feat. Someone)" into the title and who is featured.
+fn split_feat(title: &str) -> Option<(String, String)> {
+    let lowercase = title.to_ascii_lowercase();
+    let (start, marker) = FEAT_MARKERS
+        .iter()
+        .filter_map(|marker| Some((lowercase.find(marker)?, *marker)))
+        .min_by_key(|(start, _)| *start)?;
+    let rest = &title[start + marker.len()..];
+    let (featured, after) = match marker.chars().next() {
+        Some('(') => rest.split_once(')').unwrap_or((rest, "")),
+        Some('[') => rest.split_once(']').unwrap_or((rest, "")),
+        // Up to a bracket, as in "Title feat. Someone (Remix)".
+        _ => match rest.find(['(', '[']) {
+            Some(end) => (&rest[..end], &rest[end..]),
+            None => (rest, ""),
+        },
+    };
+    let featured = featured.trim();
+    if featured.is_empty() {
+        return None;
+    }
+    let title = format!("{} {}", title[..start].trim_end(), after.trim());
+    Some((title.trim().to_owned(), featured.to_owned()))
+}
+
+/// Capitalises the words, except for minor words like "of" and "the" in the middle. Words that
+/// already have capitals in them, like "AC/DC" or "iPhone", are left as they are.
+fn title_case(value: &str) -> String {
+    let words = value.split(' ').collect::<Vec<_>>();
+    let last = words.len().saturating_sub(1);
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let lowercase = word.to_lowercase();
+            if i != 0 && i != last && MINOR_WORDS.contains(&lowercase.as_str()) {
+                return lowercase;
+            }
+            if *word != lowercase {
+                return word.to_string();
+            }
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reads the rules from a file. For use as a clap value parser.
+pub fn read_tag_rules_file(path: &str) -> Result<TagRules, String> {
+    let text = fs::read_to_string(Path::new(path))
+        .map_err(|e| format!("could not read tag rules file '{path}': {e}"))?;
+    text.parse()
+}
+
+impl FromStr for TagRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line
+                .parse::<TagRule>()
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            rules.push(rule);
+        }
+        Ok(TagRules { rules })
+    }
+}
+
+impl FromStr for TagRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("feat-to-artist") {
+            return Ok(TagRule::FeatToArtist);
+        }
+        if let Some(tag) = s.strip_prefix("title-case ") {
+            return Ok(TagRule::TitleCase {
+                tag: tag.trim().to_lowercase(),
+            });
+        }
+        let rule_error = || {
+            format!(
+                "rule '{s}' should look like 'genre: Hip Hop -> Hip-Hop', 'feat-to-artist' or \
+                'title-case title'"
+            )
+        };
+        let (tag, replacement) = s.split_once(':').ok_or_else(rule_error)?;
+        let (find, replace) = replacement.split_once("->").ok_or_else(rule_error)?;
+        let (tag, find) = (tag.trim().to_lowercase(), find.trim());
+        if tag.is_empty() || find.is_empty() {
+            return Err(rule_error());
+        }
+        Ok(TagRule::Replace {
+            tag,
+            find: find.to_owned(),
+            replace: replace.trim().to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TagRules;
+    use crate::song::Song;
+
+    #[test]
+    fn tag_rules() {
+        let rules: TagRules = "
+            # Comments and empty lines are skipped.
+
+            genre: Hip Hop -> Hip-Hop
+            feat-to-artist
+            title-case title
+        "
+        .parse()
+        .unwrap();
+        let rewrite = |tags: &[(&str, &str)]| {
+            let mut changes = rules.rewrite(&Song::new_fake("a.flac", tags).metadata);
+            changes.sort();
+            changes
+        };
+        let owned = |changes: &[(&str, &str)]| {
+            changes
+                .iter()
+                .map(|(tag, value)| (tag.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            rewrite(&[
+                ("title", "return of the king (feat. Someone) [remix]"),
+                ("artist", "Band"),
+                ("genre", "Hip Hop"),
+            ]),
+            owned(&[
+                ("artist", "Band feat. Someone"),
+                ("genre", "Hip-Hop"),
+                ("title", "Return of the King [remix]"),
+            ])
+        );
+        // Already featured in the artist, and already capitalised.
+        assert_eq!(
+            rewrite(&[("title", "Song ft. Someone"), ("artist", "Band & Someone"),]),
+            owned(&[("title", "Song")])
+        );
+        assert!(rewrite(&[("title", "AC/DC in the iPhone Age"), ("genre", "Rock")]).is_empty());
+
+        for invalid in ["genre Hip Hop", ": a -> b", "genre: -> Rock"] {
+            assert!(invalid.parse::<TagRules>().is_err(), "'{invalid}' parsed");
+        }
+    }
+}
@@ -0,0 +1,941 @@
+use crate::{
+    music_library::{get_shadow_filename, MusicFileType},
+    path_template::PathTemplate,
+    song::Song,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Component, Path, PathBuf},
+};
+use unicode_normalization::UnicodeNormalization;
+
+/// How paths in the target library should be shaped. Unless a layout is given, these are
+/// applied on top of the source-library-relative path, so the target library stays a mirror of
+/// the source library.
+#[derive(Clone, Debug, Default)]
+pub struct TargetPathOptions {
+    /// Put songs in the target library according to their tags, instead of mirroring the
+    /// structure of the source library.
+    pub layout: Option<PathTemplate>,
+    /// Layout for songs on compilation albums, which have songs from various artists. A layout
+    /// based on the artist would otherwise scatter them across the target library.
+    pub compilation_layout: Option<PathTemplate>,
+    /// Get rid of the folder structure, for devices that can't browse nested folders.
+    pub flatten: Option<Flatten>,
+    /// Put the songs in disc folders of albums, like "CD1" and "CD2", in the album folder itself.
+    pub merge_discs: Option<MergeDiscs>,
+    /// Maximum length of a single file or directory name, in bytes. None means no limit.
+    pub max_component_bytes: Option<usize>,
+    /// Maximum length of the full path (including the target library itself), in characters.
+    /// None means no limit.
+    pub max_path_length: Option<usize>,
+    /// Unicode normalisation form to write all names in. None leaves names as they are.
+    pub unicode_normalization: Option<NormalizationForm>,
+    /// Replace all non-ASCII characters in names by an ASCII approximation, e.g. "Björk" becomes
+    /// "Bjork". Tags inside the files are not touched.
+    pub transliterate_to_ascii: bool,
+    /// Replace the characters that FAT32 and exFAT (and so most devices) don't allow in names,
+    /// and the trailing dots and spaces that they drop.
+    pub fat_safe: bool,
+    /// What to do when multiple songs would end up at the same place in the target library.
+    pub on_collision: CollisionResolution,
+    /// The target library is on a filesystem that does not tell upper and lower case apart
+    /// (FAT, NTFS, APFS by default), so "Remix.mp3" and "remix.mp3" are the same file there.
+    pub case_insensitive: bool,
+}
+
+/// Different songs can end up at the same place in the target library, e.g. "Song.flac" and
+/// "Song.mp3" both become "Song.opus". Only one of them can be there.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug, Default)]
+pub enum CollisionResolution {
+    /// All but the first song (in alphabetical order) get a number added to their name, like
+    /// "Song (2).opus".
+    #[default]
+    Suffix,
+    /// Only the first song (in alphabetical order) is synchronised, the others are skipped.
+    Skip,
+    /// Don't synchronise anything, so the source library can be fixed first.
+    Error,
+}
+
+/// Songs that would all end up at the same place in the target library.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collision {
+    /// Where the songs would end up.
+    pub shadow: PathBuf,
+    /// Library relative paths of the songs, in alphabetical order. The first one gets the spot.
+    pub songs: Vec<PathBuf>,
+}
+
+/// The same name can be represented by different sequences of unicode codepoints. Linux leaves
+/// names as they are given, but macOS filesystems store them decomposed. Syncing between the two
+/// makes the same file look like two different files, unless everything is normalised to the
+/// same form.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum NormalizationForm {
+    /// Canonical composition. "é" is stored as a single codepoint. What most systems use.
+    Nfc,
+    /// Canonical decomposition. "é" is stored as "e" followed by a combining accent. What macOS
+    /// filesystems use.
+    Nfd,
+}
+
+/// How to flatten the folder structure of the target library.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum Flatten {
+    /// Put all songs directly in the target library.
+    All,
+    /// Put all songs in a folder named after their (album) artist, without any further nesting.
+    Artist,
+}
+
+/// How to merge the disc folders of an album, like "CD1" and "CD2" or "Disc 1", into the album
+/// folder, so the songs don't end up in nested folders.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, Debug)]
+pub enum MergeDiscs {
+    /// Put the disc number in front of the file names: "Album/CD2/03 Song.mp3" becomes
+    /// "Album/2-03 Song.mp3".
+    Prefix,
+    /// Number the songs of all the discs in a row: "Album/CD2/03 Song.mp3" becomes
+    /// "Album/15 Song.mp3" if the first disc has 12 songs.
+    Renumber,
+}
+
+impl TargetPathOptions {
+    /// Whether songs are placed based on their tags, rather than mirroring the source library.
+    pub fn uses_layout(&self) -> bool {
+        self.layout.is_some() || self.compilation_layout.is_some()
+    }
+
+    /// The layout that applies to this specific song, if any.
+    fn layout_for(&self, song: &Song) -> Option<&PathTemplate> {
+        if song.metadata.is_compilation() {
+            self.compilation_layout.as_ref().or(self.layout.as_ref())
+        } else {
+            self.layout.as_ref()
+        }
+    }
+}
+
+impl Flatten {
+    /// Turns the nested path into a flat one, by joining all the folder names into the file
+    /// name: "Artist/Album/01 Song.mp3" becomes "Artist - Album - 01 Song.mp3". As every source
+    /// path is unique, so is every flattened name.
+    fn apply(&self, song: &Song, path: &Path) -> PathBuf {
+        let mut names = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        match self {
+            Flatten::All => names.join(" - ").into(),
+            Flatten::Artist => {
+                let artist = song
+                    .metadata
+                    .album_artist()
+                    .unwrap_or("Unknown Artist")
+                    .replace(['/', '\\'], "_");
+                // Mirrored libraries usually already start with the artist folder. No need to
+                // repeat it in every file name.
+                if names.len() > 1 && names[0] == artist {
+                    names.remove(0);
+                }
+                Path::new(&artist).join(names.join(" - "))
+            }
+        }
+    }
+}
+
+impl MergeDiscs {
+    /// Moves the songs in disc folders up into the album folder. Which songs are on an album
+    /// depends on the others, so this is done for all the paths at once. Paths that are not in
+    /// a disc folder are left as they are.
+    fn apply(&self, paths: &mut [PathBuf]) {
+        // Per album folder: the disc number, file name and index of each song on it.
+        let mut albums = BTreeMap::<PathBuf, Vec<(u32, String, usize)>>::new();
+        for (i, path) in paths.iter().enumerate() {
+            let (Some(disc_folder), Some(file_name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+            let Some(disc) = disc_folder
+                .file_name()
+                .and_then(|name| disc_number(&name.to_string_lossy()))
+            else {
+                continue;
+            };
+            let album = disc_folder.parent().unwrap_or(Path::new("")).to_path_buf();
+            let file_name = file_name.to_string_lossy().into_owned();
+            albums.entry(album).or_default().push((disc, file_name, i));
+        }
+        for (album, mut songs) in albums {
+            songs.sort();
+            let width = songs.len().to_string().len().max(2);
+            for (n, (disc, file_name, i)) in songs.into_iter().enumerate() {
+                let file_name = match self {
+                    MergeDiscs::Prefix => format!("{disc}-{file_name}"),
+                    MergeDiscs::Renumber => {
+                        let path = Path::new(&file_name);
+                        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                        // The old track number is usually at the start: "03 Song" or "03. Song".
+                        let title = stem
+                            .trim_start_matches(|c: char| c.is_ascii_digit())
+                            .trim_start_matches([' ', '-', '.', '_']);
+                        let mut name = format!("{:0width$}", n + 1);
+                        if !title.is_empty() {
+                            name.push(' ');
+                            name.push_str(title);
+                        }
+                        if let Some(extension) = path.extension() {
+                            name.push('.');
+                            name.push_str(&extension.to_string_lossy());
+                        }
+                        name
+                    }
+                };
+                paths[i] = album.join(file_name);
+            }
+        }
+    }
+}
+
+/// The number of the disc, if this is the name of a disc folder, like "CD1", "Disc 2" or
+/// "Disk 3 - Bonus".
+pub(crate) fn disc_number(folder_name: &str) -> Option<u32> {
+    let lowercase = folder_name.to_lowercase();
+    let rest = ["cd", "disc", "disk"]
+        .iter()
+        .find_map(|prefix| lowercase.strip_prefix(prefix))?
+        .trim_start_matches([' ', '-', '_', '.']);
+    let digits_end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (digits, after) = rest.split_at(digits_end);
+    if !after.is_empty() && !after.starts_with([' ', '-', '_', '(', '[']) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+impl NormalizationForm {
+    /// Normalises every name in the path. Paths that are not valid unicode are left untouched.
+    pub fn normalize_path(&self, path: &Path) -> PathBuf {
+        let Some(s) = path.to_str() else {
+            return path.to_path_buf();
+        };
+        match self {
+            NormalizationForm::Nfc => s.nfc().collect::<String>().into(),
+            NormalizationForm::Nfd => s.nfd().collect::<String>().into(),
+        }
+    }
+}
+
+/// Where in the target library the file with the given library relative path should go.
+/// Applies all the requested transformations.
+pub fn target_relative_path(
+    library_relative_path: &Path,
+    target_library: &Path,
+    options: &TargetPathOptions,
+) -> PathBuf {
+    let normalized = match options.unicode_normalization {
+        Some(form) => form.normalize_path(library_relative_path),
+        None => library_relative_path.to_path_buf(),
+    };
+    let transliterated = if options.transliterate_to_ascii {
+        transliterate_path(&normalized)
+    } else {
+        normalized
+    };
+    let transliterated = if options.fat_safe {
+        fat_safe_path(&transliterated)
+    } else {
+        transliterated
+    };
+    // Normalising can change the length of a name, so only enforce the limits afterwards.
+    enforce_path_limits(&transliterated, target_library, options)
+}
+
+/// Where every song ends up in the target library. Keys are the library relative paths of the
+/// songs in the source library, values are the full paths of their shadows.
+pub type TargetPlan = HashMap<PathBuf, PathBuf>;
+
+/// Works out where every song should go in the target library. If multiple songs end up at
+/// the same location (e.g. "Café.mp3" and "Cafe.mp3" when transliterating), all but the first
+/// (in alphabetical order of their source paths) get a number added to their name, or are left
+/// out of the plan when skipping collisions, so that the outcome is the same every run.
+/// All collisions are returned as well, so they can be reported.
+pub fn plan_target_paths(
+    songs: &[Song],
+    target_library: &Path,
+    // Rules can give songs different target filetypes.
+    filetype_for: impl Fn(&Song) -> MusicFileType,
+    options: &TargetPathOptions,
+) -> (TargetPlan, Vec<Collision>) {
+    let mut sorted = songs.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.library_relative_path.cmp(&b.library_relative_path));
+
+    // Which song got which spot. Keyed on how the filesystem compares names, so names that
+    // only differ in case are seen as the same spot when the target does not tell them apart.
+    let key = |shadow: &Path| {
+        if options.case_insensitive {
+            PathBuf::from(shadow.to_string_lossy().to_lowercase())
+        } else {
+            shadow.to_path_buf()
+        }
+    };
+    let mut taken = HashMap::<PathBuf, (&Path, PathBuf)>::with_capacity(sorted.len());
+    let mut collisions = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    let mut plan = TargetPlan::with_capacity(sorted.len());
+    let mut layout_paths = sorted
+        .iter()
+        .map(|song| match options.layout_for(song) {
+            Some(template) => template.render(song),
+            None => song.library_relative_path.clone(),
+        })
+        .collect::<Vec<_>>();
+    if let Some(merge_discs) = options.merge_discs {
+        merge_discs.apply(&mut layout_paths);
+    }
+    for (song, layout_path) in sorted.into_iter().zip(layout_paths) {
+        let layout_path = match options.flatten {
+            Some(flatten) => flatten.apply(song, &layout_path),
+            None => layout_path,
+        };
+        let filetype = filetype_for(song);
+        let mut shadow = get_shadow_filename(&layout_path, target_library, &filetype, options);
+        if let Some((owner, owner_shadow)) = taken.get(&key(&shadow)) {
+            collisions
+                .entry(owner_shadow.clone())
+                .or_insert_with(|| vec![owner.to_path_buf()])
+                .push(song.library_relative_path.clone());
+            if options.on_collision == CollisionResolution::Skip {
+                continue;
+            }
+        }
+        let mut n = 2;
+        while taken.contains_key(&key(&shadow)) {
+            shadow = get_shadow_filename(
+                &with_number_suffix(&layout_path, n),
+                target_library,
+                &filetype,
+                options,
+            );
+            n += 1;
+        }
+        taken.insert(key(&shadow), (&song.library_relative_path, shadow.clone()));
+        plan.insert(song.library_relative_path.clone(), shadow);
+    }
+    let collisions = collisions
+        .into_iter()
+        .map(|(shadow, songs)| Collision { shadow, songs })
+        .collect();
+    (plan, collisions)
+}
+
+/// "Artist/Song.mp3" becomes "Artist/Song (n).mp3"
+fn with_number_suffix(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{stem} ({n})");
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
+
+/// Replaces every name in the path by an ASCII-only approximation.
+fn transliterate_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(transliterate_name(&s.to_string_lossy())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn transliterate_name(name: &str) -> String {
+    if name.is_ascii() {
+        return name.to_owned();
+    }
+    let transliterated = deunicode::deunicode_with_tofu(name, "_")
+        // Some symbols are transliterated into something that is not allowed in a file name.
+        .replace(['/', '\\', ':', '*', '?', '"', '<', '>', '|'], "_")
+        .trim()
+        .to_owned();
+    if transliterated.is_empty() || transliterated.chars().all(|c| c == '.') {
+        "_".to_owned()
+    } else {
+        transliterated
+    }
+}
+
+/// Makes every name in the path one that FAT32 and exFAT can store as it is.
+fn fat_safe_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(fat_safe_name(&s.to_string_lossy())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn fat_safe_name(name: &str) -> String {
+    let replaced = name
+        .chars()
+        .map(|c| match c {
+            '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // These would be dropped by the filesystem, so the file would not be found again.
+    let trimmed = replaced.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        "_".to_owned()
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+/// Names are never shortened to less than this many characters (excluding the extension),
+/// because at some point it becomes impossible to recognise what the file was.
+const MIN_SHORTENED_STEM_CHARS: usize = 16;
+
+/// Shortens the library-relative path so that it fits the limits. Every name that has to be
+/// shortened gets a short hash of its original name appended, so two long names that only
+/// differ at the end do not end up as the same shortened name.
+pub fn enforce_path_limits(
+    library_relative_path: &Path,
+    target_library: &Path,
+    options: &TargetPathOptions,
+) -> PathBuf {
+    let mut components = library_relative_path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let last = components.len().saturating_sub(1);
+
+    if let Some(max_bytes) = options.max_component_bytes {
+        for (i, component) in components.iter_mut().enumerate() {
+            if component.len() > max_bytes {
+                *component = shorten_name(component, i == last, max_bytes, |s| s.len());
+            }
+        }
+    }
+
+    if let Some(max_length) = options.max_path_length {
+        // The target library itself can't be shortened, so it eats into what is available.
+        // The +1 is for the separator between each component.
+        let prefix_length = target_library.as_os_str().to_string_lossy().chars().count();
+        let total_length = |components: &[String]| {
+            prefix_length
+                + components
+                    .iter()
+                    .map(|c| c.chars().count() + 1)
+                    .sum::<usize>()
+        };
+        // Keep shortening the longest name until it fits, or until nothing can be shortened
+        // any further.
+        while total_length(&components) > max_length {
+            let excess = total_length(&components) - max_length;
+            let Some((i, longest)) = components
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, c)| c.chars().count())
+            else {
+                break;
+            };
+            let current_length = longest.chars().count();
+            let desired_length = current_length.saturating_sub(excess);
+            let shortened = shorten_name(longest, i == last, desired_length, |s| s.chars().count());
+            if shortened.chars().count() >= current_length {
+                break;
+            }
+            components[i] = shortened;
+        }
+    }
+
+    components.iter().collect()
+}
+
+/// Shortens a single file or directory name to at most `max_length`, as measured by `measure`.
+/// The extension of files is kept intact. Cuts are only made on character boundaries.
+fn shorten_name(
+    name: &str,
+    is_file: bool,
+    max_length: usize,
+    measure: fn(&str) -> usize,
+) -> String {
+    let (stem, extension) = match (is_file, name.rfind('.')) {
+        (true, Some(i)) if i > 0 => name.split_at(i),
+        _ => (name, ""),
+    };
+    let suffix = format!("~{:06x}", rapidhash::rapidhash(name.as_bytes()) & 0xff_ffff);
+
+    let available = max_length
+        .saturating_sub(measure(extension) + measure(&suffix))
+        .max(MIN_SHORTENED_STEM_CHARS);
+    let mut truncated = String::new();
+    for c in stem.chars() {
+        let mut candidate = truncated.clone();
+        candidate.push(c);
+        if measure(&candidate) > available {
+            break;
+        }
+        truncated = candidate;
+    }
+    // Windows does not like names that end in a space or a dot.
+    let truncated = truncated.trim_end_matches([' ', '.']);
+    format!("{truncated}{suffix}{extension}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        enforce_path_limits, plan_target_paths, target_relative_path, Collision,
+        CollisionResolution, Flatten, MergeDiscs, NormalizationForm, TargetPathOptions,
+    };
+    use crate::{
+        music_library::{Id3Tags, MusicFileType, OpusVbr},
+        song::Song,
+    };
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    /// Paths within the limits should not be touched at all.
+    fn short_path_is_unchanged() {
+        let options = TargetPathOptions {
+            max_component_bytes: Some(255),
+            max_path_length: Some(260),
+            ..Default::default()
+        };
+        let p = Path::new("Artist/Album/01 Song.mp3");
+        assert_eq!(enforce_path_limits(p, Path::new("/music"), &options), p);
+    }
+
+    #[test]
+    /// Long file names get cut, but keep their extension.
+    fn long_filename_keeps_extension() {
+        let options = TargetPathOptions {
+            max_component_bytes: Some(40),
+            max_path_length: None,
+            ..Default::default()
+        };
+        let name = format!("Artist/Album/{}.opus", "a".repeat(100));
+        let shortened = enforce_path_limits(Path::new(&name), Path::new("/music"), &options);
+        let file_name = shortened.file_name().unwrap().to_str().unwrap();
+        assert!(file_name.len() <= 40, "{file_name} is too long");
+        assert!(file_name.ends_with(".opus"));
+        assert!(shortened.starts_with("Artist/Album"));
+    }
+
+    #[test]
+    /// Two long names that only differ at the end should not be shortened to the same name.
+    fn shortened_names_stay_unique() {
+        let options = TargetPathOptions {
+            max_component_bytes: Some(40),
+            max_path_length: None,
+            ..Default::default()
+        };
+        let a = format!("{} part 1.mp3", "a".repeat(100));
+        let b = format!("{} part 2.mp3", "a".repeat(100));
+        assert_ne!(
+            enforce_path_limits(Path::new(&a), Path::new("/music"), &options),
+            enforce_path_limits(Path::new(&b), Path::new("/music"), &options)
+        );
+    }
+
+    #[test]
+    /// Multi-byte characters should never be cut in half.
+    fn shortening_respects_char_boundaries() {
+        let options = TargetPathOptions {
+            max_component_bytes: Some(41),
+            max_path_length: None,
+            ..Default::default()
+        };
+        let name = format!("{}.mp3", "ö".repeat(100));
+        let shortened = enforce_path_limits(Path::new(&name), Path::new("/music"), &options);
+        assert!(shortened.to_str().unwrap().len() <= 41);
+    }
+
+    #[test]
+    /// The full path should be made to fit, including the target library.
+    fn total_path_length_is_enforced() {
+        let options = TargetPathOptions {
+            max_component_bytes: None,
+            max_path_length: Some(100),
+            ..Default::default()
+        };
+        let target_library = PathBuf::from("/media/phone/Music");
+        let name = format!(
+            "{}/{}/{}.mp3",
+            "b".repeat(60),
+            "c".repeat(60),
+            "d".repeat(60)
+        );
+        let shortened = enforce_path_limits(Path::new(&name), &target_library, &options);
+        let full = target_library.join(&shortened);
+        assert!(
+            full.to_str().unwrap().chars().count() <= 100,
+            "{}",
+            full.display()
+        );
+        assert!(full.to_str().unwrap().ends_with(".mp3"));
+    }
+
+    #[test]
+    /// Composed and decomposed versions of the same name should end up identical.
+    fn normalization_makes_forms_identical() {
+        let composed = Path::new("Bj\u{f6}rk/Hom\u{e9}genic/J\u{f3}ga.flac");
+        let decomposed = Path::new("Bjo\u{308}rk/Home\u{301}genic/Jo\u{301}ga.flac");
+        assert_ne!(composed, decomposed);
+        for form in [NormalizationForm::Nfc, NormalizationForm::Nfd] {
+            assert_eq!(
+                form.normalize_path(composed),
+                form.normalize_path(decomposed)
+            );
+        }
+        assert_eq!(NormalizationForm::Nfc.normalize_path(decomposed), composed);
+    }
+
+    #[test]
+    /// Non-ASCII names should be turned into something readable, leaving ASCII names alone.
+    fn transliteration_to_ascii() {
+        let options = TargetPathOptions {
+            transliterate_to_ascii: true,
+            ..Default::default()
+        };
+        let transliterated = target_relative_path(
+            Path::new("Björk/Молчат Дома/Судно.mp3"),
+            Path::new("/music"),
+            &options,
+        );
+        let s = transliterated.to_str().unwrap();
+        assert!(s.is_ascii(), "{s} is not ASCII");
+        assert!(s.starts_with("Bjork/"));
+        assert!(s.ends_with(".mp3"));
+        let p = Path::new("Artist/Album/01 Song.mp3");
+        assert_eq!(target_relative_path(p, Path::new("/music"), &options), p);
+    }
+
+    #[test]
+    /// Names with characters that FAT32 can't store are made storable, others are left alone.
+    fn fat_safe_names() {
+        let options = TargetPathOptions {
+            fat_safe: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            target_relative_path(
+                Path::new("Who? Me./Best of: 1999 .../\"Hits\" <live>.mp3"),
+                Path::new("/music"),
+                &options,
+            ),
+            Path::new("Who_ Me/Best of_ 1999/_Hits_ _live_.mp3")
+        );
+        let p = Path::new("Björk/Post/01 Army of Me.mp3");
+        assert_eq!(target_relative_path(p, Path::new("/music"), &options), p);
+    }
+
+    #[test]
+    /// Names that end up identical get a number, in a way that does not depend on the order the
+    /// songs were found in.
+    fn plan_resolves_collisions_deterministically() {
+        let options = TargetPathOptions {
+            transliterate_to_ascii: true,
+            ..Default::default()
+        };
+        let target_library = Path::new("/music");
+        let filetype = MusicFileType::Mp3VBR {
+            quality: 3,
+            id3: Id3Tags::default(),
+        };
+        let a = Song::new_fake("Album/Café.flac", &[]);
+        let b = Song::new_fake("Album/Cafe.mp3", &[]);
+        let (plan, _) = plan_target_paths(&[a, b], target_library, |_| filetype.clone(), &options);
+        let a = Song::new_fake("Album/Café.flac", &[]);
+        let b = Song::new_fake("Album/Cafe.mp3", &[]);
+        let (reversed, _) =
+            plan_target_paths(&[b, a], target_library, |_| filetype.clone(), &options);
+        assert_eq!(plan, reversed);
+        assert_eq!(
+            plan[Path::new("Album/Cafe.mp3")],
+            target_library.join("Album/Cafe.mp3")
+        );
+        assert_eq!(
+            plan[Path::new("Album/Café.flac")],
+            target_library.join("Album/Cafe (2).mp3")
+        );
+    }
+
+    #[test]
+    /// Songs that only differ in their extension end up at the same place once transcoded. All
+    /// of them are reported, and when skipping only the first one stays in the plan.
+    fn plan_reports_and_skips_collisions() {
+        let target_library = Path::new("/music");
+        let filetype = MusicFileType::Mp3VBR {
+            quality: 3,
+            id3: Id3Tags::default(),
+        };
+        let songs = || {
+            [
+                Song::new_fake("Album/Song.mp3", &[]),
+                Song::new_fake("Album/Song.flac", &[]),
+                Song::new_fake("Album/Other.flac", &[]),
+            ]
+        };
+        let expected = vec![Collision {
+            shadow: target_library.join("Album/Song.mp3"),
+            songs: vec!["Album/Song.flac".into(), "Album/Song.mp3".into()],
+        }];
+
+        let (plan, collisions) = plan_target_paths(
+            &songs(),
+            target_library,
+            |_| filetype.clone(),
+            &TargetPathOptions::default(),
+        );
+        assert_eq!(collisions, expected);
+        assert_eq!(plan.len(), 3);
+
+        let options = TargetPathOptions {
+            on_collision: CollisionResolution::Skip,
+            ..Default::default()
+        };
+        let (plan, collisions) =
+            plan_target_paths(&songs(), target_library, |_| filetype.clone(), &options);
+        assert_eq!(collisions, expected);
+        assert_eq!(plan.len(), 2);
+        assert_eq!(
+            plan[Path::new("Album/Song.flac")],
+            target_library.join("Album/Song.mp3")
+        );
+        assert!(!plan.contains_key(Path::new("Album/Song.mp3")));
+    }
+
+    #[test]
+    /// Names that only differ in case are the same file on case-insensitive filesystems.
+    fn plan_detects_case_only_collisions() {
+        let target_library = Path::new("/music");
+        let filetype = MusicFileType::Mp3VBR {
+            quality: 3,
+            id3: Id3Tags::default(),
+        };
+        let songs = [
+            Song::new_fake("Album/Remix.mp3", &[]),
+            Song::new_fake("album/remix.mp3", &[]),
+        ];
+        let (_, collisions) = plan_target_paths(
+            &songs,
+            target_library,
+            |_| filetype.clone(),
+            &TargetPathOptions::default(),
+        );
+        assert!(collisions.is_empty());
+
+        let options = TargetPathOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let (plan, collisions) =
+            plan_target_paths(&songs, target_library, |_| filetype.clone(), &options);
+        assert_eq!(
+            collisions,
+            vec![Collision {
+                shadow: target_library.join("Album/Remix.mp3"),
+                songs: vec!["Album/Remix.mp3".into(), "album/remix.mp3".into()],
+            }]
+        );
+        assert_eq!(
+            plan[Path::new("album/remix.mp3")],
+            target_library.join("album/remix (2).mp3")
+        );
+    }
+
+    #[test]
+    /// With a layout, songs are placed according to their tags.
+    fn plan_with_layout() {
+        let options = TargetPathOptions {
+            layout: Some("{albumartist}/{album}/{track:02} {title}".parse().unwrap()),
+            ..Default::default()
+        };
+        let target_library = Path::new("/music");
+        let song = Song::new_fake(
+            "downloads/unsorted/track.flac",
+            &[
+                ("artist", "Daft Punk"),
+                ("album", "Discovery"),
+                ("title", "One More Time"),
+                ("track", "1"),
+            ],
+        );
+        let (plan, _) = plan_target_paths(
+            &[song],
+            target_library,
+            |_| MusicFileType::Opus {
+                bitrate: 128,
+                compression_level: 10,
+                vbr: OpusVbr::Off,
+            },
+            &options,
+        );
+        assert_eq!(
+            plan[Path::new("downloads/unsorted/track.flac")],
+            target_library.join("Daft Punk/Discovery/01 One More Time.opus")
+        );
+    }
+
+    #[test]
+    /// Flattening joins the folders into the file name, or keeps just the artist folder.
+    fn plan_flattened() {
+        let target_library = Path::new("/music");
+        let filetype = MusicFileType::Mp3VBR {
+            quality: 3,
+            id3: Id3Tags::default(),
+        };
+        let songs = || {
+            [
+                Song::new_fake("Queen/A Night at the Opera/01 Death on Two Legs.flac", &[]),
+                Song::new_fake(
+                    "Queen/A Night at the Opera/11 Bohemian Rhapsody.flac",
+                    &[("album_artist", "Queen")],
+                ),
+            ]
+        };
+
+        let options = TargetPathOptions {
+            flatten: Some(Flatten::All),
+            ..Default::default()
+        };
+        let (plan, _) = plan_target_paths(&songs(), target_library, |_| filetype.clone(), &options);
+        assert_eq!(
+            plan[Path::new("Queen/A Night at the Opera/01 Death on Two Legs.flac")],
+            target_library.join("Queen - A Night at the Opera - 01 Death on Two Legs.mp3")
+        );
+
+        let options = TargetPathOptions {
+            flatten: Some(Flatten::Artist),
+            ..Default::default()
+        };
+        let (plan, _) = plan_target_paths(&songs(), target_library, |_| filetype.clone(), &options);
+        assert_eq!(
+            plan[Path::new("Queen/A Night at the Opera/01 Death on Two Legs.flac")],
+            target_library
+                .join("Unknown Artist/Queen - A Night at the Opera - 01 Death on Two Legs.mp3")
+        );
+        assert_eq!(
+            plan[Path::new("Queen/A Night at the Opera/11 Bohemian Rhapsody.flac")],
+            target_library.join("Queen/A Night at the Opera - 11 Bohemian Rhapsody.mp3")
+        );
+    }
+
+    #[test]
+    /// Songs in disc folders end up in the album folder, with the disc in their name or
+    /// numbered in a row.
+    fn plan_with_merged_discs() {
+        let target_library = Path::new("/music");
+        let filetype = MusicFileType::Flac { quality: 8 };
+        let songs = [
+            Song::new_fake("Album/CD1/01 Intro.flac", &[]),
+            Song::new_fake("Album/CD1/02 Song.flac", &[]),
+            Song::new_fake("Album/Disc 2 - Live/01. Encore.flac", &[]),
+            Song::new_fake("Discography/01 Single.flac", &[]),
+        ];
+        let planned = |merge_discs| {
+            let options = TargetPathOptions {
+                merge_discs: Some(merge_discs),
+                ..Default::default()
+            };
+            let (plan, _) =
+                plan_target_paths(&songs, target_library, |_| filetype.clone(), &options);
+            songs
+                .iter()
+                .map(|song| plan[&song.library_relative_path].clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            planned(MergeDiscs::Prefix),
+            [
+                "/music/Album/1-01 Intro.flac",
+                "/music/Album/1-02 Song.flac",
+                "/music/Album/2-01. Encore.flac",
+                "/music/Discography/01 Single.flac",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            planned(MergeDiscs::Renumber),
+            [
+                "/music/Album/01 Intro.flac",
+                "/music/Album/02 Song.flac",
+                "/music/Album/03 Encore.flac",
+                "/music/Discography/01 Single.flac",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    /// Compilations get their own layout, the rest uses the normal one.
+    fn plan_with_compilation_layout() {
+        let options = TargetPathOptions {
+            layout: Some("{albumartist}/{album}/{title}".parse().unwrap()),
+            compilation_layout: Some("Compilations/{album}/{artist} - {title}".parse().unwrap()),
+            ..Default::default()
+        };
+        let target_library = Path::new("/music");
+        let songs = [
+            Song::new_fake(
+                "a.flac",
+                &[
+                    ("artist", "Nirvana"),
+                    ("album", "Nevermind"),
+                    ("title", "Lithium"),
+                ],
+            ),
+            Song::new_fake(
+                "b.flac",
+                &[
+                    ("artist", "Blur"),
+                    ("album", "Now 35"),
+                    ("title", "Song 2"),
+                    ("compilation", "1"),
+                ],
+            ),
+            Song::new_fake(
+                "c.flac",
+                &[
+                    ("artist", "Pulp"),
+                    ("album_artist", "Various Artists"),
+                    ("album", "Now 35"),
+                    ("title", "Disco 2000"),
+                ],
+            ),
+        ];
+        let (plan, _) = plan_target_paths(
+            &songs,
+            target_library,
+            |_| MusicFileType::Mp3VBR {
+                quality: 3,
+                id3: Id3Tags::default(),
+            },
+            &options,
+        );
+        assert_eq!(
+            plan[Path::new("a.flac")],
+            target_library.join("Nirvana/Nevermind/Lithium.mp3")
+        );
+        assert_eq!(
+            plan[Path::new("b.flac")],
+            target_library.join("Compilations/Now 35/Blur - Song 2.mp3")
+        );
+        assert_eq!(
+            plan[Path::new("c.flac")],
+            target_library.join("Compilations/Now 35/Pulp - Disco 2000.mp3")
+        );
+    }
+}
@@ -10,6 +10,8 @@ pub enum TestFile {
     M4aWithoutArt,
     OggWithArt,
     OggWithoutArt,
+    WavWithoutArt,
+    AiffWithoutArt,
     Jpg600,
     Rotterdam128kbpsMp3,
     Rotterdam128kbpsM4a,
@@ -35,6 +37,8 @@ impl TestFile {
             TestFile::M4aWithoutArt => "no_art.m4a",
             TestFile::OggWithArt => "with_art.ogg",
             TestFile::OggWithoutArt => "no_art.ogg",
+            TestFile::WavWithoutArt => "no_art.wav",
+            TestFile::AiffWithoutArt => "no_art.aiff",
             TestFile::Jpg600 => "cover_art.jpg",
             TestFile::Rotterdam128kbpsMp3 => "ns_rotterdam_128kbps.mp3",
             TestFile::Rotterdam128kbpsM4a => "ns_rotterdam_128kbps.m4a",
@@ -0,0 +1,234 @@
+use crate::{music_library::MusicFileType, song::Song};
+use clap::Parser;
+use std::{fs, path::Path, str::FromStr};
+
+/// Rules that pick the target filetype per song, so e.g. FLAC files can become Opus, while MP3s
+/// are copied as they are. Written one per line, like:
+///
+/// ```text
+/// # FLAC files become small Opus files.
+/// flac -> opus --bitrate 128
+/// mp3 >=256k -> opus --bitrate 128
+/// mp3 <256k -> copy
+/// m4b -> copy
+/// ```
+///
+/// On the left is the extension of the source file (or `*` for any file), optionally with a
+/// condition on the bitrate of the source. On the right is the target filetype, written like it
+/// is on the command line. The first rule that matches is used. Songs that don't match any rule
+/// use the target filetype given on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct TranscodeRules {
+    rules: Vec<TranscodeRule>,
+}
+
+#[derive(Clone, Debug)]
+struct TranscodeRule {
+    /// Lowercase, without dot. None matches any extension.
+    extension: Option<String>,
+    bitrate: Option<BitrateCondition>,
+    target: MusicFileType,
+}
+
+/// Condition on the bitrate of the source, in kbps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BitrateCondition {
+    AtLeast(u32),
+    Below(u32),
+}
+
+impl BitrateCondition {
+    fn matches(&self, bitrate_kbps: u32) -> bool {
+        match self {
+            BitrateCondition::AtLeast(min) => bitrate_kbps >= *min,
+            BitrateCondition::Below(max) => bitrate_kbps < *max,
+        }
+    }
+}
+
+impl TranscodeRule {
+    fn matches(&self, song: &Song) -> bool {
+        let extension_matches = self.extension.as_ref().is_none_or(|extension| {
+            song.library_relative_path
+                .extension()
+                .is_some_and(|e| e.to_string_lossy().to_lowercase() == *extension)
+        });
+        extension_matches
+            && self
+                .bitrate
+                .is_none_or(|condition| condition.matches(song.metadata.bitrate_kbps))
+    }
+}
+
+impl TranscodeRules {
+    /// The target filetype of the first rule that matches the song, or the default if there is
+    /// none.
+    pub fn target_filetype_for<'a>(
+        &'a self,
+        song: &Song,
+        default: &'a MusicFileType,
+    ) -> &'a MusicFileType {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(song))
+            .map_or(default, |rule| &rule.target)
+    }
+
+    /// All the target filetypes that the rules can pick.
+    pub fn target_filetypes(&self) -> impl Iterator<Item = &MusicFileType> {
+        self.rules.iter().map(|rule| &rule.target)
+    }
+}
+
+/// Reads the rules from a file. For use as a clap value parser.
+pub fn read_rules_file(path: &str) -> Result<TranscodeRules, String> {
+    let text = fs::read_to_string(Path::new(path))
+        .map_err(|e| format!("could not read rules file '{path}': {e}"))?;
+    text.parse()
+}
+
+/// To parse the target filetype like the command line does.
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct RuleTarget {
+    #[command(subcommand)]
+    filetype: MusicFileType,
+}
+
+impl FromStr for TranscodeRules {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = line
+                .parse::<TranscodeRule>()
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            rules.push(rule);
+        }
+        Ok(TranscodeRules { rules })
+    }
+}
+
+impl FromStr for TranscodeRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((condition, target)) = s.split_once("->").or_else(|| s.split_once('→')) else {
+            return Err(format!(
+                "rule '{s}' should look like 'flac -> opus --bitrate 128'"
+            ));
+        };
+        let mut condition = condition.split_whitespace();
+        let extension = match condition.next() {
+            Some("*") => None,
+            Some(extension) => Some(extension.trim_start_matches('.').to_lowercase()),
+            None => return Err(format!("rule '{s}' does not say which files it is for")),
+        };
+        let bitrate = condition.next().map(parse_bitrate_condition).transpose()?;
+        if let Some(extra) = condition.next() {
+            return Err(format!("unexpected '{extra}' in rule '{s}'"));
+        }
+        let target = parse_target_filetype(target)
+            .map_err(|e| format!("invalid target filetype in rule '{s}': {e}"))?;
+        Ok(TranscodeRule {
+            extension,
+            bitrate,
+            target,
+        })
+    }
+}
+
+/// Parses a target filetype written like it is on the command line, like "opus --bitrate 32".
+/// For use as a clap value parser.
+pub fn parse_target_filetype(s: &str) -> Result<MusicFileType, String> {
+    RuleTarget::try_parse_from(s.split_whitespace())
+        .map(|target| target.filetype)
+        .map_err(|e| e.to_string())
+}
+
+/// Like ">=256k" or "<256k".
+fn parse_bitrate_condition(s: &str) -> Result<BitrateCondition, String> {
+    let parse = |number: &str| {
+        number
+            .trim_end_matches(['k', 'K'])
+            .parse::<u32>()
+            .map_err(|_| format!("invalid bitrate '{number}', it should look like '256k'"))
+    };
+    if let Some(number) = s.strip_prefix(">=") {
+        Ok(BitrateCondition::AtLeast(parse(number)?))
+    } else if let Some(number) = s.strip_prefix('<') {
+        Ok(BitrateCondition::Below(parse(number)?))
+    } else {
+        Err(format!(
+            "invalid bitrate condition '{s}', it should look like '>=256k' or '<256k'"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TranscodeRules;
+    use crate::{music_library::MusicFileType, song::Song};
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules: TranscodeRules = "
+            # Comments and empty lines are skipped.
+
+            flac -> opus --bitrate 128
+            mp3 >=256k -> opus --bitrate 128
+            MP3 <256k → copy
+            * -> mp3-vbr --quality 2
+        "
+        .parse()
+        .unwrap();
+        let default = MusicFileType::Flac { quality: 10 };
+        let target = |path: &str, bitrate_kbps| {
+            let mut song = Song::new_fake(path, &[]);
+            song.metadata.bitrate_kbps = bitrate_kbps;
+            rules.target_filetype_for(&song, &default).clone()
+        };
+        assert!(matches!(
+            target("a.flac", 900),
+            MusicFileType::Opus { bitrate: 128, .. }
+        ));
+        assert!(matches!(
+            target("a.mp3", 320),
+            MusicFileType::Opus { bitrate: 128, .. }
+        ));
+        assert!(matches!(target("a.MP3", 192), MusicFileType::Copy));
+        assert!(matches!(
+            target("a.m4a", 256),
+            MusicFileType::Mp3VBR { quality: 2, .. }
+        ));
+
+        let no_rules = TranscodeRules::default();
+        let song = Song::new_fake("a.mp3", &[]);
+        assert!(matches!(
+            no_rules.target_filetype_for(&song, &default),
+            MusicFileType::Flac { quality: 10 }
+        ));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rules in [
+            "flac opus",
+            "-> copy",
+            "flac >256k -> copy",
+            "flac <lots -> copy",
+            "flac -> aac",
+            "flac <256k extra -> copy",
+        ] {
+            assert!(
+                rules.parse::<TranscodeRules>().is_err(),
+                "'{rules}' should not parse"
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    ffmpeg_interface::{decode_check, FfmpegError},
+    hashing::{hash_file, read_records_of_previous_sync, RecordsFormat},
+    log_failure,
+    music_library::{identify_file_type, FileType, MusicLibraryError},
+    song::Song,
+    sqlite_records::SqliteRecords,
+};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// Decode-tests every music file in a target library, to find files that got corrupted or
+/// truncated (e.g. by a full disk or a device that was unplugged too early). If there are
+/// records of the last sync, files that were changed since are found as well.
+#[derive(clap::Parser)]
+#[command(bin_name = "syncbops verify", version)]
+pub struct VerifyCli {
+    /// The target library to check.
+    target_library: PathBuf,
+
+    /// Display more info.
+    #[arg(short, long, default_value_t = false)]
+    pub verbose: bool,
+
+    /// Maximum amount of threads to use. If no value given, will use all threads.
+    #[arg(short, long)]
+    thread_count: Option<usize>,
+}
+
+/// Why a file in the target library can't be trusted.
+#[derive(thiserror::Error, Debug)]
+pub enum VerifyError {
+    #[error("can't be decoded cleanly: {0}")]
+    Undecodable(#[from] FfmpegError),
+
+    #[error("was changed since it was synchronised")]
+    Modified,
+}
+
+/// Checks the whole target library. Returns the files that can't be decoded cleanly, or that
+/// were changed outside of syncbops.
+pub fn verify_library(cli: &VerifyCli) -> Result<Vec<(PathBuf, VerifyError)>, MusicLibraryError> {
+    if !cli.target_library.is_dir() {
+        return Err(MusicLibraryError::NotADirectory {
+            path: cli.target_library.clone(),
+        });
+    }
+    if let Some(x) = cli.thread_count {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(x)
+            .build_global()
+            .unwrap_or_else(|_| panic!("Cannot set amount of threads to {}. Exiting.", x));
+    }
+
+    // Hashes of the files as they were written, keyed on their path in the target library.
+    // Whatever format they were stored in.
+    let records = match SqliteRecords::read_only(&cli.target_library)? {
+        Some(records) => Some(records),
+        None => read_records_of_previous_sync(&cli.target_library, RecordsFormat::Binary, false),
+    };
+    let target_hashes = records
+        .map(|db| {
+            db.into_values()
+                .filter_map(|record| Some((record.target_relative_path?, record.target_hash?)))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    println!("Discovering files in {}", cli.target_library.display());
+    let files = music_files_in(&cli.target_library);
+    println!("Decoding {} music files...", files.len());
+    let pb = ProgressBar::new(files.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed}] [{bar:60.cyan/blue}] {pos}/{len} [ETA: {eta}] {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    let mut failures = files
+        .par_iter()
+        .progress_with(pb.clone())
+        .filter_map(|file| {
+            let relative = file.strip_prefix(&cli.target_library).unwrap_or(file);
+            pb.set_message(format!("{}", relative.display()));
+            let result = decode_check(file)
+                .map_err(VerifyError::from)
+                .and_then(|()| match target_hashes.get(relative) {
+                    Some(hash) if hash_file(file) != Some(*hash) => Err(VerifyError::Modified),
+                    _ => Ok(()),
+                });
+            match result {
+                Ok(()) => {
+                    if cli.verbose {
+                        pb.println(format!("OK: {}", relative.display()));
+                    }
+                    None
+                }
+                Err(e) => {
+                    log_failure(format!("Broken: {}", relative.display()), Some(&pb));
+                    Some((relative.to_path_buf(), e))
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    pb.finish();
+    failures.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(failures)
+}
+
+fn music_files_in(library: &Path) -> Vec<PathBuf> {
+    WalkDir::new(library)
+        .into_iter()
+        .filter_map(|direntry_res| match direntry_res {
+            Ok(entry) => Some(entry.into_path()),
+            Err(e) => {
+                eprintln!("Could not read subdir in library: {e}");
+                None
+            }
+        })
+        .filter(|path| identify_file_type(path) == Some(FileType::Music))
+        .collect()
+}
+
+/// Decode-tests the songs in the source library, so corrupt ones can be skipped up front instead
+/// of failing halfway through with an unclear ffmpeg error. Returns the songs that are fine, and
+/// the ones that are not.
+pub fn check_source_songs(songs: Vec<Song>) -> (Vec<Song>, Vec<(Song, FfmpegError)>) {
+    let pb = ProgressBar::new(songs.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed}] [{bar:60.cyan/blue}] {pos}/{len} [ETA: {eta}] {msg}")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    let errors = songs
+        .par_iter()
+        .progress_with(pb.clone())
+        .map(|song| {
+            pb.set_message(format!("{}", song.library_relative_path.display()));
+            decode_check(&song.absolute_path).err()
+        })
+        .collect::<Vec<_>>();
+    pb.finish();
+
+    let mut fine = Vec::with_capacity(songs.len());
+    let mut corrupt = Vec::new();
+    for (song, error) in songs.into_iter().zip(errors) {
+        match error {
+            Some(e) => corrupt.push((song, e)),
+            None => fine.push(song),
+        }
+    }
+    (fine, corrupt)
+}
+
+/// Prints which files are broken, and why.
+pub fn summarize_verification(failures: &[(PathBuf, VerifyError)], verbose: bool) {
+    if failures.is_empty() {
+        println!("All music files could be decoded without errors.");
+        return;
+    }
+    println!(
+        "{} music files are broken or were changed. Delete them and synchronise again to replace them:",
+        failures.len()
+    );
+    for (path, e) in failures {
+        if verbose {
+            println!("\t- {}: {}", path.display(), e);
+        } else {
+            println!("\t- {}", path.display());
+        }
+    }
+}